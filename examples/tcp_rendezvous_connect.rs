@@ -136,9 +136,9 @@ fn main() {
                     DummyDebug(Framed::new(relay_stream).map(|bytes| bytes.freeze()));
                 TcpStream::rendezvous_connect(relay_channel, &handle, &mc)
                     .map_err(|e| panic!("rendezvous connect failed: {}", e))
-                    .and_then(|(stream, _our_pub_addr)| {
+                    .and_then(|conn| {
                         println!("connected!");
-                        tokio_io::io::write_all(stream, message)
+                        tokio_io::io::write_all(conn.stream, message)
                             .map_err(|e| panic!("error writing to tcp stream: {}", e))
                             .and_then(|(stream, _)| {
                                 unwrap!(stream.shutdown(Shutdown::Write));
@@ -138,8 +138,9 @@ fn main() {
                     DummyDebug(Framed::new(relay_stream).map(|bytes| bytes.freeze()));
                 UdpSocket::rendezvous_connect(relay_channel, &handle, &mc)
                     .map_err(|e| panic!("rendezvous connect failed: {}", e))
-                    .and_then(|(socket, addr, _our_public_addr)| {
+                    .and_then(|conn| {
                         println!("connected!");
+                        let (socket, addr) = (conn.socket, conn.their_addr);
                         socket
                             .send_dgram(message, addr)
                             .map_err(|e| panic!("error writing to udp socket: {}", e))
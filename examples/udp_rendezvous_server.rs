@@ -25,14 +25,17 @@ fn main() {
     let res = core.run({
         UdpRendezvousServer::bind_public(&addr!("0.0.0.0:0"), &handle, &mc)
             .map_err(|e| panic!("Error binding server publicly: {}", e))
-            .and_then(|(server, public_addr)| {
-                println!("listening on public socket address {}", public_addr);
+            .and_then(|(server, mapping)| {
+                println!(
+                    "listening on public socket address {}",
+                    mapping.external_addr()
+                );
                 println!(
                     "our public key is: {}",
                     unwrap!(serde_json::to_string(&server.public_key()))
                 );
 
-                future::empty().map(|()| drop(server))
+                future::empty().map(|()| drop((server, mapping)))
             })
     });
     unwrap!(res);
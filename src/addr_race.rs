@@ -0,0 +1,195 @@
+//! Our public address can be found out either by asking the router to map a port via IGD or by
+//! asking traversal servers which address they see us on. Waiting for one before trying the other
+//! adds the whole IGD timeout on networks without a responsive router, so both run concurrently.
+
+use igd_async::GetAnyAddressError;
+use priv_prelude::*;
+
+/// Results of address discovery methods that finished by the time `race_igd()` resolved.
+#[derive(Debug)]
+pub struct RaceOutcome<I, S, E> {
+    /// IGD result, `None` if IGD was still running.
+    pub igd: Option<Result<I, GetAnyAddressError>>,
+    /// Traversal servers result, `None` if servers were still being queried.
+    pub servers: Option<Result<S, E>>,
+}
+
+/// Runs IGD and traversal server queries concurrently. Resolves as soon as IGD succeeds or servers
+/// give a result that `is_trusted` accepts. Otherwise waits for both to finish.
+///
+/// If IGD is still running when servers win, it's left to finish in the background, so that its
+/// gateway search results get cached for later attempts. The address IGD gives then is unused and
+/// handed to `discard_igd`, which should remove the mapping.
+pub fn race_igd<I, S, E, D>(
+    igd: BoxFuture<I, GetAnyAddressError>,
+    servers: BoxFuture<S, E>,
+    is_trusted: fn(&S) -> bool,
+    discard_igd: D,
+    handle: &Handle,
+) -> BoxFuture<RaceOutcome<I, S, E>, Void>
+where
+    I: 'static,
+    S: 'static,
+    E: 'static,
+    D: FnOnce(I) + 'static,
+{
+    RaceIgd {
+        igd: Some(igd),
+        servers: Some(servers),
+        igd_res: None,
+        servers_res: None,
+        is_trusted,
+        discard_igd: Some(discard_igd),
+        handle: handle.clone(),
+    }.into_boxed()
+}
+
+struct RaceIgd<I, S, E, D> {
+    igd: Option<BoxFuture<I, GetAnyAddressError>>,
+    servers: Option<BoxFuture<S, E>>,
+    igd_res: Option<Result<I, GetAnyAddressError>>,
+    servers_res: Option<Result<S, E>>,
+    is_trusted: fn(&S) -> bool,
+    discard_igd: Option<D>,
+    handle: Handle,
+}
+
+impl<I: 'static, S, E, D: FnOnce(I) + 'static> Future for RaceIgd<I, S, E, D> {
+    type Item = RaceOutcome<I, S, E>;
+    type Error = Void;
+
+    fn poll(&mut self) -> Result<Async<RaceOutcome<I, S, E>>, Void> {
+        if let Some(res) = poll_unfinished(&mut self.igd) {
+            self.igd_res = Some(res);
+        }
+        if let Some(res) = poll_unfinished(&mut self.servers) {
+            self.servers_res = Some(res);
+        }
+
+        let igd_won = match self.igd_res {
+            Some(Ok(_)) => true,
+            _ => false,
+        };
+        let servers_won = match self.servers_res {
+            Some(Ok(ref res)) => (self.is_trusted)(res),
+            _ => false,
+        };
+        let all_finished = self.igd.is_none() && self.servers.is_none();
+        if !igd_won && !servers_won && !all_finished {
+            return Ok(Async::NotReady);
+        }
+
+        if let Some(igd) = self.igd.take() {
+            trace!("traversal servers won the race, leaving IGD to finish in the background");
+            let discard_igd = self.discard_igd.take();
+            self.handle.spawn(igd.then(move |res| -> Result<(), ()> {
+                match res {
+                    Ok(igd_res) => {
+                        if let Some(discard_igd) = discard_igd {
+                            discard_igd(igd_res);
+                        }
+                    }
+                    Err(e) => trace!("IGD failed after traversal servers responded: {}", e),
+                }
+                Ok(())
+            }));
+        }
+        Ok(Async::Ready(RaceOutcome {
+            igd: self.igd_res.take(),
+            servers: self.servers_res.take(),
+        }))
+    }
+}
+
+/// Polls `future` unless it has already finished. Returns its result once it's ready.
+fn poll_unfinished<T, E>(future: &mut Option<BoxFuture<T, E>>) -> Option<Result<T, E>> {
+    let res = match future.as_mut()?.poll() {
+        Ok(Async::Ready(item)) => Ok(item),
+        Ok(Async::NotReady) => return None,
+        Err(e) => Err(e),
+    };
+    *future = None;
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::oneshot;
+    use tokio_core::reactor::Core;
+
+    fn is_trusted(addr: &(SocketAddr, bool)) -> bool {
+        addr.1
+    }
+
+    fn keep(_addr: SocketAddr) {}
+
+    mod race_igd {
+        use super::*;
+
+        #[test]
+        fn when_igd_succeeds_it_does_not_wait_for_servers() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let igd = future::ok(addr!("1.2.3.4:5000")).into_boxed();
+            let servers = future::empty::<(SocketAddr, bool), ()>().into_boxed();
+            let outcome = unwrap!(core.run(race_igd(igd, servers, is_trusted, keep, &handle)));
+
+            assert_eq!(unwrap!(unwrap!(outcome.igd)), addr!("1.2.3.4:5000"));
+            assert!(outcome.servers.is_none());
+        }
+
+        #[test]
+        fn when_servers_give_trusted_result_it_does_not_wait_for_igd() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let igd = future::empty::<SocketAddr, _>().into_boxed();
+            let servers = future::ok::<_, ()>((addr!("1.2.3.4:5000"), true)).into_boxed();
+            let outcome = unwrap!(core.run(race_igd(igd, servers, is_trusted, keep, &handle)));
+
+            assert!(outcome.igd.is_none());
+            assert_eq!(unwrap!(unwrap!(outcome.servers)).0, addr!("1.2.3.4:5000"));
+        }
+
+        #[test]
+        fn when_servers_give_untrusted_result_it_waits_for_igd() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let igd = Timeout::new(Duration::from_millis(100), &handle)
+                .infallible()
+                .and_then(|()| -> Result<SocketAddr, _> { Err(GetAnyAddressError::NoGateway) })
+                .into_boxed();
+            let servers = future::ok::<_, ()>((addr!("1.2.3.4:5001"), false)).into_boxed();
+            let outcome = unwrap!(core.run(race_igd(igd, servers, is_trusted, keep, &handle)));
+
+            match outcome.igd {
+                Some(Err(GetAnyAddressError::NoGateway)) => (),
+                res => panic!("unexpected IGD result: {:?}", res),
+            }
+            assert_eq!(unwrap!(unwrap!(outcome.servers)).0, addr!("1.2.3.4:5001"));
+        }
+
+        #[test]
+        fn when_igd_finishes_after_servers_won_its_address_is_discarded() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let igd = Timeout::new(Duration::from_millis(100), &handle)
+                .infallible()
+                .map(|()| addr!("1.2.3.4:5000"))
+                .into_boxed();
+            let servers = future::ok::<_, ()>((addr!("1.2.3.4:5001"), true)).into_boxed();
+            let (discarded_tx, discarded_rx) = oneshot::channel();
+            let discard = move |addr| unwrap!(discarded_tx.send(addr));
+            let outcome = unwrap!(core.run(race_igd(igd, servers, is_trusted, discard, &handle)));
+
+            assert!(outcome.igd.is_none());
+            let discarded = discarded_rx.with_timeout(Duration::from_secs(1), &handle);
+            let discarded = unwrap!(unwrap!(core.run(discarded)));
+            assert_eq!(discarded, addr!("1.2.3.4:5000"));
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//! NAT diagnostics.
+//!
+//! When peers fail to connect, the first thing to find out is what kind of network they are in.
+//! `diagnose()` runs the same queries rendezvous and direct connections rely on and collects the
+//! results into a single report that users can send us.
+
+use futures::future::Loop;
+use hairpin::test_hairpin;
+use igd_async;
+use open_addr::unmapped_open_addr;
+use priv_prelude::*;
+use rendezvous_addr::{stun_candidates, RendezvousAddrErrorKind};
+use socket_addr::ipv6_addrs;
+
+/// How long we wait for a single traversal server to respond.
+const QUERY_TIMEOUT_SEC: u64 = 10;
+/// Lease of the port mapping we request to check if IGD works.
+const IGD_PROBE_LEASE_SEC: u64 = 60;
+/// How long we wait for our own packets to come back via our public address.
+const HAIRPIN_TIMEOUT_MS: u64 = 2000;
+/// Idle periods after which we check whether NAT still uses the same UDP mapping.
+const MAPPING_LIFETIME_PROBES_SEC: [u64; 3] = [5, 20, 60];
+
+/// Everything we could find out about the network we are in. See `diagnose()`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NatReport {
+    /// Our public IPv4 address, as seen by traversal servers or reported by IGD.
+    pub public_ipv4: Option<Ipv4Addr>,
+    /// Global IPv6 address of one of our network interfaces.
+    pub public_ipv6: Option<Ipv6Addr>,
+    /// TCP specific results.
+    pub tcp: ProtocolReport,
+    /// UDP specific results.
+    pub udp: ProtocolReport,
+    /// Whether UDP packets sent to our own public address reach us. `None`, if we don't know our
+    /// public address.
+    pub hairpin: Option<bool>,
+    /// Whether our router opens ports for us via IGD, PCP or NAT-PMP.
+    pub igd: IgdReport,
+    /// How long our NAT keeps idle UDP mappings. `None`, if no traversal server responded.
+    pub mapping_lifetime: Option<MappingLifetime>,
+}
+
+/// Diagnostics of a single protocol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolReport {
+    /// NAT type, detected the same way rendezvous connections do it, except that IGD is not used.
+    pub nat_type: NatType,
+    /// Whether NAT maps our local port to the same public port. `None`, if no traversal server
+    /// responded.
+    pub port_preserved: Option<bool>,
+    /// Address other peers can directly connect to, if any. See `open_addr()`.
+    pub open_addr: Option<SocketAddr>,
+    /// Results of querying each traversal server.
+    pub servers: Vec<ServerReport>,
+}
+
+/// Result of querying a single traversal server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerReport {
+    /// Debug representation of the address querier.
+    pub server: String,
+    /// Our public address as seen by the server.
+    pub public_addr: Option<SocketAddr>,
+    /// Why the query failed.
+    pub error: Option<String>,
+    /// How long the query took.
+    pub latency: Duration,
+}
+
+/// Port mapping diagnostics. Besides IGD, PCP and NAT-PMP are tried too, if enabled in `P2p`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IgdReport {
+    /// `true`, if router opened a port for us.
+    pub available: bool,
+    /// External IP address of the router.
+    pub external_ip: Option<IpAddr>,
+    /// Why no port mapping mechanism is available.
+    pub error: Option<String>,
+}
+
+/// Estimated lifetime of idle UDP mappings.
+///
+/// Port preserving NATs might give us the same public port again after the mapping expired, in
+/// which case we can't tell the mapping has changed and only `at_least` is known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MappingLifetime {
+    /// Mapping was still the same after idling this long.
+    pub at_least: Duration,
+    /// Mapping had changed after idling this long.
+    pub at_most: Option<Duration>,
+}
+
+quick_error! {
+    /// Errors returned by `diagnose()`.
+    #[derive(Debug)]
+    pub enum DiagnoseError {
+        /// Failure to bind local socket.
+        Bind(e: io::Error) {
+            description("error binding to local address")
+            display("error binding to local address: {}", e)
+            cause(e)
+        }
+    }
+}
+
+/// Collects information about our network that is useful when debugging failed connections:
+/// public addresses, NAT type, IGD support, etc. Traversal servers are taken from `p2p`.
+///
+/// Estimating UDP mapping lifetime requires idling for a while, hence the returned future takes
+/// well over a minute to complete.
+pub fn diagnose(p2p: &P2p, handle: &Handle) -> BoxFuture<NatReport, DiagnoseError> {
+    let lifetime_probes = MAPPING_LIFETIME_PROBES_SEC
+        .iter()
+        .map(|secs| Duration::from_secs(*secs))
+        .collect();
+    diagnose_with_lifetime_probes(p2p, handle, lifetime_probes)
+}
+
+fn diagnose_with_lifetime_probes(
+    p2p: &P2p,
+    handle: &Handle,
+    lifetime_probes: Vec<Duration>,
+) -> BoxFuture<NatReport, DiagnoseError> {
+    // keep ports reserved for address queriers, which bind to them reusably
+    let tcp_listener = try_bfut!(
+        TcpListener::bind_reusable(&addr!("0.0.0.0:0"), handle).map_err(DiagnoseError::Bind)
+    );
+    let tcp_addr = try_bfut!(tcp_listener.local_addr().map_err(DiagnoseError::Bind));
+    let udp_socket = try_bfut!(
+        UdpSocket::bind_reusable(&addr!("0.0.0.0:0"), handle).map_err(DiagnoseError::Bind)
+    );
+    let udp_addr = try_bfut!(udp_socket.local_addr().map_err(DiagnoseError::Bind));
+
+    let tcp = try_bfut!(diagnose_protocol(Protocol::Tcp, tcp_addr, handle, p2p));
+    let udp = try_bfut!(diagnose_protocol(Protocol::Udp, udp_addr, handle, p2p));
+    let public_ipv6 = global_ipv6();
+    let handle = handle.clone();
+
+    trace!("diagnosing NAT");
+    tcp.join(udp)
+        .and_then(move |(tcp, udp)| {
+            drop(tcp_listener);
+            let udp_behaviour = match udp.responsive_querier.clone() {
+                Some((querier, public_addr)) => {
+                    let handle0 = handle.clone();
+                    let timeout = Duration::from_millis(HAIRPIN_TIMEOUT_MS);
+                    test_hairpin(udp_socket, public_addr, timeout, &handle)
+                        .and_then(move |(udp_socket, hairpin)| {
+                            drop(udp_socket);
+                            estimate_mapping_lifetime(
+                                querier,
+                                udp_addr,
+                                public_addr,
+                                lifetime_probes,
+                                &handle0,
+                            ).map(move |mapping_lifetime| (hairpin, mapping_lifetime))
+                        }).into_boxed()
+                }
+                None => future::ok((None, None)).into_boxed(),
+            };
+            udp_behaviour.map(move |(hairpin, mapping_lifetime)| {
+                make_report(tcp, udp, public_ipv6, hairpin, mapping_lifetime)
+            })
+        }).infallible()
+        .into_boxed()
+}
+
+/// Everything `diagnose_protocol()` finds out, including what doesn't go into `ProtocolReport`.
+struct ProtocolDiagnosis {
+    report: ProtocolReport,
+    /// External address IGD mapped for us or error message.
+    igd_res: Result<SocketAddr, String>,
+    /// First traversal server that responded along with the address it saw us on.
+    responsive_querier: Option<(Querier, SocketAddr)>,
+}
+
+fn diagnose_protocol(
+    protocol: Protocol,
+    servers_bind_addr: SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> Result<BoxFuture<ProtocolDiagnosis, Void>, DiagnoseError> {
+    // Every check uses a different port: repeated TCP connections from the same port to the same
+    // server fail while the previous one is in TIME_WAIT.
+    let nat_type_port = ReservedPort::bind(protocol, handle).map_err(DiagnoseError::Bind)?;
+    let nat_type_bind_addr = nat_type_port.local_addr().map_err(DiagnoseError::Bind)?;
+    let open_addr_port = ReservedPort::bind(protocol, handle).map_err(DiagnoseError::Bind)?;
+    let open_addr_bind_addr = open_addr_port.local_addr().map_err(DiagnoseError::Bind)?;
+
+    let servers = query_servers(protocol, servers_bind_addr, handle, p2p);
+    let nat_type = stun_candidates(protocol, &nat_type_bind_addr, handle, p2p)
+        .then(move |res| match res {
+            Ok(candidates) => Ok(candidates.nat_type),
+            Err(RendezvousAddrErrorKind::UnpredictablePorts(_, nat_type)) => Ok(nat_type),
+            Err(e) => {
+                debug!("failed to detect {:?} NAT type: {}", protocol, e);
+                Ok(NatType::Unknown)
+            }
+        });
+    let open = probe_open_addr(protocol, open_addr_bind_addr, handle, p2p);
+
+    Ok(servers
+        .join3(nat_type, open)
+        .map(move |((servers, responsive_querier), nat_type, (open_addr, igd_res))| {
+            drop((nat_type_port, open_addr_port));
+            let port_preserved = if responsive_querier.is_some() {
+                let local_port = servers_bind_addr.port();
+                Some(
+                    servers
+                        .iter()
+                        .filter_map(|server| server.public_addr)
+                        .all(|addr| addr.port() == local_port),
+                )
+            } else {
+                None
+            };
+            ProtocolDiagnosis {
+                report: ProtocolReport {
+                    nat_type,
+                    port_preserved,
+                    open_addr,
+                    servers,
+                },
+                igd_res,
+                responsive_querier,
+            }
+        }).into_boxed())
+}
+
+/// Queries all traversal servers of given protocol concurrently from `bind_addr`.
+fn query_servers(
+    protocol: Protocol,
+    bind_addr: SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<(Vec<ServerReport>, Option<(Querier, SocketAddr)>), Void> {
+    let handle = handle.clone();
+    addr_queriers(protocol, &handle, p2p)
+        .and_then(move |queriers| {
+            let queries = queriers.into_iter().map(move |querier| {
+                let started = Instant::now();
+                querier
+                    .query(&bind_addr, &handle)
+                    .with_timeout(Duration::from_secs(QUERY_TIMEOUT_SEC), &handle)
+                    .then(move |res| {
+                        let res = match res {
+                            Ok(Some(addr)) => Ok(addr),
+                            Ok(None) => Err(String::from("timed out")),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        Ok((querier, res, started.elapsed()))
+                    })
+            });
+            future::join_all(queries)
+        }).map(|results| {
+            let mut responsive_querier = None;
+            let mut reports = Vec::new();
+            for (querier, res, latency) in results {
+                let server = querier.name();
+                let report = match res {
+                    Ok(addr) => {
+                        if responsive_querier.is_none() {
+                            responsive_querier = Some((querier, addr));
+                        }
+                        ServerReport {
+                            server,
+                            public_addr: Some(addr),
+                            error: None,
+                            latency,
+                        }
+                    }
+                    Err(error) => ServerReport {
+                        server,
+                        public_addr: None,
+                        error: Some(error),
+                        latency,
+                    },
+                };
+                reports.push(report);
+            }
+            (reports, responsive_querier)
+        }).into_boxed()
+}
+
+/// Asks IGD for a temporary port mapping and concurrently checks if we have an open address
+/// without it, i.e. a global address or full cone NAT. `open_addr()` is not used, because it would
+/// ask IGD for a permanent mapping.
+///
+/// # Returns
+///
+/// Open address, if any, and IGD result.
+fn probe_open_addr(
+    protocol: Protocol,
+    bind_addr: SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<(Option<SocketAddr>, Result<SocketAddr, String>), Void> {
+    let lease = Duration::from_secs(IGD_PROBE_LEASE_SEC);
+    let igd = igd_async::get_any_address(protocol, bind_addr, Some(lease), handle, p2p)
+        .then(|res| -> Result<_, Void> { Ok(res.map(|lease| lease.external_addr())) });
+    let unmapped = unmapped_open_addr(protocol, &bind_addr, handle, p2p).then(move |res| {
+        Ok::<_, Void>(match res {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                debug!("no open {:?} address: {}", protocol, e);
+                None
+            }
+        })
+    });
+    igd.join(unmapped)
+        .map(|(igd_res, unmapped_addr)| {
+            let open_addr = igd_res.as_ref().ok().cloned().or(unmapped_addr);
+            (open_addr, igd_res.map_err(|e| e.to_string()))
+        }).into_boxed()
+}
+
+/// Idles for each of `probes` and then queries the server again to check if NAT still uses the
+/// same mapping.
+fn estimate_mapping_lifetime(
+    querier: Querier,
+    bind_addr: SocketAddr,
+    public_addr: SocketAddr,
+    probes: Vec<Duration>,
+    handle: &Handle,
+) -> BoxFuture<Option<MappingLifetime>, Void> {
+    let handle = handle.clone();
+    future::loop_fn(
+        (probes.into_iter(), Duration::new(0, 0)),
+        move |(mut probes, at_least)| {
+            let idle = match probes.next() {
+                Some(idle) => idle,
+                None => {
+                    let lifetime = MappingLifetime {
+                        at_least,
+                        at_most: None,
+                    };
+                    return future::ok(Loop::Break(Some(lifetime))).into_boxed();
+                }
+            };
+            let querier = querier.clone();
+            let handle0 = handle.clone();
+            Timeout::new(idle, &handle)
+                .infallible()
+                .and_then(move |()| {
+                    querier
+                        .query(&bind_addr, &handle0)
+                        .with_timeout(Duration::from_secs(QUERY_TIMEOUT_SEC), &handle0)
+                }).then(move |res| match res {
+                    Ok(Some(addr)) if addr == public_addr => Ok(Loop::Continue((probes, idle))),
+                    Ok(Some(addr)) => {
+                        trace!("mapping {} changed to {} after {:?}", public_addr, addr, idle);
+                        let lifetime = MappingLifetime {
+                            at_least,
+                            at_most: Some(idle),
+                        };
+                        Ok(Loop::Break(Some(lifetime)))
+                    }
+                    Ok(None) | Err(_) => {
+                        debug!("traversal server stopped responding to mapping lifetime probes");
+                        if at_least == Duration::new(0, 0) {
+                            return Ok(Loop::Break(None));
+                        }
+                        let lifetime = MappingLifetime {
+                            at_least,
+                            at_most: None,
+                        };
+                        Ok(Loop::Break(Some(lifetime)))
+                    }
+                }).into_boxed()
+        },
+    ).into_boxed()
+}
+
+fn make_report(
+    tcp: ProtocolDiagnosis,
+    udp: ProtocolDiagnosis,
+    public_ipv6: Option<Ipv6Addr>,
+    hairpin: Option<bool>,
+    mapping_lifetime: Option<MappingLifetime>,
+) -> NatReport {
+    let igd = match (udp.igd_res, tcp.igd_res) {
+        (Ok(addr), _) | (_, Ok(addr)) => IgdReport {
+            available: true,
+            external_ip: Some(addr.ip()),
+            error: None,
+        },
+        (Err(e), Err(_)) => IgdReport {
+            available: false,
+            external_ip: None,
+            error: Some(e),
+        },
+    };
+    let public_ipv4 = udp
+        .report
+        .servers
+        .iter()
+        .chain(&tcp.report.servers)
+        .filter_map(|server| server.public_addr)
+        .map(|addr| addr.ip())
+        .chain(igd.external_ip)
+        .filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(..) => None,
+        }).next();
+    NatReport {
+        public_ipv4,
+        public_ipv6,
+        tcp: tcp.report,
+        udp: udp.report,
+        hairpin,
+        igd,
+        mapping_lifetime,
+    }
+}
+
+/// Returns global IPv6 address of one of our network interfaces.
+fn global_ipv6() -> Option<Ipv6Addr> {
+    let global_addrs = match ipv6_addrs(&addr!("[::]:0")) {
+        Ok((global_addrs, _)) => global_addrs,
+        Err(e) => {
+            debug!("failed to get IPv6 addresses: {}", e);
+            return None;
+        }
+    };
+    global_addrs
+        .into_iter()
+        .filter_map(|addr| match addr.ip() {
+            IpAddr::V6(ip) => Some(ip),
+            IpAddr::V4(..) => None,
+        }).next()
+}
+
+/// Collects currently known address queriers of given protocol.
+fn addr_queriers(
+    protocol: Protocol,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<Vec<Querier>, Void> {
+    match protocol {
+        Protocol::Tcp => p2p
+            .tcp_addr_queriers()
+            .with_readiness_timeout(Duration::from_secs(2), handle)
+            .infallible()
+            .map(Querier::Tcp)
+            .collect()
+            .into_boxed(),
+        Protocol::Udp => p2p
+            .udp_addr_queriers()
+            .with_readiness_timeout(Duration::from_secs(2), handle)
+            .infallible()
+            .map(Querier::Udp)
+            .collect()
+            .into_boxed(),
+    }
+}
+
+/// Address querier of either protocol.
+#[derive(Clone)]
+enum Querier {
+    Tcp(Arc<TcpAddrQuerier>),
+    Udp(Arc<UdpAddrQuerier>),
+}
+
+impl Querier {
+    fn query(
+        &self,
+        bind_addr: &SocketAddr,
+        handle: &Handle,
+    ) -> BoxFuture<SocketAddr, Box<Error + Send>> {
+        match *self {
+            Querier::Tcp(ref querier) => querier.query(bind_addr, handle),
+            Querier::Udp(ref querier) => querier.query(bind_addr, handle),
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Querier::Tcp(ref querier) => format!("{:?}", querier),
+            Querier::Udp(ref querier) => format!("{:?}", querier),
+        }
+    }
+}
+
+/// Socket that keeps local port reserved.
+enum ReservedPort {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl ReservedPort {
+    fn bind(protocol: Protocol, handle: &Handle) -> io::Result<ReservedPort> {
+        let addr = addr!("0.0.0.0:0");
+        Ok(match protocol {
+            Protocol::Tcp => ReservedPort::Tcp(TcpListener::bind_reusable(&addr, handle)?),
+            Protocol::Udp => ReservedPort::Udp(UdpSocket::bind_reusable(&addr, handle)?),
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            ReservedPort::Tcp(ref listener) => listener.local_addr(),
+            ReservedPort::Udp(ref socket) => socket.local_addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod diagnose {
+        use super::*;
+
+        #[test]
+        fn it_reports_localhost_servers_as_seeing_us_without_nat() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let udp_server = unwrap!(UdpRendezvousServer::bind(&addr!("127.0.0.1:0"), &handle));
+            let tcp_server = unwrap!(TcpRendezvousServer::bind(&addr!("127.0.0.1:0"), &handle));
+            let p2p = P2p::default();
+            p2p.disable_igd();
+            p2p.disable_pcp();
+            p2p.disable_nat_pmp();
+            p2p.add_udp_addr_querier(RemoteUdpRendezvousServer::new(
+                udp_server.local_addr(),
+                *udp_server.public_key(),
+            ));
+            p2p.add_tcp_addr_querier(RemoteTcpRendezvousServer::new(
+                tcp_server.local_addr(),
+                *tcp_server.public_key(),
+            ));
+
+            let probes = vec![Duration::from_millis(100)];
+            let report = unwrap!(core.run(diagnose_with_lifetime_probes(&p2p, &handle, probes)));
+
+            assert_eq!(report.public_ipv4, Some(ipv4!("127.0.0.1")));
+            for protocol_report in &[&report.tcp, &report.udp] {
+                assert_eq!(protocol_report.servers.len(), 1);
+                assert!(protocol_report.servers[0].error.is_none());
+                assert_eq!(protocol_report.port_preserved, Some(true));
+            }
+            assert_eq!(report.hairpin, Some(true));
+            assert!(!report.igd.available);
+            assert_eq!(
+                report.mapping_lifetime,
+                Some(MappingLifetime {
+                    at_least: Duration::from_millis(100),
+                    at_most: None,
+                })
+            );
+        }
+
+        #[test]
+        fn without_servers_it_reports_unknown_nat() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let p2p = P2p::default();
+            p2p.disable_igd();
+            p2p.disable_pcp();
+            p2p.disable_nat_pmp();
+
+            let report = unwrap!(core.run(diagnose(&p2p, &handle)));
+
+            assert_eq!(report.udp.nat_type, NatType::Unknown);
+            assert_eq!(report.udp.port_preserved, None);
+            assert!(report.udp.servers.is_empty());
+            assert_eq!(report.hairpin, None);
+            assert_eq!(report.mapping_lifetime, None);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Progress events of rendezvous connections.
+
+use future_utils::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use priv_prelude::*;
+use std::time::SystemTime;
+
+/// Something that happened during rendezvous connect. See `P2p::subscribe()`.
+#[derive(Debug, Clone)]
+pub struct RendezvousEvent {
+    /// Id of the rendezvous connect the event belongs to, unique per `P2p`. It's `None` for the
+    /// events that happen outside of rendezvous connect, e.g. while querying our public address.
+    pub attempt: Option<u64>,
+    /// When the event happened.
+    pub time: SystemTime,
+    /// What happened.
+    pub kind: RendezvousEventKind,
+}
+
+/// Kinds of `RendezvousEvent`.
+#[derive(Debug, Clone)]
+pub enum RendezvousEventKind {
+    /// One of our public addresses was discovered either via IGD or a traversal server.
+    AddrDiscovered {
+        /// Protocol the address was discovered for.
+        protocol: Protocol,
+        /// Our public address.
+        addr: SocketAddr,
+    },
+    /// Our NAT type was detected from the addresses traversal servers see us on.
+    NatTypeDetected {
+        /// Protocol the NAT type was detected for.
+        protocol: Protocol,
+        /// Detected NAT type.
+        nat_type: NatType,
+    },
+    /// The remote peer is behind the same public IP as we are, i.e. most likely behind the same
+    /// NAT.
+    SharedPublicIp {
+        /// Protocol of the rendezvous connection.
+        protocol: Protocol,
+        /// Public IP we share.
+        ip: IpAddr,
+        /// Whether the NAT supports hairpinning, if known. If it doesn't, only the peer's local
+        /// addresses are tried.
+        hairpin: Option<bool>,
+    },
+    /// Our candidate addresses were sent to the remote peer via rendezvous channel.
+    CandidatesSent {
+        /// Protocol of the rendezvous connection.
+        protocol: Protocol,
+        /// Addresses we sent.
+        addrs: Vec<SocketAddr>,
+    },
+    /// TTL of UDP hole punching packets was incremented.
+    TtlIncremented {
+        /// Address we are punching a hole to.
+        their_addr: SocketAddr,
+        /// New TTL value.
+        ttl: u32,
+    },
+    /// Received hole punching `Syn` from the remote peer.
+    SynReceived {
+        /// Address the message came from.
+        their_addr: SocketAddr,
+    },
+    /// Received hole punching `Ack` from the remote peer.
+    AckReceived {
+        /// Address the message came from.
+        their_addr: SocketAddr,
+    },
+    /// Received hole punching `AckAck` from the remote peer.
+    AckAckReceived {
+        /// Address the message came from.
+        their_addr: SocketAddr,
+    },
+    /// Connection to this address of the remote peer was chosen.
+    CandidateChosen {
+        /// Protocol of the rendezvous connection.
+        protocol: Protocol,
+        /// Address of the remote peer.
+        their_addr: SocketAddr,
+    },
+    /// A single attempt to connect to the remote peer failed.
+    AttemptFailed {
+        /// Protocol of the rendezvous connection.
+        protocol: Protocol,
+        /// Address we were connecting to, if known.
+        their_addr: Option<SocketAddr>,
+        /// Why the attempt failed.
+        error: String,
+    },
+}
+
+/// Senders of events to all `P2p` subscribers.
+#[derive(Default)]
+pub struct EventSubscribers {
+    txs: Vec<UnboundedSender<RendezvousEvent>>,
+}
+
+impl EventSubscribers {
+    pub fn subscribe(&mut self) -> UnboundedReceiver<RendezvousEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.txs.push(tx);
+        rx
+    }
+
+    /// Sends event to all subscribers, forgetting the ones that dropped their receivers.
+    pub fn emit(&mut self, attempt: Option<u64>, kind: RendezvousEventKind) {
+        if self.txs.is_empty() {
+            return;
+        }
+        let event = RendezvousEvent {
+            attempt,
+            time: SystemTime::now(),
+            kind,
+        };
+        self.txs
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Returns the number of subscribers, including the ones that dropped their receivers since
+    /// the last event.
+    pub fn count(&self) -> usize {
+        self.txs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    fn syn_received(port: u16) -> RendezvousEventKind {
+        RendezvousEventKind::SynReceived {
+            their_addr: SocketAddr::new(IpAddr::V4(ipv4!("1.2.3.4")), port),
+        }
+    }
+
+    fn received_ports(events: Vec<RendezvousEvent>) -> Vec<u16> {
+        events
+            .into_iter()
+            .map(|event| match event.kind {
+                RendezvousEventKind::SynReceived { their_addr } => their_addr.port(),
+                kind => panic!("unexpected event: {:?}", kind),
+            }).collect()
+    }
+
+    mod event_subscribers {
+        use super::*;
+
+        #[test]
+        fn it_delivers_events_to_every_subscriber_in_order() {
+            let mut subscribers = EventSubscribers::default();
+            let events0 = subscribers.subscribe();
+            let events1 = subscribers.subscribe();
+
+            subscribers.emit(Some(1), syn_received(1000));
+            subscribers.emit(Some(1), syn_received(2000));
+            subscribers.emit(None, syn_received(3000));
+            drop(subscribers);
+
+            let mut core = unwrap!(Core::new());
+            let events0: Vec<_> = core.run(events0.collect()).void_unwrap();
+            let events1: Vec<_> = core.run(events1.collect()).void_unwrap();
+            let attempts: Vec<_> = events0.iter().map(|event| event.attempt).collect();
+            assert_eq!(attempts, vec![Some(1), Some(1), None]);
+            assert_eq!(received_ports(events0), vec![1000, 2000, 3000]);
+            assert_eq!(received_ports(events1), vec![1000, 2000, 3000]);
+        }
+
+        #[test]
+        fn it_forgets_subscribers_that_dropped_their_receivers() {
+            let mut subscribers = EventSubscribers::default();
+            let events0 = subscribers.subscribe();
+            let events1 = subscribers.subscribe();
+            assert_eq!(subscribers.count(), 2);
+
+            drop(events0);
+            subscribers.emit(None, syn_received(1000));
+            assert_eq!(subscribers.count(), 1);
+
+            drop(events1);
+            subscribers.emit(None, syn_received(2000));
+            assert_eq!(subscribers.count(), 0);
+        }
+
+        #[test]
+        fn it_doesnt_deliver_events_emitted_before_subscribing() {
+            let mut subscribers = EventSubscribers::default();
+            subscribers.emit(None, syn_received(1000));
+
+            let events = subscribers.subscribe();
+            subscribers.emit(None, syn_received(2000));
+            drop(subscribers);
+
+            let mut core = unwrap!(Core::new());
+            let events: Vec<_> = core.run(events.collect()).void_unwrap();
+            assert_eq!(received_ports(events), vec![2000]);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Hairpinning (NAT loopback) is NAT forwarding packets sent to its public address back to the
+//! local network. Peers behind the same NAT share public IP and can only reach each other's public
+//! addresses, if the NAT supports it.
+
+use mc::set_hairpin_support;
+use priv_prelude::*;
+use rand;
+use std::cmp;
+
+/// How long we wait for our own packets to come back while discovering rendezvous addresses.
+/// Hairpinned packets never leave the local network, so they arrive quickly if at all.
+const DISCOVERY_TIMEOUT_MS: u64 = 500;
+/// How often hairpin probes are resent.
+const INTERVAL_MS: u64 = 200;
+
+/// Checks if our NAT supports hairpinning by sending packets to `public_addr`, the address our NAT
+/// mapped for `socket`. The result is remembered in `p2p` for the public IP, so the check is only
+/// done once per NAT. If the probes can't be sent, hairpinning is remembered as unsupported, so
+/// that we don't wait for the check again on every connect.
+///
+/// Every datagram arriving on `socket` during the check is consumed, so call this before the
+/// addresses of `socket` are given to anyone, e.g. the remote peer.
+///
+/// # Returns
+///
+/// `socket` once the check is done.
+pub fn detect_hairpin(
+    socket: UdpSocket,
+    public_addr: SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<UdpSocket, Void> {
+    if p2p.hairpin_support(public_addr.ip()).is_some() {
+        return future::ok(socket).into_boxed();
+    }
+    let p2p = p2p.clone();
+    let timeout = Duration::from_millis(DISCOVERY_TIMEOUT_MS);
+    test_hairpin(socket, public_addr, timeout, handle)
+        .map(move |(socket, supported)| {
+            let supported = supported.unwrap_or(false);
+            trace!("hairpinning via {} supported: {}", public_addr, supported);
+            set_hairpin_support(&p2p, public_addr.ip(), supported);
+            socket
+        }).into_boxed()
+}
+
+/// Sends packets from `socket` to its own public address and checks if they come back within
+/// `timeout`. Probes are sent from the mapped socket itself, so that NATs that filter packets from
+/// endpoints we haven't sent to don't drop them. Datagrams other than the probes that arrive on
+/// `socket` meanwhile are dropped.
+///
+/// # Returns
+///
+/// `socket` and whether hairpinning works. `None`, if we failed to send or receive the probes.
+pub fn test_hairpin(
+    socket: UdpSocket,
+    public_addr: SocketAddr,
+    timeout: Duration,
+    handle: &Handle,
+) -> BoxFuture<(UdpSocket, Option<bool>), Void> {
+    let nonce: [u8; 16] = rand::random();
+    let deadline = Instant::now() + timeout;
+    let mut next_probe = Timeout::new(Duration::new(0, 0), handle);
+    let mut socket_opt = Some(socket);
+    future::poll_fn(move || {
+        let supported = poll_probes(
+            unwrap!(socket_opt.as_ref()),
+            &mut next_probe,
+            public_addr,
+            deadline,
+            &nonce,
+        );
+        match supported {
+            Async::Ready(supported) => Ok(Async::Ready((unwrap!(socket_opt.take()), supported))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }).into_boxed()
+}
+
+fn poll_probes(
+    socket: &UdpSocket,
+    next_probe: &mut Timeout,
+    public_addr: SocketAddr,
+    deadline: Instant,
+    nonce: &[u8; 16],
+) -> Async<Option<bool>> {
+    while let Async::Ready(()) = next_probe.poll().void_unwrap() {
+        if Instant::now() >= deadline {
+            return Async::Ready(Some(false));
+        }
+        match socket.send_to(nonce, &public_addr) {
+            Ok(_) => {
+                let next = Instant::now() + Duration::from_millis(INTERVAL_MS);
+                next_probe.reset(cmp::min(next, deadline));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                debug!("failed to send hairpin probe: {}", e);
+                return Async::Ready(None);
+            }
+        }
+    }
+
+    let mut buffer = [0u8; 64];
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, addr)) => {
+                if buffer[..len] == nonce[..] {
+                    return Async::Ready(Some(true));
+                }
+                trace!("dropping datagram from {} while testing hairpinning", addr);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Async::NotReady,
+            Err(e) => {
+                debug!("failed to receive hairpin probe: {}", e);
+                return Async::Ready(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod detect_hairpin {
+        use super::*;
+
+        #[test]
+        fn it_remembers_that_packets_to_our_own_address_arrive() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let p2p = P2p::default();
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let addr = unwrap!(socket.local_addr());
+
+            let _socket = unwrap!(core.run(detect_hairpin(socket, addr, &handle, &p2p)));
+
+            assert_eq!(p2p.hairpin_support(addr.ip()), Some(true));
+        }
+
+        #[test]
+        fn it_remembers_that_packets_to_our_own_address_dont_arrive() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let p2p = P2p::default();
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            // address that swallows the probes, like NAT that doesn't hairpin
+            let black_hole = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let addr = unwrap!(black_hole.local_addr());
+
+            let _socket = unwrap!(core.run(detect_hairpin(socket, addr, &handle, &p2p)));
+
+            assert_eq!(p2p.hairpin_support(addr.ip()), Some(false));
+        }
+
+        #[test]
+        fn when_probes_cant_be_sent_it_remembers_that_hairpinning_doesnt_work() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let p2p = P2p::default();
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            // IPv4 socket can't send to IPv6 address
+            let addr = addr!("[::1]:5000");
+
+            let _socket = unwrap!(core.run(detect_hairpin(socket, addr, &handle, &p2p)));
+
+            assert_eq!(p2p.hairpin_support(addr.ip()), Some(false));
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Authentication of peers during rendezvous connect.
+//!
+//! Rendezvous connect uses fresh ephemeral keys for every connection. When peers have long-term
+//! identity keys, each of them proves its ephemeral key belongs to it by encrypting it with the
+//! secret shared by both identities. Only the holders of identity secret keys can produce such
+//! proof, hence whoever tampers with the rendezvous channel can't substitute their own keys.
+
+use priv_prelude::*;
+
+/// Long-term identities used to authenticate rendezvous connections.
+#[derive(Clone)]
+pub struct RendezvousAuth {
+    our_pk: PublicEncryptKey,
+    their_pk: PublicEncryptKey,
+    shared_secret: SharedSecretKey,
+}
+
+impl RendezvousAuth {
+    /// Takes our identity keypair and the identity the remote peer must prove.
+    pub fn new(
+        our_pk: &PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        their_pk: &PublicEncryptKey,
+    ) -> RendezvousAuth {
+        RendezvousAuth {
+            our_pk: *our_pk,
+            their_pk: *their_pk,
+            shared_secret: our_sk.shared_secret(their_pk),
+        }
+    }
+
+    /// Takes our identity keypair and the info of the peer we expect to connect to.
+    pub fn with_peer_info(
+        our_pk: &PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        peer: &PeerInfo,
+    ) -> RendezvousAuth {
+        RendezvousAuth::new(our_pk, our_sk, &peer.pub_key)
+    }
+
+    /// Returns the identity the remote peer must prove.
+    pub fn their_pk(&self) -> &PublicEncryptKey {
+        &self.their_pk
+    }
+
+    /// Proves that given ephemeral key belongs to us.
+    pub fn prove(&self, ephemeral_pk: &PublicEncryptKey) -> Result<Vec<u8>, EncryptionError> {
+        let proof = IdentityProof {
+            identity_pk: self.our_pk,
+            ephemeral_pk: *ephemeral_pk,
+        };
+        self.shared_secret.encrypt(&proof)
+    }
+
+    /// Checks if given ephemeral key belongs to the peer we expect.
+    pub fn verify(
+        &self,
+        their_ephemeral_pk: &PublicEncryptKey,
+        proof: Option<&[u8]>,
+    ) -> Result<(), IdentityError> {
+        let proof = proof.ok_or(IdentityError::MissingProof)?;
+        let proof: IdentityProof = self
+            .shared_secret
+            .decrypt(proof)
+            .map_err(IdentityError::InvalidProof)?;
+        // Shared secret is the same for both peers, so someone could reflect our own proof back
+        // to us.
+        if proof.identity_pk != self.their_pk || proof.ephemeral_pk != *their_ephemeral_pk {
+            return Err(IdentityError::WrongIdentity);
+        }
+        Ok(())
+    }
+}
+
+/// Proves that given ephemeral key belongs to the given identity.
+#[derive(Debug, Serialize, Deserialize)]
+struct IdentityProof {
+    identity_pk: PublicEncryptKey,
+    ephemeral_pk: PublicEncryptKey,
+}
+
+/// Creates identity proof for our ephemeral key, if authentication is requested.
+pub fn prove_identity(
+    auth: Option<&RendezvousAuth>,
+    ephemeral_pk: &PublicEncryptKey,
+) -> Result<Option<Vec<u8>>, EncryptionError> {
+    match auth {
+        Some(auth) => auth.prove(ephemeral_pk).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Verifies the identity of remote peer, if authentication is requested.
+pub fn verify_identity(
+    auth: Option<&RendezvousAuth>,
+    their_ephemeral_pk: &PublicEncryptKey,
+    proof: Option<&[u8]>,
+) -> Result<(), IdentityError> {
+    match auth {
+        Some(auth) => auth.verify(their_ephemeral_pk, proof),
+        None => Ok(()),
+    }
+}
+
+quick_error! {
+    /// Remote peer failed to prove its identity.
+    #[derive(Debug)]
+    pub enum IdentityError {
+        /// Peer didn't send identity proof.
+        MissingProof {
+            description("peer didn't prove its identity")
+        }
+        /// Identity proof was not made with the expected identity keys.
+        InvalidProof(e: EncryptionError) {
+            description("invalid identity proof")
+            display("invalid identity proof: {}", e)
+            cause(e)
+        }
+        /// Identity proof was made for some other identity or ephemeral key.
+        WrongIdentity {
+            description("identity proof doesn't match peer's keys")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod rendezvous_auth {
+        use super::*;
+
+        fn peers() -> (RendezvousAuth, RendezvousAuth) {
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let (pk2, sk2) = gen_encrypt_keypair();
+            (
+                RendezvousAuth::new(&pk1, &sk1, &pk2),
+                RendezvousAuth::new(&pk2, &sk2, &pk1),
+            )
+        }
+
+        #[test]
+        fn it_verifies_proof_of_expected_peer() {
+            let (auth1, auth2) = peers();
+            let (ephemeral_pk, _) = gen_encrypt_keypair();
+
+            let proof = unwrap!(auth1.prove(&ephemeral_pk));
+
+            unwrap!(auth2.verify(&ephemeral_pk, Some(&proof)));
+        }
+
+        #[test]
+        fn when_proof_is_missing_it_returns_error() {
+            let (_auth1, auth2) = peers();
+            let (ephemeral_pk, _) = gen_encrypt_keypair();
+
+            match auth2.verify(&ephemeral_pk, None) {
+                Err(IdentityError::MissingProof) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_proof_is_made_for_other_ephemeral_key_it_returns_error() {
+            let (auth1, auth2) = peers();
+            let (ephemeral_pk, _) = gen_encrypt_keypair();
+            let (attacker_pk, _) = gen_encrypt_keypair();
+
+            let proof = unwrap!(auth1.prove(&ephemeral_pk));
+
+            match auth2.verify(&attacker_pk, Some(&proof)) {
+                Err(IdentityError::WrongIdentity) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_our_own_proof_is_reflected_it_returns_error() {
+            let (auth1, _auth2) = peers();
+            let (ephemeral_pk, _) = gen_encrypt_keypair();
+
+            let proof = unwrap!(auth1.prove(&ephemeral_pk));
+
+            match auth1.verify(&ephemeral_pk, Some(&proof)) {
+                Err(IdentityError::WrongIdentity) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_proof_is_made_by_other_identity_it_returns_error() {
+            let (_auth1, auth2) = peers();
+            let (attacker_pk, attacker_sk) = gen_encrypt_keypair();
+            let attacker = RendezvousAuth::new(&attacker_pk, &attacker_sk, auth2.their_pk());
+            let (ephemeral_pk, _) = gen_encrypt_keypair();
+
+            let proof = unwrap!(attacker.prove(&ephemeral_pk));
+
+            match auth2.verify(&ephemeral_pk, Some(&proof)) {
+                Err(IdentityError::InvalidProof(..)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -1,26 +1,84 @@
+use futures::future::Loop;
+use futures::stream::FuturesUnordered;
+use futures::sync::oneshot;
 use future_utils::thread_future;
 use get_if_addrs::{self, IfAddr, Interface};
-use igd::{self, AddAnyPortError, PortMappingProtocol, RemovePortError, SearchError};
+use igd::{
+    self, AddAnyPortError, AddPortError, GetExternalIpError, PortMappingProtocol, RemovePortError,
+};
+use igd_firewall::{self, FirewallControl, ServiceControl, UpnpError};
+use mc::{stale_mapping_description, start_stale_mapping_cleanup, with_gateway_cache};
+use port_mapping::{self, nat_pmp, pcp, PortMapping, PortMappingError};
 use priv_prelude::*;
+use std::cmp;
+
+/// Lifetime of PCP and NAT-PMP mappings requested for servers. Neither protocol supports
+/// permanent mappings, RFC 6886 recommends this lifetime instead.
+const PERMANENT_MAPPING_LIFETIME_SEC: u32 = 7200;
+/// Lease of IGD mappings requested for servers. Routers that only support permanent leases get
+/// permanent mappings instead.
+const PERMANENT_IGD_LEASE_SEC: u32 = 3600;
+/// Lease of IPv6 pinholes opened for servers.
+const PERMANENT_PINHOLE_LEASE_SEC: u32 = 3600;
+/// How often permanent IGD mappings are refreshed to notice external IP changes.
+const PERMANENT_LEASE_RECHECK_SEC: u64 = 1800;
+/// How long IGD gateway search results are cached.
+const GATEWAY_CACHE_TTL_SEC: u64 = 600;
+/// How long we remember that there's no IGD gateway on the network.
+const NO_GATEWAY_CACHE_TTL_SEC: u64 = 60;
+/// SSDP search target of IGD gateways. IGDv2 gateways answer searches for IGDv1 devices too.
+const INTERNET_GATEWAY_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// IGD services that manage port mappings, in order of preference.
+const WAN_CONNECTION_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// UPnP error codes routers return when asked for port mapping past the end of the list.
+const NO_SUCH_ENTRY_IN_ARRAY: u16 = 714;
+const SPECIFIED_ARRAY_INDEX_INVALID: u16 = 713;
+/// Guards against routers that never report the end of the port mapping list.
+const MAX_PORT_MAPPINGS: u32 = 1024;
 
 pub fn search_gateway_from_timeout(
     ipv4: Ipv4Addr,
     timeout: Duration,
-) -> BoxFuture<Gateway, SearchError> {
+) -> BoxFuture<Gateway, UpnpError> {
     thread_future(move || {
-        let res = igd::search_gateway_from_timeout(ipv4, timeout);
-        res.map(|gateway| Gateway { inner: gateway })
+        let control = igd_firewall::search_service(
+            ipv4,
+            INTERNET_GATEWAY_DEVICE,
+            WAN_CONNECTION_SERVICES,
+            timeout,
+        )?;
+        Gateway::new(control)
     }).infallible()
     .and_then(|r| r)
     .into_boxed()
 }
 
-#[derive(Debug)]
+/// IGD gateway found by `search_gateway_from_timeout()`. Port mappings are listed and removed via
+/// the `WANIPConnection` or `WANPPPConnection` service the gateway has. The `igd` crate, which
+/// makes the mappings, talks to the same control URL.
+#[derive(Debug, Clone)]
 pub struct Gateway {
     inner: igd::Gateway,
+    control: ServiceControl,
 }
 
 impl Gateway {
+    fn new(control: ServiceControl) -> Result<Gateway, UpnpError> {
+        let addr = match control.addr() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(..) => return Err(UpnpError::InvalidDescription),
+        };
+        let inner = igd::Gateway {
+            addr,
+            control_url: control.path().to_string(),
+        };
+        Ok(Gateway { inner, control })
+    }
+
     /// Asynchronously maps local address to external one.
     ///
     /// # Returns
@@ -86,6 +144,158 @@ impl Gateway {
             .and_then(|r| r)
             .into_boxed()
     }
+
+    /// Maps the same external port again, which extends the lease of an existing mapping.
+    ///
+    /// # Returns
+    ///
+    /// Future that resolves to mapped external `IP:port`. The IP might have changed since the
+    /// port was mapped.
+    pub fn renew_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> BoxFuture<SocketAddrV4, GetAnyAddressError> {
+        let description = String::from(description);
+        let gateway = self.inner.clone();
+
+        thread_future(move || {
+            let local_addr = specified_local_addr_to_gateway(local_addr, *gateway.addr.ip())
+                .map_err(GetAnyAddressError::PathToGateway)?;
+            gateway
+                .add_port(
+                    protocol,
+                    external_port,
+                    local_addr,
+                    lease_duration,
+                    &description,
+                ).map_err(GetAnyAddressError::RenewPort)?;
+            let external_ip = gateway
+                .get_external_ip()
+                .map_err(GetAnyAddressError::ExternalIp)?;
+            Ok(SocketAddrV4::new(external_ip, external_port))
+        }).infallible()
+        .and_then(|r| r)
+        .into_boxed()
+    }
+
+    /// Lists all port mappings of the router, including the ones made by other hosts.
+    pub fn port_mappings(&self) -> BoxFuture<Vec<PortMappingEntry>, UpnpError> {
+        let control = self.control.clone();
+
+        thread_future(move || {
+            let mut entries = Vec::new();
+            for index in 0..MAX_PORT_MAPPINGS {
+                let res = control.soap_action(
+                    "GetGenericPortMappingEntry",
+                    &[("NewPortMappingIndex", index.to_string())],
+                );
+                let response = match res {
+                    Ok(response) => response,
+                    Err(UpnpError::Soap(code, _))
+                        if code == SPECIFIED_ARRAY_INDEX_INVALID
+                            || code == NO_SUCH_ENTRY_IN_ARRAY =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                };
+                let entry =
+                    parse_port_mapping_entry(&response).ok_or(UpnpError::InvalidResponse)?;
+                entries.push(entry);
+            }
+            Ok(entries)
+        }).infallible()
+        .and_then(|r| r)
+        .into_boxed()
+    }
+
+    /// Removes a port mapping via the service found by gateway search.
+    pub fn delete_port_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> BoxFuture<(), UpnpError> {
+        let control = self.control.clone();
+        let protocol = match protocol {
+            PortMappingProtocol::TCP => "TCP",
+            PortMappingProtocol::UDP => "UDP",
+        };
+
+        thread_future(move || {
+            control
+                .soap_action(
+                    "DeletePortMapping",
+                    &[
+                        ("NewRemoteHost", String::new()),
+                        ("NewExternalPort", external_port.to_string()),
+                        ("NewProtocol", protocol.to_string()),
+                    ],
+                ).map(|_response| ())
+        }).infallible()
+        .and_then(|r| r)
+        .into_boxed()
+    }
+
+    /// Removes mappings to our host with exactly the given description. Failure to remove one
+    /// mapping doesn't stop removal of the others.
+    ///
+    /// # Returns
+    ///
+    /// Number of removed mappings and errors of the removals that failed.
+    pub fn remove_stale_mappings(
+        &self,
+        description: &str,
+    ) -> BoxFuture<(usize, Vec<RemoveStaleMappingsError>), RemoveStaleMappingsError> {
+        let internal_ip = try_bfut!(
+            discover_local_addr_to_gateway(*self.inner.addr.ip())
+                .map_err(RemoveStaleMappingsError::PathToGateway)
+        );
+        let description = String::from(description);
+        let gateway = self.clone();
+        self.port_mappings()
+            .map_err(RemoveStaleMappingsError::ListPorts)
+            .and_then(move |entries| {
+                let removals = entries
+                    .into_iter()
+                    .filter(|entry| {
+                        entry.internal_client == internal_ip && entry.description == description
+                    }).map(|entry| {
+                        trace!("removing stale port mapping: {:?}", entry);
+                        gateway
+                            .delete_port_mapping(entry.protocol, entry.external_port)
+                            .map_err(RemoveStaleMappingsError::RemovePort)
+                            .then(Ok)
+                    }).collect::<Vec<_>>();
+                future::join_all(removals).map(|results| {
+                    let mut removed = 0;
+                    let mut errors = Vec::new();
+                    for res in results {
+                        match res {
+                            Ok(()) => removed += 1,
+                            Err(e) => errors.push(e),
+                        }
+                    }
+                    (removed, errors)
+                })
+            }).into_boxed()
+    }
+}
+
+/// Port mapping as listed by the router.
+#[derive(Debug, Clone)]
+pub struct PortMappingEntry {
+    pub protocol: PortMappingProtocol,
+    pub external_port: u16,
+    /// Host the port is mapped to.
+    pub internal_client: Ipv4Addr,
+    pub internal_port: u16,
+    pub description: String,
+    /// Lease duration in seconds, zero for permanent mappings.
+    pub lease_duration: u32,
 }
 
 fn specified_local_addr_to_gateway(
@@ -106,11 +316,14 @@ quick_error! {
         Ipv6NotSupported {
             description("IPv6 not supported for UPnP")
         }
-        FindGateway(e: SearchError) {
+        FindGateway(e: UpnpError) {
             description("failed to find IGD gateway")
             display("failed to find IGD gateway: {}", e)
             cause(e)
         }
+        NoGateway {
+            description("no IGD gateway found by a recent search")
+        }
         PathToGateway(e: io::Error) {
             description("error finding path to gateway")
             display("error finding path to gateway: {}", e)
@@ -121,21 +334,107 @@ quick_error! {
             display("error opening port on UPnP router: {}", e)
             cause(e)
         }
+        RenewPort(e: AddPortError) {
+            description("error renewing port on UPnP router")
+            display("error renewing port on UPnP router: {}", e)
+            cause(e)
+        }
+        ExternalIp(e: GetExternalIpError) {
+            description("error getting external IP of UPnP router")
+            display("error getting external IP of UPnP router: {}", e)
+            cause(e)
+        }
+        RemovePort(e: RemovePortError) {
+            description("error removing port on UPnP router")
+            display("error removing port on UPnP router: {}", e)
+            cause(e)
+        }
+        Pcp(e: PortMappingError) {
+            description("error opening port with PCP")
+            display("error opening port with PCP: {}", e)
+            cause(e)
+        }
+        Pinhole(e: UpnpError) {
+            description("error opening IPv6 firewall pinhole")
+            display("error opening IPv6 firewall pinhole: {}", e)
+            cause(e)
+        }
+        NatPmp(e: PortMappingError) {
+            description("error opening port with NAT-PMP")
+            display("error opening port with NAT-PMP: {}", e)
+            cause(e)
+        }
+        AllFailed(v: Vec<GetAnyAddressError>) {
+            description("all port mapping mechanisms failed")
+            display("all port mapping mechanisms failed: {:#?}", v)
+        }
         Disabled {
-            description("IGD has been disabled in the library")
+            description("port mapping has been disabled in the library")
+        }
+    }
+}
+
+quick_error! {
+    /// Errors returned when removing stale IGD port mappings.
+    #[derive(Debug)]
+    pub enum RemoveStaleMappingsError {
+        /// Failure to find IGD router.
+        FindGateway(e: GetAnyAddressError) {
+            description("failed to find IGD gateway")
+            display("failed to find IGD gateway: {}", e)
+            cause(e)
+        }
+        /// Failure to find our IP address on the router's network.
+        PathToGateway(e: io::Error) {
+            description("error finding path to gateway")
+            display("error finding path to gateway: {}", e)
+            cause(e)
+        }
+        /// Failure to list the port mappings of the router.
+        ListPorts(e: UpnpError) {
+            description("error listing port mappings")
+            display("error listing port mappings: {}", e)
+            cause(e)
+        }
+        /// Failure to remove a port mapping.
+        RemovePort(e: UpnpError) {
+            description("error removing port mapping")
+            display("error removing port mapping: {}", e)
+            cause(e)
+        }
+        /// Port mapping description is the default one, which other applications use too.
+        DefaultDescription {
+            description("refusing to remove port mappings with the default description")
         }
     }
 }
 
+/// Finds IGD router and removes mappings to our host with the port mapping description of `mc`,
+/// which processes that crashed have left behind. Resolves to the number of removed mappings and
+/// errors of the removals that failed. Fails without touching the router, if `mc` has the default
+/// port mapping description.
+pub fn remove_stale_mappings(
+    mc: &P2p,
+) -> BoxFuture<(usize, Vec<RemoveStaleMappingsError>), RemoveStaleMappingsError> {
+    let description = match stale_mapping_description(mc) {
+        Some(description) => description,
+        None => return future::err(RemoveStaleMappingsError::DefaultDescription).into_boxed(),
+    };
+    find_gateway(Ipv4Addr::new(0, 0, 0, 0), mc)
+        .map_err(RemoveStaleMappingsError::FindGateway)
+        .and_then(move |gateway| gateway.gateway.remove_stale_mappings(&description))
+        .into_boxed()
+}
+
 /// Used by the `rendezvous_addr` module. This function will try to temporarily open a port for you
-/// during a rendezvous connect.
+/// during a rendezvous connect. The returned lease lets the mapping be removed, if it's not used.
 pub fn get_any_address_rendezvous(
     protocol: Protocol,
     local_addr: SocketAddr,
     timeout: Duration,
     handle: &Handle,
     mc: &P2p,
-) -> BoxFuture<SocketAddr, GetAnyAddressError> {
+) -> BoxFuture<Lease, GetAnyAddressError> {
     if !mc.is_igd_enabled_for_rendezvous() {
         return future::err(GetAnyAddressError::Disabled).into_boxed();
     }
@@ -143,32 +442,417 @@ pub fn get_any_address_rendezvous(
     get_any_address(protocol, local_addr, Some(timeout), handle, mc)
 }
 
-/// Used by the `open_addr` module. This function will try to permanently open port for a server to
-/// listen on.
+/// Used by the `open_addr` module. This function will try to open port for a server to listen on
+/// for as long as the returned handle lives.
 pub fn get_any_address_open(
     protocol: Protocol,
     local_addr: SocketAddr,
     handle: &Handle,
     mc: &P2p,
-) -> BoxFuture<SocketAddr, GetAnyAddressError> {
-    get_any_address(protocol, local_addr, None, handle, mc)
+) -> BoxFuture<PortMapping, GetAnyAddressError> {
+    let handle = handle.clone();
+    get_any_address(protocol, local_addr, None, &handle, mc)
+        .map(move |lease| PortMapping::from_lease(lease, &handle))
+        .into_boxed()
+}
+
+/// Port mapping made by one of the mechanisms, along with everything needed to renew or remove
+/// it.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    external_addr: SocketAddr,
+    /// Lifetime in seconds, zero for permanent IGD mappings.
+    lifetime: u32,
+    kind: LeaseKind,
+}
+
+#[derive(Debug, Clone)]
+enum LeaseKind {
+    Igd {
+        gateway: Gateway,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        description: String,
+    },
+    Pcp {
+        server_addr: SocketAddr,
+        nonce: [u8; 12],
+        protocol: Protocol,
+        internal_port: u16,
+    },
+    NatPmp {
+        server_addr: SocketAddr,
+        protocol: Protocol,
+        internal_port: u16,
+    },
+    Pinhole {
+        control: FirewallControl,
+        id: u16,
+    },
 }
 
-fn get_any_address(
+impl Lease {
+    pub fn external_addr(&self) -> SocketAddr {
+        self.external_addr
+    }
+
+    /// How long to wait before renewing the lease. Permanent mappings are renewed too, so that
+    /// we notice when router's external IP changes.
+    pub fn renew_after(&self) -> Duration {
+        if self.lifetime == 0 {
+            Duration::from_secs(PERMANENT_LEASE_RECHECK_SEC)
+        } else {
+            Duration::from_secs(u64::from(cmp::max(self.lifetime / 2, 1)))
+        }
+    }
+
+    /// Requests the same lifetime again.
+    ///
+    /// # Returns
+    ///
+    /// Renewed lease, external address of which might have changed.
+    pub fn renew(&self, handle: &Handle) -> BoxFuture<Lease, GetAnyAddressError> {
+        let lifetime = self.lifetime;
+        let renewed = match self.kind {
+            LeaseKind::Igd {
+                ref gateway,
+                protocol,
+                local_addr,
+                ref description,
+            } => gateway
+                .renew_port(
+                    protocol,
+                    self.external_addr.port(),
+                    local_addr,
+                    lifetime,
+                    description,
+                ).map(move |addr| (SocketAddr::V4(addr), lifetime))
+                .into_boxed(),
+            LeaseKind::Pcp {
+                server_addr,
+                nonce,
+                protocol,
+                internal_port,
+            } => pcp::map_port(
+                &server_addr,
+                nonce,
+                protocol,
+                internal_port,
+                lifetime,
+                handle,
+            ).map_err(GetAnyAddressError::Pcp)
+            .into_boxed(),
+            LeaseKind::NatPmp {
+                server_addr,
+                protocol,
+                internal_port,
+            } => nat_pmp::map_port(&server_addr, protocol, internal_port, lifetime, handle)
+                .map(|(addr, lifetime)| (SocketAddr::V4(addr), lifetime))
+                .map_err(GetAnyAddressError::NatPmp)
+                .into_boxed(),
+            LeaseKind::Pinhole { ref control, id } => {
+                let external_addr = self.external_addr;
+                control
+                    .update_pinhole(id, lifetime)
+                    .map(move |()| (external_addr, lifetime))
+                    .map_err(GetAnyAddressError::Pinhole)
+                    .into_boxed()
+            }
+        };
+        let kind = self.kind.clone();
+        renewed
+            .map(move |(external_addr, lifetime)| Lease {
+                external_addr,
+                lifetime,
+                kind,
+            }).into_boxed()
+    }
+
+    /// Removes the mapping from the router.
+    pub fn remove(&self, handle: &Handle) -> BoxFuture<(), GetAnyAddressError> {
+        match self.kind {
+            LeaseKind::Igd {
+                ref gateway,
+                protocol,
+                ..
+            } => gateway
+                .remove_port(protocol, self.external_addr.port())
+                .map_err(GetAnyAddressError::RemovePort)
+                .into_boxed(),
+            // zero lifetime deletes PCP and NAT-PMP mappings
+            LeaseKind::Pcp {
+                server_addr,
+                nonce,
+                protocol,
+                internal_port,
+            } => pcp::map_port(&server_addr, nonce, protocol, internal_port, 0, handle)
+                .map(|_| ())
+                .map_err(GetAnyAddressError::Pcp)
+                .into_boxed(),
+            LeaseKind::NatPmp {
+                server_addr,
+                protocol,
+                internal_port,
+            } => nat_pmp::map_port(&server_addr, protocol, internal_port, 0, handle)
+                .map(|_| ())
+                .map_err(GetAnyAddressError::NatPmp)
+                .into_boxed(),
+            LeaseKind::Pinhole { ref control, id } => control
+                .delete_pinhole(id)
+                .map_err(GetAnyAddressError::Pinhole)
+                .into_boxed(),
+        }
+    }
+}
+
+/// Port mapping mechanisms, in the order of preference.
+#[derive(Debug, Clone, Copy)]
+enum Mechanism {
+    Igd,
+    Pcp,
+    NatPmp,
+    IgdPinhole,
+}
+
+/// Maps `local_addr` to an external address. If `timeout` is given, the mapping is only
+/// temporary. IGD, PCP and NAT-PMP are tried at the same time, unless disabled in `mc`, and the
+/// mapping made by the first of them in this order that succeeds is used. Mappings made by the
+/// others are removed. IPv6 addresses need no mapping, instead a pinhole is opened in the
+/// router's firewall via IGD.
+pub fn get_any_address(
     protocol: Protocol,
     local_addr: SocketAddr,
     timeout: Option<Duration>,
     handle: &Handle,
     mc: &P2p,
-) -> BoxFuture<SocketAddr, GetAnyAddressError> {
-    if !mc.is_igd_enabled() {
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let mut mechanisms = Vec::new();
+    if local_addr.is_ipv6() {
+        if mc.is_igd_enabled() {
+            mechanisms.push(Mechanism::IgdPinhole);
+        }
+    } else {
+        if mc.is_igd_enabled() {
+            mechanisms.push(Mechanism::Igd);
+        }
+        if mc.is_pcp_enabled() {
+            mechanisms.push(Mechanism::Pcp);
+        }
+        if mc.is_nat_pmp_enabled() {
+            mechanisms.push(Mechanism::NatPmp);
+        }
+    }
+    if mechanisms.is_empty() {
         return future::err(GetAnyAddressError::Disabled).into_boxed();
     }
 
+    let mut attempts = FuturesUnordered::new();
+    for (index, &mechanism) in mechanisms.iter().enumerate() {
+        let attempt = match mechanism {
+            Mechanism::Igd => get_any_address_igd(protocol, local_addr, timeout, handle, mc),
+            Mechanism::Pcp => get_any_address_pcp(protocol, local_addr, timeout, handle),
+            Mechanism::NatPmp => get_any_address_nat_pmp(protocol, local_addr, timeout, handle),
+            Mechanism::IgdPinhole => get_any_address_pinhole(protocol, local_addr, timeout, mc),
+        };
+        attempts.push(attempt.then(move |res| {
+            if let Err(ref e) = res {
+                trace!("{:?} port mapping failed: {}", mechanism, e);
+            }
+            Ok::<_, Void>((index, res))
+        }));
+    }
+
+    let handle = handle.clone();
+    let mut results: Vec<Option<Result<Lease, GetAnyAddressError>>> =
+        mechanisms.iter().map(|_| None).collect();
+    future::poll_fn(move || {
+        while let Async::Ready(Some((index, res))) = attempts.poll().void_unwrap() {
+            results[index] = Some(res);
+        }
+
+        let winner = match results.iter().position(|res| match *res {
+            Some(Err(..)) => false,
+            _ => true,
+        }) {
+            Some(index) => index,
+            None => {
+                let mut errors: Vec<GetAnyAddressError> = results
+                    .drain(..)
+                    .filter_map(|res| res.and_then(|res| res.err()))
+                    .collect();
+                let err = if errors.len() == 1 {
+                    errors.remove(0)
+                } else {
+                    GetAnyAddressError::AllFailed(errors)
+                };
+                return Err(err);
+            }
+        };
+        let lease = match results[winner].take() {
+            Some(Ok(lease)) => lease,
+            _ => return Ok(Async::NotReady),
+        };
+
+        for res in results.drain(..) {
+            if let Some(Ok(lease)) = res {
+                remove_unused_lease(lease, &handle);
+            }
+        }
+        let handle_copy = handle.clone();
+        let unfinished = mem::replace(&mut attempts, FuturesUnordered::new());
+        handle.spawn(
+            unfinished
+                .for_each(move |(_, res)| {
+                    if let Ok(lease) = res {
+                        remove_unused_lease(lease, &handle_copy);
+                    }
+                    Ok(())
+                }).infallible(),
+        );
+        Ok(Async::Ready(lease))
+    }).into_boxed()
+}
+
+/// Removes in the background the mapping that lost to a mapping made by another mechanism or to
+/// another address discovery method.
+pub fn remove_unused_lease(lease: Lease, handle: &Handle) {
+    trace!("removing unused port mapping {}", lease.external_addr());
+    handle.spawn(
+        lease
+            .remove(handle)
+            .log_error(LogLevel::Debug, "remove unused port mapping")
+            .infallible(),
+    );
+}
+
+fn get_any_address_pcp(
+    protocol: Protocol,
+    local_addr: SocketAddr,
+    timeout: Option<Duration>,
+    handle: &Handle,
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let server_addr =
+        try_bfut!(port_mapping::gateway_server_addr().map_err(GetAnyAddressError::Pcp));
+    let nonce = pcp::new_nonce();
+    let internal_port = local_addr.port();
+    pcp::map_port(
+        &server_addr,
+        nonce,
+        protocol,
+        internal_port,
+        mapping_lifetime(timeout),
+        handle,
+    ).map(move |(addr, lifetime)| {
+        trace!("PCP mapped {} for {} seconds", addr, lifetime);
+        Lease {
+            external_addr: addr,
+            lifetime,
+            kind: LeaseKind::Pcp {
+                server_addr,
+                nonce,
+                protocol,
+                internal_port,
+            },
+        }
+    }).map_err(GetAnyAddressError::Pcp)
+    .into_boxed()
+}
+
+fn get_any_address_nat_pmp(
+    protocol: Protocol,
+    local_addr: SocketAddr,
+    timeout: Option<Duration>,
+    handle: &Handle,
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let server_addr =
+        try_bfut!(port_mapping::gateway_server_addr().map_err(GetAnyAddressError::NatPmp));
+    map_nat_pmp(
+        &server_addr,
+        protocol,
+        local_addr.port(),
+        mapping_lifetime(timeout),
+        handle,
+    )
+}
+
+/// Maps `internal_port` with NAT-PMP server at `server_addr`.
+pub fn map_nat_pmp(
+    server_addr: &SocketAddr,
+    protocol: Protocol,
+    internal_port: u16,
+    lifetime: u32,
+    handle: &Handle,
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let server_addr = *server_addr;
+    nat_pmp::map_port(&server_addr, protocol, internal_port, lifetime, handle)
+        .map(move |(addr, lifetime)| {
+            trace!("NAT-PMP mapped {} for {} seconds", addr, lifetime);
+            Lease {
+                external_addr: SocketAddr::V4(addr),
+                lifetime,
+                kind: LeaseKind::NatPmp {
+                    server_addr,
+                    protocol,
+                    internal_port,
+                },
+            }
+        }).map_err(GetAnyAddressError::NatPmp)
+        .into_boxed()
+}
+
+/// Opens IPv6 firewall pinhole to `local_addr`.
+fn get_any_address_pinhole(
+    protocol: Protocol,
+    local_addr: SocketAddr,
+    timeout: Option<Duration>,
+    mc: &P2p,
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let pinhole_addr =
+        try_bfut!(igd_firewall::pinhole_addr(&local_addr).map_err(GetAnyAddressError::Pinhole));
+    let lease = match timeout {
+        None => PERMANENT_PINHOLE_LEASE_SEC,
+        Some(duration) => cmp::min(
+            cmp::max(duration.as_secs() as u32, 1),
+            igd_firewall::MAX_LEASE_SEC,
+        ),
+    };
+
+    igd_firewall::search_firewall_control(mc.igd_search_timeout())
+        .and_then(move |control| {
+            control
+                .add_pinhole(protocol, pinhole_addr, lease)
+                .map(move |id| (control, id))
+        }).map(move |(control, id)| {
+            trace!("opened pinhole {} to {} for {} seconds", id, pinhole_addr, lease);
+            Lease {
+                external_addr: SocketAddr::V6(pinhole_addr),
+                lifetime: lease,
+                kind: LeaseKind::Pinhole { control, id },
+            }
+        }).map_err(GetAnyAddressError::Pinhole)
+        .into_boxed()
+}
+
+/// Converts mapping timeout into PCP and NAT-PMP lifetime. Zero lifetime would delete the mapping.
+fn mapping_lifetime(timeout: Option<Duration>) -> u32 {
+    match timeout {
+        None => PERMANENT_MAPPING_LIFETIME_SEC,
+        Some(duration) => cmp::max(duration.as_secs() as u32, 1),
+    }
+}
+
+fn get_any_address_igd(
+    protocol: Protocol,
+    local_addr: SocketAddr,
+    timeout: Option<Duration>,
+    handle: &Handle,
+    mc: &P2p,
+) -> BoxFuture<Lease, GetAnyAddressError> {
     let lease_duration = match timeout {
-        None => 0,
+        None => PERMANENT_IGD_LEASE_SEC,
         Some(duration) => duration.as_secs() as u32,
     };
+    let mc = mc.clone();
 
     let handle = handle.clone();
     let try = || {
@@ -177,42 +861,358 @@ fn get_any_address(
             SocketAddr::V6(..) => return Err(GetAnyAddressError::Ipv6NotSupported),
         };
         Ok({
-            search_gateway_from_timeout(*socket_addr_v4.ip(), Duration::from_millis(200))
-                .map_err(GetAnyAddressError::FindGateway)
-                .and_then(move |gateway| {
-                    let protocol = match protocol {
-                        Protocol::Tcp => PortMappingProtocol::TCP,
-                        Protocol::Udp => PortMappingProtocol::UDP,
-                    };
-                    // TODO(povilas): make port mapping description configurable
-                    gateway
-                        .get_any_address(protocol, socket_addr_v4, lease_duration, "p2p")
-                        .or_else(move |e| {
-                            if let GetAnyAddressError::RequestPort(
-                                AddAnyPortError::OnlyPermanentLeasesSupported,
-                            ) = e
-                            {
-                                if let Some(timeout) = timeout {
-                                    return gateway.get_any_address_manual_timeout(
-                                        protocol,
-                                        socket_addr_v4,
-                                        timeout,
-                                        "p2p",
-                                        &handle,
-                                    );
-                                }
-                            }
-                            future::err(e).into_boxed()
-                        }).map(|addr| {
-                            trace!("igd returned address {}", addr);
-                            SocketAddr::V4(addr)
-                        })
-                })
+            find_gateway(*socket_addr_v4.ip(), &mc).and_then(move |gateway| {
+                cleanup_stale_mappings(&gateway.gateway, &mc)
+                    .infallible()
+                    .and_then(move |()| {
+                        map_igd_port(
+                            gateway,
+                            protocol,
+                            socket_addr_v4,
+                            lease_duration,
+                            timeout,
+                            &mc,
+                            &handle,
+                        )
+                    })
+            })
         })
     };
     future::result(try()).flatten().into_boxed()
 }
 
+/// Removes stale mappings before the first mapping made with `mc`, if enabled.
+fn cleanup_stale_mappings(gateway: &Gateway, mc: &P2p) -> BoxFuture<(), Void> {
+    if !start_stale_mapping_cleanup(mc) {
+        return future::ok(()).into_boxed();
+    }
+    let description = match stale_mapping_description(mc) {
+        Some(description) => description,
+        None => {
+            debug!("not removing stale port mappings with the default description");
+            return future::ok(()).into_boxed();
+        }
+    };
+    gateway
+        .remove_stale_mappings(&description)
+        .then(|res| {
+            match res {
+                Ok((removed, errors)) => {
+                    debug!("removed {} stale port mappings", removed);
+                    for e in errors {
+                        debug!("failed to remove stale port mapping: {}", e);
+                    }
+                }
+                Err(e) => debug!("failed to remove stale port mappings: {}", e),
+            }
+            Ok(())
+        }).into_boxed()
+}
+
+/// Maps `local_addr` to an external port. If the gateway only supports permanent leases, the
+/// mapping is permanent and that's remembered in the gateway cache of `mc`, so that we don't ask
+/// for temporary leases again.
+fn map_igd_port(
+    gateway: CachedGateway,
+    protocol: Protocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    timeout: Option<Duration>,
+    mc: &P2p,
+    handle: &Handle,
+) -> BoxFuture<Lease, GetAnyAddressError> {
+    let protocol = match protocol {
+        Protocol::Tcp => PortMappingProtocol::TCP,
+        Protocol::Udp => PortMappingProtocol::UDP,
+    };
+    let description = mc.port_mapping_description();
+    let CachedGateway {
+        gateway,
+        search_ip,
+        only_permanent_leases,
+    } = gateway;
+
+    let mapped = if only_permanent_leases && lease_duration != 0 {
+        future::err(GetAnyAddressError::RequestPort(
+            AddAnyPortError::OnlyPermanentLeasesSupported,
+        )).into_boxed()
+    } else {
+        gateway
+            .get_any_address(protocol, local_addr, lease_duration, &description)
+            .map(move |addr| (addr, lease_duration))
+            .into_boxed()
+    };
+    let fallback_gateway = gateway.clone();
+    let fallback_description = description.clone();
+    let mc = mc.clone();
+    let handle = handle.clone();
+    mapped
+        .or_else(move |e| {
+            if let GetAnyAddressError::RequestPort(AddAnyPortError::OnlyPermanentLeasesSupported) =
+                e
+            {
+                if !only_permanent_leases {
+                    with_gateway_cache(&mc, |cache| cache.set_only_permanent_leases(search_ip));
+                }
+                let gateway = fallback_gateway;
+                let description = fallback_description;
+                return match timeout {
+                    Some(timeout) => gateway.get_any_address_manual_timeout(
+                        protocol,
+                        local_addr,
+                        timeout,
+                        &description,
+                        &handle,
+                    ),
+                    None => gateway.get_any_address(protocol, local_addr, 0, &description),
+                }.map(|addr| (addr, 0))
+                .into_boxed();
+            }
+            future::err(e).into_boxed()
+        }).map(move |(addr, lifetime)| {
+            trace!("igd returned address {}", addr);
+            Lease {
+                external_addr: SocketAddr::V4(addr),
+                lifetime,
+                kind: LeaseKind::Igd {
+                    gateway,
+                    protocol,
+                    local_addr,
+                    description,
+                },
+            }
+        }).into_boxed()
+}
+
+/// IGD gateway found by a recent search.
+#[derive(Debug, Clone)]
+pub struct CachedGateway {
+    pub gateway: Gateway,
+    /// Local IP the gateway was searched from.
+    pub search_ip: Ipv4Addr,
+    /// Gateway refused to map ports with finite lease before.
+    pub only_permanent_leases: bool,
+}
+
+/// Result of a gateway cache lookup.
+pub enum Lookup {
+    Found(CachedGateway),
+    /// Recent search didn't find any gateway.
+    NotFound,
+    /// Someone else is searching already, their result will be sent here.
+    Wait(oneshot::Receiver<Option<CachedGateway>>),
+    /// Nothing is known, caller should search and `insert()` the result.
+    Search,
+}
+
+enum CacheEntry {
+    Searching {
+        waiters: Vec<oneshot::Sender<Option<CachedGateway>>>,
+        expires: Instant,
+    },
+    Found {
+        gateway: CachedGateway,
+        expires: Instant,
+    },
+    NotFound {
+        expires: Instant,
+    },
+}
+
+/// Results of IGD gateway searches, per local IP they were searched from. `P2p` keeps this, so
+/// that mappings for many sockets don't search for the same gateway again and again.
+#[derive(Default)]
+pub struct GatewayCache {
+    entries: HashMap<Ipv4Addr, CacheEntry>,
+}
+
+impl GatewayCache {
+    /// Looks up gateway reachable from `search_ip`. If the caller is asked to search, searches of
+    /// others wait for its result until `search_expires`.
+    pub fn lookup(&mut self, search_ip: Ipv4Addr, now: Instant, search_expires: Instant) -> Lookup {
+        let lookup = match self.entries.get_mut(&search_ip) {
+            Some(&mut CacheEntry::Searching {
+                ref mut waiters,
+                expires,
+            }) if expires > now =>
+            {
+                let (result_tx, result_rx) = oneshot::channel();
+                waiters.push(result_tx);
+                Lookup::Wait(result_rx)
+            }
+            Some(&mut CacheEntry::Found {
+                ref gateway,
+                expires,
+            }) if expires > now =>
+            {
+                Lookup::Found(gateway.clone())
+            }
+            Some(&mut CacheEntry::NotFound { expires }) if expires > now => Lookup::NotFound,
+            _ => Lookup::Search,
+        };
+        if let Lookup::Search = lookup {
+            let entry = CacheEntry::Searching {
+                waiters: Vec::new(),
+                expires: search_expires,
+            };
+            let _ = self.entries.insert(search_ip, entry);
+        }
+        lookup
+    }
+
+    /// Caches search result and hands it to those waiting for it.
+    pub fn insert(&mut self, search_ip: Ipv4Addr, gateway: Option<Gateway>, now: Instant) {
+        let gateway = gateway.map(|gateway| CachedGateway {
+            gateway,
+            search_ip,
+            only_permanent_leases: false,
+        });
+        let entry = match gateway {
+            Some(ref gateway) => CacheEntry::Found {
+                gateway: gateway.clone(),
+                expires: now + Duration::from_secs(GATEWAY_CACHE_TTL_SEC),
+            },
+            None => CacheEntry::NotFound {
+                expires: now + Duration::from_secs(NO_GATEWAY_CACHE_TTL_SEC),
+            },
+        };
+        if let Some(CacheEntry::Searching { waiters, .. }) = self.entries.insert(search_ip, entry) {
+            for waiter in waiters {
+                let _ = waiter.send(gateway.clone());
+            }
+        }
+    }
+
+    /// Forgets the search that was started with `search_expires`, if its result wasn't inserted.
+    /// Those waiting for its result are told that no gateway was found.
+    pub fn abandon(&mut self, search_ip: Ipv4Addr, search_expires: Instant) {
+        let abandoned = match self.entries.get(&search_ip) {
+            Some(&CacheEntry::Searching { expires, .. }) => expires == search_expires,
+            _ => false,
+        };
+        if abandoned {
+            let _ = self.entries.remove(&search_ip);
+        }
+    }
+
+    /// Remembers that gateway reachable from `search_ip` only supports permanent leases.
+    pub fn set_only_permanent_leases(&mut self, search_ip: Ipv4Addr) {
+        if let Some(&mut CacheEntry::Found {
+            ref mut gateway, ..
+        }) = self.entries.get_mut(&search_ip)
+        {
+            gateway.only_permanent_leases = true;
+        }
+    }
+
+    /// Forgets all search results.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Finds IGD gateway reachable from `search_ip`, reusing recent search results of `mc`.
+pub fn find_gateway(search_ip: Ipv4Addr, mc: &P2p) -> BoxFuture<CachedGateway, GetAnyAddressError> {
+    let search_timeout = mc.igd_search_timeout();
+    let search_retries = mc.igd_search_retries();
+    let now = Instant::now();
+    // leave some slack for the search thread to start
+    let search_expires = now + search_timeout * (search_retries + 1) + Duration::from_secs(1);
+    let lookup = with_gateway_cache(mc, |cache| cache.lookup(search_ip, now, search_expires));
+    match lookup {
+        Lookup::Found(gateway) => future::ok(gateway).into_boxed(),
+        Lookup::NotFound => future::err(GetAnyAddressError::NoGateway).into_boxed(),
+        Lookup::Wait(result_rx) => result_rx
+            .then(|res| match res {
+                Ok(Some(gateway)) => Ok(gateway),
+                _ => Err(GetAnyAddressError::NoGateway),
+            }).into_boxed(),
+        Lookup::Search => {
+            let search = SearchGuard {
+                mc: mc.clone(),
+                search_ip,
+                search_expires,
+                finished: false,
+            };
+            search_gateway_with_retries(search_ip, search_timeout, search_retries)
+                .then(move |res| {
+                    search.finish(res.as_ref().ok().cloned());
+                    res.map(|gateway| CachedGateway {
+                        gateway,
+                        search_ip,
+                        only_permanent_leases: false,
+                    }).map_err(GetAnyAddressError::FindGateway)
+                }).into_boxed()
+        }
+    }
+}
+
+/// Gateway search we were asked to do by the gateway cache. If the search is dropped before it
+/// finishes, it's abandoned, so that others don't wait for its result forever.
+struct SearchGuard {
+    mc: P2p,
+    search_ip: Ipv4Addr,
+    search_expires: Instant,
+    finished: bool,
+}
+
+impl SearchGuard {
+    /// Caches the search result and hands it to those waiting for it.
+    fn finish(mut self, gateway: Option<Gateway>) {
+        let search_ip = self.search_ip;
+        with_gateway_cache(&self.mc, |cache| cache.insert(search_ip, gateway, Instant::now()));
+        self.finished = true;
+    }
+}
+
+impl Drop for SearchGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            trace!("gateway search from {} was abandoned", self.search_ip);
+            let (search_ip, search_expires) = (self.search_ip, self.search_expires);
+            with_gateway_cache(&self.mc, |cache| cache.abandon(search_ip, search_expires));
+        }
+    }
+}
+
+/// Searches for IGD gateway, retrying `retries` times on failure.
+fn search_gateway_with_retries(
+    search_ip: Ipv4Addr,
+    timeout: Duration,
+    retries: u32,
+) -> BoxFuture<Gateway, UpnpError> {
+    future::loop_fn(retries, move |retries_left| {
+        search_gateway_from_timeout(search_ip, timeout).then(move |res| match res {
+            Ok(gateway) => Ok(Loop::Break(gateway)),
+            Err(e) => {
+                if retries_left == 0 {
+                    return Err(e);
+                }
+                trace!("IGD gateway search failed, retrying: {}", e);
+                Ok(Loop::Continue(retries_left - 1))
+            }
+        })
+    }).into_boxed()
+}
+
+/// Parses `GetGenericPortMappingEntry` response.
+fn parse_port_mapping_entry(response: &str) -> Option<PortMappingEntry> {
+    let protocol = match igd_firewall::element_text(response, "NewProtocol")? {
+        "TCP" => PortMappingProtocol::TCP,
+        "UDP" => PortMappingProtocol::UDP,
+        _ => return None,
+    };
+    let text = |tag: &str| igd_firewall::element_text(response, tag);
+    Some(PortMappingEntry {
+        protocol,
+        external_port: text("NewExternalPort")?.parse().ok()?,
+        internal_client: text("NewInternalClient")?.parse().ok()?,
+        internal_port: text("NewInternalPort")?.parse().ok()?,
+        description: text("NewPortMappingDescription").unwrap_or("").to_string(),
+        lease_duration: text("NewLeaseDuration")?.parse().ok()?,
+    })
+}
+
 /// # Returns
 ///
 /// Local IP address that is on the same subnet as gateway address. Returned address is always
@@ -322,4 +1322,185 @@ mod test {
             assert_eq!(unwrap!(local_addr), ipv4!("192.168.1.100"));
         }
     }
+
+    mod gateway_cache {
+        use super::*;
+
+        fn gateway() -> Gateway {
+            unwrap!(Gateway::new(ServiceControl::new(
+                addr!("192.168.1.1:5000"),
+                String::from("/ctl/IPConn"),
+                String::from(WAN_CONNECTION_SERVICES[0]),
+            )))
+        }
+
+        #[test]
+        fn it_lets_one_caller_search_and_others_wait_for_the_result() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_expires = now + Duration::from_secs(1);
+            let search_ip = ipv4!("192.168.1.100");
+
+            match cache.lookup(search_ip, now, search_expires) {
+                Lookup::Search => (),
+                _ => panic!("expected to search"),
+            }
+            let result_rx = match cache.lookup(search_ip, now, search_expires) {
+                Lookup::Wait(result_rx) => result_rx,
+                _ => panic!("expected to wait for search result"),
+            };
+            cache.insert(search_ip, Some(gateway()), now);
+
+            let waited = unwrap!(unwrap!(result_rx.wait()));
+            assert_eq!(waited.gateway.inner.addr, gateway().inner.addr);
+            match cache.lookup(search_ip, now, search_expires) {
+                Lookup::Found(found) => assert_eq!(found.search_ip, search_ip),
+                _ => panic!("expected cached gateway"),
+            }
+        }
+
+        #[test]
+        fn it_remembers_that_no_gateway_was_found_for_a_while() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_expires = now + Duration::from_secs(1);
+            let search_ip = ipv4!("0.0.0.0");
+            let _ = cache.lookup(search_ip, now, search_expires);
+            cache.insert(search_ip, None, now);
+
+            match cache.lookup(search_ip, now, search_expires) {
+                Lookup::NotFound => (),
+                _ => panic!("expected cached negative result"),
+            }
+            let later = now + Duration::from_secs(NO_GATEWAY_CACHE_TTL_SEC + 1);
+            match cache.lookup(search_ip, later, later + Duration::from_secs(1)) {
+                Lookup::Search => (),
+                _ => panic!("expected to search again"),
+            }
+        }
+
+        #[test]
+        fn it_searches_again_when_previous_search_never_finished() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_ip = ipv4!("0.0.0.0");
+            let _ = cache.lookup(search_ip, now, now + Duration::from_secs(1));
+
+            let later = now + Duration::from_secs(2);
+            match cache.lookup(search_ip, later, later + Duration::from_secs(1)) {
+                Lookup::Search => (),
+                _ => panic!("expected to search again"),
+            }
+        }
+
+        #[test]
+        fn when_search_is_abandoned_waiters_get_no_gateway_and_next_caller_searches() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_expires = now + Duration::from_secs(1);
+            let search_ip = ipv4!("0.0.0.0");
+            let _ = cache.lookup(search_ip, now, search_expires);
+            let result_rx = match cache.lookup(search_ip, now, search_expires) {
+                Lookup::Wait(result_rx) => result_rx,
+                _ => panic!("expected to wait for search result"),
+            };
+
+            cache.abandon(search_ip, search_expires);
+
+            assert!(result_rx.wait().is_err());
+            match cache.lookup(search_ip, now, search_expires) {
+                Lookup::Search => (),
+                _ => panic!("expected to search again"),
+            }
+        }
+
+        #[test]
+        fn it_doesnt_abandon_newer_search() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_ip = ipv4!("0.0.0.0");
+            let _ = cache.lookup(search_ip, now, now + Duration::from_secs(1));
+            let later = now + Duration::from_secs(2);
+            let _ = cache.lookup(search_ip, later, later + Duration::from_secs(1));
+
+            cache.abandon(search_ip, now + Duration::from_secs(1));
+
+            match cache.lookup(search_ip, later, later + Duration::from_secs(1)) {
+                Lookup::Wait(..) => (),
+                _ => panic!("expected to wait for newer search"),
+            }
+        }
+
+        #[test]
+        fn it_remembers_that_gateway_only_supports_permanent_leases() {
+            let mut cache = GatewayCache::default();
+            let now = Instant::now();
+            let search_ip = ipv4!("0.0.0.0");
+            let _ = cache.lookup(search_ip, now, now + Duration::from_secs(1));
+            cache.insert(search_ip, Some(gateway()), now);
+
+            cache.set_only_permanent_leases(search_ip);
+
+            match cache.lookup(search_ip, now, now + Duration::from_secs(1)) {
+                Lookup::Found(found) => assert!(found.only_permanent_leases),
+                _ => panic!("expected cached gateway"),
+            }
+        }
+    }
+
+    mod parse_port_mapping_entry {
+        use super::*;
+
+        fn response(protocol: &str, internal_client: &str) -> String {
+            format!(
+                "<?xml version=\"1.0\"?><s:Envelope><s:Body>\
+                 <u:GetGenericPortMappingEntryResponse>\
+                 <NewRemoteHost></NewRemoteHost><NewExternalPort>5001</NewExternalPort>\
+                 <NewProtocol>{}</NewProtocol><NewInternalPort>5000</NewInternalPort>\
+                 <NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+                 <NewPortMappingDescription>p2p</NewPortMappingDescription>\
+                 <NewLeaseDuration>0</NewLeaseDuration>\
+                 </u:GetGenericPortMappingEntryResponse></s:Body></s:Envelope>",
+                protocol, internal_client,
+            )
+        }
+
+        #[test]
+        fn it_returns_port_mapping_entry() {
+            let entry = unwrap!(parse_port_mapping_entry(&response("UDP", "192.168.1.100")));
+
+            match entry.protocol {
+                PortMappingProtocol::UDP => (),
+                protocol => panic!("unexpected protocol: {:?}", protocol),
+            }
+            assert_eq!(entry.external_port, 5001);
+            assert_eq!(entry.internal_client, ipv4!("192.168.1.100"));
+            assert_eq!(entry.internal_port, 5000);
+            assert_eq!(entry.description, "p2p");
+            assert_eq!(entry.lease_duration, 0);
+        }
+
+        #[test]
+        fn it_returns_none_when_internal_client_is_not_ip_address() {
+            let entry = parse_port_mapping_entry(&response("TCP", "my-laptop"));
+
+            assert!(entry.is_none());
+        }
+    }
+
+    mod remove_stale_mappings {
+        use super::*;
+
+        #[test]
+        fn when_port_mapping_description_is_the_default_one_it_refuses_to_remove_mappings() {
+            let p2p = P2p::default();
+
+            let res = remove_stale_mappings(&p2p).wait();
+
+            match res {
+                Err(RemoveStaleMappingsError::DefaultDescription) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
 }
@@ -0,0 +1,644 @@
+//! Client of UPnP IGDv2 `WANIPv6FirewallControl` service.
+//!
+//! IPv6 hosts don't need NAT traversal, but home routers usually run a stateful firewall which
+//! drops unsolicited inbound packets. IGDv2 routers let us open pinholes in that firewall. The
+//! `igd` crate doesn't support this service, so here's a minimal SSDP, HTTP and SOAP client. The
+//! SOAP client is also used for the actions of other UPnP services that `igd` lacks.
+
+use future_utils::thread_future;
+use priv_prelude::*;
+use socket_addr::ipv6_addrs;
+use std::io::{Read, Write};
+use std::net;
+
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+/// SSDP multicast address.
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+/// How long we wait for the router to respond to HTTP requests.
+const HTTP_TIMEOUT_SEC: u64 = 3;
+/// Pinhole lease time allowed by the standard is between 1 second and 1 day.
+pub const MAX_LEASE_SEC: u32 = 86_400;
+
+quick_error! {
+    /// Errors returned by our UPnP client.
+    #[derive(Debug)]
+    pub enum UpnpError {
+        /// Network failure talking to the router.
+        Io(e: io::Error) {
+            description("error communicating with the router")
+            display("error communicating with the router: {}", e)
+            cause(e)
+        }
+        /// No router with `WANIPv6FirewallControl` service responded.
+        NotFound {
+            description("no router with IPv6 firewall control found")
+        }
+        /// We don't have a global IPv6 address to open the pinhole to.
+        NoGlobalAddr {
+            description("no global IPv6 address to open pinhole to")
+        }
+        /// Router's device description is missing the control URL or it's not understood.
+        InvalidDescription {
+            description("invalid device description of the router")
+        }
+        /// Router responded with unexpected HTTP status.
+        Http(status: u16) {
+            description("unexpected HTTP response status")
+            display("unexpected HTTP response status: {}", status)
+        }
+        /// Router refused SOAP action, e.g. because pinholes are not allowed.
+        Soap(code: u16, description: String) {
+            description("router returned UPnP error")
+            display("router returned UPnP error {}: {}", code, description)
+        }
+        /// Malformed response.
+        InvalidResponse {
+            description("invalid response from router")
+        }
+    }
+}
+
+impl From<io::Error> for UpnpError {
+    fn from(e: io::Error) -> UpnpError {
+        UpnpError::Io(e)
+    }
+}
+
+/// Control endpoint of `WANIPv6FirewallControl` service.
+#[derive(Debug, Clone)]
+pub struct FirewallControl {
+    addr: SocketAddr,
+    path: String,
+}
+
+/// Control endpoint of a UPnP service found by `search_service()`.
+#[derive(Debug, Clone)]
+pub struct ServiceControl {
+    addr: SocketAddr,
+    path: String,
+    service_type: String,
+}
+
+impl ServiceControl {
+    /// Creates control endpoint of `service_type` service at `http://addr/path`.
+    pub fn new(addr: SocketAddr, path: String, service_type: String) -> ServiceControl {
+        ServiceControl {
+            addr,
+            path,
+            service_type,
+        }
+    }
+
+    /// Address of the HTTP server the service is controlled via.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Path of the control URL.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Type of the service as listed in the device description, e.g.
+    /// `urn:schemas-upnp-org:service:WANPPPConnection:1`.
+    pub fn service_type(&self) -> &str {
+        &self.service_type
+    }
+
+    /// Invokes SOAP action of the service and returns the response body.
+    pub fn soap_action(&self, action: &str, args: &[(&str, String)]) -> Result<String, UpnpError> {
+        soap_action(&self.addr, &self.path, &self.service_type, action, args)
+    }
+}
+
+/// Sends SSDP search for `search_target` devices from `bind_ip` and finds the first service of
+/// the first responding device, type of which is one of `service_types`. Blocks for up to
+/// `timeout`, plus the time it takes to fetch the device description.
+pub fn search_service(
+    bind_ip: Ipv4Addr,
+    search_target: &str,
+    service_types: &[&str],
+    timeout: Duration,
+) -> Result<ServiceControl, UpnpError> {
+    let bind_addr = SocketAddr::new(IpAddr::V4(bind_ip), 0);
+    let ssdp_addr: SocketAddr = unwrap!(SSDP_ADDR.parse());
+    search_service_at(&bind_addr, &ssdp_addr, search_target, service_types, timeout)
+}
+
+/// Asynchronously searches local network for a router with `WANIPv6FirewallControl` service.
+pub fn search_firewall_control(timeout: Duration) -> BoxFuture<FirewallControl, UpnpError> {
+    thread_future(move || {
+        let ssdp_addr: SocketAddr = unwrap!(SSDP_ADDR.parse());
+        search_at(&ssdp_addr, timeout)
+    }).infallible()
+    .and_then(|r| r)
+    .into_boxed()
+}
+
+/// Picks the global IPv6 address pinholes for `local_addr` are opened to: `local_addr` itself or
+/// one of our addresses, if it's unspecified.
+pub fn pinhole_addr(local_addr: &SocketAddr) -> Result<SocketAddrV6, UpnpError> {
+    let (global_addrs, _) = ipv6_addrs(local_addr)?;
+    match global_addrs.first() {
+        Some(&SocketAddr::V6(addr)) => Ok(addr),
+        _ => Err(UpnpError::NoGlobalAddr),
+    }
+}
+
+impl FirewallControl {
+    /// Opens a pinhole for packets from any remote endpoint to `internal_addr` for `lease`
+    /// seconds.
+    ///
+    /// # Returns
+    ///
+    /// Pinhole ID, which is used to renew it.
+    pub fn add_pinhole(
+        &self,
+        protocol: Protocol,
+        internal_addr: SocketAddrV6,
+        lease: u32,
+    ) -> BoxFuture<u16, UpnpError> {
+        let control = self.clone();
+        thread_future(move || control.add_pinhole_sync(protocol, &internal_addr, lease))
+            .infallible()
+            .and_then(|r| r)
+            .into_boxed()
+    }
+
+    /// Extends the lease of a pinhole.
+    pub fn update_pinhole(&self, id: u16, lease: u32) -> BoxFuture<(), UpnpError> {
+        let control = self.clone();
+        thread_future(move || control.update_pinhole_sync(id, lease))
+            .infallible()
+            .and_then(|r| r)
+            .into_boxed()
+    }
+
+    /// Closes a pinhole before its lease expires.
+    pub fn delete_pinhole(&self, id: u16) -> BoxFuture<(), UpnpError> {
+        let control = self.clone();
+        thread_future(move || control.delete_pinhole_sync(id))
+            .infallible()
+            .and_then(|r| r)
+            .into_boxed()
+    }
+
+    fn add_pinhole_sync(
+        &self,
+        protocol: Protocol,
+        internal_addr: &SocketAddrV6,
+        lease: u32,
+    ) -> Result<u16, UpnpError> {
+        let protocol = match protocol {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        };
+        let response = self.soap_action(
+            "AddPinhole",
+            &[
+                // empty remote host and zero remote port are wildcards
+                ("RemoteHost", String::new()),
+                ("RemotePort", String::from("0")),
+                ("InternalClient", internal_addr.ip().to_string()),
+                ("InternalPort", internal_addr.port().to_string()),
+                ("Protocol", protocol.to_string()),
+                ("LeaseTime", lease.to_string()),
+            ],
+        )?;
+        element_text(&response, "UniqueID")
+            .and_then(|id| id.parse().ok())
+            .ok_or(UpnpError::InvalidResponse)
+    }
+
+    fn update_pinhole_sync(&self, id: u16, lease: u32) -> Result<(), UpnpError> {
+        let _ = self.soap_action(
+            "UpdatePinhole",
+            &[
+                ("UniqueID", id.to_string()),
+                ("NewLeaseTime", lease.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_pinhole_sync(&self, id: u16) -> Result<(), UpnpError> {
+        let _ = self.soap_action("DeletePinhole", &[("UniqueID", id.to_string())])?;
+        Ok(())
+    }
+
+    fn soap_action(&self, action: &str, args: &[(&str, String)]) -> Result<String, UpnpError> {
+        soap_action(&self.addr, &self.path, SERVICE_TYPE, action, args)
+    }
+}
+
+/// Invokes SOAP action of UPnP `service` at the given control endpoint and returns the response
+/// body.
+pub fn soap_action(
+    addr: &SocketAddr,
+    path: &str,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)],
+) -> Result<String, UpnpError> {
+    let args: String = args
+        .iter()
+        .map(|&(name, ref value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>",
+        action = action,
+        service = service,
+        args = args,
+    );
+    let request = format!(
+        "POST {path} HTTP/1.0\r\n\
+         Host: {addr}\r\n\
+         Content-Type: text/xml; charset=\"utf-8\"\r\n\
+         Content-Length: {len}\r\n\
+         SOAPAction: \"{service}#{action}\"\r\n\
+         \r\n\
+         {body}",
+        path = path,
+        addr = addr,
+        len = body.len(),
+        service = service,
+        action = action,
+        body = body,
+    );
+    let (status, response) = http_request(addr, &request)?;
+    if status != 200 {
+        let code = element_text(&response, "errorCode").and_then(|code| code.parse().ok());
+        let description = element_text(&response, "errorDescription").unwrap_or("");
+        return Err(match code {
+            Some(code) => UpnpError::Soap(code, description.to_string()),
+            None => UpnpError::Http(status),
+        });
+    }
+    Ok(response)
+}
+
+/// Sends SSDP search request for `WANIPv6FirewallControl` service to `ssdp_addr`.
+fn search_at(ssdp_addr: &SocketAddr, timeout: Duration) -> Result<FirewallControl, UpnpError> {
+    let control = search_service_at(
+        &addr!("0.0.0.0:0"),
+        ssdp_addr,
+        SERVICE_TYPE,
+        &[SERVICE_TYPE],
+        timeout,
+    )?;
+    Ok(FirewallControl {
+        addr: control.addr,
+        path: control.path,
+    })
+}
+
+/// Sends SSDP search request to `ssdp_addr` and fetches device description of the first
+/// responding device.
+fn search_service_at(
+    bind_addr: &SocketAddr,
+    ssdp_addr: &SocketAddr,
+    search_target: &str,
+    service_types: &[&str],
+    timeout: Duration,
+) -> Result<ServiceControl, UpnpError> {
+    let socket = net::UdpSocket::bind(bind_addr)?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 1\r\n\
+         ST: {}\r\n\
+         \r\n",
+        SSDP_ADDR, search_target,
+    );
+    let _ = socket.send_to(request.as_bytes(), ssdp_addr)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UpnpError::NotFound);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let mut buffer = [0u8; 1500];
+        let len = match socket.recv_from(&mut buffer) {
+            Ok((len, _)) => len,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(UpnpError::NotFound)
+            }
+            Err(e) => return Err(UpnpError::Io(e)),
+        };
+        let response = String::from_utf8_lossy(&buffer[..len]);
+        if let Some(location) = header_value(&response, "location") {
+            trace!("UPnP device found at {}", location);
+            return fetch_control(location, service_types);
+        }
+    }
+}
+
+/// Fetches device description from `location` and finds control URL of the first service of one
+/// of `service_types` in it.
+fn fetch_control(location: &str, service_types: &[&str]) -> Result<ServiceControl, UpnpError> {
+    let (addr, path) = parse_url(location).ok_or(UpnpError::InvalidDescription)?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr);
+    let (status, description) = http_request(&addr, &request)?;
+    if status != 200 {
+        return Err(UpnpError::Http(status));
+    }
+
+    let (service_type, control_url) = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| {
+            let service_type = element_text(service, "serviceType")?;
+            if !service_types.contains(&service_type) {
+                return None;
+            }
+            Some((service_type.to_string(), element_text(service, "controlURL")?))
+        }).next()
+        .ok_or(UpnpError::InvalidDescription)?;
+    if control_url.starts_with("http://") {
+        let (addr, path) = parse_url(control_url).ok_or(UpnpError::InvalidDescription)?;
+        return Ok(ServiceControl::new(addr, path, service_type));
+    }
+    let base_addr = match element_text(&description, "URLBase") {
+        Some(base) => parse_url(base).ok_or(UpnpError::InvalidDescription)?.0,
+        None => addr,
+    };
+    let path = if control_url.starts_with('/') {
+        control_url.to_string()
+    } else {
+        format!("/{}", control_url)
+    };
+    Ok(ServiceControl::new(base_addr, path, service_type))
+}
+
+/// Sends HTTP request and reads the whole response.
+///
+/// # Returns
+///
+/// Response status and body.
+fn http_request(addr: &SocketAddr, request: &str) -> Result<(u16, String), UpnpError> {
+    let timeout = Duration::from_secs(HTTP_TIMEOUT_SEC);
+    let mut stream = net::TcpStream::connect_timeout(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response)?;
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(UpnpError::InvalidResponse)?;
+    let body_start = response
+        .find("\r\n\r\n")
+        .ok_or(UpnpError::InvalidResponse)?
+        + 4;
+    Ok((status, response[body_start..].to_string()))
+}
+
+/// Returns value of the given HTTP header. Header names are case insensitive.
+fn header_value<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().skip(1).filter_map(|line| {
+        let colon = line.find(':')?;
+        if line[..colon].trim().eq_ignore_ascii_case(name) {
+            Some(line[colon + 1..].trim())
+        } else {
+            None
+        }
+    }).next()
+}
+
+/// Splits `http://host:port/path` into the address and path. Only IP address hosts are
+/// supported.
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+    let url = url.trim();
+    if !url.starts_with("http://") {
+        return None;
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let addr = host
+        .parse()
+        .ok()
+        .or_else(|| format!("{}:80", host).parse().ok())?;
+    Some((addr, path.to_string()))
+}
+
+/// Returns trimmed text of the first `tag` element in `xml`. Namespace prefixes are ignored and
+/// elements with attributes are not supported.
+pub fn element_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let mut pos = 0;
+    while let Some(i) = xml[pos..].find(tag) {
+        let start = pos + i;
+        let end = start + tag.len();
+        pos = end;
+        let is_opening_tag = xml[..start].rfind('<').map_or(false, |lt| {
+            let prefix = &xml[lt + 1..start];
+            let is_namespace = |c: char| c == ':' || c.is_alphanumeric();
+            prefix.is_empty() || (prefix.ends_with(':') && prefix.chars().all(is_namespace))
+        });
+        if !is_opening_tag || !xml[end..].starts_with('>') {
+            continue;
+        }
+        let text = &xml[end + 1..];
+        let text_end = text.find('<')?;
+        return Some(text[..text_end].trim());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1\
+        </serviceType><controlURL>/ctl/IP6FCtl</controlURL></service>\
+        </serviceList></device></root>";
+
+    /// Stands in for IGDv2 router: answers SSDP search on one socket and serves device
+    /// description and firewall control on an HTTP port. SOAP actions are answered with
+    /// `soap_response` and requests are sent to `requests_tx`.
+    fn spawn_router(
+        soap_response: &'static str,
+        requests_tx: ::std::sync::mpsc::Sender<String>,
+    ) -> SocketAddr {
+        let http_listener = unwrap!(net::TcpListener::bind("127.0.0.1:0"));
+        let http_addr = unwrap!(http_listener.local_addr());
+        let ssdp_socket = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+        let ssdp_addr = unwrap!(ssdp_socket.local_addr());
+
+        let _ = thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            let (_, addr) = unwrap!(ssdp_socket.recv_from(&mut buffer));
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nST: {}\r\nLOCATION: http://{}/rootDesc.xml\r\n\r\n",
+                SERVICE_TYPE, http_addr,
+            );
+            let _ = unwrap!(ssdp_socket.send_to(response.as_bytes(), &addr));
+        });
+        let _ = thread::spawn(move || {
+            for stream in http_listener.incoming() {
+                let mut stream = unwrap!(stream);
+                let mut buffer = [0u8; 4096];
+                let len = unwrap!(stream.read(&mut buffer));
+                let request = String::from_utf8_lossy(&buffer[..len]).into_owned();
+                let response = if request.starts_with("GET /rootDesc.xml") {
+                    format!("HTTP/1.0 200 OK\r\n\r\n{}", DESCRIPTION)
+                } else {
+                    String::from(soap_response)
+                };
+                let _ = requests_tx.send(request);
+                unwrap!(stream.write_all(response.as_bytes()));
+            }
+        });
+        ssdp_addr
+    }
+
+    mod search_at {
+        use super::*;
+
+        #[test]
+        fn it_finds_firewall_control_url_in_device_description() {
+            let (requests_tx, _requests_rx) = ::std::sync::mpsc::channel();
+            let ssdp_addr = spawn_router("", requests_tx);
+
+            let control = unwrap!(search_at(&ssdp_addr, Duration::from_secs(3)));
+
+            assert_eq!(control.path, "/ctl/IP6FCtl");
+            assert_eq!(control.addr.ip(), ipv4!("127.0.0.1"));
+        }
+
+        #[test]
+        fn when_nobody_responds_it_returns_not_found() {
+            let socket = unwrap!(net::UdpSocket::bind("127.0.0.1:0"));
+            let ssdp_addr = unwrap!(socket.local_addr());
+
+            let res = search_at(&ssdp_addr, Duration::from_millis(200));
+
+            match res {
+                Err(UpnpError::NotFound) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod search_service_at {
+        use super::*;
+
+        #[test]
+        fn it_returns_the_type_of_the_service_found() {
+            let (requests_tx, requests_rx) = ::std::sync::mpsc::channel();
+            let ssdp_addr = spawn_router("HTTP/1.0 200 OK\r\n\r\n", requests_tx);
+            let service_types = [
+                "urn:schemas-upnp-org:service:WANPPPConnection:1",
+                "urn:schemas-upnp-org:service:WANIPConnection:2",
+            ];
+
+            let control = unwrap!(search_service_at(
+                &addr!("0.0.0.0:0"),
+                &ssdp_addr,
+                "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+                &service_types,
+                Duration::from_secs(3),
+            ));
+            let _ = unwrap!(control.soap_action("GetGenericPortMappingEntry", &[]));
+
+            assert_eq!(control.path(), "/ctl/IPConn");
+            assert_eq!(
+                control.service_type(),
+                "urn:schemas-upnp-org:service:WANIPConnection:2"
+            );
+            let _description_request = unwrap!(requests_rx.recv());
+            let request = unwrap!(requests_rx.recv());
+            assert!(request.contains(
+                "SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:2\
+                 #GetGenericPortMappingEntry\""
+            ));
+        }
+    }
+
+    mod add_pinhole_sync {
+        use super::*;
+
+        #[test]
+        fn it_returns_pinhole_id() {
+            let (requests_tx, requests_rx) = ::std::sync::mpsc::channel();
+            let ssdp_addr = spawn_router(
+                "HTTP/1.0 200 OK\r\n\r\n<s:Envelope><s:Body><u:AddPinholeResponse>\
+                 <UniqueID>42</UniqueID></u:AddPinholeResponse></s:Body></s:Envelope>",
+                requests_tx,
+            );
+            let control = unwrap!(search_at(&ssdp_addr, Duration::from_secs(3)));
+
+            let internal_addr = SocketAddrV6::new(unwrap!("2001:db8::1".parse()), 5000, 0, 0);
+            let id = unwrap!(control.add_pinhole_sync(Protocol::Udp, &internal_addr, 3600));
+
+            assert_eq!(id, 42);
+            let _description_request = unwrap!(requests_rx.recv());
+            let request = unwrap!(requests_rx.recv());
+            assert!(request.contains("#AddPinhole\""));
+            assert!(request.contains("<InternalClient>2001:db8::1</InternalClient>"));
+            assert!(request.contains("<InternalPort>5000</InternalPort>"));
+            assert!(request.contains("<Protocol>17</Protocol>"));
+            assert!(request.contains("<LeaseTime>3600</LeaseTime>"));
+        }
+
+        #[test]
+        fn when_router_refuses_it_returns_upnp_error() {
+            let (requests_tx, _requests_rx) = ::std::sync::mpsc::channel();
+            let ssdp_addr = spawn_router(
+                "HTTP/1.0 500 Internal Server Error\r\n\r\n<s:Envelope><s:Body><s:Fault>\
+                 <detail><UPnPError><errorCode>702</errorCode>\
+                 <errorDescription>PinholeSpaceExhausted</errorDescription>\
+                 </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                requests_tx,
+            );
+            let control = unwrap!(search_at(&ssdp_addr, Duration::from_secs(3)));
+
+            let internal_addr = SocketAddrV6::new(unwrap!("2001:db8::1".parse()), 5000, 0, 0);
+            let res = control.add_pinhole_sync(Protocol::Tcp, &internal_addr, 3600);
+
+            match res {
+                Err(UpnpError::Soap(702, ref description)) => {
+                    assert_eq!(description, "PinholeSpaceExhausted")
+                }
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod element_text {
+        use super::*;
+
+        #[test]
+        fn it_ignores_namespace_prefixes_and_closing_tags() {
+            let xml = "<a></UniqueID><u:UniqueID> 7 </u:UniqueID>";
+
+            assert_eq!(element_text(xml, "UniqueID"), Some("7"));
+        }
+
+        #[test]
+        fn it_does_not_match_tags_with_longer_names() {
+            let xml = "<controlURLs>a</controlURLs><controlURL>b</controlURL>";
+
+            assert_eq!(element_text(xml, "controlURL"), Some("b"));
+        }
+    }
+}
@@ -119,8 +119,22 @@ impl Ipv4AddrExt for Ipv4Addr {
 
 impl Ipv6AddrExt for Ipv6Addr {
     fn is_global(&self) -> bool {
-        // TODO: this is very incomplete
-        !self.is_loopback() && !self.is_unspecified()
+        let segments = self.segments();
+        let is_unique_local = segments[0] & 0xfe00 == 0xfc00;
+        let is_unicast_link_local = segments[0] & 0xffc0 == 0xfe80;
+        let is_site_local = segments[0] & 0xffc0 == 0xfec0;
+        let is_documentation = segments[0] == 0x2001 && segments[1] == 0x0db8;
+        let is_discard_only = segments[0] == 0x0100 && segments[1..4] == [0, 0, 0];
+        let is_ipv4_mapped = segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff;
+        !self.is_loopback()
+            && !self.is_unspecified()
+            && !self.is_multicast()
+            && !is_unique_local
+            && !is_unicast_link_local
+            && !is_site_local
+            && !is_documentation
+            && !is_discard_only
+            && !is_ipv4_mapped
     }
 
     fn expand_local_unspecified(&self) -> io::Result<Vec<Ipv6Addr>> {
@@ -146,3 +160,35 @@ impl Ipv6AddrExt for Ipv6Addr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod ipv6_addr {
+        use super::*;
+
+        #[test]
+        fn is_global_returns_true_for_global_unicast_addresses() {
+            assert!(Ipv6AddrExt::is_global(&ipv6!("2a00:1450:4001:81c::200e")));
+        }
+
+        #[test]
+        fn is_global_returns_false_for_non_routable_addresses() {
+            let addrs = [
+                ipv6!("::"),
+                ipv6!("::1"),
+                ipv6!("fe80::1"),
+                ipv6!("fd12:3456:789a::1"),
+                ipv6!("fec0::1"),
+                ipv6!("ff0e::1"),
+                ipv6!("2001:db8::1"),
+                ipv6!("100::1"),
+                ipv6!("::ffff:1.2.3.4"),
+            ];
+            for addr in &addrs {
+                assert!(!Ipv6AddrExt::is_global(addr), "{} is not global", addr);
+            }
+        }
+    }
+}
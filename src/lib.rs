@@ -30,6 +30,10 @@
 //! because if we cover this then the less restrictive `Endpoint Address Dependent Filtering` will
 //! be automatically covered (in other words we cover the worst case scenario for *EIMs*).
 //!
+//! To tell the filtering types apart, run `UdpRendezvousServer::bind_with_alternate()` on a host
+//! with two public IPs and probe it with `udp_discover_nat_behaviour()`, which classifies both
+//! mapping and filtering behaviour as per RFC 5780.
+//!
 //! When we talk to the rendezvous servers, such a NAT allows us to talk to only one of them at a
 //! time from the same local *UDP* endpoint. Once we talk to them in succession we can easily find
 //! out if we are behind an *EIM* NAT. This is because the external address seen by all the servers
@@ -95,25 +99,32 @@
 //!
 //! This is trickier because our external address as seen by the outsiders will change depending on
 //! the remote endpoint we are talking to, irrespective of the fact we are using the same local
-//! endpoint. That is why the recommended number of rendezvous servers is 3. Using them we can
-//! predict how our router maps our address by inspecting the different addresses returned by
-//! different rendezvous servers. Most of the time a fixed delta can be predicted and that is what
-//! is used by this crate to guess what our address will be when we start hole-punching to the peer
-//! and we exchange that guessed information (out of band as usual), then start sending packets to
-//! the peer on their guessed address. This has worked for most of the routers. For filtering, it's
-//! the same as before and we proceed in the same way with our guessed addresses.
+//! endpoint. That is why the recommended number of rendezvous servers is at least 3, and up to 5
+//! are queried. Using them we can predict how our router maps our address by inspecting the
+//! different addresses returned by different rendezvous servers. Routers usually apply a fixed
+//! delta, allocate ports sequentially with some jitter caused by other hosts behind the same NAT,
+//! or pick ports from a small block. From these patterns the crate derives a ranked list of
+//! addresses we are likely to get when we start hole-punching to the peer, along with a confidence
+//! level. We exchange the candidates (out of band as usual), then start sending packets to all of
+//! the peer's candidates. This has worked for most of the routers. For filtering, it's the same as
+//! before and we proceed in the same way with our guessed addresses.
 //!
 //! There are unfriendly routers in this category too in which the mapping is random and unrelated
-//! to any deltas/offsets. Such cases are currently not supported by this crate (though there is
-//! some work in pipeline to alleviate that too using a whole lot of sockets in a hope of getting
-//! one of them right). Some might even make slight changes in IP (and not only the ports) though
-//! this has not yet been encountered during testing with various routers. However all these will
+//! to any deltas/offsets. For these we rely on the birthday paradox: the side behind such a router
+//! opens a whole lot of sockets and sends packets to the peer from all of them, creating as many
+//! mappings, while the other side sprays packets over random ports of the former's public IP in a
+//! hope of hitting one of those mappings. A few hundred sockets and a couple thousand packets give
+//! very good odds. Both the number of sockets and packets are configurable via `P2p`. If both peers
+//! are behind such routers, the same technique is attempted from both sides, but the chances of
+//! success are low. Some routers might even make slight changes in IP (and not only the ports)
+//! though this has not yet been encountered during testing with various routers. Such cases will
 //! be detected and will be logged (if logging is turned on) to the user and connection attempt to
 //! the peer will be discarded.
 //!
 //! Also one more thing to note is, even with the friendlier NATs which apply fixed deltas to port
 //! increment, the resultant port might already be occupied by another socket. In such a case they
-//! would skip that port and yield some other unused ones and our prediction would fail here too.
+//! would skip that port and yield some other unused ones. That's why a few following ports are
+//! also tried.
 //!
 //! ## Hair-pinning
 //!
@@ -121,8 +132,11 @@
 //! same NAT, both their external addresses would be similar. When non-hair-pinning routers see
 //! this, a packet with source and destination containing IP's they know are allocated by them from
 //! the pool (although source and destination endpoints maybe quite different), they will discard
-//! and not route it further. This is really a tough one and currently there is no solution to
-//! this in this crate.
+//! and not route it further. If both peers are on the same LAN, this crate exchanges the addresses
+//! of the local network interfaces too and tries to connect to them in parallel with the public
+//! ones, so whichever answers first is used. Peers in different LANs behind the same
+//! non-hair-pinning NAT are still a tough one and currently there is no solution to this in this
+//! crate.
 //!
 //! ## Secure communication
 //!
@@ -137,6 +151,50 @@
 //! the router's pool they try to figure out it's a rendezvous/STUN attempt. With encrypted
 //! contents there is no chance of such detection, so we are safe there.
 //!
+//! Standard STUN servers can be used as UDP rendezvous servers too, see `StunUdpAddrQuerier`.
+//! Their responses are not encrypted, so prefer them only where our own servers are not
+//! deployed.
+//!
+//! ## Protocol versioning
+//!
+//! Every message sent to peers and rendezvous servers is wrapped into an envelope carrying the
+//! range of protocol versions the sender speaks, its capabilities and, for peers, an optional
+//! application tag (see `P2p::set_app_tag()`). Both sides use the highest version they have in
+//! common. If there is none, or the application tags differ, rendezvous connect fails with a
+//! `VersionMismatch` error instead of misinterpreting the messages.
+//!
+//! ## Peer authentication
+//!
+//! Rendezvous connect generates fresh encryption keys for each connection, so by itself it can't
+//! tell who is on the other end of the rendezvous channel. When peers know each other's long-term
+//! identity keys, they should use `rendezvous_connect_authenticated()` instead: each peer proves
+//! its fresh keys belong to its identity, and the connection fails with an `Identity` error if the
+//! remote peer can't do so.
+//!
+//! ## Progress events
+//!
+//! Rendezvous connect may take a while and fail for many reasons. `P2p::subscribe()` returns a
+//! stream of `RendezvousEvent`s: discovered public addresses, detected NAT type, candidates sent to
+//! the peer, hole punching progress, failed attempts and the chosen connection.
+//!
+//! When a user can't connect at all, `diagnose()` collects a serializable `NatReport` of their
+//! network: public addresses, NAT type per protocol, port preservation, hairpin support, IGD
+//! availability, estimated UDP mapping lifetime and results of each traversal server.
+//!
+//! ## Relaying
+//!
+//! When both peers are behind EDM NATs allocating ports randomly, no amount of hole punching may
+//! help. For such cases peers can fall back to a relay server: run `UdpRelayServer` or
+//! `TcpRelayServer` on a publicly reachable host and tell peers about it with
+//! `P2p::set_udp_relay_server()` or `P2p::set_tcp_relay_server()`. Peers agree on a single relay
+//! server during rendezvous and, once all direct attempts fail, ask it to forward their traffic.
+//! Such connections are marked as `relayed`.
+//!
+//! UDP connections can also fall back to a standard TURN server, such as coturn. Set it along
+//! with long-term credentials via `P2p::set_turn_server()`: during rendezvous a relayed address is
+//! allocated on it and offered to the peer as the last resort candidate. If both a relay server
+//! and a TURN candidate are available, the relay server is preferred.
+//!
 //! ## TCP
 //!
 //! With *TCP* some of the challenges are greater. The usual process is going through the same
@@ -240,6 +298,7 @@ extern crate igd;
 #[macro_use]
 extern crate log;
 extern crate maidsafe_utilities;
+extern crate md5;
 extern crate net2;
 #[macro_use]
 extern crate net_literals;
@@ -250,8 +309,10 @@ extern crate netsim;
 #[macro_use]
 extern crate quick_error;
 extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate sha1;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_shared_udp_socket;
@@ -266,18 +327,30 @@ mod priv_prelude;
 #[macro_use]
 mod util;
 
+mod addr_race;
+mod diagnose;
+mod events;
+mod hairpin;
+mod identity;
 mod igd_async;
+mod igd_firewall;
 mod ip_addr;
 mod mc;
 mod open_addr;
 mod peer;
+mod port_mapping;
+mod port_prediction;
 mod protocol;
 mod querier_set;
 mod query;
+mod rate_limit;
+mod relay;
 mod rendezvous_addr;
 mod socket_addr;
+mod stun;
 mod tcp;
 mod udp;
+mod version;
 
 pub use prelude::*;
 
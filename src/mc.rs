@@ -1,7 +1,23 @@
 //! Port mapping context utilities.
 
+use events::{EventSubscribers, RendezvousEvent, RendezvousEventKind};
 use future_utils::mpsc::UnboundedReceiver;
+use igd_async::{self, GatewayCache, RemoveStaleMappingsError};
 use priv_prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use version::{Capabilities, VersionMismatch};
+
+/// By default, how many sockets we open when our NAT allocates ports randomly.
+const DEFAULT_BIRTHDAY_SOCKET_BUDGET: usize = 256;
+/// By default, how many probes we spray over the ports of a peer whose NAT allocates them randomly.
+const DEFAULT_BIRTHDAY_PACKET_BUDGET: usize = 2048;
+/// Description of port mappings we make on IGD routers, by default.
+const DEFAULT_PORT_MAPPING_DESCRIPTION: &str = "p2p";
+/// By default, how long we wait for IGD routers to respond to a search.
+const DEFAULT_IGD_SEARCH_TIMEOUT_MS: u64 = 200;
+/// By default, how many times IGD search is retried. Search requests are sent over UDP and might
+/// get lost.
+const DEFAULT_IGD_SEARCH_RETRIES: u32 = 1;
 
 /// `P2p` allows you to manage how NAT traversal works.
 ///
@@ -9,15 +25,67 @@ use priv_prelude::*;
 #[derive(Default, Clone)]
 pub struct P2p {
     inner: Arc<Mutex<P2pInner>>,
+    /// Number of event subscribers, so that events can be dropped without locking `inner` when
+    /// nobody listens.
+    event_subscriber_count: Arc<AtomicUsize>,
+    /// Id of the rendezvous connect this `P2p` was handed to, see `start_attempt()`.
+    attempt: Option<u64>,
 }
 
-#[derive(Default)]
 struct P2pInner {
     tcp_addr_querier_set: TcpAddrQuerierSet,
     udp_addr_querier_set: UdpAddrQuerierSet,
     igd_disabled: bool,
+    pcp_disabled: bool,
+    nat_pmp_disabled: bool,
     igd_disabled_for_rendezvous: bool,
+    igd_search_timeout: Duration,
+    igd_search_retries: u32,
+    igd_gateway_cache: GatewayCache,
+    port_mapping_description: String,
+    stale_mapping_cleanup_enabled: bool,
+    stale_mapping_cleanup_started: bool,
+    hairpin_support: HashMap<IpAddr, bool>,
     force_use_local_port: bool,
+    birthday_socket_budget: usize,
+    birthday_packet_budget: usize,
+    candidate_trickling_enabled: bool,
+    app_tag: Option<String>,
+    udp_relay_server: Option<RemoteUdpRelayServer>,
+    tcp_relay_server: Option<RemoteTcpRelayServer>,
+    turn_server: Option<RemoteTurnServer>,
+    event_subscribers: EventSubscribers,
+    next_attempt: u64,
+}
+
+impl Default for P2pInner {
+    fn default() -> P2pInner {
+        P2pInner {
+            tcp_addr_querier_set: Default::default(),
+            udp_addr_querier_set: Default::default(),
+            igd_disabled: false,
+            pcp_disabled: false,
+            nat_pmp_disabled: false,
+            igd_disabled_for_rendezvous: false,
+            igd_search_timeout: Duration::from_millis(DEFAULT_IGD_SEARCH_TIMEOUT_MS),
+            igd_search_retries: DEFAULT_IGD_SEARCH_RETRIES,
+            igd_gateway_cache: Default::default(),
+            port_mapping_description: String::from(DEFAULT_PORT_MAPPING_DESCRIPTION),
+            stale_mapping_cleanup_enabled: false,
+            stale_mapping_cleanup_started: false,
+            hairpin_support: HashMap::new(),
+            force_use_local_port: false,
+            birthday_socket_budget: DEFAULT_BIRTHDAY_SOCKET_BUDGET,
+            birthday_packet_budget: DEFAULT_BIRTHDAY_PACKET_BUDGET,
+            candidate_trickling_enabled: false,
+            app_tag: None,
+            udp_relay_server: None,
+            tcp_relay_server: None,
+            turn_server: None,
+            event_subscribers: Default::default(),
+            next_attempt: 1,
+        }
+    }
 }
 
 // Some macros to reduce boilerplate
@@ -80,6 +148,215 @@ impl P2p {
         inner_set!(self, igd_disabled, false);
     }
 
+    /// Returns how long we wait for IGD routers to respond to a search.
+    pub fn igd_search_timeout(&self) -> Duration {
+        inner_get!(self, igd_search_timeout)
+    }
+
+    /// Set how long we wait for IGD routers to respond to a search, 200ms by default.
+    pub fn set_igd_search_timeout(&self, timeout: Duration) {
+        inner_set!(self, igd_search_timeout, timeout);
+    }
+
+    /// Returns how many times IGD search is retried before concluding there's no IGD router.
+    pub fn igd_search_retries(&self) -> u32 {
+        inner_get!(self, igd_search_retries)
+    }
+
+    /// Set how many times IGD search is retried before concluding there's no IGD router, once by
+    /// default.
+    pub fn set_igd_search_retries(&self, retries: u32) {
+        inner_set!(self, igd_search_retries, retries);
+    }
+
+    /// Forgets IGD routers found by previous searches, as well as the fact that there were none.
+    /// Search results are cached for a while, call this when our network changes to search again
+    /// right away.
+    pub fn clear_igd_cache(&self) {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.igd_gateway_cache.clear();
+    }
+
+    /// Returns description of port mappings we make on IGD routers.
+    pub fn port_mapping_description(&self) -> String {
+        let inner = unwrap!(self.inner.lock());
+        inner.port_mapping_description.clone()
+    }
+
+    /// Set description of port mappings we make on IGD routers, "p2p" by default. Stale mapping
+    /// cleanup removes mappings to our host with exactly this description and refuses to run with
+    /// the default one, so make it unique to your application and, if multiple instances of it run
+    /// on the same host, to the instance.
+    pub fn set_port_mapping_description(&self, description: String) {
+        inner_set!(self, port_mapping_description, description);
+    }
+
+    /// Tests if stale IGD mappings are removed before making the first mapping. It's disabled by
+    /// default.
+    pub fn is_stale_mapping_cleanup_enabled(&self) -> bool {
+        inner_get!(self, stale_mapping_cleanup_enabled)
+    }
+
+    /// Before making the first IGD mapping, remove the mappings to our host with our port mapping
+    /// description. Processes that crash leave such mappings behind, since IGD mappings for
+    /// servers might be permanent. Nothing is removed unless the description was changed from the
+    /// default one, see `set_port_mapping_description()`.
+    pub fn enable_stale_mapping_cleanup(&self) {
+        inner_set!(self, stale_mapping_cleanup_enabled, true);
+    }
+
+    /// Don't remove stale IGD mappings automatically.
+    pub fn disable_stale_mapping_cleanup(&self) {
+        inner_set!(self, stale_mapping_cleanup_enabled, false);
+    }
+
+    /// Removes IGD mappings to our host with our port mapping description, which processes that
+    /// crashed have left behind. Resolves to the number of removed mappings and errors of the
+    /// removals that failed, failure to remove one mapping doesn't stop removal of the others.
+    /// Fails with `RemoveStaleMappingsError::DefaultDescription`, unless the port mapping
+    /// description was changed from the default one.
+    pub fn remove_stale_port_mappings(
+        &self,
+    ) -> BoxFuture<(usize, Vec<RemoveStaleMappingsError>), RemoveStaleMappingsError> {
+        igd_async::remove_stale_mappings(self)
+    }
+
+    /// Tests if PCP (RFC 6887) port mapping is enabled. It's enabled by default.
+    pub fn is_pcp_enabled(&self) -> bool {
+        !inner_get!(self, pcp_disabled)
+    }
+
+    /// Use PCP alongside IGD to open external ports. IGD mappings are preferred.
+    pub fn enable_pcp(&self) {
+        inner_set!(self, pcp_disabled, false);
+    }
+
+    /// Don't use PCP to open external ports.
+    pub fn disable_pcp(&self) {
+        inner_set!(self, pcp_disabled, true);
+    }
+
+    /// Tests if NAT-PMP (RFC 6886) port mapping is enabled. It's enabled by default.
+    pub fn is_nat_pmp_enabled(&self) -> bool {
+        !inner_get!(self, nat_pmp_disabled)
+    }
+
+    /// Use NAT-PMP alongside IGD and PCP to open external ports. Mappings made with those are
+    /// preferred.
+    pub fn enable_nat_pmp(&self) {
+        inner_set!(self, nat_pmp_disabled, false);
+    }
+
+    /// Don't use NAT-PMP to open external ports.
+    pub fn disable_nat_pmp(&self) {
+        inner_set!(self, nat_pmp_disabled, true);
+    }
+
+    /// Returns how many sockets are opened for birthday paradox hole punching when our NAT
+    /// allocates ports randomly.
+    pub fn birthday_socket_budget(&self) -> usize {
+        inner_get!(self, birthday_socket_budget)
+    }
+
+    /// Set how many sockets are opened for birthday paradox hole punching when our NAT allocates
+    /// ports randomly. The more sockets, the better chances for the peer to hit one of them.
+    pub fn set_birthday_socket_budget(&self, budget: usize) {
+        inner_set!(self, birthday_socket_budget, budget);
+    }
+
+    /// Returns how many probes are sprayed over random ports of a peer whose NAT allocates
+    /// ports randomly.
+    pub fn birthday_packet_budget(&self) -> usize {
+        inner_get!(self, birthday_packet_budget)
+    }
+
+    /// Set how many probes are sprayed over random ports of a peer whose NAT allocates ports
+    /// randomly. Each probe is sent to a different port.
+    pub fn set_birthday_packet_budget(&self, budget: usize) {
+        inner_set!(self, birthday_packet_budget, budget);
+    }
+
+    /// Tests if candidate trickling is enabled. It's disabled by default.
+    pub fn is_candidate_trickling_enabled(&self) -> bool {
+        inner_get!(self, candidate_trickling_enabled)
+    }
+
+    /// With candidate trickling enabled, rendezvous connections send our addresses over the
+    /// rendezvous channel in multiple messages, as soon as each of them is discovered, and start
+    /// punching holes as soon as the peer's addresses arrive. The rendezvous channel must be able
+    /// to carry multiple messages and both peers must have trickling enabled.
+    pub fn enable_candidate_trickling(&self) {
+        inner_set!(self, candidate_trickling_enabled, true);
+    }
+
+    /// Send all our addresses in a single rendezvous channel message. This is the default.
+    pub fn disable_candidate_trickling(&self) {
+        inner_set!(self, candidate_trickling_enabled, false);
+    }
+
+    /// Returns the tag of our application, if one was set.
+    pub fn app_tag(&self) -> Option<String> {
+        let inner = unwrap!(self.inner.lock());
+        inner.app_tag.clone()
+    }
+
+    /// Set the tag of our application. Every rendezvous message carries this tag and peers refuse
+    /// to connect to peers with a different one. This allows multiple applications to share the
+    /// same rendezvous infrastructure. No tag is set by default.
+    pub fn set_app_tag(&self, app_tag: Option<String>) {
+        inner_set!(self, app_tag, app_tag);
+    }
+
+    /// Returns the UDP relay server we offer to peers, if one was set.
+    pub fn udp_relay_server(&self) -> Option<RemoteUdpRelayServer> {
+        let inner = unwrap!(self.inner.lock());
+        inner.udp_relay_server.clone()
+    }
+
+    /// Set the UDP relay server we offer to peers. When UDP hole punching fails, both peers fall
+    /// back to the relay server offered by one of them and the connection is relayed. No relay
+    /// server is set by default.
+    pub fn set_udp_relay_server(&self, udp_relay_server: Option<RemoteUdpRelayServer>) {
+        inner_set!(self, udp_relay_server, udp_relay_server);
+    }
+
+    /// Returns the TCP relay server we offer to peers, if one was set.
+    pub fn tcp_relay_server(&self) -> Option<RemoteTcpRelayServer> {
+        let inner = unwrap!(self.inner.lock());
+        inner.tcp_relay_server.clone()
+    }
+
+    /// Set the TCP relay server we offer to peers. When all TCP rendezvous connection attempts
+    /// fail, both peers fall back to the relay server offered by one of them and the connection
+    /// is relayed. No relay server is set by default.
+    pub fn set_tcp_relay_server(&self, tcp_relay_server: Option<RemoteTcpRelayServer>) {
+        inner_set!(self, tcp_relay_server, tcp_relay_server);
+    }
+
+    /// Returns the TURN server we allocate relayed addresses on, if one was set.
+    pub fn turn_server(&self) -> Option<RemoteTurnServer> {
+        let inner = unwrap!(self.inner.lock());
+        inner.turn_server.clone()
+    }
+
+    /// Set the TURN server, such as coturn, along with our long-term credentials for it. During
+    /// UDP rendezvous connect we allocate a relayed address on it and offer it to the peer as the
+    /// last resort candidate, used only when hole punching fails. No TURN server is set by
+    /// default.
+    pub fn set_turn_server(&self, turn_server: Option<RemoteTurnServer>) {
+        inner_set!(self, turn_server, turn_server);
+    }
+
+    /// Returns the optional protocol features we announce to peers.
+    pub fn capabilities(&self) -> Capabilities {
+        let capabilities = Capabilities::IPV6.union(Capabilities::BIRTHDAY);
+        if self.is_candidate_trickling_enabled() {
+            capabilities.union(Capabilities::TRICKLE)
+        } else {
+            capabilities
+        }
+    }
+
     /// Register a TCP addr_querier with p2p
     pub fn add_tcp_addr_querier(&self, tcp_addr_querier: impl TcpAddrQuerier + Hash) {
         let mut inner = unwrap!(self.inner.lock());
@@ -118,11 +395,97 @@ impl P2p {
         inner.tcp_addr_querier_set.addr_queriers()
     }
 
+    /// Returns whether our NAT with public IP `public_ip` supports hairpinning, i.e. forwards UDP
+    /// packets sent to our own public address back to us. `None`, if that's not known yet. It's
+    /// detected while discovering rendezvous addresses for UDP hole punching, and determines if
+    /// peers behind the same NAT try to reach each other via their public addresses.
+    pub fn hairpin_support(&self, public_ip: IpAddr) -> Option<bool> {
+        let inner = unwrap!(self.inner.lock());
+        inner.hairpin_support.get(&public_ip).cloned()
+    }
+
+    /// Forgets detected hairpinning support. Call this when our network changes.
+    pub fn clear_hairpin_support(&self) {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.hairpin_support.clear();
+    }
+
     /// Iterate over the registered UDP addr_queriers
     pub fn udp_addr_queriers(&self) -> UnboundedReceiver<Arc<UdpAddrQuerier>> {
         let mut inner = unwrap!(self.inner.lock());
         inner.udp_addr_querier_set.addr_queriers()
     }
+
+    /// Subscribe to progress events of all UDP and TCP rendezvous connections made with this
+    /// `P2p`. Only the events that happen after subscribing are received. Drop the receiver to
+    /// unsubscribe.
+    pub fn subscribe(&self) -> UnboundedReceiver<RendezvousEvent> {
+        let mut inner = unwrap!(self.inner.lock());
+        let events = inner.event_subscribers.subscribe();
+        self.event_subscriber_count
+            .store(inner.event_subscribers.count(), Ordering::SeqCst);
+        events
+    }
+}
+
+/// Returns `true` if stale IGD mappings should be removed now. That's only the case once per
+/// `P2p`, if stale mapping cleanup is enabled.
+pub fn start_stale_mapping_cleanup(p2p: &P2p) -> bool {
+    let mut inner = unwrap!(p2p.inner.lock());
+    let start = inner.stale_mapping_cleanup_enabled && !inner.stale_mapping_cleanup_started;
+    if start {
+        inner.stale_mapping_cleanup_started = true;
+    }
+    start
+}
+
+/// Returns the port mapping description stale mappings are removed by, unless it's the default
+/// one. Other applications might use the default description too.
+pub fn stale_mapping_description(p2p: &P2p) -> Option<String> {
+    let description = p2p.port_mapping_description();
+    if description == DEFAULT_PORT_MAPPING_DESCRIPTION {
+        return None;
+    }
+    Some(description)
+}
+
+/// Gives access to the IGD gateway cache of `p2p`.
+pub fn with_gateway_cache<F, R>(p2p: &P2p, f: F) -> R
+where
+    F: FnOnce(&mut GatewayCache) -> R,
+{
+    let mut inner = unwrap!(p2p.inner.lock());
+    f(&mut inner.igd_gateway_cache)
+}
+
+/// Remembers whether our NAT with public IP `public_ip` supports hairpinning.
+pub fn set_hairpin_support(p2p: &P2p, public_ip: IpAddr, supported: bool) {
+    let mut inner = unwrap!(p2p.inner.lock());
+    let _ = inner.hairpin_support.insert(public_ip, supported);
+}
+
+/// Returns a `P2p` sharing the state of `p2p` whose events are tagged with a new attempt id. Call
+/// this once at the start of every rendezvous connect.
+pub fn start_attempt(p2p: &P2p) -> P2p {
+    let mut inner = unwrap!(p2p.inner.lock());
+    let attempt = inner.next_attempt;
+    inner.next_attempt += 1;
+    P2p {
+        inner: p2p.inner.clone(),
+        event_subscriber_count: p2p.event_subscriber_count.clone(),
+        attempt: Some(attempt),
+    }
+}
+
+/// Notifies `P2p` subscribers about rendezvous connect progress.
+pub fn emit_event(p2p: &P2p, kind: RendezvousEventKind) {
+    if p2p.event_subscriber_count.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let mut inner = unwrap!(p2p.inner.lock());
+    inner.event_subscribers.emit(p2p.attempt, kind);
+    p2p.event_subscriber_count
+        .store(inner.event_subscribers.count(), Ordering::SeqCst);
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -178,6 +541,33 @@ quick_error! {
             display("Error decrypting message: {}", e)
             cause(e)
         }
+        /// Failure to serialize request.
+        Serialize(e: SerialisationError) {
+            description("Error serializing message")
+            display("Error serializing message: {}", e)
+            cause(e)
+        }
+        /// Failure to deserialize response.
+        Deserialize(e: SerialisationError) {
+            description("Error deserializing message")
+            display("Error deserializing message: {}", e)
+            cause(e)
+        }
+        /// Server doesn't speak any of our protocol versions.
+        VersionMismatch(e: VersionMismatch) {
+            description("Server speaks incompatible protocol")
+            display("Server speaks incompatible protocol: {}", e)
+            cause(e)
+        }
+        /// STUN server answered with an error response.
+        ErrorResponse(code: u16, reason: String) {
+            description("STUN server responded with error")
+            display("STUN server responded with error: {} {}", code, reason)
+        }
+        /// Server response doesn't carry our address.
+        InvalidResponse {
+            description("invalid response from server")
+        }
     }
 }
 
@@ -199,6 +589,33 @@ mod tests {
                 assert!(p2p.is_igd_enabled())
             }
 
+            #[test]
+            fn it_creates_mapping_context_with_pcp_and_nat_pmp_enabled() {
+                let p2p = P2p::default();
+
+                assert!(p2p.is_pcp_enabled());
+                assert!(p2p.is_nat_pmp_enabled());
+            }
+
+            #[test]
+            fn it_creates_mapping_context_with_default_igd_search_options() {
+                let p2p = P2p::default();
+
+                assert_eq!(
+                    p2p.igd_search_timeout(),
+                    Duration::from_millis(DEFAULT_IGD_SEARCH_TIMEOUT_MS)
+                );
+                assert_eq!(p2p.igd_search_retries(), DEFAULT_IGD_SEARCH_RETRIES);
+            }
+
+            #[test]
+            fn it_creates_mapping_context_with_default_port_mapping_description() {
+                let p2p = P2p::default();
+
+                assert_eq!(p2p.port_mapping_description(), DEFAULT_PORT_MAPPING_DESCRIPTION);
+                assert!(!p2p.is_stale_mapping_cleanup_enabled());
+            }
+
             #[test]
             fn it_creates_mapping_context_with_igd_enabled_for_rendezvous() {
                 let p2p = P2p::default();
@@ -212,6 +629,95 @@ mod tests {
 
                 assert!(!p2p.force_use_local_port())
             }
+
+            #[test]
+            fn it_creates_mapping_context_with_default_birthday_budgets() {
+                let p2p = P2p::default();
+
+                assert_eq!(p2p.birthday_socket_budget(), DEFAULT_BIRTHDAY_SOCKET_BUDGET);
+                assert_eq!(p2p.birthday_packet_budget(), DEFAULT_BIRTHDAY_PACKET_BUDGET);
+            }
+
+            #[test]
+            fn it_creates_mapping_context_without_app_tag() {
+                let p2p = P2p::default();
+
+                assert_eq!(p2p.app_tag(), None);
+            }
+
+            #[test]
+            fn it_creates_mapping_context_with_candidate_trickling_disabled() {
+                let p2p = P2p::default();
+
+                assert!(!p2p.is_candidate_trickling_enabled())
+            }
+        }
+
+        mod subscribe {
+            use super::*;
+
+            #[test]
+            fn it_delivers_events_emitted_after_subscribing() {
+                let p2p = P2p::default();
+                emit_event(
+                    &p2p,
+                    RendezvousEventKind::SynReceived {
+                        their_addr: addr!("1.2.3.4:5000"),
+                    },
+                );
+
+                let events = p2p.subscribe();
+                emit_event(
+                    &p2p,
+                    RendezvousEventKind::AckReceived {
+                        their_addr: addr!("1.2.3.4:5000"),
+                    },
+                );
+                drop(p2p);
+
+                let mut core = unwrap!(Core::new());
+                let events: Vec<_> = core.run(events.collect()).void_unwrap();
+                assert_eq!(events.len(), 1);
+                match events[0].kind {
+                    RendezvousEventKind::AckReceived { their_addr } => {
+                        assert_eq!(their_addr, addr!("1.2.3.4:5000"))
+                    }
+                    ref kind => panic!("unexpected event: {:?}", kind),
+                }
+            }
+
+            #[test]
+            fn it_tags_events_with_the_attempt_they_belong_to() {
+                let p2p = P2p::default();
+                let events = p2p.subscribe();
+                let attempt0 = start_attempt(&p2p);
+                let attempt1 = start_attempt(&p2p);
+
+                emit_event(
+                    &attempt0,
+                    RendezvousEventKind::SynReceived {
+                        their_addr: addr!("1.2.3.4:5000"),
+                    },
+                );
+                emit_event(
+                    &attempt1,
+                    RendezvousEventKind::SynReceived {
+                        their_addr: addr!("1.2.3.4:5000"),
+                    },
+                );
+                emit_event(
+                    &p2p,
+                    RendezvousEventKind::SynReceived {
+                        their_addr: addr!("1.2.3.4:5000"),
+                    },
+                );
+                drop((p2p, attempt0, attempt1));
+
+                let mut core = unwrap!(Core::new());
+                let events: Vec<_> = core.run(events.collect()).void_unwrap();
+                let attempts: Vec<_> = events.iter().map(|event| event.attempt).collect();
+                assert_eq!(attempts, vec![Some(1), Some(2), None]);
+            }
         }
 
         mod tcp_addr_queriers {
@@ -241,4 +747,24 @@ mod tests {
             }
         }
     }
+
+    mod start_stale_mapping_cleanup {
+        use super::*;
+
+        #[test]
+        fn it_returns_false_when_cleanup_is_disabled() {
+            let p2p = P2p::default();
+
+            assert!(!start_stale_mapping_cleanup(&p2p));
+        }
+
+        #[test]
+        fn it_returns_true_only_once_when_cleanup_is_enabled() {
+            let p2p = P2p::default();
+            p2p.enable_stale_mapping_cleanup();
+
+            assert!(start_stale_mapping_cleanup(&p2p));
+            assert!(!start_stale_mapping_cleanup(&p2p.clone()));
+        }
+    }
 }
@@ -1,5 +1,7 @@
+use addr_race::{race_igd, RaceOutcome};
 use future_utils::mpsc::UnboundedReceiver;
 use igd_async::{self, GetAnyAddressError};
+use port_mapping::PortMapping;
 use priv_prelude::*;
 use std::error::Error;
 
@@ -81,60 +83,130 @@ quick_error! {
 
 /// Attempts to get a public address others could contact us with.
 /// We have public address if:
-/// 1. one of our bind addresses is public
+/// 1. one of our bind addresses is public. For IPv6 addresses we also try to open a pinhole in
+///    router's firewall.
 /// 2. we have IGD enabled router
 /// 3. our router NAT is full cone
+///
+/// 2 and 3 are checked concurrently.
+///
+/// Mapping made on the router is kept alive for as long as the returned handle lives.
 // TODO(povilas): move this function to Crust crate? It has nothing to do with hole punching.
 pub fn open_addr(
     protocol: Protocol,
     bind_addr: &SocketAddr,
     handle: &Handle,
     mc: &P2p,
-) -> BoxFuture<SocketAddr, OpenAddrError> {
-    let addrs_res = {
-        bind_addr
-            .expand_local_unspecified()
-            .map_err(|e| OpenAddrError {
-                igd_err: None,
-                kind: OpenAddrErrorKind::IfAddrs(e),
-            })
-    };
-
-    let addrs = match addrs_res {
-        Ok(addrs) => addrs,
+) -> BoxFuture<PortMapping, OpenAddrError> {
+    let addr = match global_addr(bind_addr) {
+        Ok(addr) => addr,
         Err(e) => return future::err(e).into_boxed(),
     };
+    if let Some(addr) = addr {
+        if addr.is_ipv4() {
+            return future::ok(PortMapping::unmanaged(addr)).into_boxed();
+        }
+        // IPv6 routers usually drop unsolicited incoming traffic, so ask them to let it through.
+        return igd_async::get_any_address_open(protocol, addr, handle, mc)
+            .or_else(move |e| {
+                debug!("failed to open IPv6 pinhole for {}: {}", addr, e);
+                Ok(PortMapping::unmanaged(addr))
+            }).into_boxed();
+    }
+
+    let igd = igd_async::get_any_address_open(protocol, *bind_addr, handle, mc);
+    let servers = query_servers(protocol, *bind_addr, handle, mc);
+    // address seen by a single server is only used if IGD fails, unused mapping is removed when
+    // its handle is dropped
+    race_igd(igd, servers, |addr| addr.verified, drop, handle)
+        .infallible()
+        .and_then(|outcome| match outcome {
+            RaceOutcome {
+                igd: Some(Ok(mapping)),
+                ..
+            } => Ok(mapping),
+            RaceOutcome {
+                servers: Some(Ok(addr)),
+                ..
+            } => Ok(PortMapping::unmanaged(addr.addr)),
+            RaceOutcome {
+                igd: Some(Err(igd_err)),
+                servers: Some(Err(kind)),
+            } => Err(OpenAddrError {
+                igd_err: Some(igd_err),
+                kind,
+            }),
+            _ => unreachable!("race_igd() resolved without a result"),
+        }).into_boxed()
+}
+
+/// Like `open_addr()`, but never asks the router to open a port. So the address is only open if
+/// it's global or our NAT is full cone. Used by diagnostics, which probe IGD separately.
+pub fn unmapped_open_addr(
+    protocol: Protocol,
+    bind_addr: &SocketAddr,
+    handle: &Handle,
+    mc: &P2p,
+) -> BoxFuture<SocketAddr, OpenAddrError> {
+    match global_addr(bind_addr) {
+        Ok(Some(addr)) => future::ok(addr).into_boxed(),
+        Ok(None) => query_servers(protocol, *bind_addr, handle, mc)
+            .map(|addr| addr.addr)
+            .map_err(|kind| OpenAddrError {
+                igd_err: None,
+                kind,
+            }).into_boxed(),
+        Err(e) => future::err(e).into_boxed(),
+    }
+}
 
-    if let Some(addr) = addrs
+/// Returns global address of our network interface that `bind_addr` expands to, if any.
+fn global_addr(bind_addr: &SocketAddr) -> Result<Option<SocketAddr>, OpenAddrError> {
+    let addrs = bind_addr
+        .expand_local_unspecified()
+        .map_err(|e| OpenAddrError {
+            igd_err: None,
+            kind: OpenAddrErrorKind::IfAddrs(e),
+        })?;
+    let addr = addrs
         .into_iter()
-        .find(|addr| IpAddrExt::is_global(&addr.ip()))
-    {
+        .find(|addr| IpAddrExt::is_global(&addr.ip()));
+    if let Some(addr) = addr {
         trace!("we have a global local address: {}", addr);
-        return future::ok(addr).into_boxed();
     }
+    Ok(addr)
+}
 
-    let bind_addr = *bind_addr;
-    let handle = handle.clone();
-
+/// Asks traversal servers which address they see us on.
+fn query_servers(
+    protocol: Protocol,
+    bind_addr: SocketAddr,
+    handle: &Handle,
+    mc: &P2p,
+) -> BoxFuture<QueriedAddr, OpenAddrErrorKind> {
     let addr_queriers = match protocol {
         Protocol::Tcp => Queriers::Tcp(mc.tcp_addr_queriers()),
         Protocol::Udp => Queriers::Udp(mc.udp_addr_queriers()),
     };
-    igd_async::get_any_address_open(protocol, bind_addr, &handle, mc)
-        .or_else(move |igd_err| OpenAddr {
-            igd_err: Some(igd_err),
-            handle,
-            bind_addr,
-            known_addr_opt: None,
-            addr_queriers,
-            active_queries: stream::FuturesUnordered::new(),
-            errors: Vec::new(),
-            more_servers_timeout: None,
-        }).into_boxed()
+    OpenAddr {
+        handle: handle.clone(),
+        bind_addr,
+        known_addr_opt: None,
+        addr_queriers,
+        active_queries: stream::FuturesUnordered::new(),
+        errors: Vec::new(),
+        more_servers_timeout: None,
+    }.into_boxed()
+}
+
+/// Address traversal servers see us on.
+struct QueriedAddr {
+    addr: SocketAddr,
+    /// Whether multiple servers agree on the address, i.e. NAT is full cone.
+    verified: bool,
 }
 
 struct OpenAddr {
-    igd_err: Option<GetAnyAddressError>,
     handle: Handle,
     bind_addr: SocketAddr,
     known_addr_opt: Option<SocketAddr>,
@@ -150,10 +222,10 @@ enum Queriers {
 }
 
 impl Future for OpenAddr {
-    type Item = SocketAddr;
-    type Error = OpenAddrError;
+    type Item = QueriedAddr;
+    type Error = OpenAddrErrorKind;
 
-    fn poll(&mut self) -> Result<Async<SocketAddr>, OpenAddrError> {
+    fn poll(&mut self) -> Result<Async<QueriedAddr>, OpenAddrErrorKind> {
         loop {
             trace!("in open_addr loop");
             loop {
@@ -166,12 +238,12 @@ impl Future for OpenAddr {
                         trace!("query returned address: {}", addr);
                         if let Some(known_addr) = self.known_addr_opt {
                             if known_addr == addr {
-                                return Ok(Async::Ready(addr));
+                                return Ok(Async::Ready(QueriedAddr {
+                                    addr,
+                                    verified: true,
+                                }));
                             } else {
-                                return Err(OpenAddrError {
-                                    igd_err: self.igd_err.take(),
-                                    kind: OpenAddrErrorKind::InconsistentAddrs(known_addr, addr),
-                                });
+                                return Err(OpenAddrErrorKind::InconsistentAddrs(known_addr, addr));
                             }
                         }
                         self.known_addr_opt = Some(addr);
@@ -182,10 +254,7 @@ impl Future for OpenAddr {
 
             if self.errors.len() >= 5 {
                 let errors = mem::replace(&mut self.errors, Vec::new());
-                return Err(OpenAddrError {
-                    igd_err: self.igd_err.take(),
-                    kind: OpenAddrErrorKind::HitErrorLimit(errors),
-                });
+                return Err(OpenAddrErrorKind::HitErrorLimit(errors));
             }
 
             if self.active_queries.len() == 2 {
@@ -230,13 +299,13 @@ impl Future for OpenAddr {
                     if self.active_queries.is_empty() {
                         if let Some(known_addr) = self.known_addr_opt {
                             trace!("returning unverified (!) address: {}", known_addr);
-                            return Ok(Async::Ready(known_addr));
+                            return Ok(Async::Ready(QueriedAddr {
+                                addr: known_addr,
+                                verified: false,
+                            }));
                         }
                         trace!("giving up");
-                        return Err(OpenAddrError {
-                            igd_err: self.igd_err.take(),
-                            kind: OpenAddrErrorKind::LackOfServers,
-                        });
+                        return Err(OpenAddrErrorKind::LackOfServers);
                     }
                     trace!(
                         "waiting for {} more queries to finish",
@@ -251,12 +320,12 @@ impl Future for OpenAddr {
                                 trace!("... timed out");
                                 if let Async::Ready(()) = timeout.poll().void_unwrap() {
                                     if let Some(known_addr) = self.known_addr_opt {
-                                        return Ok(Async::Ready(known_addr));
+                                        return Ok(Async::Ready(QueriedAddr {
+                                            addr: known_addr,
+                                            verified: false,
+                                        }));
                                     }
-                                    return Err(OpenAddrError {
-                                        igd_err: self.igd_err.take(),
-                                        kind: OpenAddrErrorKind::LackOfServers,
-                                    });
+                                    return Err(OpenAddrErrorKind::LackOfServers);
                                 }
                                 break;
                             } else {
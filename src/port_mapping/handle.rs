@@ -0,0 +1,210 @@
+use future_utils::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use igd_async::Lease;
+use priv_prelude::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// How long to wait before retrying failed renewal. Leases are renewed halfway through, so there's
+/// usually time for a few more attempts.
+const RENEW_RETRY_SEC: u64 = 30;
+
+/// External address opened for a server, see `open_addr()`. If the address was mapped on the
+/// router, the mapping is renewed in the background for as long as this handle lives and removed
+/// once the handle is dropped or closed.
+pub struct PortMapping {
+    external_addr: Rc<Cell<SocketAddr>>,
+    addr_changes: Option<UnboundedReceiver<SocketAddr>>,
+    removed_rx: Option<oneshot::Receiver<()>>,
+    _drop_tx: Option<DropNotify>,
+}
+
+impl PortMapping {
+    /// Creates handle of an address that needs no mapping, eg. a global address of our network
+    /// interface.
+    pub fn unmanaged(external_addr: SocketAddr) -> PortMapping {
+        let (_, addr_changes) = mpsc::unbounded();
+        PortMapping {
+            external_addr: Rc::new(Cell::new(external_addr)),
+            addr_changes: Some(addr_changes),
+            removed_rx: None,
+            _drop_tx: None,
+        }
+    }
+
+    /// Takes over the mapping made by one of the port mapping mechanisms.
+    pub fn from_lease(lease: Lease, handle: &Handle) -> PortMapping {
+        let external_addr = Rc::new(Cell::new(lease.external_addr()));
+        let (addr_tx, addr_rx) = mpsc::unbounded();
+        let (removed_tx, removed_rx) = oneshot::channel();
+        let (drop_tx, drop_rx) = drop_notify();
+
+        let lease = Rc::new(RefCell::new(lease));
+        let renewals = renew_lease(&lease, &external_addr, addr_tx, handle);
+        let handle_copy = handle.clone();
+        handle.spawn(
+            renewals
+                .until(drop_rx)
+                .and_then(move |_| {
+                    let lease = lease.borrow().clone();
+                    lease
+                        .remove(&handle_copy)
+                        .log_error(LogLevel::Warn, "remove port mapping")
+                }).then(move |_| -> Result<(), ()> {
+                    let _ = removed_tx.send(());
+                    Ok(())
+                }),
+        );
+
+        PortMapping {
+            external_addr,
+            addr_changes: Some(addr_rx),
+            removed_rx: Some(removed_rx),
+            _drop_tx: Some(drop_tx),
+        }
+    }
+
+    /// Returns the current external address.
+    pub fn external_addr(&self) -> SocketAddr {
+        self.external_addr.get()
+    }
+
+    /// Returns stream of new external addresses. Router's external IP might change, which we
+    /// notice when renewing the mapping. The stream can only be taken once.
+    pub fn addr_changes(&mut self) -> Option<UnboundedReceiver<SocketAddr>> {
+        self.addr_changes.take()
+    }
+
+    /// Removes the mapping from the router. The returned future resolves once that's done. Failure
+    /// to remove the mapping is only logged, since the mapping expires eventually anyway.
+    pub fn close(self) -> BoxFuture<(), Void> {
+        let PortMapping { removed_rx, .. } = self;
+        match removed_rx {
+            Some(removed_rx) => removed_rx.then(|_| Ok(())).into_boxed(),
+            None => future::ok(()).into_boxed(),
+        }
+    }
+}
+
+impl fmt::Debug for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PortMapping")
+            .field("external_addr", &self.external_addr())
+            .field("managed", &self.removed_rx.is_some())
+            .finish()
+    }
+}
+
+/// Renews the lease forever, notifying about external address changes.
+fn renew_lease(
+    lease: &Rc<RefCell<Lease>>,
+    external_addr: &Rc<Cell<SocketAddr>>,
+    addr_tx: UnboundedSender<SocketAddr>,
+    handle: &Handle,
+) -> BoxFuture<(), Void> {
+    let lease = lease.clone();
+    let external_addr = external_addr.clone();
+    let handle = handle.clone();
+    let renew_after = lease.borrow().renew_after();
+    future::loop_fn(renew_after, move |renew_after| {
+        let lease = lease.clone();
+        let external_addr = external_addr.clone();
+        let addr_tx = addr_tx.clone();
+        let handle_copy = handle.clone();
+        Timeout::new(renew_after, &handle)
+            .infallible()
+            .and_then(move |()| {
+                let current = lease.borrow().clone();
+                current.renew(&handle_copy).then(move |res| match res {
+                    Ok(renewed) => {
+                        let addr = renewed.external_addr();
+                        if addr != external_addr.get() {
+                            debug!(
+                                "external address changed from {} to {}",
+                                external_addr.get(),
+                                addr
+                            );
+                            external_addr.set(addr);
+                            let _ = addr_tx.unbounded_send(addr);
+                        }
+                        let renew_after = renewed.renew_after();
+                        *lease.borrow_mut() = renewed;
+                        Ok(Loop::Continue(renew_after))
+                    }
+                    Err(e) => {
+                        warn!("failed to renew port mapping: {}", e);
+                        Ok(Loop::Continue(Duration::from_secs(RENEW_RETRY_SEC)))
+                    }
+                })
+            })
+    }).into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use igd_async;
+    use tokio_core::reactor::Core;
+    use util::{read_u16, read_u32, u16_bytes, u32_bytes};
+
+    /// Stands in for NAT-PMP server, external IP of which changes from `1.2.3.4` to `5.6.7.8`
+    /// after the first request. Lifetimes of received map requests are sent to `lifetimes_tx`.
+    fn spawn_nat_pmp_server(
+        handle: &Handle,
+        lifetimes_tx: UnboundedSender<u32>,
+    ) -> SocketAddr {
+        let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let server_addr = unwrap!(socket.local_addr());
+        let mut external_ip = [1, 2, 3, 4];
+        let server = future::poll_fn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[..len];
+                let mut response = vec![0, 128 | request[1], 0, 0, 0, 0, 0, 1];
+                if request[1] == 0 {
+                    response.extend_from_slice(&external_ip);
+                    external_ip = [5, 6, 7, 8];
+                } else {
+                    let internal_port = read_u16(&request[4..]);
+                    let lifetime = read_u32(&request[8..]);
+                    let _ = lifetimes_tx.unbounded_send(lifetime);
+                    response.extend_from_slice(&u16_bytes(internal_port));
+                    response.extend_from_slice(&u16_bytes(internal_port));
+                    response.extend_from_slice(&u32_bytes(lifetime));
+                }
+                let _ = unwrap!(socket.send_to(&response, &addr));
+            }
+            Ok(Async::NotReady)
+        });
+        handle.spawn(server);
+        server_addr
+    }
+
+    mod from_lease {
+        use super::*;
+
+        #[test]
+        fn it_renews_mapping_notifies_about_new_address_and_removes_mapping_when_closed() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (lifetimes_tx, lifetimes_rx) = mpsc::unbounded();
+            let server_addr = spawn_nat_pmp_server(&handle, lifetimes_tx);
+
+            let task = igd_async::map_nat_pmp(&server_addr, Protocol::Udp, 5000, 2, &handle);
+            let lease = unwrap!(core.run(task));
+            let mut mapping = PortMapping::from_lease(lease, &handle);
+            assert_eq!(mapping.external_addr(), addr!("1.2.3.4:5000"));
+
+            let addr_changes = unwrap!(mapping.addr_changes());
+            let task = addr_changes.into_future().map_err(|(e, _)| e);
+            let (new_addr, _) = unwrap!(core.run(task));
+            assert_eq!(new_addr, Some(addr!("5.6.7.8:5000")));
+            assert_eq!(mapping.external_addr(), addr!("5.6.7.8:5000"));
+
+            unwrap!(core.run(mapping.close()));
+            let lifetimes = unwrap!(core.run(lifetimes_rx.take(3).collect()));
+            assert_eq!(lifetimes, vec![2, 2, 0]);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Port mapping protocols that talk to the default gateway directly: NAT-PMP (RFC 6886) and its
+//! successor PCP (RFC 6887). Both use the same server port and request/response pattern, hence
+//! share the transport here.
+//!
+//! `PortMapping` keeps mappings made by any of the mechanisms, IGD included, alive.
+
+use priv_prelude::*;
+use std::fs::File;
+use std::io::Read;
+
+mod handle;
+pub mod nat_pmp;
+pub mod pcp;
+
+pub use self::handle::PortMapping;
+
+/// Port NAT-PMP and PCP servers listen on.
+pub const SERVER_PORT: u16 = 5351;
+/// Initial retransmission timeout, doubled after each retransmission.
+const INITIAL_RTO_MS: u64 = 250;
+/// How many requests are sent before giving up. RFC 6886 suggests 9, which takes over a minute.
+/// Gateways that speak these protocols respond quickly, so we give up much sooner to not delay
+/// falling back to other means of NAT traversal.
+const MAX_REQUESTS: u32 = 3;
+
+quick_error! {
+    /// Errors returned by NAT-PMP and PCP clients.
+    #[derive(Debug)]
+    pub enum PortMappingError {
+        /// Failure to bind socket.
+        Bind(e: io::Error) {
+            description("error binding to socket address")
+            display("error binding to socket address: {}", e)
+            cause(e)
+        }
+        /// Failure to send request to the gateway.
+        SendRequest(e: io::Error) {
+            description("error sending request to gateway")
+            display("error sending request to gateway: {}", e)
+            cause(e)
+        }
+        /// Failure to read response from the gateway.
+        ReadResponse(e: io::Error) {
+            description("error reading response from gateway")
+            display("error reading response from gateway: {}", e)
+            cause(e)
+        }
+        /// Gateway didn't respond, most likely it doesn't support the protocol.
+        NoResponse {
+            description("gateway didn't respond")
+        }
+        /// We don't know the address of our default gateway.
+        NoGateway {
+            description("failed to find default gateway")
+        }
+        /// NAT-PMP server responded with non zero result code.
+        NatPmp(code: u16) {
+            description("NAT-PMP request failed")
+            display("NAT-PMP request failed: {}", nat_pmp::describe_result(*code))
+        }
+        /// PCP server responded with non zero result code.
+        Pcp(code: u8) {
+            description("PCP request failed")
+            display("PCP request failed: {}", pcp::describe_result(*code))
+        }
+        /// Malformed response.
+        InvalidResponse {
+            description("invalid response from gateway")
+        }
+    }
+}
+
+/// Returns NAT-PMP/PCP server address of our default gateway.
+pub fn gateway_server_addr() -> Result<SocketAddr, PortMappingError> {
+    let gateway_ip = default_gateway().ok_or(PortMappingError::NoGateway)?;
+    Ok(SocketAddr::new(IpAddr::V4(gateway_ip), SERVER_PORT))
+}
+
+/// Looks up the default gateway in the routing table. Only Linux routing table is read, elsewhere
+/// the gateway is unknown and PCP and NAT-PMP are not used: probing a guessed address would only
+/// delay falling back to other means of NAT traversal.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let mut route_table = String::new();
+    let res = File::open("/proc/net/route").and_then(|mut f| f.read_to_string(&mut route_table));
+    match res {
+        Ok(_) => parse_route_table(&route_table),
+        Err(e) => {
+            trace!("failed to read routing table: {}", e);
+            None
+        }
+    }
+}
+
+/// Finds default gateway in Linux `/proc/net/route`.
+fn parse_route_table(route_table: &str) -> Option<Ipv4Addr> {
+    for line in route_table.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 3 || columns[1] != "00000000" {
+            continue;
+        }
+        // addresses are printed as hex of u32 in host byte order
+        if let Ok(gateway) = u32::from_str_radix(columns[2], 16) {
+            let gateway = Ipv4Addr::from(u32::from_be(gateway));
+            if !gateway.is_unspecified() {
+                return Some(gateway);
+            }
+        }
+    }
+    None
+}
+
+/// Returns IP address of our network interface that packets to `dest` are sent from.
+fn local_ip_to(dest: &SocketAddr) -> io::Result<IpAddr> {
+    let socket = ::std::net::UdpSocket::bind(&addr!("0.0.0.0:0"))?;
+    socket.connect(dest)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Sends `request` to `server_addr` until it responds with a datagram that `is_response` accepts.
+/// Requests are retransmitted with exponential backoff.
+fn transact<F>(
+    server_addr: &SocketAddr,
+    request: Vec<u8>,
+    handle: &Handle,
+    is_response: F,
+) -> BoxFuture<Vec<u8>, PortMappingError>
+where
+    F: Fn(&[u8]) -> bool + 'static,
+{
+    let socket = try_bfut!(
+        UdpSocket::bind(&addr!("0.0.0.0:0"), handle).map_err(PortMappingError::Bind)
+    );
+    let server_addr = *server_addr;
+    let mut rto = Duration::from_millis(INITIAL_RTO_MS);
+    let mut requests_sent = 0;
+    let mut timeout = Timeout::new(Duration::new(0, 0), handle);
+    future::poll_fn(move || {
+        while let Async::Ready(()) = timeout.poll().void_unwrap() {
+            if requests_sent == MAX_REQUESTS {
+                return Err(PortMappingError::NoResponse);
+            }
+            match socket.send_to(&request, &server_addr) {
+                Ok(_) => {
+                    requests_sent += 1;
+                    timeout.reset(Instant::now() + rto);
+                    rto *= 2;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(PortMappingError::SendRequest(e)),
+            }
+        }
+
+        loop {
+            let mut buffer = [0u8; 1100];
+            let (len, addr) = match socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(PortMappingError::ReadResponse(e)),
+            };
+            if addr != server_addr || !is_response(&buffer[..len]) {
+                debug!("ignoring unexpected datagram from {}", addr);
+                continue;
+            }
+            return Ok(Async::Ready(buffer[..len].to_vec()));
+        }
+    }).into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_route_table {
+        use super::*;
+
+        #[test]
+        fn it_returns_gateway_of_default_route() {
+            let route_table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+";
+
+            let gateway = parse_route_table(route_table);
+
+            assert_eq!(gateway, Some(ipv4!("192.168.0.1")));
+        }
+
+        #[test]
+        fn it_returns_none_when_there_is_no_default_route() {
+            let route_table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+            let gateway = parse_route_table(route_table);
+
+            assert_eq!(gateway, None);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! NAT-PMP (RFC 6886) client.
+
+use super::{transact, PortMappingError};
+use priv_prelude::*;
+use util::{read_u16, read_u32, u16_bytes, u32_bytes};
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDR: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+/// Set in the opcode of responses.
+const RESPONSE_BIT: u8 = 128;
+const EXTERNAL_ADDR_RESPONSE_LEN: usize = 12;
+const MAP_RESPONSE_LEN: usize = 16;
+
+/// Asks NAT-PMP server for our external IP address.
+pub fn external_ip(
+    server_addr: &SocketAddr,
+    handle: &Handle,
+) -> BoxFuture<Ipv4Addr, PortMappingError> {
+    transact(server_addr, vec![VERSION, OP_EXTERNAL_ADDR], handle, |response| {
+        is_response(response, OP_EXTERNAL_ADDR)
+    }).and_then(|response| {
+        check_result(&response)?;
+        if response.len() < EXTERNAL_ADDR_RESPONSE_LEN {
+            return Err(PortMappingError::InvalidResponse);
+        }
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }).into_boxed()
+}
+
+/// Maps `internal_port` of our host to an external port of the gateway for `lifetime` seconds.
+/// Repeating the request renews the mapping and zero `lifetime` deletes it.
+///
+/// # Returns
+///
+/// Mapped external address and the lifetime server granted, which might be shorter than
+/// requested.
+pub fn map_port(
+    server_addr: &SocketAddr,
+    protocol: Protocol,
+    internal_port: u16,
+    lifetime: u32,
+    handle: &Handle,
+) -> BoxFuture<(SocketAddrV4, u32), PortMappingError> {
+    let opcode = match protocol {
+        Protocol::Tcp => OP_MAP_TCP,
+        Protocol::Udp => OP_MAP_UDP,
+    };
+    let mut request = vec![VERSION, opcode, 0, 0];
+    request.extend_from_slice(&u16_bytes(internal_port));
+    // suggest the same external port, RFC 6886 requires zero when deleting the mapping
+    let suggested_port = if lifetime == 0 { 0 } else { internal_port };
+    request.extend_from_slice(&u16_bytes(suggested_port));
+    request.extend_from_slice(&u32_bytes(lifetime));
+
+    let server_addr = *server_addr;
+    let handle = handle.clone();
+    transact(&server_addr, request, &handle, move |response| {
+        is_response(response, opcode)
+    }).and_then(move |response| {
+        check_result(&response)?;
+        if response.len() < MAP_RESPONSE_LEN || read_u16(&response[8..]) != internal_port {
+            return Err(PortMappingError::InvalidResponse);
+        }
+        let external_port = read_u16(&response[10..]);
+        let lifetime = read_u32(&response[12..]);
+        Ok((external_port, lifetime))
+    }).and_then(move |(external_port, lifetime)| {
+        external_ip(&server_addr, &handle)
+            .map(move |ip| (SocketAddrV4::new(ip, external_port), lifetime))
+    }).into_boxed()
+}
+
+/// Returns human readable description of NAT-PMP result code.
+pub fn describe_result(code: u16) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unsupported version",
+        2 => "not authorized/refused",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result code",
+    }
+}
+
+fn is_response(response: &[u8], opcode: u8) -> bool {
+    response.len() >= 4 && response[0] == VERSION && response[1] == (RESPONSE_BIT | opcode)
+}
+
+fn check_result(response: &[u8]) -> Result<(), PortMappingError> {
+    match read_u16(&response[2..]) {
+        0 => Ok(()),
+        code => Err(PortMappingError::NatPmp(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    /// Stands in for NAT-PMP server of a gateway with external IP `1.2.3.4`. Maps internal ports
+    /// to the next external port and caps lifetimes at one hour. Responds to map requests with
+    /// `map_result` code.
+    fn spawn_nat_pmp_server(handle: &Handle, map_result: u16) -> SocketAddr {
+        let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let server_addr = unwrap!(socket.local_addr());
+        let server = future::poll_fn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[..len];
+                let mut response = vec![VERSION, RESPONSE_BIT | request[1]];
+                match request[1] {
+                    OP_EXTERNAL_ADDR => {
+                        response.extend_from_slice(&[0, 0, 0, 0, 0, 1]);
+                        response.extend_from_slice(&[1, 2, 3, 4]);
+                    }
+                    _ => {
+                        let internal_port = read_u16(&request[4..]);
+                        let lifetime = ::std::cmp::min(read_u32(&request[8..]), 3600);
+                        response.extend_from_slice(&u16_bytes(map_result));
+                        response.extend_from_slice(&[0, 0, 0, 1]);
+                        response.extend_from_slice(&u16_bytes(internal_port));
+                        response.extend_from_slice(&u16_bytes(internal_port + 1));
+                        response.extend_from_slice(&u32_bytes(lifetime));
+                    }
+                }
+                let _ = unwrap!(socket.send_to(&response, &addr));
+            }
+            Ok(Async::NotReady)
+        });
+        handle.spawn(server);
+        server_addr
+    }
+
+    mod external_ip {
+        use super::*;
+
+        #[test]
+        fn it_returns_external_ip_of_gateway() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_nat_pmp_server(&handle, 0);
+
+            let ip = unwrap!(core.run(external_ip(&server_addr, &handle)));
+
+            assert_eq!(ip, ipv4!("1.2.3.4"));
+        }
+    }
+
+    mod map_port {
+        use super::*;
+
+        #[test]
+        fn it_returns_mapped_external_address_and_granted_lifetime() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_nat_pmp_server(&handle, 0);
+
+            let task = map_port(&server_addr, Protocol::Udp, 5000, 7200, &handle);
+            let (external_addr, lifetime) = unwrap!(core.run(task));
+
+            assert_eq!(external_addr, SocketAddrV4::new(ipv4!("1.2.3.4"), 5001));
+            assert_eq!(lifetime, 3600);
+        }
+
+        #[test]
+        fn it_returns_error_when_server_refuses_to_map_port() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_nat_pmp_server(&handle, 2);
+
+            let res = core.run(map_port(&server_addr, Protocol::Tcp, 5000, 7200, &handle));
+
+            match res {
+                Err(PortMappingError::NatPmp(2)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_server_does_not_respond_it_returns_error() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let server_addr = unwrap!(socket.local_addr());
+
+            let res = core.run(map_port(&server_addr, Protocol::Udp, 5000, 7200, &handle));
+
+            match res {
+                Err(PortMappingError::NoResponse) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! PCP (RFC 6887) client. Only the MAP opcode is implemented.
+
+use super::{local_ip_to, transact, PortMappingError};
+use priv_prelude::*;
+use rand;
+use util::{read_u16, read_u32, u16_bytes, u32_bytes};
+
+const VERSION: u8 = 2;
+/// NAT-PMP servers respond to PCP requests with this version.
+const NAT_PMP_VERSION: u8 = 0;
+const OP_MAP: u8 = 1;
+/// Set in the opcode of responses.
+const RESPONSE_BIT: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const RESULT_UNSUPP_VERSION: u8 = 1;
+
+/// Maps `internal_port` of our host to an external port of the gateway for `lifetime` seconds.
+/// The mapping is renewed by repeating the request with the same `nonce` and deleted with zero
+/// `lifetime`.
+///
+/// # Returns
+///
+/// Mapped external address and the lifetime server granted, which might be shorter than
+/// requested.
+pub fn map_port(
+    server_addr: &SocketAddr,
+    nonce: [u8; 12],
+    protocol: Protocol,
+    internal_port: u16,
+    lifetime: u32,
+    handle: &Handle,
+) -> BoxFuture<(SocketAddr, u32), PortMappingError> {
+    // server checks that the client address in the request matches the source of the packet
+    let client_ip = try_bfut!(local_ip_to(server_addr).map_err(PortMappingError::Bind));
+    let protocol = match protocol {
+        Protocol::Tcp => PROTOCOL_TCP,
+        Protocol::Udp => PROTOCOL_UDP,
+    };
+    let request = map_request(client_ip, lifetime, nonce, protocol, internal_port);
+
+    transact(server_addr, request, handle, move |response| {
+        is_response(response, &nonce)
+    }).and_then(move |response| {
+        if response[0] == NAT_PMP_VERSION {
+            return Err(PortMappingError::Pcp(RESULT_UNSUPP_VERSION));
+        }
+        match response[3] {
+            0 => (),
+            code => return Err(PortMappingError::Pcp(code)),
+        }
+        if response.len() < HEADER_LEN + MAP_LEN {
+            return Err(PortMappingError::InvalidResponse);
+        }
+        let lifetime = read_u32(&response[4..]);
+        let map = &response[HEADER_LEN..];
+        if map[12] != protocol || read_u16(&map[16..]) != internal_port {
+            return Err(PortMappingError::InvalidResponse);
+        }
+        let external_port = read_u16(&map[18..]);
+        let external_ip = from_pcp_ip(&map[20..36]);
+        Ok((SocketAddr::new(external_ip, external_port), lifetime))
+    }).into_boxed()
+}
+
+/// Generates mapping nonce, which identifies us to the server as the owner of the mapping.
+pub fn new_nonce() -> [u8; 12] {
+    rand::random()
+}
+
+/// Returns human readable description of PCP result code.
+pub fn describe_result(code: u8) -> &'static str {
+    match code {
+        0 => "success",
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide external address",
+        12 => "address mismatch",
+        13 => "excessive remote peers",
+        _ => "unknown result code",
+    }
+}
+
+fn map_request(
+    client_ip: IpAddr,
+    lifetime: u32,
+    nonce: [u8; 12],
+    protocol: u8,
+    internal_port: u16,
+) -> Vec<u8> {
+    let mut request = Vec::with_capacity(HEADER_LEN + MAP_LEN);
+    request.extend_from_slice(&[VERSION, OP_MAP, 0, 0]);
+    request.extend_from_slice(&u32_bytes(lifetime));
+    request.extend_from_slice(&to_pcp_ip(client_ip));
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[protocol, 0, 0, 0]);
+    request.extend_from_slice(&u16_bytes(internal_port));
+    // suggest the same external port and any IPv4 address
+    request.extend_from_slice(&u16_bytes(internal_port));
+    request.extend_from_slice(&to_pcp_ip(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))));
+    request
+}
+
+/// Accepts MAP responses of our request and NAT-PMP "unsupported version" responses. Responses
+/// with error result codes might not carry the nonce.
+fn is_response(response: &[u8], nonce: &[u8; 12]) -> bool {
+    if response.len() < 4 {
+        return false;
+    }
+    if response[0] == NAT_PMP_VERSION {
+        return true;
+    }
+    if response[0] != VERSION || response[1] != (RESPONSE_BIT | OP_MAP) {
+        return false;
+    }
+    response.len() < HEADER_LEN + MAP_LEN || response[HEADER_LEN..HEADER_LEN + 12] == nonce[..]
+}
+
+/// PCP carries IPv4 addresses as IPv4-mapped IPv6 addresses.
+fn to_pcp_ip(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn from_pcp_ip(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(bytes);
+    let ip = Ipv6Addr::from(octets);
+    if octets[..10] == [0; 10] && octets[10..12] == [0xff, 0xff] {
+        IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else {
+        IpAddr::V6(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    /// Stands in for PCP server of a gateway with external IP `1.2.3.4`. Maps internal ports to
+    /// the next external port and caps lifetimes at one hour.
+    fn spawn_pcp_server(handle: &Handle) -> SocketAddr {
+        let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let server_addr = unwrap!(socket.local_addr());
+        let server = future::poll_fn(move || {
+            let mut buffer = [0u8; 1100];
+            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[..len];
+                let result = if from_pcp_ip(&request[8..24]) == addr.ip() {
+                    0
+                } else {
+                    12
+                };
+                let lifetime = ::std::cmp::min(read_u32(&request[4..]), 3600);
+                let map = &request[HEADER_LEN..];
+                let internal_port = read_u16(&map[16..]);
+
+                let mut response = vec![VERSION, RESPONSE_BIT | OP_MAP, 0, result];
+                response.extend_from_slice(&u32_bytes(lifetime));
+                response.extend_from_slice(&[0; 16]);
+                response.extend_from_slice(&map[..16]);
+                response.extend_from_slice(&u16_bytes(internal_port));
+                response.extend_from_slice(&u16_bytes(internal_port + 1));
+                response.extend_from_slice(&to_pcp_ip(IpAddr::V4(ipv4!("1.2.3.4"))));
+                let _ = unwrap!(socket.send_to(&response, &addr));
+            }
+            Ok(Async::NotReady)
+        });
+        handle.spawn(server);
+        server_addr
+    }
+
+    /// Stands in for NAT-PMP only server, which doesn't understand PCP.
+    fn spawn_nat_pmp_server(handle: &Handle) -> SocketAddr {
+        let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let server_addr = unwrap!(socket.local_addr());
+        let server = future::poll_fn(move || {
+            let mut buffer = [0u8; 1100];
+            while let Ok((_, addr)) = socket.recv_from(&mut buffer) {
+                let response = [NAT_PMP_VERSION, 0x80 | buffer[1], 0, 1, 0, 0, 0, 1];
+                let _ = unwrap!(socket.send_to(&response, &addr));
+            }
+            Ok(Async::NotReady)
+        });
+        handle.spawn(server);
+        server_addr
+    }
+
+    mod map_port {
+        use super::*;
+
+        #[test]
+        fn it_returns_mapped_external_address_and_granted_lifetime() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_pcp_server(&handle);
+
+            let task = map_port(&server_addr, new_nonce(), Protocol::Tcp, 5000, 7200, &handle);
+            let (external_addr, lifetime) = unwrap!(core.run(task));
+
+            assert_eq!(external_addr, addr!("1.2.3.4:5001"));
+            assert_eq!(lifetime, 3600);
+        }
+
+        #[test]
+        fn when_server_only_supports_nat_pmp_it_returns_unsupported_version_error() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_nat_pmp_server(&handle);
+
+            let task = map_port(&server_addr, new_nonce(), Protocol::Udp, 5000, 7200, &handle);
+            let res = core.run(task);
+
+            match res {
+                Err(PortMappingError::Pcp(RESULT_UNSUPP_VERSION)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
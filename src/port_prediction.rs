@@ -0,0 +1,351 @@
+//! Statistical prediction of external ports allocated by NATs.
+//!
+//! We query a number of traversal servers from the same local endpoint and observe which external
+//! ports our NAT allocated for each of them, in order. Then we check the observations against a
+//! few allocation patterns commonly seen in home routers and carrier grade NATs.
+
+use priv_prelude::*;
+
+/// Max number of candidate ports returned by the predictor.
+pub const MAX_CANDIDATES: usize = 8;
+/// Max difference between the smallest and biggest port delta for allocation to still be
+/// considered sequential.
+const MAX_JITTER: i32 = 32;
+/// Max size of port block for allocation to be considered block based.
+const MAX_BLOCK_SIZE: u32 = 1024;
+/// Smallest port block we consider.
+const MIN_BLOCK_SIZE: u32 = 16;
+
+/// How confident we are that our NAT will use one of the predicted ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum PredictionConfidence {
+    /// Prediction is more of a guess.
+    Low,
+    /// One of the candidates is likely to be right.
+    Medium,
+    /// First candidate is almost certainly right.
+    High,
+}
+
+impl PredictionConfidence {
+    /// How many of the predicted candidates are worth punching to. The less confident we are, the
+    /// more candidates the peer punches to.
+    pub fn max_candidates(self) -> usize {
+        match self {
+            PredictionConfidence::Low => MAX_CANDIDATES,
+            PredictionConfidence::Medium => MAX_CANDIDATES / 2,
+            PredictionConfidence::High => MAX_CANDIDATES / 4,
+        }
+    }
+}
+
+/// Port allocation pattern recognised from observed external ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// The same external port is used for all destinations.
+    EndpointIndependent,
+    /// External port changes by the same delta for each new destination.
+    ConstantDelta(i32),
+    /// Ports are allocated sequentially, but connections of other hosts behind the same NAT
+    /// get interleaved with ours, hence deltas vary a bit.
+    Sequential {
+        /// Smallest observed difference between consecutive ports.
+        min_delta: i32,
+        /// Biggest observed difference between consecutive ports.
+        max_delta: i32,
+    },
+    /// Ports are picked randomly from a fixed, aligned block of ports.
+    Block {
+        /// First port of the block.
+        start: u16,
+        /// Number of ports in the block.
+        size: u32,
+    },
+    /// No recognisable pattern.
+    Random,
+}
+
+/// Result of port prediction.
+#[derive(Debug, Clone)]
+pub struct PortPrediction {
+    /// Detected allocation pattern.
+    pub allocation: PortAllocation,
+    /// Ports our NAT will most likely use for the next destination, most likely first.
+    /// Empty, if allocation is random.
+    pub candidates: Vec<u16>,
+    /// How confident we are in the candidates.
+    pub confidence: PredictionConfidence,
+}
+
+impl PortPrediction {
+    /// Converts prediction to the coarser `NatType`.
+    pub fn nat_type(&self, observed_ports: &[u16]) -> NatType {
+        match self.allocation {
+            PortAllocation::EndpointIndependent => NatType::EIM,
+            PortAllocation::ConstantDelta(..)
+            | PortAllocation::Sequential { .. }
+            | PortAllocation::Block { .. } => NatType::EDM,
+            PortAllocation::Random => NatType::EDMRandomPorts(observed_ports.to_vec()),
+        }
+    }
+}
+
+/// Predicts which external ports our NAT will allocate next. `ports` must be given in the order
+/// they were allocated.
+pub fn predict_ports(ports: &[u16]) -> PortPrediction {
+    let last_port = match ports.last() {
+        Some(port) => *port,
+        None => return random(),
+    };
+    if ports.len() == 1 {
+        return PortPrediction {
+            allocation: PortAllocation::EndpointIndependent,
+            candidates: vec![last_port],
+            confidence: PredictionConfidence::Low,
+        };
+    }
+
+    let deltas: Vec<i32> = ports
+        .windows(2)
+        .map(|pair| i32::from(pair[1].wrapping_sub(pair[0]) as i16))
+        .collect();
+    let min_delta = unwrap!(deltas.iter().min().cloned());
+    let max_delta = unwrap!(deltas.iter().max().cloned());
+
+    if min_delta == 0 && max_delta == 0 {
+        return PortPrediction {
+            allocation: PortAllocation::EndpointIndependent,
+            candidates: vec![last_port],
+            confidence: PredictionConfidence::High,
+        };
+    }
+
+    if min_delta == max_delta {
+        let delta = min_delta;
+        // the following ports are there in case some other host behind the NAT stole the port
+        let candidates = (1..4)
+            .map(|i| offset_port(last_port, delta * i))
+            .collect();
+        // a single delta might as well be a coincidence
+        let confidence = if deltas.len() > 1 {
+            PredictionConfidence::High
+        } else {
+            PredictionConfidence::Low
+        };
+        return PortPrediction {
+            allocation: PortAllocation::ConstantDelta(delta),
+            candidates,
+            confidence,
+        };
+    }
+
+    let same_direction = min_delta > 0 || max_delta < 0;
+    if same_direction && max_delta - min_delta <= MAX_JITTER {
+        return sequential(last_port, &deltas, min_delta, max_delta);
+    }
+
+    if ports.len() >= 3 {
+        if let Some(prediction) = block(ports) {
+            return prediction;
+        }
+    }
+
+    random()
+}
+
+fn random() -> PortPrediction {
+    PortPrediction {
+        allocation: PortAllocation::Random,
+        candidates: Vec::new(),
+        confidence: PredictionConfidence::Low,
+    }
+}
+
+fn sequential(last_port: u16, deltas: &[i32], min_delta: i32, max_delta: i32) -> PortPrediction {
+    let mut sorted_deltas = deltas.to_vec();
+    sorted_deltas.sort();
+    let median_delta = sorted_deltas[(sorted_deltas.len() - 1) / 2];
+
+    // Try deltas from the observed range first, closest to median first. Then extend beyond the
+    // range since more hosts might have been allocated ports in between.
+    let (step, lo, hi) = if median_delta > 0 {
+        (1, min_delta, max_delta + MAX_JITTER)
+    } else {
+        (-1, min_delta - MAX_JITTER, max_delta)
+    };
+    let mut offsets: Vec<i32> = (lo..hi + 1).filter(|d| *d != 0).collect();
+    offsets.sort_by_key(|d| {
+        let out_of_range = *d < min_delta || *d > max_delta;
+        (out_of_range, (d - median_delta).abs(), d * step)
+    });
+    let candidates = offsets
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|d| offset_port(last_port, d))
+        .collect();
+
+    let confidence = if max_delta - min_delta <= 2 {
+        PredictionConfidence::Medium
+    } else {
+        PredictionConfidence::Low
+    };
+    PortPrediction {
+        allocation: PortAllocation::Sequential {
+            min_delta,
+            max_delta,
+        },
+        candidates,
+        confidence,
+    }
+}
+
+/// Checks if all ports fall into the same small, aligned block of ports. The chance of random
+/// ports doing so is negligible.
+fn block(ports: &[u16]) -> Option<PortPrediction> {
+    let mut size = MIN_BLOCK_SIZE;
+    while size <= MAX_BLOCK_SIZE {
+        let start = block_start(ports[0], size);
+        if ports.iter().all(|port| block_start(*port, size) == start) {
+            let last_port = ports[ports.len() - 1];
+            let mut free_ports: Vec<u16> = (0..size)
+                .map(|i| (u32::from(start) + i) as u16)
+                .filter(|port| !ports.contains(port))
+                .collect();
+            // prefer the ports that are close to the last allocated one
+            free_ports.sort_by_key(|port| (i32::from(*port) - i32::from(last_port)).abs());
+            free_ports.truncate(MAX_CANDIDATES);
+            return Some(PortPrediction {
+                allocation: PortAllocation::Block { start, size },
+                candidates: free_ports,
+                confidence: PredictionConfidence::Low,
+            });
+        }
+        size *= 2;
+    }
+    None
+}
+
+fn block_start(port: u16, block_size: u32) -> u16 {
+    (u32::from(port) / block_size * block_size) as u16
+}
+
+fn offset_port(port: u16, offset: i32) -> u16 {
+    port.wrapping_add(offset as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod predict_ports {
+        use super::*;
+
+        #[test]
+        fn when_no_ports_are_given_it_returns_random_allocation() {
+            let prediction = predict_ports(&[]);
+
+            assert_eq!(prediction.allocation, PortAllocation::Random);
+            assert!(prediction.candidates.is_empty());
+        }
+
+        #[test]
+        fn when_single_port_is_given_it_guesses_the_same_port_with_low_confidence() {
+            let prediction = predict_ports(&[5000]);
+
+            assert_eq!(prediction.candidates, vec![5000]);
+            assert_eq!(prediction.confidence, PredictionConfidence::Low);
+        }
+
+        #[test]
+        fn when_ports_are_the_same_it_detects_endpoint_independent_mapping() {
+            let prediction = predict_ports(&[5000, 5000]);
+
+            assert_eq!(prediction.allocation, PortAllocation::EndpointIndependent);
+            assert_eq!(prediction.candidates, vec![5000]);
+            assert_eq!(prediction.confidence, PredictionConfidence::High);
+        }
+
+        #[test]
+        fn when_ports_change_by_constant_delta_it_predicts_next_port() {
+            let prediction = predict_ports(&[5000, 5002, 5004]);
+
+            assert_eq!(prediction.allocation, PortAllocation::ConstantDelta(2));
+            assert_eq!(prediction.candidates, vec![5006, 5008, 5010]);
+            assert_eq!(prediction.confidence, PredictionConfidence::High);
+        }
+
+        #[test]
+        fn it_handles_negative_deltas() {
+            let prediction = predict_ports(&[5000, 4999, 4998]);
+
+            assert_eq!(prediction.allocation, PortAllocation::ConstantDelta(-1));
+            assert_eq!(prediction.candidates[0], 4997);
+        }
+
+        #[test]
+        fn it_handles_port_wraparound() {
+            let prediction = predict_ports(&[65534, 65535, 0]);
+
+            assert_eq!(prediction.allocation, PortAllocation::ConstantDelta(1));
+            assert_eq!(prediction.candidates[0], 1);
+        }
+
+        #[test]
+        fn when_two_different_ports_are_given_confidence_is_low() {
+            let prediction = predict_ports(&[5000, 5010]);
+
+            assert_eq!(prediction.candidates[0], 5020);
+            assert_eq!(prediction.confidence, PredictionConfidence::Low);
+        }
+
+        #[test]
+        fn when_ports_grow_with_jitter_it_detects_sequential_allocation() {
+            let prediction = predict_ports(&[5000, 5001, 5003, 5004, 5007]);
+
+            assert_eq!(
+                prediction.allocation,
+                PortAllocation::Sequential {
+                    min_delta: 1,
+                    max_delta: 3,
+                }
+            );
+            assert_eq!(prediction.candidates.len(), MAX_CANDIDATES);
+            // median delta is 1
+            assert_eq!(prediction.candidates[0], 5008);
+            assert!(prediction.candidates.contains(&5009));
+            assert!(prediction.candidates.contains(&5010));
+        }
+
+        #[test]
+        fn when_ports_are_in_the_same_block_it_detects_block_allocation() {
+            let prediction = predict_ports(&[40_970, 40_961, 41_000]);
+
+            assert_eq!(
+                prediction.allocation,
+                PortAllocation::Block {
+                    start: 40_960,
+                    size: 64,
+                }
+            );
+            assert!(!prediction.candidates.contains(&41_000));
+            assert!(
+                prediction
+                    .candidates
+                    .iter()
+                    .all(|port| *port >= 40_960 && *port < 41_024)
+            );
+        }
+
+        #[test]
+        fn when_ports_are_random_it_returns_no_candidates() {
+            let prediction = predict_ports(&[1500, 60_123, 23_456]);
+
+            assert_eq!(prediction.allocation, PortAllocation::Random);
+            assert!(prediction.candidates.is_empty());
+            assert_eq!(
+                prediction.nat_type(&[1500, 60_123, 23_456]),
+                NatType::EDMRandomPorts(vec![1500, 60_123, 23_456])
+            );
+        }
+    }
+}
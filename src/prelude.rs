@@ -1,20 +1,47 @@
+pub use diagnose::{
+    diagnose, DiagnoseError, IgdReport, MappingLifetime, NatReport, ProtocolReport, ServerReport,
+};
+pub use events::{RendezvousEvent, RendezvousEventKind};
+pub use identity::{IdentityError, RendezvousAuth};
+pub use igd_async::RemoveStaleMappingsError;
+pub use igd_firewall::UpnpError;
 pub use ip_addr::{IpAddrExt, Ipv4AddrExt, Ipv6AddrExt};
 pub use mc::{P2p, QueryPublicAddrError};
 pub use open_addr::{BindPublicError, OpenAddrError, OpenAddrErrorKind};
 pub use peer::PeerInfo;
+pub use port_mapping::PortMapping;
+pub use port_prediction::PredictionConfidence;
 pub use protocol::Protocol;
 pub use query::{TcpAddrQuerier, UdpAddrQuerier};
-pub use rendezvous_addr::{rendezvous_addr, RendezvousAddrError, RendezvousAddrErrorKind};
+pub use rate_limit::{DroppedRequests, ServerLimits};
+pub use relay::RelayError;
+pub use rendezvous_addr::{
+    rendezvous_addr, rendezvous_candidates, RendezvousAddrError, RendezvousAddrErrorKind,
+    RendezvousCandidates,
+};
 pub use socket_addr::{SocketAddrExt, SocketAddrV4Ext, SocketAddrV6Ext};
 pub use tcp::addr_querier::RemoteTcpRendezvousServer;
 pub use tcp::builder::TcpBuilderExt;
 pub use tcp::listener::{bind_public_with_addr as tcp_bind_public_with_addr, TcpListenerExt};
+pub use tcp::relay::{RemoteTcpRelayServer, TcpRelayServer};
 pub use tcp::rendezvous_server::respond_with_addr as tcp_respond_with_addr;
 pub use tcp::rendezvous_server::{RendezvousServerError, TcpRendezvousServer};
-pub use tcp::stream::{ConnectReusableError, TcpRendezvousConnectError, TcpStreamExt};
-pub use udp::addr_querier::RemoteUdpRendezvousServer;
+pub use tcp::stream::{
+    ConnectReusableError, TcpRendezvousConnectError, TcpRendezvousConnection, TcpStreamExt,
+};
+pub use udp::addr_querier::{RemoteUdpRendezvousServer, StunUdpAddrQuerier};
+pub use udp::keepalive::{UdpKeepalive, UdpKeepaliveError};
+pub use udp::nat_behaviour::discover_nat_behaviour as udp_discover_nat_behaviour;
+pub use udp::nat_behaviour::{
+    FilteringBehaviour, MappingBehaviour, NatBehaviour, NatBehaviourError,
+};
+pub use udp::relay::{RemoteUdpRelayServer, UdpRelayServer};
 pub use udp::rendezvous_server::respond_with_addr as udp_respond_with_addr;
 pub use udp::rendezvous_server::UdpRendezvousServer;
+pub use udp::session::{UdpSession, UdpSessionError};
 pub use udp::socket::{
-    bind_public_with_addr as udp_bind_public_with_addr, UdpRendezvousConnectError, UdpSocketExt,
+    bind_public_with_addr as udp_bind_public_with_addr, UdpRendezvousConnectError,
+    UdpRendezvousConnection, UdpSocketExt,
 };
+pub use udp::turn::{RemoteTurnServer, TurnAllocation, TurnError, TurnProxyHandle};
+pub use version::{Capabilities, VersionMismatch, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
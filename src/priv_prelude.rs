@@ -7,7 +7,7 @@ pub use future_utils::{BoxFuture, BoxStream, FutureExt, StreamExt};
 pub use futures::{future, sink, stream, Async, AsyncSink, Future, Poll, Sink, Stream};
 pub use log::LogLevel;
 pub use maidsafe_utilities::serialisation::SerialisationError;
-pub use mc::{EchoRequest, P2p, QueryPublicAddrError};
+pub use mc::{emit_event, start_attempt, EchoRequest, P2p, QueryPublicAddrError};
 pub use net2::{TcpBuilder, UdpBuilder};
 pub use prelude::*;
 pub use protocol::Protocol;
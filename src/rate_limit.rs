@@ -0,0 +1,310 @@
+//! Limits of requests rendezvous servers process. Every echo request costs the server an
+//! anonymous decryption, so without limits a single host could monopolise the server or exhaust
+//! its CPU.
+
+use priv_prelude::*;
+
+/// By default, how many requests per second a single IP can make.
+const DEFAULT_PER_IP_RATE: u32 = 10;
+/// By default, how many requests a single IP can make in a burst.
+const DEFAULT_PER_IP_BURST: u32 = 20;
+/// By default, how many requests per second all clients together can make.
+const DEFAULT_GLOBAL_RATE: u32 = 1000;
+/// By default, how many requests all clients together can make in a burst.
+const DEFAULT_GLOBAL_BURST: u32 = 2000;
+/// By default, how many TCP connections a server keeps open at once.
+const DEFAULT_MAX_TCP_CONNECTIONS: usize = 1024;
+/// How many client IPs we keep track of. Clients whose buckets have refilled are forgotten first.
+const MAX_TRACKED_IPS: usize = 64 * 1024;
+
+/// Limits of requests a rendezvous server processes. Limits are enforced with token buckets:
+/// every request takes a token, buckets refill at the given rate and hold at most burst tokens.
+/// Per IP limits apply to whole /64 networks of IPv6 clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// How many requests per second a single IP can make.
+    pub per_ip_rate: u32,
+    /// How many requests a single IP can make in a burst.
+    pub per_ip_burst: u32,
+    /// How many requests per second all clients together can make.
+    pub global_rate: u32,
+    /// How many requests all clients together can make in a burst.
+    pub global_burst: u32,
+    /// How many TCP connections the server keeps open at once. Not used by UDP servers.
+    pub max_tcp_connections: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> ServerLimits {
+        ServerLimits {
+            per_ip_rate: DEFAULT_PER_IP_RATE,
+            per_ip_burst: DEFAULT_PER_IP_BURST,
+            global_rate: DEFAULT_GLOBAL_RATE,
+            global_burst: DEFAULT_GLOBAL_BURST,
+            max_tcp_connections: DEFAULT_MAX_TCP_CONNECTIONS,
+        }
+    }
+}
+
+/// How many requests a rendezvous server dropped, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DroppedRequests {
+    /// Requests from clients that exceeded the per IP limit.
+    pub per_ip_limit: u64,
+    /// Requests that exceeded the global limit.
+    pub global_limit: u64,
+    /// TCP connections closed right away, because too many were open.
+    pub connection_limit: u64,
+    /// TCP requests longer than any valid request.
+    pub oversized: u64,
+}
+
+/// Enforces `ServerLimits` and counts dropped requests. Shared between the server handle and its
+/// task.
+pub struct RateLimiter {
+    limits: ServerLimits,
+    global: TokenBucket,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+    connections: usize,
+    dropped: DroppedRequests,
+}
+
+impl RateLimiter {
+    pub fn new(limits: ServerLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            global: TokenBucket::full(limits.global_burst, Instant::now()),
+            per_ip: HashMap::new(),
+            connections: 0,
+            dropped: DroppedRequests::default(),
+        }
+    }
+
+    pub fn limits(&self) -> ServerLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: ServerLimits) {
+        self.limits = limits;
+    }
+
+    pub fn dropped(&self) -> DroppedRequests {
+        self.dropped
+    }
+
+    /// Returns `true`, if request from `ip` should be processed. Otherwise counts it as dropped.
+    /// Tokens are only taken, if both the per IP and the global bucket have one, so that dropped
+    /// requests don't use up the limits.
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let limits = self.limits;
+        let client = client_key(ip);
+        if !self.per_ip.contains_key(&client) && !self.make_room(now) {
+            self.dropped.global_limit += 1;
+            return false;
+        }
+        let bucket = self
+            .per_ip
+            .entry(client)
+            .or_insert_with(|| TokenBucket::full(limits.per_ip_burst, now));
+        if !bucket.has_token(limits.per_ip_rate, limits.per_ip_burst, now) {
+            self.dropped.per_ip_limit += 1;
+            return false;
+        }
+        if !self
+            .global
+            .has_token(limits.global_rate, limits.global_burst, now)
+        {
+            self.dropped.global_limit += 1;
+            return false;
+        }
+        bucket.take();
+        self.global.take();
+        true
+    }
+
+    /// Returns `true`, if another TCP connection can be kept open. It must then be closed with
+    /// `connection_closed()`. Otherwise counts it as dropped.
+    pub fn connection_opened(&mut self) -> bool {
+        if self.connections >= self.limits.max_tcp_connections {
+            self.dropped.connection_limit += 1;
+            return false;
+        }
+        self.connections += 1;
+        true
+    }
+
+    pub fn connection_closed(&mut self) {
+        self.connections -= 1;
+    }
+
+    pub fn count_oversized(&mut self) {
+        self.dropped.oversized += 1;
+    }
+
+    /// Forgets clients whose buckets have refilled, if we track too many of them. Returns `false`,
+    /// if there's still no room for another client.
+    fn make_room(&mut self, now: Instant) -> bool {
+        if self.per_ip.len() < MAX_TRACKED_IPS {
+            return true;
+        }
+        let limits = self.limits;
+        self.per_ip
+            .retain(|_, bucket| !bucket.is_full(limits.per_ip_rate, limits.per_ip_burst, now));
+        self.per_ip.len() < MAX_TRACKED_IPS
+    }
+}
+
+/// Returns the address clients are limited by: IPv4 address itself or /64 network of IPv6
+/// address, since a single host usually gets a whole /64 to pick addresses from.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(..) => ip,
+        IpAddr::V6(ipv6) => {
+            let s = ipv6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(burst),
+            last_refill: now,
+        }
+    }
+
+    /// Returns `true`, if there's a token to take.
+    fn has_token(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.tokens >= 1.0
+    }
+
+    /// Takes a token, which `has_token()` must have confirmed is there.
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    fn is_full(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.tokens >= f64::from(burst)
+    }
+
+    fn refill(&mut self, rate: u32, burst: u32, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let elapsed_sec = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed_sec * f64::from(rate)).min(f64::from(burst));
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_ip_burst: u32, global_burst: u32) -> ServerLimits {
+        ServerLimits {
+            per_ip_rate: 1,
+            per_ip_burst,
+            global_rate: 1,
+            global_burst,
+            max_tcp_connections: 1,
+        }
+    }
+
+    mod allow {
+        use super::*;
+
+        #[test]
+        fn it_drops_requests_exceeding_per_ip_burst() {
+            let mut limiter = RateLimiter::new(limits(2, 10));
+            let now = Instant::now();
+
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(!limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(limiter.allow(ipv4!("5.6.7.8").into(), now));
+
+            assert_eq!(limiter.dropped().per_ip_limit, 1);
+        }
+
+        #[test]
+        fn it_drops_requests_exceeding_global_burst() {
+            let mut limiter = RateLimiter::new(limits(2, 2));
+            let now = Instant::now();
+
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(limiter.allow(ipv4!("5.6.7.8").into(), now));
+            assert!(!limiter.allow(ipv4!("9.10.11.12").into(), now));
+
+            assert_eq!(limiter.dropped().global_limit, 1);
+        }
+
+        #[test]
+        fn when_global_limit_is_exceeded_it_doesnt_take_per_ip_tokens() {
+            let mut limiter = RateLimiter::new(ServerLimits {
+                global_rate: 10,
+                ..limits(2, 1)
+            });
+            let now = Instant::now();
+
+            assert!(limiter.allow(ipv4!("5.6.7.8").into(), now));
+            assert!(!limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(!limiter.allow(ipv4!("1.2.3.4").into(), now));
+            // global bucket refills way faster than the per IP one
+            let later = now + Duration::from_millis(100);
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), later));
+            let later = later + Duration::from_millis(100);
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), later));
+
+            assert_eq!(limiter.dropped().global_limit, 2);
+            assert_eq!(limiter.dropped().per_ip_limit, 0);
+        }
+
+        #[test]
+        fn it_limits_ipv6_clients_per_64_bit_prefix() {
+            let mut limiter = RateLimiter::new(limits(1, 10));
+            let now = Instant::now();
+
+            assert!(limiter.allow(ipv6!("2001:db8::1").into(), now));
+            assert!(!limiter.allow(ipv6!("2001:db8::2").into(), now));
+            assert!(limiter.allow(ipv6!("2001:db8:0:1::1").into(), now));
+
+            assert_eq!(limiter.dropped().per_ip_limit, 1);
+        }
+
+        #[test]
+        fn it_refills_buckets_over_time() {
+            let mut limiter = RateLimiter::new(limits(1, 10));
+            let now = Instant::now();
+
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(!limiter.allow(ipv4!("1.2.3.4").into(), now));
+            assert!(limiter.allow(ipv4!("1.2.3.4").into(), now + Duration::from_secs(1)));
+        }
+    }
+
+    mod connection_opened {
+        use super::*;
+
+        #[test]
+        fn it_drops_connections_exceeding_the_limit_until_one_is_closed() {
+            let mut limiter = RateLimiter::new(limits(1, 1));
+
+            assert!(limiter.connection_opened());
+            assert!(!limiter.connection_opened());
+            limiter.connection_closed();
+            assert!(limiter.connection_opened());
+
+            assert_eq!(limiter.dropped().connection_limit, 1);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Relaying of traffic between peers that failed to punch holes to each other.
+//!
+//! Both peers offer a relay server in their rendezvous messages and use the same one: the relay
+//! offered by the peer with the greater public key, or the other one, if that peer offered none.
+//! Once all direct connection attempts fail, each peer asks the relay server to pair it with the
+//! holder of the ephemeral key exchanged during rendezvous connect. The request also proves the
+//! client holds its own ephemeral key, so whoever sees the rendezvous messages can't take its
+//! place. When both peers show up, the relay forwards traffic between them.
+
+use priv_prelude::*;
+use version::{self, Capabilities, Envelope};
+
+/// How long a peer waits at the relay server for the other peer to show up.
+pub const RELAY_PAIRING_TIMEOUT_SEC: u64 = 120;
+
+quick_error! {
+    /// Failure to get a relayed connection to the remote peer.
+    #[derive(Debug)]
+    pub enum RelayError {
+        /// Failure to bind socket.
+        Bind(e: io::Error) {
+            description("error binding to port")
+            display("error binding to port: {}", e)
+            cause(e)
+        }
+        /// Failure to connect to the relay server.
+        Connect(e: io::Error) {
+            description("error connecting to relay server")
+            display("error connecting to relay server: {}", e)
+            cause(e)
+        }
+        /// Failure to send request to the relay server.
+        SendRequest(e: io::Error) {
+            description("error sending request to relay server")
+            display("error sending request to relay server: {}", e)
+            cause(e)
+        }
+        /// Failure to read response from the relay server.
+        ReadResponse(e: io::Error) {
+            description("error reading response from relay server")
+            display("error reading response from relay server: {}", e)
+            cause(e)
+        }
+        /// Failure to serialize request.
+        Serialize(e: SerialisationError) {
+            description("error serializing relay request")
+            display("error serializing relay request: {}", e)
+            cause(e)
+        }
+        /// Failure to encrypt request.
+        Encrypt(e: EncryptionError) {
+            description("error encrypting relay request")
+            display("error encrypting relay request: {}", e)
+            cause(e)
+        }
+        /// Relay server sent invalid response.
+        Response(e: QueryPublicAddrError) {
+            description("invalid response from relay server")
+            display("invalid response from relay server: {}", e)
+            cause(e)
+        }
+        /// Relay server closed the connection.
+        ConnectionClosed {
+            description("relay server closed the connection")
+        }
+        /// Remote peer didn't show up at the relay server in time.
+        Timeout {
+            description("timed out waiting for remote peer at relay server")
+        }
+    }
+}
+
+/// Request to pair us with the peer holding `peer_pk`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayRequest {
+    /// Our ephemeral key used during rendezvous connect.
+    pub client_pk: PublicEncryptKey,
+    /// Ephemeral key of the peer we want to be paired with.
+    pub peer_pk: PublicEncryptKey,
+    /// `peer_pk` encrypted with the secret shared by the client and the relay server. Proves that
+    /// the client holds the secret key of `client_pk`.
+    pub proof: Vec<u8>,
+}
+
+impl RelayRequest {
+    /// Creates a request encrypted for the relay server.
+    pub fn seal(
+        server_pk: &PublicEncryptKey,
+        our_pk: &PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        peer_pk: &PublicEncryptKey,
+    ) -> Result<Vec<u8>, RelayError> {
+        let proof = our_sk
+            .shared_secret(server_pk)
+            .encrypt(peer_pk)
+            .map_err(RelayError::Encrypt)?;
+        let request = RelayRequest {
+            client_pk: *our_pk,
+            peer_pk: *peer_pk,
+            proof,
+        };
+        let envelope = version::seal_server_msg(&request).map_err(RelayError::Serialize)?;
+        server_pk
+            .anonymously_encrypt(&envelope)
+            .map_err(RelayError::Encrypt)
+    }
+
+    /// Decrypts a request received by the relay server and checks its proof.
+    pub fn open(
+        msg: &[u8],
+        our_sk: &SecretEncryptKey,
+        our_pk: &PublicEncryptKey,
+    ) -> Result<RelayRequest, RendezvousServerError> {
+        let envelope: Envelope = our_sk
+            .anonymously_decrypt(msg, our_pk)
+            .map_err(RendezvousServerError::Decrypt)?;
+        let _ = envelope
+            .negotiate_version(Capabilities::empty())
+            .map_err(RendezvousServerError::VersionMismatch)?;
+        let request: RelayRequest = envelope
+            .open()
+            .map_err(RendezvousServerError::Deserialize)?;
+        let peer_pk: PublicEncryptKey = our_sk
+            .shared_secret(&request.client_pk)
+            .decrypt(&request.proof)
+            .map_err(|_| RendezvousServerError::InvalidRelayProof)?;
+        if peer_pk != request.peer_pk {
+            return Err(RendezvousServerError::InvalidRelayProof);
+        }
+        Ok(request)
+    }
+}
+
+/// Relay server's response to a client that was paired with its peer. Carries the client's
+/// address as seen by the relay server, just like the rendezvous server's response does.
+pub fn seal_relay_response(
+    client_addr: &SocketAddr,
+    shared_secret: &SharedSecretKey,
+) -> Result<Vec<u8>, RendezvousServerError> {
+    let envelope =
+        version::seal_server_msg(client_addr).map_err(RendezvousServerError::Serialize)?;
+    shared_secret
+        .encrypt(&envelope)
+        .map_err(RendezvousServerError::Encrypt)
+}
+
+/// Relay clients waiting for their peers to show up.
+pub struct RelaySessions<T> {
+    waiting: HashMap<(PublicEncryptKey, PublicEncryptKey), (T, Instant)>,
+}
+
+impl<T> RelaySessions<T> {
+    /// Creates an empty set of sessions.
+    pub fn new() -> RelaySessions<T> {
+        RelaySessions {
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Returns the client who sent `request` along with its peer, if the peer is already waiting.
+    /// Otherwise the client is left waiting for its peer. If some client already waits with the
+    /// same keys, it keeps its place and the new one is dropped, so that a replayed request can't
+    /// take it.
+    pub fn pair(&mut self, request: &RelayRequest, client: T) -> Option<(T, T)> {
+        match self.waiting.remove(&(request.peer_pk, request.client_pk)) {
+            Some((peer, _since)) => Some((client, peer)),
+            None => {
+                let key = (request.client_pk, request.peer_pk);
+                let _ = self
+                    .waiting
+                    .entry(key)
+                    .or_insert_with(|| (client, Instant::now()));
+                None
+            }
+        }
+    }
+
+    /// Forgets clients that have been waiting longer than `timeout`.
+    pub fn expire(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        self.waiting
+            .retain(|_, &mut (_, since)| now.duration_since(since) < timeout);
+    }
+}
+
+/// Picks the relay server both peers use: the one offered by the peer with the greater key, or the
+/// other one, if that peer offered none.
+pub fn choose_relay<R>(
+    our_pk: &PublicEncryptKey,
+    their_pk: &PublicEncryptKey,
+    our_relay: Option<R>,
+    their_relay: Option<R>,
+) -> Option<R> {
+    if our_pk > their_pk {
+        our_relay.or(their_relay)
+    } else {
+        their_relay.or(our_relay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod relay_request {
+        use super::*;
+
+        #[test]
+        fn it_opens_sealed_request() {
+            let (server_pk, server_sk) = gen_encrypt_keypair();
+            let (client_pk, client_sk) = gen_encrypt_keypair();
+            let (peer_pk, _) = gen_encrypt_keypair();
+            let msg = unwrap!(RelayRequest::seal(
+                &server_pk, &client_pk, &client_sk, &peer_pk
+            ));
+
+            let request = unwrap!(RelayRequest::open(&msg, &server_sk, &server_pk));
+
+            assert_eq!(request.client_pk, client_pk);
+            assert_eq!(request.peer_pk, peer_pk);
+        }
+
+        #[test]
+        fn when_client_doesnt_hold_its_key_it_returns_error() {
+            let (server_pk, server_sk) = gen_encrypt_keypair();
+            let (victim_pk, _) = gen_encrypt_keypair();
+            let (peer_pk, _) = gen_encrypt_keypair();
+            let (_, attacker_sk) = gen_encrypt_keypair();
+            let msg = unwrap!(RelayRequest::seal(
+                &server_pk,
+                &victim_pk,
+                &attacker_sk,
+                &peer_pk
+            ));
+
+            match RelayRequest::open(&msg, &server_sk, &server_pk) {
+                Err(RendezvousServerError::InvalidRelayProof) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    mod relay_sessions {
+        use super::*;
+
+        fn request(client_pk: &PublicEncryptKey, peer_pk: &PublicEncryptKey) -> RelayRequest {
+            RelayRequest {
+                client_pk: *client_pk,
+                peer_pk: *peer_pk,
+                proof: Vec::new(),
+            }
+        }
+
+        #[test]
+        fn it_pairs_clients_that_ask_for_each_other() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            let mut sessions = RelaySessions::new();
+
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 1), None);
+            assert_eq!(sessions.pair(&request(&pk2, &pk1), 2), Some((2, 1)));
+        }
+
+        #[test]
+        fn it_doesnt_pair_clients_that_ask_for_the_same_peer() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            let (pk3, _) = gen_encrypt_keypair();
+            let mut sessions = RelaySessions::new();
+
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 1), None);
+            assert_eq!(sessions.pair(&request(&pk3, &pk2), 3), None);
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 1), None);
+        }
+
+        #[test]
+        fn it_keeps_the_first_client_waiting() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            let mut sessions = RelaySessions::new();
+
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 1), None);
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 3), None);
+
+            assert_eq!(sessions.pair(&request(&pk2, &pk1), 2), Some((2, 1)));
+        }
+
+        #[test]
+        fn it_forgets_expired_clients() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+            let mut sessions = RelaySessions::new();
+
+            assert_eq!(sessions.pair(&request(&pk1, &pk2), 1), None);
+            sessions.expire(Duration::from_secs(0));
+
+            assert_eq!(sessions.pair(&request(&pk2, &pk1), 2), None);
+        }
+    }
+
+    mod choose_relay {
+        use super::*;
+
+        #[test]
+        fn both_peers_choose_the_same_relay() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+
+            let relay1 = choose_relay(&pk1, &pk2, Some(1), Some(2));
+            let relay2 = choose_relay(&pk2, &pk1, Some(2), Some(1));
+
+            assert_eq!(relay1, relay2);
+        }
+
+        #[test]
+        fn when_only_one_peer_offers_relay_it_is_chosen() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+
+            assert_eq!(choose_relay(&pk1, &pk2, None, Some(2)), Some(2));
+            assert_eq!(choose_relay(&pk2, &pk1, Some(2), None), Some(2));
+        }
+    }
+}
@@ -1,9 +1,14 @@
+use addr_race::{race_igd, RaceOutcome};
 use futures::future::Loop;
 use futures::stream::FuturesOrdered;
 use igd_async::{self, GetAnyAddressError};
+use port_prediction::predict_ports;
 use priv_prelude::*;
 use std::error::Error;
 
+/// How many traversal servers we query to predict our public port.
+const MAX_PORT_SAMPLES: usize = 5;
+
 /// Wrapper around rendezvous connect error and IGD error.
 #[derive(Debug)]
 pub struct RendezvousAddrError {
@@ -15,7 +20,16 @@ impl RendezvousAddrError {
     /// If the error kind is `UnpredictablePorts`, returns `NatType` with port details.
     pub fn unpredictable_ports(&self) -> Option<NatType> {
         match self.kind {
-            RendezvousAddrErrorKind::UnpredictablePorts(ref nat_type) => Some(nat_type.clone()),
+            RendezvousAddrErrorKind::UnpredictablePorts(_, ref nat_type) => Some(nat_type.clone()),
+            _ => None,
+        }
+    }
+
+    /// If the error kind is `UnpredictablePorts`, returns our public IP address. Although we
+    /// can't predict our public port, the IP can still be used for birthday paradox hole punching.
+    pub fn unpredictable_ports_ip(&self) -> Option<IpAddr> {
+        match self.kind {
+            RendezvousAddrErrorKind::UnpredictablePorts(ip, _) => Some(ip),
             _ => None,
         }
     }
@@ -49,7 +63,7 @@ quick_error! {
         }
         /// NAT assigns us ports in an unpredictable manner. Hence we don't know what our public
         /// port would be when remote peer connected to us.
-        UnpredictablePorts(nat: NatType) {
+        UnpredictablePorts(ip: IpAddr, nat: NatType) {
             display("NAT is not giving us consistent or predictable external ports.")
         }
         /// *p2p* only tolerates specific number of errors. If that exceeds, *p2p* stops trying.
@@ -65,6 +79,17 @@ quick_error! {
     }
 }
 
+/// Our public addresses that the NAT will most likely use when we contact the remote peer.
+#[derive(Debug, Clone)]
+pub struct RendezvousCandidates {
+    /// Candidate addresses, most likely first. Never empty.
+    pub addrs: Vec<SocketAddr>,
+    /// Detected NAT type.
+    pub nat_type: NatType,
+    /// How confident we are that our NAT will use one of `addrs`.
+    pub confidence: PredictionConfidence,
+}
+
 /// When we are behind NAT, try to guess an address we could use for hole punching.
 /// In addition NAT type is also returned.
 pub fn rendezvous_addr(
@@ -73,41 +98,113 @@ pub fn rendezvous_addr(
     handle: &Handle,
     p2p: &P2p,
 ) -> BoxFuture<(SocketAddr, NatType), RendezvousAddrError> {
+    rendezvous_candidates(protocol, bind_addr, handle, p2p)
+        .map(|candidates| (candidates.addrs[0], candidates.nat_type))
+        .into_boxed()
+}
+
+/// Like `rendezvous_addr()`, but returns a ranked list of addresses our NAT might use. Ports are
+/// predicted from the ports our NAT allocated when querying multiple traversal servers.
+/// Meanwhile IGD is asked to map a port, and the mapped address is used unless servers give a
+/// confident prediction first.
+pub fn rendezvous_candidates(
+    protocol: Protocol,
+    bind_addr: &SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<RendezvousCandidates, RendezvousAddrError> {
     let bind_addr = *bind_addr;
     let handle = handle.clone();
     let p2p = p2p.clone();
+    let p2p1 = p2p.clone();
 
     trace!("creating rendezvous addr");
     let timeout = Duration::from_secs(300);
-    igd_async::get_any_address_rendezvous(protocol, bind_addr, timeout, &handle, &p2p)
-        .map(|public_addr| (public_addr, NatType::None))
-        .or_else(move |igd_error| {
-            trace!("failed to open port with igd: {}", igd_error);
-            public_addrs_from_stun(&handle, &p2p, protocol, bind_addr)
-                .map_err(|kind| RendezvousAddrError { igd_error, kind })
-                .map(move |(addr, nat_type)| {
-                    if p2p.force_use_local_port() {
-                        (SocketAddr::new(addr.ip(), bind_addr.port()), nat_type)
-                    } else {
-                        (addr, nat_type)
-                    }
+    let igd = igd_async::get_any_address_rendezvous(protocol, bind_addr, timeout, &handle, &p2p);
+    let servers = public_addrs_from_stun(&handle, &p2p, protocol, bind_addr).into_boxed();
+    let handle0 = handle.clone();
+    let discard_igd = move |lease| igd_async::remove_unused_lease(lease, &handle0);
+    race_igd(igd, servers, is_confident, discard_igd, &handle)
+        .infallible()
+        .and_then(move |outcome| match outcome {
+            RaceOutcome {
+                igd: Some(Ok(lease)),
+                ..
+            } => {
+                let public_addr = lease.external_addr();
+                emit_event(
+                    &p2p,
+                    RendezvousEventKind::AddrDiscovered {
+                        protocol,
+                        addr: public_addr,
+                    },
+                );
+                Ok(RendezvousCandidates {
+                    addrs: vec![public_addr],
+                    nat_type: NatType::None,
+                    confidence: PredictionConfidence::High,
                 })
+            }
+            RaceOutcome {
+                servers: Some(Ok(mut candidates)),
+                igd,
+            } => {
+                if let Some(Err(igd_error)) = igd {
+                    trace!("failed to open port with igd: {}", igd_error);
+                }
+                if p2p.force_use_local_port() {
+                    let addr = SocketAddr::new(candidates.addrs[0].ip(), bind_addr.port());
+                    candidates.addrs = vec![addr];
+                }
+                Ok(candidates)
+            }
+            RaceOutcome {
+                igd: Some(Err(igd_error)),
+                servers: Some(Err(kind)),
+            } => Err(RendezvousAddrError { igd_error, kind }),
+            _ => unreachable!("race_igd() resolved without a result"),
+        }).map(move |candidates| {
+            emit_event(
+                &p2p1,
+                RendezvousEventKind::NatTypeDetected {
+                    protocol,
+                    nat_type: candidates.nat_type.clone(),
+                },
+            );
+            candidates
         }).into_boxed()
 }
 
+/// Like `rendezvous_candidates()`, but only asks traversal servers, even if IGD is available.
+/// Used by diagnostics to detect NAT type of routers that would open a port for us.
+pub fn stun_candidates(
+    protocol: Protocol,
+    bind_addr: &SocketAddr,
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxFuture<RendezvousCandidates, RendezvousAddrErrorKind> {
+    public_addrs_from_stun(handle, p2p, protocol, *bind_addr).into_boxed()
+}
+
+/// Ports predicted with lower confidence are only used if IGD fails.
+fn is_confident(candidates: &RendezvousCandidates) -> bool {
+    candidates.confidence == PredictionConfidence::High
+}
+
 fn public_addrs_from_stun(
     handle: &Handle,
     p2p: &P2p,
     protocol: Protocol,
     bind_addr: SocketAddr,
-) -> impl Future<Item = (SocketAddr, NatType), Error = RendezvousAddrErrorKind> {
+) -> impl Future<Item = RendezvousCandidates, Error = RendezvousAddrErrorKind> {
     let querier_stream = addr_queriers(handle, p2p, protocol, bind_addr);
+    let p2p = p2p.clone();
     let errors = Vec::new();
     future::loop_fn(
         (querier_stream, errors),
         move |(querier_stream, mut errors)| {
-            GuessPort::start(querier_stream).and_then(|res| match res {
-                Ok(addr) => Ok(Loop::Break(addr)),
+            GuessPort::start(querier_stream, &p2p, protocol).and_then(|res| match res {
+                Ok(candidates) => Ok(Loop::Break(candidates)),
                 Err((querier_stream, error)) => {
                     errors.push(error);
                     if errors.len() == 5 {
@@ -165,31 +262,36 @@ fn addr_queriers(
 
 type QueryFuture = BoxFuture<SocketAddr, Box<Error + Send>>;
 type GuessPortResult =
-    Result<(SocketAddr, NatType), (BoxStream<QueryFuture, Void>, Box<Error + Send>)>;
+    Result<RendezvousCandidates, (BoxStream<QueryFuture, Void>, Box<Error + Send>)>;
 
 struct GuessPort {
     known_ip_opt: Option<IpAddr>,
     known_ports: Vec<u16>,
     active_queriers: FuturesOrdered<QueryFuture>,
     querier_stream: Option<BoxStream<QueryFuture, Void>>,
+    p2p: P2p,
+    protocol: Protocol,
 }
 
 impl GuessPort {
     fn start(
         querier_stream: BoxStream<BoxFuture<SocketAddr, Box<Error + Send>>, Void>,
+        p2p: &P2p,
+        protocol: Protocol,
     ) -> GuessPort {
         GuessPort {
             known_ip_opt: None,
             known_ports: Vec::new(),
             active_queriers: FuturesOrdered::new(),
             querier_stream: Some(querier_stream),
+            p2p: p2p.clone(),
+            protocol,
         }
     }
 
     /// Returns `true` when queriers stream is exhausted.
     fn poll_for_more_queriers(&mut self) -> bool {
-        // keep max 3 responses
-        while self.known_ports.len() + self.active_queriers.len() < 3 {
+        while self.known_ports.len() + self.active_queriers.len() < MAX_PORT_SAMPLES {
             match unwrap!(self.querier_stream.as_mut()).poll().void_unwrap() {
                 Async::Ready(Some(querier)) => {
                     self.active_queriers.push(querier);
@@ -204,8 +306,15 @@ impl GuessPort {
     fn handle_new_address(
         &mut self,
         addr: SocketAddr,
-    ) -> Result<Async<(SocketAddr, NatType)>, RendezvousAddrErrorKind> {
+    ) -> Result<Async<RendezvousCandidates>, RendezvousAddrErrorKind> {
         trace!("Received addr from STUN server: {}", addr);
+        emit_event(
+            &self.p2p,
+            RendezvousEventKind::AddrDiscovered {
+                protocol: self.protocol,
+                addr,
+            },
+        );
         let received_ip = addr.ip();
         let known_ip = match self.known_ip_opt {
             Some(known_ip) => known_ip,
@@ -221,30 +330,18 @@ impl GuessPort {
 
         self.known_ports.push(addr.port());
         if self.known_ports.len() == 2 && self.known_ports[0] == self.known_ports[1] {
-            // same port for multiple queries - endpoint independent mapping
-            return Ok(Async::Ready((addr, NatType::EIM)));
+            // same port for multiple queries - endpoint independent mapping, no need to query
+            // any more servers
+            return self.predict(known_ip).map(Async::Ready);
         }
-
-        if self.known_ports.len() == 3 {
-            let diff0 = self.known_ports[1].wrapping_sub(self.known_ports[0]);
-            let diff1 = self.known_ports[2].wrapping_sub(self.known_ports[1]);
-            if diff0 == diff1 {
-                let next_port = self.known_ports[2].wrapping_add(diff0);
-                let addr = SocketAddr::new(known_ip, next_port);
-                // different port for different queries - endpoint dependent mapping
-                return Ok(Async::Ready((addr, NatType::EDM)));
-            } else {
-                let err = RendezvousAddrErrorKind::UnpredictablePorts(NatType::EDMRandomPorts(
-                    self.known_ports.clone(),
-                ));
-                return Err(err);
-            }
+        if self.known_ports.len() == MAX_PORT_SAMPLES {
+            return self.predict(known_ip).map(Async::Ready);
         }
 
         Ok(Async::NotReady)
     }
 
-    fn queriers_exhausted(&mut self) -> Result<(SocketAddr, NatType), RendezvousAddrErrorKind> {
+    fn queriers_exhausted(&mut self) -> Result<RendezvousCandidates, RendezvousAddrErrorKind> {
         info!("Unable to contact enough query servers to hole-punch reliably.");
         let known_ip = match self.known_ip_opt {
             Some(known_ip) => known_ip,
@@ -253,29 +350,39 @@ impl GuessPort {
                 return Err(err);
             }
         };
-        match self.known_ports.len() {
-            0 => unreachable!(), // since we have known_ip we must have gotten a port
-            1 => {
-                info!("Guessing port based on only one response.");
-                Ok((
-                    SocketAddr::new(known_ip, self.known_ports[0]),
-                    NatType::Unknown,
-                ))
-            }
-            2 => {
-                info!("Guessing port based on only two responses.");
-                let diff = self.known_ports[1].wrapping_sub(self.known_ports[0]);
-                let next_port = self.known_ports[1].wrapping_add(diff);
-                Ok((SocketAddr::new(known_ip, next_port), NatType::EDM))
-            }
-            3 => {
-                let err = RendezvousAddrErrorKind::UnpredictablePorts(NatType::EDMRandomPorts(
-                    self.known_ports.clone(),
-                ));
-                Err(err)
-            }
-            _ => unreachable!(), // we never collect more than 3 ports,
+        info!(
+            "Guessing port based on only {} responses.",
+            self.known_ports.len()
+        );
+        self.predict(known_ip)
+    }
+
+    /// Predicts our next public port from the ports received so far.
+    fn predict(&self, known_ip: IpAddr) -> Result<RendezvousCandidates, RendezvousAddrErrorKind> {
+        let prediction = predict_ports(&self.known_ports);
+        trace!(
+            "ports {:?} look like {:?} allocation",
+            self.known_ports,
+            prediction.allocation
+        );
+        let nat_type = if self.known_ports.len() == 1 {
+            NatType::Unknown
+        } else {
+            prediction.nat_type(&self.known_ports)
+        };
+        if prediction.candidates.is_empty() {
+            let err = RendezvousAddrErrorKind::UnpredictablePorts(known_ip, nat_type);
+            return Err(err);
         }
+        Ok(RendezvousCandidates {
+            addrs: prediction
+                .candidates
+                .into_iter()
+                .map(|port| SocketAddr::new(known_ip, port))
+                .collect(),
+            nat_type,
+            confidence: prediction.confidence,
+        })
     }
 }
 
@@ -289,13 +396,13 @@ impl Future for GuessPort {
 
             match self.active_queriers.poll() {
                 Ok(Async::Ready(Some(addr))) => match self.handle_new_address(addr)? {
-                    Async::Ready((addr, nat_type)) => return Ok(Async::Ready(Ok((addr, nat_type)))),
+                    Async::Ready(candidates) => return Ok(Async::Ready(Ok(candidates))),
                     Async::NotReady => (),
                 },
                 Ok(Async::Ready(None)) => {
                     if querier_stream_exhausted {
-                        let (addr, nat_type) = self.queriers_exhausted()?;
-                        return Ok(Async::Ready(Ok((addr, nat_type))));
+                        let candidates = self.queriers_exhausted()?;
+                        return Ok(Async::Ready(Ok(candidates)));
                     }
                     return Ok(Async::NotReady);
                 }
@@ -341,4 +448,41 @@ mod tests {
             assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
         }
     }
+
+    mod rendezvous_candidates {
+        use super::*;
+
+        #[test]
+        fn it_predicts_port_preserving_nat_on_localhost() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+
+            let p2p = P2p::default();
+            p2p.disable_igd();
+            p2p.disable_igd_for_rendezvous();
+            let mut servers = Vec::new();
+            for _ in 0..3 {
+                let server = unwrap!(UdpRendezvousServer::bind_reusable(
+                    &addr!("0.0.0.0:0"),
+                    &handle
+                ));
+                let server_addr = server.local_addr().unspecified_to_localhost();
+                p2p.add_udp_addr_querier(RemoteUdpRendezvousServer::new(
+                    server_addr,
+                    *server.public_key(),
+                ));
+                servers.push(server);
+            }
+
+            let socket = unwrap!(UdpSocket::bind_reusable(&addr!("0.0.0.0:0"), &handle));
+            let bind_addr = unwrap!(socket.local_addr());
+            let task = rendezvous_candidates(Protocol::Udp, &bind_addr, &handle, &p2p);
+            let candidates = unwrap!(evloop.run(task));
+
+            let expected_addr = SocketAddr::new(IpAddr::V4(ipv4!("127.0.0.1")), bind_addr.port());
+            assert_eq!(candidates.addrs, vec![expected_addr]);
+            assert_eq!(candidates.nat_type, NatType::EIM);
+            assert_eq!(candidates.confidence, PredictionConfidence::High);
+        }
+    }
 }
@@ -32,6 +32,26 @@ impl SocketAddrExt for SocketAddr {
     }
 }
 
+/// Expands IPv6 `bind_addr` into addresses of our network interfaces and returns them as a pair
+/// of: global addresses, that can be reached from the internet without NAT traversal, and all
+/// addresses that can be reached from the local network. Link-local addresses are skipped since
+/// they are useless without a scope ID.
+pub fn ipv6_addrs(bind_addr: &SocketAddr) -> io::Result<(Vec<SocketAddr>, Vec<SocketAddr>)> {
+    let local_addrs: Vec<SocketAddr> = bind_addr
+        .expand_local_unspecified()?
+        .into_iter()
+        .filter(|addr| match *addr {
+            SocketAddr::V6(ref addr) => addr.ip().segments()[0] & 0xffc0 != 0xfe80,
+            SocketAddr::V4(..) => false,
+        }).collect();
+    let global_addrs = local_addrs
+        .iter()
+        .filter(|addr| IpAddrExt::is_global(&addr.ip()))
+        .cloned()
+        .collect();
+    Ok((global_addrs, local_addrs))
+}
+
 /// Some helpful additional methods for `SocketAddrV4`.
 pub trait SocketAddrV4Ext {
     /// If the IP address is the unspecified address `0.0.0.0`, then it is expanded into a vector
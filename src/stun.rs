@@ -0,0 +1,852 @@
+//! Minimal STUN (RFC 5389) message codec, as spoken by standard TURN and STUN servers.
+//!
+//! Only the attributes this crate needs are understood, the rest are kept as raw bytes.
+//! `MESSAGE-INTEGRITY` and `FINGERPRINT` are not part of the attribute list: they are computed
+//! when a message is encoded and checked when it's decoded.
+
+use md5;
+use priv_prelude::*;
+use rand;
+use sha1::Sha1;
+use util::{read_u16, read_u32, u16_bytes, u32_bytes};
+
+/// Fixed value every STUN message carries, also used to XOR addresses.
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+/// STUN message header length.
+pub const HEADER_LEN: usize = 20;
+
+/// Binding method: asks the server for our reflexive address.
+pub const BINDING: u16 = 0x001;
+/// TURN Allocate method.
+pub const ALLOCATE: u16 = 0x003;
+/// TURN Refresh method.
+pub const REFRESH: u16 = 0x004;
+/// TURN Send method.
+pub const SEND: u16 = 0x006;
+/// TURN Data method.
+pub const DATA: u16 = 0x007;
+/// TURN CreatePermission method.
+pub const CREATE_PERMISSION: u16 = 0x008;
+/// TURN ChannelBind method.
+pub const CHANNEL_BIND: u16 = 0x009;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000c;
+const ATTR_LIFETIME: u16 = 0x000d;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_SOFTWARE: u16 = 0x8022;
+const ATTR_RESPONSE_ORIGIN: u16 = 0x802b;
+const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+
+const CHANGE_IP: u8 = 0x04;
+const CHANGE_PORT: u8 = 0x02;
+
+const INTEGRITY_LEN: usize = 20;
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+quick_error! {
+    /// Failure to decode STUN message.
+    #[derive(Debug, PartialEq)]
+    pub enum StunError {
+        /// Datagram is not a STUN message.
+        NotStun {
+            description("not a STUN message")
+        }
+        /// Message is shorter than its header says.
+        Truncated {
+            description("truncated STUN message")
+        }
+        /// Attribute value doesn't match its type.
+        InvalidAttribute(attr_type: u16) {
+            description("invalid STUN attribute")
+            display("invalid STUN attribute: 0x{:04x}", attr_type)
+        }
+        /// `FINGERPRINT` attribute doesn't match the message.
+        Fingerprint {
+            description("STUN message fingerprint mismatch")
+        }
+    }
+}
+
+/// STUN message class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Request, expects a response.
+    Request,
+    /// Indication, expects no response.
+    Indication,
+    /// Success response.
+    Success,
+    /// Error response.
+    Error,
+}
+
+/// STUN attributes understood by this crate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    /// Reflexive address, as sent by old RFC 3489 servers.
+    MappedAddress(SocketAddr),
+    /// Reflexive address.
+    XorMappedAddress(SocketAddr),
+    /// TURN peer address.
+    XorPeerAddress(SocketAddr),
+    /// Relayed transport address allocated by TURN server.
+    XorRelayedAddress(SocketAddr),
+    /// Long-term credential username.
+    Username(String),
+    /// Long-term credential realm.
+    Realm(String),
+    /// Nonce the server expects to be echoed back.
+    Nonce(String),
+    /// Error code and reason phrase of an error response.
+    ErrorCode(u16, String),
+    /// TURN channel number.
+    ChannelNumber(u16),
+    /// TURN allocation lifetime in seconds.
+    Lifetime(u32),
+    /// Data relayed by TURN server.
+    Data(Vec<u8>),
+    /// TURN transport protocol number.
+    RequestedTransport(u8),
+    /// Software the sender runs.
+    Software(String),
+    /// RFC 5780: asks the server to respond from its alternate IP and/or port.
+    ChangeRequest {
+        /// Respond from the alternate IP.
+        change_ip: bool,
+        /// Respond from the alternate port.
+        change_port: bool,
+    },
+    /// RFC 5780: address the response was sent from.
+    ResponseOrigin(SocketAddr),
+    /// RFC 5780: alternate IP and port of the server.
+    OtherAddress(SocketAddr),
+    /// Attribute this crate doesn't understand.
+    Unknown(u16, Vec<u8>),
+}
+
+/// STUN message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunMessage {
+    /// Message method, eg. `BINDING`.
+    pub method: u16,
+    /// Message class.
+    pub class: Class,
+    /// Transaction ID that matches responses to requests.
+    pub transaction_id: [u8; 12],
+    /// Message attributes, except `MESSAGE-INTEGRITY` and `FINGERPRINT`.
+    pub attributes: Vec<Attribute>,
+}
+
+impl StunMessage {
+    /// Creates a new request with random transaction ID.
+    pub fn request(method: u16) -> StunMessage {
+        StunMessage::new(method, Class::Request, rand::random())
+    }
+
+    /// Creates a new indication with random transaction ID.
+    pub fn indication(method: u16) -> StunMessage {
+        StunMessage::new(method, Class::Indication, rand::random())
+    }
+
+    /// Creates a success response to this message.
+    pub fn success(&self) -> StunMessage {
+        StunMessage::new(self.method, Class::Success, self.transaction_id)
+    }
+
+    /// Creates an error response to this message.
+    pub fn error(&self, code: u16, reason: &str) -> StunMessage {
+        StunMessage::new(self.method, Class::Error, self.transaction_id)
+            .with(Attribute::ErrorCode(code, reason.to_owned()))
+    }
+
+    /// Creates a message with no attributes.
+    pub fn new(method: u16, class: Class, transaction_id: [u8; 12]) -> StunMessage {
+        StunMessage {
+            method,
+            class,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds an attribute to the message.
+    pub fn with(mut self, attribute: Attribute) -> StunMessage {
+        self.attributes.push(attribute);
+        self
+    }
+
+    /// Returns the first attribute `f` maps to `Some`.
+    pub fn find<T, F>(&self, f: F) -> Option<T>
+    where
+        F: Fn(&Attribute) -> Option<T>,
+    {
+        self.attributes.iter().filter_map(f).next()
+    }
+
+    /// Returns reflexive address of the sender, preferring `XOR-MAPPED-ADDRESS`.
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.find(|attr| match *attr {
+            Attribute::XorMappedAddress(addr) => Some(addr),
+            _ => None,
+        }).or_else(|| {
+            self.find(|attr| match *attr {
+                Attribute::MappedAddress(addr) => Some(addr),
+                _ => None,
+            })
+        })
+    }
+
+    /// Returns the IP and port changes requested via `CHANGE-REQUEST`.
+    pub fn change_request(&self) -> (bool, bool) {
+        self.find(|attr| match *attr {
+            Attribute::ChangeRequest {
+                change_ip,
+                change_port,
+            } => Some((change_ip, change_port)),
+            _ => None,
+        }).unwrap_or((false, false))
+    }
+
+    /// Returns alternate address of the server, if it advertises one.
+    pub fn other_addr(&self) -> Option<SocketAddr> {
+        self.find(|attr| match *attr {
+            Attribute::OtherAddress(addr) => Some(addr),
+            _ => None,
+        })
+    }
+
+    /// Returns error code of an error response.
+    pub fn error_code(&self) -> Option<(u16, String)> {
+        self.find(|attr| match *attr {
+            Attribute::ErrorCode(code, ref reason) => Some((code, reason.clone())),
+            _ => None,
+        })
+    }
+
+    /// Encodes the message. If `key` is given, `MESSAGE-INTEGRITY` is appended. `FINGERPRINT` is
+    /// always appended.
+    pub fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&u16_bytes(encode_type(self.method, self.class)));
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&u32_bytes(MAGIC_COOKIE));
+        buf.extend_from_slice(&self.transaction_id);
+        for attribute in &self.attributes {
+            let (attr_type, value) = encode_attribute(attribute, &self.transaction_id);
+            push_attribute(&mut buf, attr_type, &value);
+        }
+
+        if let Some(key) = key {
+            set_len(&mut buf, 4 + INTEGRITY_LEN);
+            let hmac = hmac_sha1(key, &buf);
+            push_attribute(&mut buf, ATTR_MESSAGE_INTEGRITY, &hmac);
+        }
+        set_len(&mut buf, 8);
+        let fingerprint = crc32(&buf) ^ FINGERPRINT_XOR;
+        push_attribute(&mut buf, ATTR_FINGERPRINT, &u32_bytes(fingerprint));
+        buf
+    }
+
+    /// Decodes the message and checks its fingerprint, if one is present. `MESSAGE-INTEGRITY`
+    /// is skipped, use `check_integrity()` to verify it. Attributes following it are not covered
+    /// by it, hence ignored, except for `FINGERPRINT`.
+    pub fn decode(buf: &[u8]) -> Result<StunMessage, StunError> {
+        if !is_stun(buf) {
+            return Err(StunError::NotStun);
+        }
+        let len = read_u16(&buf[2..]) as usize;
+        if buf.len() < HEADER_LEN + len {
+            return Err(StunError::Truncated);
+        }
+        let buf = &buf[..HEADER_LEN + len];
+        let (method, class) = decode_type(read_u16(buf));
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&buf[8..HEADER_LEN]);
+
+        let mut attributes = Vec::new();
+        let mut integrity = false;
+        for (offset, attr_type, value) in Attributes::new(buf)? {
+            match attr_type {
+                ATTR_FINGERPRINT => {
+                    if value.len() != 4 {
+                        return Err(StunError::InvalidAttribute(attr_type));
+                    }
+                    let mut signed = buf[..offset].to_vec();
+                    set_len(&mut signed, 8);
+                    if crc32(&signed) ^ FINGERPRINT_XOR != read_u32(value) {
+                        return Err(StunError::Fingerprint);
+                    }
+                    // fingerprint is always the last attribute
+                    break;
+                }
+                _ if integrity => (),
+                ATTR_MESSAGE_INTEGRITY => integrity = true,
+                _ => attributes.push(decode_attribute(attr_type, value, &transaction_id)?),
+            }
+        }
+        Ok(StunMessage {
+            method,
+            class,
+            transaction_id,
+            attributes,
+        })
+    }
+}
+
+/// Quickly tells if the datagram looks like a STUN message.
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN && buf[0] & 0xc0 == 0 && read_u32(&buf[4..]) == MAGIC_COOKIE
+}
+
+/// Returns the length of message attributes given the message header.
+pub fn body_len(header: &[u8]) -> usize {
+    read_u16(&header[2..]) as usize
+}
+
+/// Answers STUN Binding request with the reflexive address of the client. Returns `None`, if the
+/// message is not a Binding request.
+pub fn respond_to_binding(msg: &[u8], client_addr: SocketAddr) -> Option<Vec<u8>> {
+    let request = decode_binding_request(msg, client_addr)?;
+    Some(binding_response(&request, client_addr).encode(None))
+}
+
+/// Decodes STUN Binding request. Returns `None`, if the message is anything else.
+pub fn decode_binding_request(msg: &[u8], client_addr: SocketAddr) -> Option<StunMessage> {
+    let request = match StunMessage::decode(msg) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("invalid STUN message from {}: {}", client_addr, e);
+            return None;
+        }
+    };
+    if request.method != BINDING || request.class != Class::Request {
+        debug!("unexpected STUN message from {}: {:?}", client_addr, request);
+        return None;
+    }
+    Some(request)
+}
+
+/// Creates a success response to Binding request carrying the reflexive address of the client.
+pub fn binding_response(request: &StunMessage, client_addr: SocketAddr) -> StunMessage {
+    // old clients only understand MAPPED-ADDRESS
+    request
+        .success()
+        .with(Attribute::XorMappedAddress(client_addr))
+        .with(Attribute::MappedAddress(client_addr))
+}
+
+/// Checks `MESSAGE-INTEGRITY` of an encoded message. Returns `false`, if the message doesn't
+/// carry one.
+pub fn check_integrity(buf: &[u8], key: &[u8]) -> bool {
+    if !is_stun(buf) {
+        return false;
+    }
+    let len = read_u16(&buf[2..]) as usize;
+    if buf.len() < HEADER_LEN + len {
+        return false;
+    }
+    let attributes = match Attributes::new(&buf[..HEADER_LEN + len]) {
+        Ok(attributes) => attributes,
+        Err(_) => return false,
+    };
+    for (offset, attr_type, value) in attributes {
+        if attr_type == ATTR_MESSAGE_INTEGRITY {
+            let mut signed = buf[..offset].to_vec();
+            set_len(&mut signed, 4 + INTEGRITY_LEN);
+            return constant_time_eq(value, &hmac_sha1(key, &signed));
+        }
+    }
+    false
+}
+
+/// Key used to sign messages with long-term credentials.
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    md5::compute(format!("{}:{}:{}", username, realm, password)).0.to_vec()
+}
+
+/// Iterates over raw attributes of a message: yields attribute offset, type and value.
+struct Attributes<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Attributes<'a> {
+    fn new(buf: &'a [u8]) -> Result<Attributes<'a>, StunError> {
+        // validate all the attribute boundaries upfront, so iteration can't fail
+        let mut offset = HEADER_LEN;
+        while offset < buf.len() {
+            if buf.len() < offset + 4 {
+                return Err(StunError::Truncated);
+            }
+            let len = read_u16(&buf[offset + 2..]) as usize;
+            if buf.len() < offset + 4 + len {
+                return Err(StunError::Truncated);
+            }
+            offset += 4 + padded(len);
+        }
+        Ok(Attributes {
+            buf,
+            offset: HEADER_LEN,
+        })
+    }
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (usize, u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<(usize, u16, &'a [u8])> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let attr_type = read_u16(&self.buf[offset..]);
+        let len = read_u16(&self.buf[offset + 2..]) as usize;
+        let value = &self.buf[offset + 4..offset + 4 + len];
+        self.offset += 4 + padded(len);
+        Some((offset, attr_type, value))
+    }
+}
+
+fn encode_type(method: u16, class: Class) -> u16 {
+    let class_bits = match class {
+        Class::Request => 0x0000,
+        Class::Indication => 0x0010,
+        Class::Success => 0x0100,
+        Class::Error => 0x0110,
+    };
+    ((method & 0x0f80) << 2) | ((method & 0x0070) << 1) | (method & 0x000f) | class_bits
+}
+
+fn decode_type(msg_type: u16) -> (u16, Class) {
+    let method = (msg_type & 0x000f) | ((msg_type & 0x00e0) >> 1) | ((msg_type & 0x3e00) >> 2);
+    let class = match msg_type & 0x0110 {
+        0x0000 => Class::Request,
+        0x0010 => Class::Indication,
+        0x0100 => Class::Success,
+        _ => Class::Error,
+    };
+    (method, class)
+}
+
+fn encode_attribute(attribute: &Attribute, transaction_id: &[u8; 12]) -> (u16, Vec<u8>) {
+    match *attribute {
+        Attribute::MappedAddress(addr) => (ATTR_MAPPED_ADDRESS, encode_addr(&addr)),
+        Attribute::XorMappedAddress(addr) => (
+            ATTR_XOR_MAPPED_ADDRESS,
+            encode_addr(&xor_addr(&addr, transaction_id)),
+        ),
+        Attribute::XorPeerAddress(addr) => (
+            ATTR_XOR_PEER_ADDRESS,
+            encode_addr(&xor_addr(&addr, transaction_id)),
+        ),
+        Attribute::XorRelayedAddress(addr) => (
+            ATTR_XOR_RELAYED_ADDRESS,
+            encode_addr(&xor_addr(&addr, transaction_id)),
+        ),
+        Attribute::Username(ref username) => (ATTR_USERNAME, username.as_bytes().to_vec()),
+        Attribute::Realm(ref realm) => (ATTR_REALM, realm.as_bytes().to_vec()),
+        Attribute::Nonce(ref nonce) => (ATTR_NONCE, nonce.as_bytes().to_vec()),
+        Attribute::ErrorCode(code, ref reason) => {
+            let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+            value.extend_from_slice(reason.as_bytes());
+            (ATTR_ERROR_CODE, value)
+        }
+        Attribute::ChannelNumber(channel) => {
+            let mut value = u16_bytes(channel).to_vec();
+            value.extend_from_slice(&[0, 0]);
+            (ATTR_CHANNEL_NUMBER, value)
+        }
+        Attribute::Lifetime(lifetime) => (ATTR_LIFETIME, u32_bytes(lifetime).to_vec()),
+        Attribute::Data(ref data) => (ATTR_DATA, data.clone()),
+        Attribute::RequestedTransport(protocol) => {
+            (ATTR_REQUESTED_TRANSPORT, vec![protocol, 0, 0, 0])
+        }
+        Attribute::Software(ref software) => (ATTR_SOFTWARE, software.as_bytes().to_vec()),
+        Attribute::ChangeRequest {
+            change_ip,
+            change_port,
+        } => {
+            let mut flags = 0;
+            if change_ip {
+                flags |= CHANGE_IP;
+            }
+            if change_port {
+                flags |= CHANGE_PORT;
+            }
+            (ATTR_CHANGE_REQUEST, vec![0, 0, 0, flags])
+        }
+        Attribute::ResponseOrigin(addr) => (ATTR_RESPONSE_ORIGIN, encode_addr(&addr)),
+        Attribute::OtherAddress(addr) => (ATTR_OTHER_ADDRESS, encode_addr(&addr)),
+        Attribute::Unknown(attr_type, ref value) => (attr_type, value.clone()),
+    }
+}
+
+fn decode_attribute(
+    attr_type: u16,
+    value: &[u8],
+    transaction_id: &[u8; 12],
+) -> Result<Attribute, StunError> {
+    let invalid = || StunError::InvalidAttribute(attr_type);
+    let string = || String::from_utf8(value.to_vec()).map_err(|_| invalid());
+    let xor_addr_value = || {
+        decode_addr(value)
+            .map(|addr| xor_addr(&addr, transaction_id))
+            .ok_or_else(invalid)
+    };
+    let attribute = match attr_type {
+        ATTR_MAPPED_ADDRESS => Attribute::MappedAddress(decode_addr(value).ok_or_else(invalid)?),
+        ATTR_XOR_MAPPED_ADDRESS => Attribute::XorMappedAddress(xor_addr_value()?),
+        ATTR_XOR_PEER_ADDRESS => Attribute::XorPeerAddress(xor_addr_value()?),
+        ATTR_XOR_RELAYED_ADDRESS => Attribute::XorRelayedAddress(xor_addr_value()?),
+        ATTR_USERNAME => Attribute::Username(string()?),
+        ATTR_REALM => Attribute::Realm(string()?),
+        ATTR_NONCE => Attribute::Nonce(string()?),
+        ATTR_ERROR_CODE => {
+            if value.len() < 4 {
+                return Err(invalid());
+            }
+            let code = u16::from(value[2] & 0x07) * 100 + u16::from(value[3]);
+            let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+            Attribute::ErrorCode(code, reason)
+        }
+        ATTR_CHANNEL_NUMBER => {
+            if value.len() != 4 {
+                return Err(invalid());
+            }
+            Attribute::ChannelNumber(read_u16(value))
+        }
+        ATTR_LIFETIME => {
+            if value.len() != 4 {
+                return Err(invalid());
+            }
+            Attribute::Lifetime(read_u32(value))
+        }
+        ATTR_DATA => Attribute::Data(value.to_vec()),
+        ATTR_REQUESTED_TRANSPORT => {
+            if value.len() != 4 {
+                return Err(invalid());
+            }
+            Attribute::RequestedTransport(value[0])
+        }
+        ATTR_SOFTWARE => Attribute::Software(string()?),
+        ATTR_CHANGE_REQUEST => {
+            if value.len() != 4 {
+                return Err(invalid());
+            }
+            Attribute::ChangeRequest {
+                change_ip: value[3] & CHANGE_IP != 0,
+                change_port: value[3] & CHANGE_PORT != 0,
+            }
+        }
+        ATTR_RESPONSE_ORIGIN => Attribute::ResponseOrigin(decode_addr(value).ok_or_else(invalid)?),
+        ATTR_OTHER_ADDRESS => Attribute::OtherAddress(decode_addr(value).ok_or_else(invalid)?),
+        _ => Attribute::Unknown(attr_type, value.to_vec()),
+    };
+    Ok(attribute)
+}
+
+pub fn encode_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut value = Vec::with_capacity(20);
+    match *addr {
+        SocketAddr::V4(ref addr) => {
+            value.extend_from_slice(&[0, 0x01]);
+            value.extend_from_slice(&u16_bytes(addr.port()));
+            value.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(ref addr) => {
+            value.extend_from_slice(&[0, 0x02]);
+            value.extend_from_slice(&u16_bytes(addr.port()));
+            value.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    value
+}
+
+fn decode_addr(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let port = read_u16(&value[2..]);
+    match (value[1], value.len()) {
+        (0x01, 8) => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&value[4..8]);
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port))
+        }
+        (0x02, 20) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        _ => None,
+    }
+}
+
+/// XORs the address with magic cookie and transaction ID. Applying it twice yields the original
+/// address.
+fn xor_addr(addr: &SocketAddr, transaction_id: &[u8; 12]) -> SocketAddr {
+    let cookie = u32_bytes(MAGIC_COOKIE);
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            for (octet, mask) in octets.iter_mut().zip(&cookie) {
+                *octet ^= mask;
+            }
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port)
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            for (octet, mask) in octets.iter_mut().zip(cookie.iter().chain(transaction_id)) {
+                *octet ^= mask;
+            }
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+        }
+    }
+}
+
+fn push_attribute(buf: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    buf.extend_from_slice(&u16_bytes(attr_type));
+    buf.extend_from_slice(&u16_bytes(value.len() as u16));
+    buf.extend_from_slice(value);
+    for _ in value.len()..padded(value.len()) {
+        buf.push(0);
+    }
+}
+
+/// Sets header length field so it covers the current message and `extra` more bytes.
+fn set_len(buf: &mut [u8], extra: usize) {
+    let len = (buf.len() - HEADER_LEN + extra) as u16;
+    buf[2..4].copy_from_slice(&u16_bytes(len));
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+pub fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut key_block = [0u8; 64];
+    if key.len() > key_block.len() {
+        key_block[..20].copy_from_slice(&sha1_digest(&[key]));
+    } else {
+        key_block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner_pad = [0x36u8; 64];
+    let mut outer_pad = [0x5cu8; 64];
+    for ((inner, outer), key) in inner_pad.iter_mut().zip(outer_pad.iter_mut()).zip(&key_block) {
+        *inner ^= key;
+        *outer ^= key;
+    }
+    let inner_hash = sha1_digest(&[&inner_pad[..], data]);
+    sha1_digest(&[&outer_pad[..], &inner_hash[..]])
+}
+
+/// Compares MACs in time that doesn't depend on where they differ, so that attackers can't guess
+/// a valid MAC byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn sha1_digest(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod stun_message {
+        use super::*;
+
+        #[test]
+        fn it_decodes_encoded_message() {
+            let msg = StunMessage::request(ALLOCATE)
+                .with(Attribute::RequestedTransport(17))
+                .with(Attribute::Username(String::from("user")))
+                .with(Attribute::XorPeerAddress(addr!("1.2.3.4:5678")))
+                .with(Attribute::XorRelayedAddress(addr!("[1:2:3::4]:5678")))
+                .with(Attribute::ErrorCode(438, String::from("Stale Nonce")))
+                .with(Attribute::Data(vec![1, 2, 3]));
+
+            let decoded = unwrap!(StunMessage::decode(&msg.encode(None)));
+
+            assert_eq!(decoded, msg);
+        }
+
+        #[test]
+        fn it_decodes_nat_behaviour_discovery_attributes() {
+            let msg = StunMessage::request(BINDING)
+                .with(Attribute::ChangeRequest {
+                    change_ip: true,
+                    change_port: false,
+                }).with(Attribute::ResponseOrigin(addr!("1.2.3.4:3478")))
+                .with(Attribute::OtherAddress(addr!("1.2.3.5:3479")));
+
+            let decoded = unwrap!(StunMessage::decode(&msg.encode(None)));
+
+            assert_eq!(decoded.change_request(), (true, false));
+            assert_eq!(decoded.other_addr(), Some(addr!("1.2.3.5:3479")));
+            assert_eq!(decoded, msg);
+        }
+
+        #[test]
+        fn it_maps_methods_and_classes_to_message_types() {
+            assert_eq!(encode_type(BINDING, Class::Request), 0x0001);
+            assert_eq!(encode_type(BINDING, Class::Success), 0x0101);
+            assert_eq!(encode_type(SEND, Class::Indication), 0x0016);
+            assert_eq!(encode_type(ALLOCATE, Class::Error), 0x0113);
+            assert_eq!(decode_type(0x0119), (CHANNEL_BIND, Class::Error));
+        }
+
+        #[test]
+        fn when_message_is_corrupted_it_returns_fingerprint_error() {
+            let msg = StunMessage::request(BINDING).with(Attribute::Lifetime(600));
+            let mut buf = msg.encode(None);
+            buf[HEADER_LEN + 4] ^= 1;
+
+            assert_eq!(StunMessage::decode(&buf), Err(StunError::Fingerprint));
+        }
+
+        #[test]
+        fn it_ignores_attributes_following_message_integrity() {
+            let mut buf = StunMessage::request(BINDING).encode(Some(b"key"));
+            // replace fingerprint with an attribute integrity doesn't cover and fingerprint again
+            let len = buf.len() - 8;
+            buf.truncate(len);
+            let mut transaction_id = [0u8; 12];
+            transaction_id.copy_from_slice(&buf[8..HEADER_LEN]);
+            let (attr_type, value) = encode_attribute(
+                &Attribute::MappedAddress(addr!("1.2.3.4:5678")),
+                &transaction_id,
+            );
+            push_attribute(&mut buf, attr_type, &value);
+            set_len(&mut buf, 8);
+            let fingerprint = crc32(&buf) ^ FINGERPRINT_XOR;
+            push_attribute(&mut buf, ATTR_FINGERPRINT, &u32_bytes(fingerprint));
+
+            let decoded = unwrap!(StunMessage::decode(&buf));
+
+            assert!(check_integrity(&buf, b"key"));
+            assert_eq!(decoded.mapped_addr(), None);
+        }
+
+        #[test]
+        fn when_datagram_is_not_stun_it_returns_error() {
+            assert_eq!(StunMessage::decode(&[1u8; 32]), Err(StunError::NotStun));
+        }
+    }
+
+    mod check_integrity {
+        use super::*;
+
+        #[test]
+        fn it_accepts_message_signed_with_the_same_key() {
+            let key = long_term_key("user", "realm", "pass");
+            let buf = StunMessage::request(REFRESH)
+                .with(Attribute::Lifetime(0))
+                .encode(Some(&key));
+
+            assert!(check_integrity(&buf, &key));
+            assert!(StunMessage::decode(&buf).is_ok());
+        }
+
+        #[test]
+        fn it_rejects_message_signed_with_different_key() {
+            let buf = StunMessage::request(REFRESH).encode(Some(b"key"));
+
+            assert!(!check_integrity(&buf, b"other key"));
+        }
+
+        #[test]
+        fn it_rejects_unsigned_message() {
+            let buf = StunMessage::request(REFRESH).encode(None);
+
+            assert!(!check_integrity(&buf, b"key"));
+        }
+    }
+
+    mod long_term_key {
+        use super::*;
+
+        #[test]
+        fn it_hashes_credentials_with_md5() {
+            let key = long_term_key("user", "realm", "pass");
+
+            assert_eq!(
+                key,
+                vec![
+                    0x84, 0x93, 0xfb, 0xc5, 0x3b, 0xa5, 0x82, 0xfb, 0x4c, 0x04, 0x4c, 0x45, 0x6b,
+                    0xdc, 0x40, 0xeb,
+                ]
+            );
+        }
+    }
+
+    mod hmac_sha1 {
+        use super::*;
+
+        #[test]
+        fn it_matches_rfc_2202_test_vectors() {
+            assert_eq!(
+                hmac_sha1(b"Jefe", b"what do ya want for nothing?"),
+                [
+                    0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74, 0x16, 0xd5, 0xf1,
+                    0x84, 0xdf, 0x9c, 0x25, 0x9a, 0x7c, 0x79,
+                ]
+            );
+            assert_eq!(
+                hmac_sha1(
+                    &[0xaa; 80],
+                    b"Test Using Larger Than Block-Size Key - Hash Key First"
+                ),
+                [
+                    0xaa, 0x4a, 0xe5, 0xe1, 0x52, 0x72, 0xd0, 0x0e, 0x95, 0x70, 0x56, 0x37, 0xce,
+                    0x8a, 0x3b, 0x55, 0xed, 0x40, 0x21, 0x12,
+                ]
+            );
+        }
+    }
+
+    mod crc32 {
+        use super::*;
+
+        #[test]
+        fn it_computes_ieee_checksum() {
+            assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use priv_prelude::*;
 use tokio_io::codec::length_delimited::Framed;
+use version;
 
 #[derive(Debug, Clone, Hash)]
 /// A remote `TcpRendezvousServer` that we can query for our external address.
@@ -34,6 +35,9 @@ impl TcpAddrQuerier for RemoteTcpRendezvousServer {
                 let stream = try_bfut!(stream_opt.ok_or(QueryPublicAddrError::ConnectTimeout));
                 let (client_pk, client_sk) = gen_encrypt_keypair();
                 let msg = EchoRequest { client_pk };
+                let msg = try_bfut!(
+                    version::seal_server_msg(&msg).map_err(QueryPublicAddrError::Serialize)
+                );
                 let msg = try_bfut!(
                     server_pk
                         .anonymously_encrypt(&msg)
@@ -53,9 +57,7 @@ impl TcpAddrQuerier for RemoteTcpRendezvousServer {
                                 let msg = msg_opt.ok_or(QueryPublicAddrError::ResponseTimeout)?;
                                 let shared_secret = client_sk.shared_secret(&server_pk);
 
-                                shared_secret
-                                    .decrypt(&msg)
-                                    .map_err(QueryPublicAddrError::Decrypt)
+                                version::open_server_response(&msg, &shared_secret)
                             })
                     }).into_boxed()
             }).map_err(|e| Box::new(e) as Box<Error + Send>)
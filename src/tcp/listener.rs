@@ -1,4 +1,5 @@
 use open_addr::{open_addr, BindPublicError};
+use port_mapping::PortMapping;
 use priv_prelude::*;
 use socket_addr::SocketAddrExt;
 use tcp::builder::TcpBuilderExt;
@@ -30,16 +31,17 @@ impl TcpListenerExt for TcpListener {
     }
 }
 
-/// Returns a `TcpListener` listening on the given address along with a public `SocketAddr`
-/// that can be used to connect to the listener from across the internet.
+/// Returns a `TcpListener` listening on the given address along with a public address that can be
+/// used to connect to the listener from across the internet.
 ///
 /// This method will try to open a port on the local router (if there is one) and return the
-/// external address of the port if successful.
+/// external address of the port if successful. The port is closed once the returned `PortMapping`
+/// is dropped, so keep it for as long as the listener is used.
 pub fn bind_public_with_addr(
     addr: &SocketAddr,
     handle: &Handle,
     mc: &P2p,
-) -> BoxFuture<(TcpListener, SocketAddr, SocketAddr), BindPublicError> {
+) -> BoxFuture<(TcpListener, SocketAddr, PortMapping), BindPublicError> {
     let handle = handle.clone();
     let try = || {
         let listener =
@@ -48,7 +50,7 @@ pub fn bind_public_with_addr(
         Ok({
             open_addr(Protocol::Tcp, &bind_addr, &handle, mc)
                 .map_err(BindPublicError::OpenAddr)
-                .map(move |public_addr| (listener, bind_addr, public_addr))
+                .map(move |mapping| (listener, bind_addr, mapping))
         })
     };
     future::result(try()).flatten().into_boxed()
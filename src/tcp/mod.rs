@@ -1,5 +1,6 @@
 pub mod addr_querier;
 pub mod builder;
 pub mod listener;
+pub mod relay;
 pub mod rendezvous_server;
 pub mod stream;
@@ -0,0 +1,260 @@
+//! TCP relay server and client.
+
+use priv_prelude::*;
+use relay::{
+    seal_relay_response, RelayError, RelayRequest, RelaySessions, RELAY_PAIRING_TIMEOUT_SEC,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio_io::{self, AsyncRead};
+use version;
+
+/// How long the relay server waits for client's request.
+const RELAY_REQUEST_TIMEOUT_SEC: u64 = 10;
+
+/// A remote `TcpRelayServer` that peers can fall back to when TCP rendezvous connect fails.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteTcpRelayServer {
+    addr: SocketAddr,
+    pub_key: PublicEncryptKey,
+}
+
+impl RemoteTcpRelayServer {
+    /// Define a new remote relay server.
+    pub fn new(addr: SocketAddr, pub_key: PublicEncryptKey) -> RemoteTcpRelayServer {
+        RemoteTcpRelayServer { addr, pub_key }
+    }
+
+    /// Returns relay server address.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns relay server public key.
+    pub fn public_key(&self) -> &PublicEncryptKey {
+        &self.pub_key
+    }
+}
+
+/// Relays TCP streams between peers that failed to connect to each other directly. Peers are
+/// paired by the ephemeral keys they exchanged during rendezvous connect. Once paired, everything
+/// one peer writes to its connection is forwarded to the other peer's connection.
+pub struct TcpRelayServer {
+    local_addr: SocketAddr,
+    our_pk: PublicEncryptKey,
+    _drop_tx: DropNotify,
+}
+
+impl TcpRelayServer {
+    /// Create a relay server from a `TcpListener`.
+    pub fn from_listener(listener: TcpListener, handle: &Handle) -> io::Result<TcpRelayServer> {
+        let local_addr = listener.local_addr()?;
+        let (drop_tx, drop_rx) = drop_notify();
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let sessions = Rc::new(RefCell::new(RelaySessions::new()));
+        let handle_connections = {
+            let handle = handle.clone();
+            listener
+                .incoming()
+                .map_err(RendezvousServerError::AcceptError)
+                .map(move |(stream, addr)| {
+                    handle_connection(stream, addr, &handle, &our_sk, &our_pk, &sessions)
+                }).buffer_unordered(1024)
+                .log_errors(LogLevel::Info, "relaying tcp connection")
+                .until(drop_rx)
+                .for_each(|()| Ok(()))
+                .infallible()
+        };
+        handle.spawn(handle_connections);
+        Ok(TcpRelayServer {
+            local_addr,
+            our_pk,
+            _drop_tx: drop_tx,
+        })
+    }
+
+    /// Create a new relay server, bound to the given address.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<TcpRelayServer> {
+        let listener = TcpListener::bind(addr, handle)?;
+        TcpRelayServer::from_listener(listener, handle)
+    }
+
+    /// Create a new relay server, reusably bound to the given address.
+    pub fn bind_reusable(addr: &SocketAddr, handle: &Handle) -> io::Result<TcpRelayServer> {
+        let listener = TcpListener::bind_reusable(addr, handle)?;
+        TcpRelayServer::from_listener(listener, handle)
+    }
+
+    /// Returns the local address that this relay server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns server public key.
+    /// Server expects relay requests to be encrypted with this public key.
+    pub fn public_key(&self) -> &PublicEncryptKey {
+        &self.our_pk
+    }
+}
+
+/// Client whose request was accepted.
+struct RelayClient {
+    stream: TcpStream,
+    addr: SocketAddr,
+    client_pk: PublicEncryptKey,
+}
+
+/// Reads client's relay request. If the client's peer is already waiting, relays traffic between
+/// them until both connections are closed. Otherwise the client is left waiting for its peer.
+fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    handle: &Handle,
+    our_sk: &SecretEncryptKey,
+    our_pk: &PublicEncryptKey,
+    sessions: &Rc<RefCell<RelaySessions<RelayClient>>>,
+) -> BoxFuture<(), RendezvousServerError> {
+    let our_sk = our_sk.clone();
+    let our_pk = *our_pk;
+    let sessions = sessions.clone();
+
+    FramedUnbuffered::new(stream)
+        .into_future()
+        .map_err(|(e, _framed)| RendezvousServerError::ReadError(e))
+        .with_timeout(Duration::from_secs(RELAY_REQUEST_TIMEOUT_SEC), handle)
+        .and_then(|opt| opt.ok_or(RendezvousServerError::Timeout))
+        .and_then(move |(req_opt, framed)| {
+            let req = try_bfut!(req_opt.ok_or(RendezvousServerError::ConnectionClosed));
+            let request = try_bfut!(RelayRequest::open(&req, &our_sk, &our_pk));
+            let client = RelayClient {
+                stream: unwrap!(framed.into_inner()),
+                addr,
+                client_pk: request.client_pk,
+            };
+            let pair_opt = {
+                let mut sessions = sessions.borrow_mut();
+                sessions.expire(Duration::from_secs(RELAY_PAIRING_TIMEOUT_SEC));
+                sessions.pair(&request, client)
+            };
+            match pair_opt {
+                Some((client, peer)) => relay_streams(client, peer, &our_sk),
+                None => {
+                    trace!("relay client {} waits for its peer", addr);
+                    future::ok(()).into_boxed()
+                }
+            }
+        }).into_boxed()
+}
+
+/// Tells both peers they were paired and forwards data between them.
+fn relay_streams(
+    client: RelayClient,
+    peer: RelayClient,
+    our_sk: &SecretEncryptKey,
+) -> BoxFuture<(), RendezvousServerError> {
+    trace!("relaying between {} and {}", client.addr, peer.addr);
+    respond(client, our_sk)
+        .join(respond(peer, our_sk))
+        .and_then(|(client, peer)| {
+            let (client_rx, client_tx) = client.split();
+            let (peer_rx, peer_tx) = peer.split();
+            let upstream = tokio_io::io::copy(client_rx, peer_tx)
+                .and_then(|(_n, _client_rx, peer_tx)| tokio_io::io::shutdown(peer_tx));
+            let downstream = tokio_io::io::copy(peer_rx, client_tx)
+                .and_then(|(_n, _peer_rx, client_tx)| tokio_io::io::shutdown(client_tx));
+            upstream
+                .join(downstream)
+                .map(|_| ())
+                .map_err(RendezvousServerError::Relay)
+        }).into_boxed()
+}
+
+fn respond(
+    client: RelayClient,
+    our_sk: &SecretEncryptKey,
+) -> BoxFuture<TcpStream, RendezvousServerError> {
+    let shared_secret = our_sk.shared_secret(&client.client_pk);
+    let msg = try_bfut!(seal_relay_response(&client.addr, &shared_secret));
+    FramedUnbuffered::new(client.stream)
+        .send(Bytes::from(msg))
+        .map_err(RendezvousServerError::SendError)
+        .map(|framed| unwrap!(framed.into_inner()))
+        .into_boxed()
+}
+
+/// Asks the relay server to pair us with the peer holding `their_pk`. Yields a connection to the
+/// relay server which is forwarded to the peer, along with our address as seen by the relay
+/// server.
+pub fn relay_connect(
+    handle: &Handle,
+    relay: &RemoteTcpRelayServer,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    their_pk: &PublicEncryptKey,
+) -> BoxFuture<(TcpStream, SocketAddr), RelayError> {
+    let msg = try_bfut!(RelayRequest::seal(
+        &relay.pub_key,
+        our_pk,
+        our_sk,
+        their_pk
+    ));
+    let shared_secret = our_sk.shared_secret(&relay.pub_key);
+    let relay_addr = relay.addr;
+
+    TcpStream::connect(&relay_addr, handle)
+        .map_err(RelayError::Connect)
+        .and_then(move |stream| {
+            FramedUnbuffered::new(stream)
+                .send(Bytes::from(msg))
+                .map_err(RelayError::SendRequest)
+        }).and_then(|framed| {
+            framed
+                .into_future()
+                .map_err(|(e, _framed)| RelayError::ReadResponse(e))
+        }).and_then(move |(resp_opt, framed)| -> Result<_, RelayError> {
+            let resp = resp_opt.ok_or(RelayError::ConnectionClosed)?;
+            let our_addr: SocketAddr =
+                version::open_server_response(&resp, &shared_secret).map_err(RelayError::Response)?;
+            trace!("relay server {} paired us, we are {}", relay_addr, our_addr);
+            Ok((unwrap!(framed.into_inner()), our_addr))
+        }).with_timeout(Duration::from_secs(RELAY_PAIRING_TIMEOUT_SEC), handle)
+        .and_then(|opt| opt.ok_or(RelayError::Timeout))
+        .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod tcp_relay_server {
+        use super::*;
+
+        #[test]
+        fn it_relays_data_between_paired_peers() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server = unwrap!(TcpRelayServer::bind(&addr!("127.0.0.1:0"), &handle));
+            let relay = RemoteTcpRelayServer::new(server.local_addr(), *server.public_key());
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let (pk2, sk2) = gen_encrypt_keypair();
+
+            let relayed1 = relay_connect(&handle, &relay, &pk1, &sk1, &pk2);
+            let relayed2 = relay_connect(&handle, &relay, &pk2, &sk2, &pk1);
+            let task = relayed1
+                .join(relayed2)
+                .map_err(|e| panic!("relay connect failed: {}", e))
+                .and_then(|((stream1, _addr1), (stream2, _addr2))| {
+                    tokio_io::io::write_all(stream1, b"hello")
+                        .and_then(|(stream1, _msg)| {
+                            unwrap!(stream1.shutdown(::std::net::Shutdown::Write));
+                            tokio_io::io::read_to_end(stream2, Vec::new())
+                        }).map_err(|e| panic!("relayed stream error: {}", e))
+                });
+
+            let (_stream2, data) = unwrap!(core.run(task));
+
+            assert_eq!(&data[..], b"hello");
+        }
+    }
+}
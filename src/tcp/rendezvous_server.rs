@@ -1,7 +1,17 @@
 use open_addr::BindPublicError;
+use port_mapping::PortMapping;
 use priv_prelude::*;
+use rate_limit::RateLimiter;
+use stun;
 use tcp::listener::{self, TcpListenerExt};
 use tokio_io::codec::length_delimited::{self, Framed};
+use tokio_io::io::{read_exact, write_all};
+use version::{self, Capabilities, Envelope};
+
+/// Echo requests are tiny, anything longer than this is not one.
+const MAX_ECHO_REQUEST_LEN: usize = 64 * 1024;
+/// Length of the header `length_delimited` prepends to frames.
+const FRAME_HEADER_LEN: usize = 4;
 
 /// Sends response to echo address request (`ECHO_REQ`).
 pub fn respond_with_addr<S>(
@@ -12,9 +22,11 @@ pub fn respond_with_addr<S>(
 where
     S: Sink<SinkItem = BytesMut, SinkError = io::Error> + 'static,
 {
+    let envelope =
+        try_bfut!(version::seal_server_msg(&addr).map_err(RendezvousServerError::Serialize));
     let encrypted = try_bfut!(
         shared_secret
-            .encrypt(&addr)
+            .encrypt(&envelope)
             .map_err(RendezvousServerError::Encrypt)
     );
     let encrypted = BytesMut::from(encrypted);
@@ -25,10 +37,13 @@ where
 }
 
 /// A TCP rendezvous server. Other peers can use this when performing rendezvous connects and
-/// hole-punching.
+/// hole-punching. Standard STUN (RFC 5389) Binding requests over TCP are answered too.
+///
+/// Requests are subject to `ServerLimits`, connections exceeding them are closed right away.
 pub struct TcpRendezvousServer {
     local_addr: SocketAddr,
     our_pk: PublicEncryptKey,
+    limiter: Arc<Mutex<RateLimiter>>,
     _drop_tx: DropNotify,
 }
 
@@ -58,19 +73,17 @@ impl TcpRendezvousServer {
 
     /// Create a new rendezvous server, reusably bound to the given address. Returns a global,
     /// external socket address on which this server can be contacted if it can successfully create
-    /// such an address (eg. by opening a port on the local network's router).
+    /// such an address (eg. by opening a port on the local network's router). The port stays open
+    /// for as long as the returned `PortMapping` lives.
     pub fn bind_public(
         addr: &SocketAddr,
         handle: &Handle,
         mc: &P2p,
-    ) -> BoxFuture<(TcpRendezvousServer, SocketAddr), BindPublicError> {
+    ) -> BoxFuture<(TcpRendezvousServer, PortMapping), BindPublicError> {
         let handle = handle.clone();
         listener::bind_public_with_addr(addr, &handle, mc)
-            .map(move |(listener, bind_addr, public_addr)| {
-                (
-                    from_listener_inner(listener, &bind_addr, &handle),
-                    public_addr,
-                )
+            .map(move |(listener, bind_addr, mapping)| {
+                (from_listener_inner(listener, &bind_addr, &handle), mapping)
             }).into_boxed()
     }
 
@@ -91,6 +104,22 @@ impl TcpRendezvousServer {
     pub fn public_key(&self) -> &PublicEncryptKey {
         &self.our_pk
     }
+
+    /// Returns the limits of requests this server processes.
+    pub fn limits(&self) -> ServerLimits {
+        unwrap!(self.limiter.lock()).limits()
+    }
+
+    /// Changes the limits of requests this server processes. Connections that are already open
+    /// are not affected.
+    pub fn set_limits(&self, limits: ServerLimits) {
+        unwrap!(self.limiter.lock()).set_limits(limits)
+    }
+
+    /// Returns how many requests this server has dropped so far.
+    pub fn dropped_requests(&self) -> DroppedRequests {
+        unwrap!(self.limiter.lock()).dropped()
+    }
 }
 
 fn from_listener_inner(
@@ -100,24 +129,32 @@ fn from_listener_inner(
 ) -> TcpRendezvousServer {
     let (drop_tx, drop_rx) = drop_notify();
     let (our_pk, our_sk) = gen_encrypt_keypair();
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(ServerLimits::default())));
     let handle_connections = {
         let handle = handle.clone();
+        let limiter = limiter.clone();
         listener
             .incoming()
             .map_err(RendezvousServerError::AcceptError)
-            .map(move |(stream, addr)| {
-                handle_connection(stream, addr, &handle, our_sk.clone(), our_pk)
-            }).buffer_unordered(1024)
-            .log_errors(LogLevel::Info, "processing echo request")
+            .log_errors(LogLevel::Info, "accepting client connection")
             .until(drop_rx)
-            .for_each(|()| Ok(()))
-            .infallible()
+            .for_each(move |(stream, addr)| {
+                // spawned rather than buffered, so that `max_tcp_connections` is the only cap
+                let conn =
+                    handle_limited_connection(stream, addr, &handle, &limiter, &our_sk, our_pk);
+                handle.spawn(
+                    conn.log_error(LogLevel::Info, "processing echo request")
+                        .infallible(),
+                );
+                Ok(())
+            }).infallible()
     };
     handle.spawn(handle_connections);
     TcpRendezvousServer {
         _drop_tx: drop_tx,
         local_addr: *bind_addr,
         our_pk,
+        limiter,
     }
 }
 
@@ -151,6 +188,11 @@ quick_error! {
         ConnectionClosed {
             description("Connection was closed prematurely")
         }
+        /// Client request is longer than any valid request.
+        RequestTooLarge(len: usize) {
+            description("Request is too large")
+            display("Request of {} bytes is too large", len)
+        }
         /// Failure to encrypt data.
         Encrypt(e: EncryptionError) {
             description("Error encrypting message")
@@ -163,9 +205,72 @@ quick_error! {
             display("Error decrypting message: {}", e)
             cause(e)
         }
+        /// Failure to serialize data.
+        Serialize(e: SerialisationError) {
+            description("Error serializing message")
+            display("Error serializing message: {}", e)
+            cause(e)
+        }
+        /// Failure to deserialize data.
+        Deserialize(e: SerialisationError) {
+            description("Error deserializing message")
+            display("Error deserializing message: {}", e)
+            cause(e)
+        }
+        /// Client speaks a protocol version we don't understand.
+        VersionMismatch(e: VersionMismatch) {
+            description("Client speaks incompatible protocol")
+            display("Client speaks incompatible protocol: {}", e)
+            cause(e)
+        }
+        /// Relay client failed to prove it holds the key it wants to be relayed for.
+        InvalidRelayProof {
+            description("Invalid relay request proof")
+        }
+        /// Failure to forward traffic between relayed peers.
+        Relay(e: io::Error) {
+            description("Error relaying traffic between peers")
+            display("Error relaying traffic between peers: {}", e)
+            cause(e)
+        }
     }
 }
 
+/// Handles client connection, unless it exceeds server limits. Every handled connection takes a
+/// connection slot until it's done.
+fn handle_limited_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    handle: &Handle,
+    limiter: &Arc<Mutex<RateLimiter>>,
+    our_sk: &SecretEncryptKey,
+    our_pk: PublicEncryptKey,
+) -> BoxFuture<(), RendezvousServerError> {
+    {
+        let mut limiter = unwrap!(limiter.lock());
+        if !limiter.allow(addr.ip(), Instant::now()) {
+            trace!("tcp rendezvous server dropped rate limited client {}", addr);
+            return future::ok(()).into_boxed();
+        }
+        if !limiter.connection_opened() {
+            trace!("tcp rendezvous server has too many connections, dropped {}", addr);
+            return future::ok(()).into_boxed();
+        }
+    }
+    let limiter = limiter.clone();
+    handle_connection(stream, addr, handle, our_sk.clone(), our_pk)
+        .then(move |res| {
+            let mut limiter = unwrap!(limiter.lock());
+            limiter.connection_closed();
+            if let Err(RendezvousServerError::RequestTooLarge(..)) = res {
+                limiter.count_oversized();
+            }
+            res
+        }).into_boxed()
+}
+
+/// Reads enough of the first request to tell STUN messages from our length delimited echo
+/// requests and answers accordingly.
 fn handle_connection(
     stream: TcpStream,
     addr: SocketAddr,
@@ -173,29 +278,93 @@ fn handle_connection(
     our_sk: SecretEncryptKey,
     our_pk: PublicEncryptKey,
 ) -> BoxFuture<(), RendezvousServerError> {
-    let stream: Framed<_, BytesMut> = length_delimited::Builder::new().new_framed(stream);
-    stream
-        .into_future()
-        .map_err(|(err, _stream)| RendezvousServerError::ReadError(err))
-        .and_then(|(req_opt, stream)| {
-            req_opt
-                .map(|req| (req, stream))
-                .ok_or(RendezvousServerError::ConnectionClosed)
-        }).and_then(move |(req, stream)| {
-            let req: EchoRequest = try_bfut!(
-                our_sk
-                    .anonymously_decrypt(&req, &our_pk)
-                    .map_err(RendezvousServerError::Decrypt,)
-            );
-            let shared_secret = our_sk.shared_secret(&req.client_pk);
-            respond_with_addr(stream, addr, &shared_secret)
-                .map(|_stream| ())
-                .into_boxed()
+    read_exact(stream, [0u8; stun::HEADER_LEN])
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => RendezvousServerError::ConnectionClosed,
+            _ => RendezvousServerError::ReadError(e),
+        }).and_then(move |(stream, header)| {
+            if stun::is_stun(&header) {
+                return on_stun_request(stream, header, addr);
+            }
+            let frame_len = header[..FRAME_HEADER_LEN]
+                .iter()
+                .fold(0, |len, byte| (len << 8) | usize::from(*byte));
+            let frame_start = stun::HEADER_LEN - FRAME_HEADER_LEN;
+            if frame_len > MAX_ECHO_REQUEST_LEN {
+                return future::err(RendezvousServerError::RequestTooLarge(frame_len)).into_boxed();
+            }
+            if frame_len < frame_start {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "invalid request length");
+                return future::err(RendezvousServerError::ReadError(e)).into_boxed();
+            }
+            read_exact(stream, vec![0u8; frame_len - frame_start])
+                .map_err(RendezvousServerError::ReadError)
+                .and_then(move |(stream, rest)| {
+                    let mut req = header[FRAME_HEADER_LEN..].to_vec();
+                    req.extend_from_slice(&rest);
+                    on_echo_request(stream, addr, &req, &our_sk, &our_pk)
+                }).into_boxed()
         }).with_timeout(Duration::from_secs(2), handle)
         .and_then(|opt| opt.ok_or(RendezvousServerError::Timeout))
         .into_boxed()
 }
 
+fn on_echo_request(
+    stream: TcpStream,
+    addr: SocketAddr,
+    req: &[u8],
+    our_sk: &SecretEncryptKey,
+    our_pk: &PublicEncryptKey,
+) -> BoxFuture<(), RendezvousServerError> {
+    let stream: Framed<_, BytesMut> = length_delimited::Builder::new()
+        .max_frame_length(MAX_ECHO_REQUEST_LEN)
+        .new_framed(stream);
+    let envelope: Envelope = try_bfut!(
+        our_sk
+            .anonymously_decrypt(req, our_pk)
+            .map_err(RendezvousServerError::Decrypt,)
+    );
+    if let Err(e) = envelope.negotiate_version(Capabilities::empty()) {
+        debug!("client {} speaks incompatible protocol: {}", addr, e);
+        let resp = try_bfut!(
+            version::unsupported_version_response().map_err(RendezvousServerError::Serialize)
+        );
+        return stream
+            .send(BytesMut::from(resp))
+            .map_err(RendezvousServerError::SendError)
+            .map(|_stream| ())
+            .into_boxed();
+    }
+    let req: EchoRequest = try_bfut!(envelope.open().map_err(RendezvousServerError::Deserialize));
+    let shared_secret = our_sk.shared_secret(&req.client_pk);
+    respond_with_addr(stream, addr, &shared_secret)
+        .map(|_stream| ())
+        .into_boxed()
+}
+
+/// STUN messages carry their length, hence they need no extra framing over TCP.
+fn on_stun_request(
+    stream: TcpStream,
+    header: [u8; stun::HEADER_LEN],
+    addr: SocketAddr,
+) -> BoxFuture<(), RendezvousServerError> {
+    trace!("tcp rendezvous server received STUN message from {}", addr);
+    let body_len = stun::body_len(&header);
+    read_exact(stream, vec![0u8; body_len])
+        .map_err(RendezvousServerError::ReadError)
+        .and_then(move |(stream, body)| {
+            let mut msg = header.to_vec();
+            msg.extend_from_slice(&body);
+            match stun::respond_to_binding(&msg, addr) {
+                Some(resp) => write_all(stream, resp)
+                    .map_err(RendezvousServerError::SendError)
+                    .map(|(_stream, _resp)| ())
+                    .into_boxed(),
+                None => future::ok(()).into_boxed(),
+            }
+        }).into_boxed()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -244,9 +413,8 @@ mod tests {
                                     let shared_secret = client_sk.shared_secret(&server_pk);
                                     let resp = unwrap!(resp_opt);
                                     let received_addr: SocketAddr = unwrap!(
-                                    shared_secret
-                                        .decrypt(&resp)
-                                );
+                                        version::open_server_response(&resp, &shared_secret)
+                                    );
                                     assert_eq!(received_addr, actual_addr);
                                 })
                         }).join(handle_conns)
@@ -258,6 +426,7 @@ mod tests {
     mod rendezvous_server {
         use super::*;
         use maidsafe_utilities::serialisation;
+        use stun::StunMessage;
 
         #[test]
         fn when_unencrypted_request_is_sent_client_connection_is_closed() {
@@ -299,7 +468,7 @@ mod tests {
             let server_pk = server.public_key();
             let (client_pk, _) = gen_encrypt_keypair();
 
-            let request = EchoRequest { client_pk };
+            let request = unwrap!(version::seal_server_msg(&EchoRequest { client_pk }));
             let encrypted_request =
                 BytesMut::from(unwrap!(server_pk.anonymously_encrypt(&request)));
             let (_, tmp_sk) = gen_encrypt_keypair();
@@ -318,7 +487,7 @@ mod tests {
                                     .map_err(|(e, _stream)| panic!("error reading: {}", e))
                                     .map(move |(msg_opt, _stream)| {
                                         let msg = unwrap!(msg_opt);
-                                        match invalid_shared_secret.decrypt::<EchoRequest>(&msg) {
+                                        match invalid_shared_secret.decrypt::<Envelope>(&msg) {
                                             Err(_) => (),
                                             Ok(x) => panic!("unexpected success: {:?}", x),
                                         }
@@ -329,5 +498,68 @@ mod tests {
 
             evloop.run(f).void_unwrap()
         }
+
+        #[test]
+        fn it_answers_stun_binding_requests() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(TcpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            let server_addr = server.local_addr().unspecified_to_localhost();
+            let request = StunMessage::request(stun::BINDING);
+            let transaction_id = request.transaction_id;
+
+            let f = {
+                TcpStream::connect(&server_addr, &handle)
+                    .map_err(|e| panic!("error connecting: {}", e))
+                    .and_then(move |stream| {
+                        let our_addr = unwrap!(stream.local_addr());
+                        write_all(stream, request.encode(None))
+                            .and_then(|(stream, _req)| read_exact(stream, [0u8; stun::HEADER_LEN]))
+                            .and_then(|(stream, header)| {
+                                let body = vec![0u8; stun::body_len(&header)];
+                                read_exact(stream, body).map(move |(_stream, body)| {
+                                    let mut msg = header.to_vec();
+                                    msg.extend_from_slice(&body);
+                                    msg
+                                })
+                            }).map_err(|e| panic!("STUN transaction failed: {}", e))
+                            .map(move |msg| {
+                                let response = unwrap!(StunMessage::decode(&msg));
+                                assert_eq!(response.transaction_id, transaction_id);
+                                assert_eq!(response.mapped_addr(), Some(our_addr));
+                            })
+                    })
+            };
+
+            evloop.run(f).void_unwrap()
+        }
+
+        #[test]
+        fn it_closes_connections_exceeding_connection_limit() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(TcpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            server.set_limits(ServerLimits {
+                max_tcp_connections: 0,
+                ..ServerLimits::default()
+            });
+            let server_addr = server.local_addr().unspecified_to_localhost();
+
+            let f = {
+                TcpStream::connect(&server_addr, &handle)
+                    .map_err(|e| panic!("error connecting: {}", e))
+                    .and_then(|stream| {
+                        Framed::<_, BytesMut>::new(stream)
+                            .into_future()
+                            .map_err(|(e, _stream)| panic!("error reading: {}", e))
+                            .map(|(opt, _stream)| {
+                                assert!(opt.is_none());
+                            })
+                    })
+            };
+            evloop.run(f).void_unwrap();
+
+            assert_eq!(server.dropped_requests().connection_limit, 1);
+        }
     }
 }
@@ -1,8 +1,14 @@
-use maidsafe_utilities::serialisation;
+use identity::{prove_identity, verify_identity, IdentityError, RendezvousAuth};
 use priv_prelude::*;
-use rendezvous_addr::{rendezvous_addr, RendezvousAddrError};
+use relay::{choose_relay, RelayError};
+use rendezvous_addr::{rendezvous_candidates, RendezvousAddrError};
+use socket_addr::ipv6_addrs;
+use std::cell::RefCell;
 use std::error::Error;
+use std::rc::Rc;
 use tcp::builder::TcpBuilderExt;
+use tcp::relay::{relay_connect, RemoteTcpRelayServer};
+use version::{self, Capabilities, OpenEnvelopeError, VersionMismatch};
 
 const RENDEZVOUS_TIMEOUT_SEC: u64 = 10;
 const RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC: u64 = 120;
@@ -11,8 +17,31 @@ const RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC: u64 = 120;
 pub enum TcpRendezvousMsg {
     Init {
         enc_pk: PublicEncryptKey,
-        rendezvous_addr: SocketAddr,
+        /// Proof that `enc_pk` belongs to our long-term identity, if peers authenticate each
+        /// other.
+        identity_proof: Option<Vec<u8>>,
+        /// Candidate public addresses of our listener, most likely first.
+        rendezvous_addrs: Vec<SocketAddr>,
+        /// Addresses of our listener on our network interfaces. Used to connect to peers on the
+        /// same LAN when their NAT doesn't support hairpinning.
+        local_addrs: Vec<SocketAddr>,
+        /// Relay server we offer to use, if direct connection attempts fail.
+        relay: Option<RemoteTcpRelayServer>,
     },
+    /// Trickle mode: the first message, carries the addresses that are known right away.
+    Hello {
+        enc_pk: PublicEncryptKey,
+        identity_proof: Option<Vec<u8>>,
+        /// Global IPv6 addresses of our listener.
+        rendezvous_addrs: Vec<SocketAddr>,
+        /// Addresses of our listener on our network interfaces.
+        local_addrs: Vec<SocketAddr>,
+        relay: Option<RemoteTcpRelayServer>,
+    },
+    /// Trickle mode: candidate public addresses of our listener, sent once they are known.
+    Candidates { rendezvous_addrs: Vec<SocketAddr> },
+    /// Trickle mode: all our addresses were sent.
+    End,
 }
 
 quick_error! {
@@ -53,6 +82,8 @@ pub enum TcpRendezvousConnectError<Ei, Eo> {
     SerializeMsg(SerialisationError),
     /// Failure to deserialize  message received via rendezvous channel
     DeserializeMsg(SerialisationError),
+    /// Received a message that is not valid at this point of the rendezvous connect.
+    UnexpectedMessage,
     /// Failure to encrypt message
     Encrypt(EncryptionError),
     /// Failure to decrypt message from remote peer
@@ -61,6 +92,12 @@ pub enum TcpRendezvousConnectError<Ei, Eo> {
     AllAttemptsFailed(Vec<SingleRendezvousAttemptError>),
     /// Failure to get rendezvous address.
     RendezvousAddrError(RendezvousAddrError),
+    /// Peer speaks a rendezvous protocol we don't understand.
+    VersionMismatch(VersionMismatch),
+    /// Peer failed to prove it's the peer we expect to connect to.
+    Identity(IdentityError),
+    /// All direct connection attempts failed and so did the relay server.
+    Relay(RelayError),
 }
 
 impl<Ei, Eo> fmt::Display for TcpRendezvousConnectError<Ei, Eo>
@@ -75,7 +112,7 @@ where
             Bind(ref e) | IfAddrs(ref e) => {
                 write!(f, "IO error: {}", e)?;
             }
-            ChannelClosed | ChannelTimedOut => (),
+            ChannelClosed | ChannelTimedOut | UnexpectedMessage => (),
             ChannelRead(ref e) => {
                 write!(f, "channel error: {}", e)?;
             }
@@ -105,6 +142,15 @@ where
             RendezvousAddrError(ref e) => {
                 write!(f, "Failed to find rendezvous address: {}", e)?;
             }
+            VersionMismatch(ref e) => {
+                write!(f, "{}", e)?;
+            }
+            Identity(ref e) => {
+                write!(f, "{}", e)?;
+            }
+            Relay(ref e) => {
+                write!(f, "{}", e)?;
+            }
         }
         Ok(())
     }
@@ -126,10 +172,14 @@ where
             ChannelWrite(..) => "error writing to rendezvous channel",
             SerializeMsg(..) => "error serializing rendezvous message",
             DeserializeMsg(..) => "error deserializing rendezvous message",
+            UnexpectedMessage => "unexpected message received via rendezvous channel",
             Encrypt(..) => "error encrypting message to send to remote peer",
             Decrypt(..) => "error decrypting message received from remote peer",
             AllAttemptsFailed(..) => "all attempts to connect to the remote host failed",
             RendezvousAddrError(..) => "failed to find rendezvous address",
+            VersionMismatch(..) => "peer speaks incompatible rendezvous protocol",
+            Identity(..) => "failed to authenticate remote peer",
+            Relay(..) => "failed to relay connection via relay server",
         }
     }
 
@@ -144,7 +194,19 @@ where
             Encrypt(ref e) => Some(e),
             Decrypt(ref e) => Some(e),
             RendezvousAddrError(ref e) => Some(e),
-            ChannelClosed | ChannelTimedOut | AllAttemptsFailed(..) => None,
+            VersionMismatch(ref e) => Some(e),
+            Identity(ref e) => Some(e),
+            Relay(ref e) => Some(e),
+            ChannelClosed | ChannelTimedOut | UnexpectedMessage | AllAttemptsFailed(..) => None,
+        }
+    }
+}
+
+impl<Ei, Eo> From<OpenEnvelopeError> for TcpRendezvousConnectError<Ei, Eo> {
+    fn from(e: OpenEnvelopeError) -> TcpRendezvousConnectError<Ei, Eo> {
+        match e {
+            OpenEnvelopeError::Deserialize(e) => TcpRendezvousConnectError::DeserializeMsg(e),
+            OpenEnvelopeError::VersionMismatch(e) => TcpRendezvousConnectError::VersionMismatch(e),
         }
     }
 }
@@ -199,7 +261,11 @@ pub trait TcpStreamExt {
     /// Perform a TCP rendezvous connect. Both peers must call this method simultaneously in order
     /// to form one TCP connection, connected from both ends. `channel` must provide a channel
     /// through which the two connecting peers can communicate with each other out-of-band while
-    /// negotiating the connection.
+    /// negotiating the connection. If IPv6 is available, it is attempted alongside IPv4: the
+    /// family of the resulting stream's peer address tells which one won.
+    ///
+    /// If candidate trickling is enabled in `mc`, our addresses are sent to the peer in several
+    /// messages, as soon as they are known. Both peers must agree on the mode in such case.
     fn rendezvous_connect<C>(channel: C, handle: &Handle, mc: &P2p) -> TcpRendezvousConnect<C>
     where
         C: Stream<Item = Bytes>,
@@ -207,6 +273,22 @@ pub trait TcpStreamExt {
         <C as Stream>::Error: fmt::Debug,
         <C as Sink>::SinkError: fmt::Debug,
         C: 'static;
+
+    /// Like `rendezvous_connect()`, but also makes sure the remote peer holds the long-term
+    /// identity given in `auth`. Both peers must use this method. Fails with
+    /// `TcpRendezvousConnectError::Identity` if the peer can't prove its identity.
+    fn rendezvous_connect_authenticated<C>(
+        channel: C,
+        handle: &Handle,
+        mc: &P2p,
+        auth: &RendezvousAuth,
+    ) -> TcpRendezvousConnect<C>
+    where
+        C: Stream<Item = Bytes>,
+        C: Sink<SinkItem = Bytes>,
+        <C as Stream>::Error: fmt::Debug,
+        <C as Sink>::SinkError: fmt::Debug,
+        C: 'static;
 }
 
 impl TcpStreamExt for TcpStream {
@@ -236,72 +318,570 @@ impl TcpStreamExt for TcpStream {
         <C as Sink>::SinkError: fmt::Debug,
         C: 'static,
     {
-        // TODO(canndrew): In the current implementation, we send all data in the first message
-        // along the channel. This is because we can't (currently) rely on routing to forward
-        // anything other than the first message to the other peer.
+        rendezvous_connect(channel, handle, mc, None)
+    }
+
+    fn rendezvous_connect_authenticated<C>(
+        channel: C,
+        handle: &Handle,
+        mc: &P2p,
+        auth: &RendezvousAuth,
+    ) -> TcpRendezvousConnect<C>
+    where
+        C: Stream<Item = Bytes>,
+        C: Sink<SinkItem = Bytes>,
+        <C as Stream>::Error: fmt::Debug,
+        <C as Sink>::SinkError: fmt::Debug,
+        C: 'static,
+    {
+        rendezvous_connect(channel, handle, mc, Some(auth))
+    }
+}
 
-        let handle0 = handle.clone();
-        let (our_pk, our_sk) = gen_encrypt_keypair();
+fn rendezvous_connect<C>(
+    channel: C,
+    handle: &Handle,
+    mc: &P2p,
+    auth: Option<&RendezvousAuth>,
+) -> TcpRendezvousConnect<C>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    // Unless candidate trickling is enabled, we send all data in the first message along the
+    // channel. This is because some channels (eg. routing) can't be relied on to forward
+    // anything other than the first message to the other peer.
 
-        let try = || {
-            trace!("starting tcp rendezvous connect");
-            let listener = {
-                TcpListener::bind_reusable(&addr!("0.0.0.0:0"), &handle0)
-                    .map_err(TcpRendezvousConnectError::Bind)
-            }?;
-            let bind_addr = {
-                listener
-                    .local_addr()
-                    .map_err(TcpRendezvousConnectError::Bind)?
-            };
+    let mc = &start_attempt(mc);
+    let handle0 = handle.clone();
+    let mc0 = mc.clone();
+    let (our_pk, our_sk) = gen_encrypt_keypair();
+    let auth = auth.cloned();
 
-            Ok({
-                trace!("getting rendezvous address");
-                rendezvous_addr(Protocol::Tcp, &bind_addr, &handle0, mc)
-                    .map_err(TcpRendezvousConnectError::RendezvousAddrError)
-                    .and_then(move |(our_rendezvous_addr, _nat_type)| {
-                        trace!("got rendezvous address: {}", our_rendezvous_addr);
-                        let msg = TcpRendezvousMsg::Init {
-                            enc_pk: our_pk,
-                            rendezvous_addr: our_rendezvous_addr,
+    let try = || {
+        trace!("starting tcp rendezvous connect");
+        let listener = {
+            TcpListener::bind_reusable(&addr!("0.0.0.0:0"), &handle0)
+                .map_err(TcpRendezvousConnectError::Bind)
+        }?;
+        let bind_addr = {
+            listener
+                .local_addr()
+                .map_err(TcpRendezvousConnectError::Bind)?
+        };
+        let local_addrs = {
+            listener
+                .expanded_local_addrs()
+                .map_err(TcpRendezvousConnectError::IfAddrs)?
+        };
+
+        let ipv6_listener = bind_ipv6_listener(&handle0);
+        if mc.is_candidate_trickling_enabled() {
+            return Ok(trickle_rendezvous_connect(
+                channel,
+                &handle0,
+                mc,
+                listener,
+                bind_addr,
+                local_addrs,
+                ipv6_listener,
+                auth.as_ref(),
+            ));
+        }
+        let ipv6_bind_addr = ipv6_listener.as_ref().map(|&(_, bind_addr, _, _)| bind_addr);
+        let identity_proof = {
+            prove_identity(auth.as_ref(), &our_pk).map_err(TcpRendezvousConnectError::Encrypt)?
+        };
+
+        Ok({
+            trace!("getting rendezvous address");
+            rendezvous_candidates(Protocol::Tcp, &bind_addr, &handle0, mc)
+                .then(move |res| {
+                    let (ipv6_listener, ipv6_rendezvous_addrs, ipv6_local_addrs) =
+                        match ipv6_listener {
+                            Some((listener, _, global_addrs, local_addrs)) => {
+                                (Some(listener), global_addrs, local_addrs)
+                            }
+                            None => (None, Vec::new(), Vec::new()),
+                        };
+                    let mut rendezvous_addrs = match res {
+                        Ok(candidates) => candidates.addrs,
+                        // we might still be reachable via IPv6
+                        Err(ref e) if !ipv6_rendezvous_addrs.is_empty() => {
+                            debug!("IPv4 rendezvous address not available: {}", e);
+                            Vec::new()
+                        }
+                        Err(e) => return Err(TcpRendezvousConnectError::RendezvousAddrError(e)),
+                    };
+                    rendezvous_addrs.extend(ipv6_rendezvous_addrs);
+                    let mut local_addrs = local_addrs;
+                    local_addrs.extend(ipv6_local_addrs);
+                    Ok((ipv6_listener, rendezvous_addrs, local_addrs))
+                }).and_then(move |(ipv6_listener, rendezvous_addrs, local_addrs)| {
+                    trace!("got rendezvous addresses: {:?}", rendezvous_addrs);
+                    let our_rendezvous_addr = rendezvous_addrs[0];
+                    emit_event(
+                        &mc0,
+                        RendezvousEventKind::CandidatesSent {
+                            protocol: Protocol::Tcp,
+                            addrs: rendezvous_addrs.iter().chain(&local_addrs).cloned().collect(),
+                        },
+                    );
+                    let our_relay = mc0.tcp_relay_server();
+                    let msg = TcpRendezvousMsg::Init {
+                        enc_pk: our_pk,
+                        identity_proof,
+                        rendezvous_addrs,
+                        local_addrs,
+                        relay: our_relay.clone(),
+                    };
+
+                    trace!("exchanging rendezvous info with peer");
+
+                    exchange_conn_info(channel, &handle0, &mc0, &msg).and_then(move |msg| {
+                        let (
+                            their_pk,
+                            their_identity_proof,
+                            their_rendezvous_addrs,
+                            their_local_addrs,
+                            their_relay,
+                        ) = match msg {
+                            TcpRendezvousMsg::Init {
+                                enc_pk,
+                                identity_proof,
+                                rendezvous_addrs,
+                                local_addrs,
+                                relay,
+                            } => (enc_pk, identity_proof, rendezvous_addrs, local_addrs, relay),
+                            _ => {
+                                return future::err(
+                                    TcpRendezvousConnectError::UnexpectedMessage,
+                                ).into_boxed()
+                            }
                         };
+                        try_bfut!(
+                            verify_identity(
+                                auth.as_ref(),
+                                &their_pk,
+                                their_identity_proof.as_ref().map(|proof| &proof[..]),
+                            ).map_err(TcpRendezvousConnectError::Identity)
+                        );
+
+                        // local addresses are tried in parallel with public ones
+                        let their_addrs = their_rendezvous_addrs.iter().chain(
+                            their_local_addrs
+                                .iter()
+                                .filter(|addr| !their_rendezvous_addrs.contains(*addr)),
+                        );
+                        let connectors = stream::futures_unordered(their_addrs.filter_map(
+                            |their_addr| {
+                                // connect from the listener of the same IP family
+                                let bind_addr = if their_addr.is_ipv4() {
+                                    bind_addr
+                                } else {
+                                    ipv6_bind_addr?
+                                };
+                                let connector = TcpStream::connect_reusable(
+                                    &bind_addr, their_addr, &handle0,
+                                ).map_err(SingleRendezvousAttemptError::Connect);
+                                Some(connector)
+                            },
+                        ));
+                        let incoming = rendezvous_incoming(listener, ipv6_listener, &handle0);
+                        let all_incoming = connectors.select(incoming).into_boxed();
+                        let direct =
+                            choose_connections(all_incoming, &mc0, &their_pk, &our_sk, &our_pk);
+                        let relay = choose_relay(&our_pk, &their_pk, our_relay, their_relay);
+                        relay_if_failed(&handle0, direct, relay, &our_pk, &our_sk, &their_pk)
+                            .map(move |(stream, relayed_addr)| TcpRendezvousConnection {
+                                stream,
+                                our_public_addr: relayed_addr.unwrap_or(our_rendezvous_addr),
+                                relayed: relayed_addr.is_some(),
+                            }).into_boxed()
+                    })
+                }).into_boxed()
+        })
+    };
+
+    TcpRendezvousConnect {
+        inner: future::result(try()).flatten().into_boxed(),
+    }
+}
 
-                        trace!("exchanging rendezvous info with peer");
-
-                        exchange_conn_info(channel, &handle0, &msg).and_then(move |msg| {
-                            let TcpRendezvousMsg::Init {
-                                enc_pk: their_pk,
-                                rendezvous_addr: their_rendezvous_addr,
-                            } = msg;
-
-                            let connector = TcpStream::connect_reusable(
-                                &bind_addr,
-                                &their_rendezvous_addr,
-                                &handle0,
-                            ).map_err(SingleRendezvousAttemptError::Connect);
-                            let incoming = {
-                                listener
-                                    .incoming()
-                                    .map(|(stream, _addr)| stream)
-                                    .map_err(SingleRendezvousAttemptError::Accept)
-                                    .until({
-                                        Timeout::new(
-                                            Duration::from_secs(RENDEZVOUS_TIMEOUT_SEC),
-                                            &handle0,
-                                        ).infallible()
-                                    })
+/// Rendezvous connect which trickles our addresses to the peer: local and IPv6 addresses are sent
+/// right away, public IPv4 addresses as soon as they are known. Connecting to the peer's addresses
+/// starts as soon as they arrive.
+fn trickle_rendezvous_connect<C>(
+    channel: C,
+    handle: &Handle,
+    mc: &P2p,
+    listener: TcpListener,
+    bind_addr: SocketAddr,
+    local_addrs: Vec<SocketAddr>,
+    ipv6_listener: Option<(TcpListener, SocketAddr, Vec<SocketAddr>, Vec<SocketAddr>)>,
+    auth: Option<&RendezvousAuth>,
+) -> BoxFuture<RendezvousConnectResult, TcpRendezvousConnectError<C::Error, C::SinkError>>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    let handle = handle.clone();
+    let mc = mc.clone();
+    let (our_pk, our_sk) = gen_encrypt_keypair();
+    let identity_proof =
+        try_bfut!(prove_identity(auth, &our_pk).map_err(TcpRendezvousConnectError::Encrypt));
+    let auth = auth.cloned();
+
+    let mut local_addrs = local_addrs;
+    let (ipv6_listener, ipv6_bind_addr, ipv6_rendezvous_addrs) = match ipv6_listener {
+        Some((listener, bind_addr, global_addrs, ipv6_local_addrs)) => {
+            local_addrs.extend(ipv6_local_addrs);
+            (Some(listener), Some(bind_addr), global_addrs)
+        }
+        None => (None, None, Vec::new()),
+    };
+    emit_event(
+        &mc,
+        RendezvousEventKind::CandidatesSent {
+            protocol: Protocol::Tcp,
+            addrs: ipv6_rendezvous_addrs.iter().chain(&local_addrs).cloned().collect(),
+        },
+    );
+    let our_relay = mc.tcp_relay_server();
+    // peers that don't trickle ignore our hello and wait for all our addresses in a single message
+    let init_parts = (identity_proof.clone(), local_addrs.clone(), our_relay.clone());
+    let hello = TcpRendezvousMsg::Hello {
+        enc_pk: our_pk,
+        identity_proof,
+        rendezvous_addrs: ipv6_rendezvous_addrs.clone(),
+        local_addrs,
+        relay: our_relay.clone(),
+    };
+    let hello =
+        try_bfut!(version::seal(&hello, &mc).map_err(TcpRendezvousConnectError::SerializeMsg));
+
+    trace!("trickling rendezvous info with peer");
+    let (channel_tx, channel_rx) = channel.split();
+    let send_hello = channel_tx
+        .send(Bytes::from(hello))
+        .map_err(TcpRendezvousConnectError::ChannelWrite);
+    let mc0 = mc.clone();
+    let recv_hello = channel_rx
+        .map_err(TcpRendezvousConnectError::ChannelRead)
+        .and_then(move |msg| version::open(&msg, &mc0).map_err(TcpRendezvousConnectError::from))
+        .into_future()
+        .map_err(|(e, _their_msgs)| e)
+        .with_timeout(
+            Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC),
+            &handle,
+        ).and_then(|opt| opt.ok_or(TcpRendezvousConnectError::ChannelTimedOut))
+        .and_then(|(msg_opt, their_msgs)| {
+            let (msg, negotiated) = match msg_opt {
+                Some(msg) => msg,
+                None => return Err(TcpRendezvousConnectError::ChannelClosed),
+            };
+            let trickle = negotiated.capabilities.contains(Capabilities::TRICKLE);
+            match msg {
+                TcpRendezvousMsg::Hello {
+                    enc_pk,
+                    identity_proof,
+                    rendezvous_addrs,
+                    local_addrs,
+                    relay,
+                } if trickle => Ok((
+                    enc_pk,
+                    identity_proof,
+                    rendezvous_addrs,
+                    local_addrs,
+                    relay,
+                    Some(their_msgs),
+                    trickle,
+                )),
+                // peer sent everything at once, there's nothing more to wait for
+                TcpRendezvousMsg::Init {
+                    enc_pk,
+                    identity_proof,
+                    rendezvous_addrs,
+                    local_addrs,
+                    relay,
+                } => Ok((
+                    enc_pk,
+                    identity_proof,
+                    rendezvous_addrs,
+                    local_addrs,
+                    relay,
+                    None,
+                    trickle,
+                )),
+                _ => Err(TcpRendezvousConnectError::UnexpectedMessage),
+            }
+        }).and_then(
+            move |their_hello| -> Result<_, TcpRendezvousConnectError<C::Error, C::SinkError>> {
+                {
+                    let (ref their_pk, ref identity_proof, ..) = their_hello;
+                    verify_identity(
+                        auth.as_ref(),
+                        their_pk,
+                        identity_proof.as_ref().map(|proof| &proof[..]),
+                    ).map_err(TcpRendezvousConnectError::Identity)?;
+                }
+                Ok(their_hello)
+            },
+        );
+
+    send_hello
+        .join(recv_hello)
+        .and_then(move |(channel_tx, their_hello)| {
+            let (
+                their_pk,
+                _identity_proof,
+                their_rendezvous_addrs,
+                their_local_addrs,
+                their_relay,
+                their_msgs,
+                trickle,
+            ) = their_hello;
+            let later_addrs = match their_msgs {
+                Some(their_msgs) => their_msgs
+                    .then(|res| -> Result<_, SingleRendezvousAttemptError> {
+                        match res {
+                            Ok((TcpRendezvousMsg::Candidates { rendezvous_addrs }, _)) => {
+                                trace!("got their rendezvous addresses: {:?}", rendezvous_addrs);
+                                Ok(Some(rendezvous_addrs))
+                            }
+                            Ok((TcpRendezvousMsg::End, _)) => Ok(None),
+                            Ok((msg, _)) => {
+                                debug!("unexpected rendezvous message: {:?}", msg);
+                                Ok(None)
+                            }
+                            // we can still connect to the addresses we've got so far
+                            Err(e) => {
+                                debug!("failed to receive rendezvous message: {:?}", e);
+                                Ok(None)
+                            }
+                        }
+                    }).take_while(|addrs_opt| Ok(addrs_opt.is_some()))
+                    .filter_map(|addrs_opt| addrs_opt)
+                    .until({
+                        Timeout::new(
+                            Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC),
+                            &handle,
+                        ).infallible()
+                    }).into_boxed(),
+                None => stream::empty().into_boxed(),
+            };
+
+            // local addresses are tried in parallel with public ones
+            let their_addrs = their_rendezvous_addrs
+                .into_iter()
+                .chain(their_local_addrs)
+                .collect::<Vec<_>>();
+            let mut tried_addrs = HashSet::new();
+            let handle0 = handle.clone();
+            let connectors = stream::once(Ok(their_addrs))
+                .chain(later_addrs)
+                .map(move |their_addrs| {
+                    let connectors = their_addrs
+                        .into_iter()
+                        .filter(|their_addr| tried_addrs.insert(*their_addr))
+                        .filter_map(|their_addr| {
+                            // connect from the listener of the same IP family
+                            let bind_addr = if their_addr.is_ipv4() {
+                                bind_addr
+                            } else {
+                                ipv6_bind_addr?
                             };
-                            let all_incoming =
-                                connector.into_stream().select(incoming).into_boxed();
-                            choose_connections(all_incoming, &their_pk, &our_sk, &our_pk)
-                                .map(move |tcp_stream| (tcp_stream, our_rendezvous_addr))
+                            let connector =
+                                TcpStream::connect_reusable(&bind_addr, &their_addr, &handle0)
+                                    .map_err(SingleRendezvousAttemptError::Connect);
+                            Some(connector)
+                        }).collect::<Vec<_>>();
+                    stream::iter_ok::<_, SingleRendezvousAttemptError>(connectors)
+                }).flatten()
+                .buffer_unordered(256);
+            let incoming = rendezvous_incoming(listener, ipv6_listener, &handle);
+            let all_incoming = connectors.select(incoming).into_boxed();
+            let direct = choose_connections(all_incoming, &mc, &their_pk, &our_sk, &our_pk);
+            let relay = choose_relay(&our_pk, &their_pk, our_relay, their_relay);
+            let connect = relay_if_failed(&handle, direct, relay, &our_pk, &our_sk, &their_pk);
+
+            trace!("getting rendezvous address");
+            let fallback_addr = ipv6_rendezvous_addrs.first().cloned();
+            let discovered_addr = Rc::new(RefCell::new(None));
+            let discovered_addr_tx = discovered_addr.clone();
+            let send_candidates = rendezvous_candidates(Protocol::Tcp, &bind_addr, &handle, &mc)
+                .then(move |res| -> Result<_, TcpRendezvousConnectError<C::Error, C::SinkError>> {
+                    let rendezvous_addrs = match res {
+                        Ok(candidates) => candidates.addrs,
+                        // we might still be reachable via IPv6
+                        Err(ref e) if !ipv6_rendezvous_addrs.is_empty() => {
+                            debug!("IPv4 rendezvous address not available: {}", e);
+                            Vec::new()
+                        }
+                        Err(e) => return Err(TcpRendezvousConnectError::RendezvousAddrError(e)),
+                    };
+                    trace!("got rendezvous addresses: {:?}", rendezvous_addrs);
+                    let our_rendezvous_addr = unwrap!(
+                        rendezvous_addrs
+                            .iter()
+                            .chain(&ipv6_rendezvous_addrs)
+                            .next()
+                            .cloned()
+                    );
+                    emit_event(
+                        &mc,
+                        RendezvousEventKind::CandidatesSent {
+                            protocol: Protocol::Tcp,
+                            addrs: rendezvous_addrs.clone(),
+                        },
+                    );
+                    let msgs = if trickle {
+                        vec![
+                            TcpRendezvousMsg::Candidates { rendezvous_addrs },
+                            TcpRendezvousMsg::End,
+                        ]
+                    } else {
+                        let (identity_proof, local_addrs, relay) = init_parts;
+                        let mut rendezvous_addrs = rendezvous_addrs;
+                        rendezvous_addrs.extend(ipv6_rendezvous_addrs);
+                        vec![TcpRendezvousMsg::Init {
+                            enc_pk: our_pk,
+                            identity_proof,
+                            rendezvous_addrs,
+                            local_addrs,
+                            relay,
+                        }]
+                    };
+                    let mut serialized = Vec::with_capacity(msgs.len());
+                    for msg in &msgs {
+                        let msg = version::seal(msg, &mc)
+                            .map_err(TcpRendezvousConnectError::SerializeMsg)?;
+                        serialized.push(Bytes::from(msg));
+                    }
+                    Ok((serialized, our_rendezvous_addr))
+                }).and_then(move |(msgs, our_rendezvous_addr)| {
+                    channel_tx
+                        .send_all(stream::iter_ok::<_, C::SinkError>(msgs))
+                        .map_err(TcpRendezvousConnectError::ChannelWrite)
+                        .map(move |_| {
+                            *discovered_addr_tx.borrow_mut() = Some(our_rendezvous_addr);
                         })
-                    })
+                });
+            // the connection doesn't wait for our candidates, peer might reach us without them
+            handle.spawn(send_candidates.then(|res| {
+                if let Err(e) = res {
+                    debug!("failed to trickle our rendezvous candidates: {:?}", e);
+                }
+                Ok(())
+            }));
+
+            connect.map(move |(stream, relayed_addr)| {
+                let our_public_addr = relayed_addr
+                    .or(*discovered_addr.borrow())
+                    .or(fallback_addr)
+                    .unwrap_or_else(|| stream.local_addr().unwrap_or(bind_addr));
+                TcpRendezvousConnection {
+                    stream,
+                    our_public_addr,
+                    relayed: relayed_addr.is_some(),
+                }
             })
-        };
+        }).into_boxed()
+}
 
-        TcpRendezvousConnect {
-            inner: future::result(try()).flatten().into_boxed(),
+/// Falls back to the relay server, if all direct connection attempts fail. Along with the stream
+/// yields our address as seen by the relay server, if the connection is relayed.
+fn relay_if_failed<Ei, Eo>(
+    handle: &Handle,
+    direct: BoxFuture<TcpStream, TcpRendezvousConnectError<Ei, Eo>>,
+    relay: Option<RemoteTcpRelayServer>,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    their_pk: &PublicEncryptKey,
+) -> BoxFuture<(TcpStream, Option<SocketAddr>), TcpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let relay = match relay {
+        Some(relay) => relay,
+        None => return direct.map(|stream| (stream, None)).into_boxed(),
+    };
+    let handle = handle.clone();
+    let our_pk = *our_pk;
+    let our_sk = our_sk.clone();
+    let their_pk = *their_pk;
+    direct
+        .map(|stream| (stream, None))
+        .or_else(move |e| match e {
+            TcpRendezvousConnectError::AllAttemptsFailed(errors) => {
+                debug!(
+                    "direct connection failed, falling back to relay server {}: {:?}",
+                    relay.addr(),
+                    errors
+                );
+                relay_connect(&handle, &relay, &our_pk, &our_sk, &their_pk)
+                    .map(|(stream, our_relayed_addr)| (stream, Some(our_relayed_addr)))
+                    .map_err(TcpRendezvousConnectError::Relay)
+                    .into_boxed()
+            }
+            e => future::err(e).into_boxed(),
+        }).into_boxed()
+}
+
+/// Yields streams accepted by our rendezvous listeners for `RENDEZVOUS_TIMEOUT_SEC`.
+fn rendezvous_incoming(
+    listener: TcpListener,
+    ipv6_listener: Option<TcpListener>,
+    handle: &Handle,
+) -> BoxStream<TcpStream, SingleRendezvousAttemptError> {
+    let mut incoming = {
+        listener
+            .incoming()
+            .map(|(stream, _addr)| stream)
+            .map_err(SingleRendezvousAttemptError::Accept)
+            .into_boxed()
+    };
+    if let Some(ipv6_listener) = ipv6_listener {
+        let ipv6_incoming = ipv6_listener
+            .incoming()
+            .map(|(stream, _addr)| stream)
+            .map_err(SingleRendezvousAttemptError::Accept);
+        incoming = incoming.select(ipv6_incoming).into_boxed();
+    }
+    incoming
+        .until({
+            Timeout::new(Duration::from_secs(RENDEZVOUS_TIMEOUT_SEC), handle).infallible()
+        }).into_boxed()
+}
+
+/// Binds IPv6 listener for rendezvous connections. Returns the listener, its bind address, global
+/// addresses and all addresses reachable from the local network. Returns `None`, if IPv6 is not
+/// available on this host.
+fn bind_ipv6_listener(
+    handle: &Handle,
+) -> Option<(TcpListener, SocketAddr, Vec<SocketAddr>, Vec<SocketAddr>)> {
+    let try = || -> io::Result<_> {
+        let listener = TcpListener::bind_reusable(&addr!("[::]:0"), handle)?;
+        let bind_addr = listener.local_addr()?;
+        let (global_addrs, local_addrs) = ipv6_addrs(&bind_addr)?;
+        Ok((listener, bind_addr, global_addrs, local_addrs))
+    };
+    match try() {
+        Ok((_, _, _, ref local_addrs)) if local_addrs.is_empty() => {
+            debug!("no usable IPv6 addresses found");
+            None
+        }
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            debug!("failed to bind IPv6 rendezvous listener: {}", e);
+            None
         }
     }
 }
@@ -309,6 +889,7 @@ impl TcpStreamExt for TcpStream {
 fn exchange_conn_info<C>(
     channel: C,
     handle: &Handle,
+    p2p: &P2p,
     msg: &TcpRendezvousMsg,
 ) -> BoxFuture<TcpRendezvousMsg, TcpRendezvousConnectError<C::Error, C::SinkError>>
 where
@@ -319,8 +900,8 @@ where
     C: 'static,
 {
     let handle = handle.clone();
-    let msg =
-        try_bfut!(serialisation::serialise(&msg).map_err(TcpRendezvousConnectError::SerializeMsg));
+    let p2p = p2p.clone();
+    let msg = try_bfut!(version::seal(&msg, &p2p).map_err(TcpRendezvousConnectError::SerializeMsg));
     let msg = Bytes::from(msg);
     channel
         .send(msg)
@@ -328,15 +909,20 @@ where
         .and_then(move |channel| {
             channel
                 .map_err(TcpRendezvousConnectError::ChannelRead)
-                .next_or_else(|| TcpRendezvousConnectError::ChannelClosed)
+                .and_then(move |msg| -> Result<_, TcpRendezvousConnectError<_, _>> {
+                    let (msg, negotiated) = version::open(&msg, &p2p)?;
+                    trace!("negotiated rendezvous protocol: {:?}", negotiated);
+                    Ok(msg)
+                }).filter(|msg| match *msg {
+                    // peer trickles, but knows we don't and sends all its addresses in `Init` too
+                    TcpRendezvousMsg::Hello { .. } => false,
+                    _ => true,
+                }).next_or_else(|| TcpRendezvousConnectError::ChannelClosed)
                 .with_timeout(
                     Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC),
                     &handle,
                 ).and_then(|opt| opt.ok_or(TcpRendezvousConnectError::ChannelTimedOut))
-                .and_then(|(msg, _channel)| {
-                    serialisation::deserialise(&msg)
-                        .map_err(TcpRendezvousConnectError::DeserializeMsg)
-                })
+                .map(|(msg, _channel)| msg)
         }).into_boxed()
 }
 
@@ -348,6 +934,7 @@ struct ChooseMessage;
 /// determined by public keys.
 fn choose_connections<Ei: 'static, Eo: 'static>(
     all_incoming: BoxStream<TcpStream, SingleRendezvousAttemptError>,
+    p2p: &P2p,
     their_pk: &PublicEncryptKey,
     our_sk: &SecretEncryptKey,
     our_pk: &PublicEncryptKey,
@@ -359,7 +946,7 @@ fn choose_connections<Ei: 'static, Eo: 'static>(
             .map_err(TcpRendezvousConnectError::Encrypt)
     );
 
-    if our_pk > their_pk {
+    let attempts = if our_pk > their_pk {
         all_incoming
             .and_then(move |stream| {
                 trace!(
@@ -386,9 +973,35 @@ fn choose_connections<Ei: 'static, Eo: 'static>(
                 recv_choose_conn_msg(framed, shared_secret.clone())
             }).filter_map(|stream_opt| stream_opt)
             .into_boxed()
-    }.first_ok()
-    .map_err(TcpRendezvousConnectError::AllAttemptsFailed)
-    .into_boxed()
+    };
+
+    let p2p0 = p2p.clone();
+    let p2p1 = p2p.clone();
+    attempts
+        .map_err(move |e| {
+            emit_event(
+                &p2p0,
+                RendezvousEventKind::AttemptFailed {
+                    protocol: Protocol::Tcp,
+                    their_addr: None,
+                    error: e.to_string(),
+                },
+            );
+            e
+        }).first_ok()
+        .map(move |stream| {
+            if let Ok(their_addr) = stream.peer_addr() {
+                emit_event(
+                    &p2p1,
+                    RendezvousEventKind::CandidateChosen {
+                        protocol: Protocol::Tcp,
+                        their_addr,
+                    },
+                );
+            }
+            stream
+        }).map_err(TcpRendezvousConnectError::AllAttemptsFailed)
+        .into_boxed()
 }
 
 /// Receives incoming data stream and check's if it's connection choose message.
@@ -414,10 +1027,22 @@ fn recv_choose_conn_msg(
         }).into_boxed()
 }
 
-/// TCP stream and it's public rendezvous address.
-type RendezvousConnectResult = (TcpStream, SocketAddr);
+type RendezvousConnectResult = TcpRendezvousConnection;
+
+/// Stream connected by `TcpStreamExt::rendezvous_connect`.
+#[derive(Debug)]
+pub struct TcpRendezvousConnection {
+    /// Stream connected to the remote peer.
+    pub stream: TcpStream,
+    /// Our public rendezvous address. If the connection is relayed, this is our address as seen
+    /// by the relay server. In trickle mode the connection might be established before our public
+    /// address is discovered, in which case this is the local address of the stream.
+    pub our_public_addr: SocketAddr,
+    /// Whether direct connection failed and the stream goes via relay server.
+    pub relayed: bool,
+}
 
-/// Future that yields `TcpStream` and our public address, if one was detected.
+/// Future that yields `TcpRendezvousConnection`.
 pub struct TcpRendezvousConnect<C>
 where
     C: Stream<Item = Bytes>,
@@ -1,4 +1,14 @@
 use priv_prelude::*;
+use stun::{self, Class, StunMessage};
+use udp::cookie;
+use version;
+
+/// Initial retransmission timeout of STUN Binding requests, doubled after each retransmission.
+const STUN_INITIAL_RTO_MS: u64 = 500;
+/// How many Binding requests are sent before giving up (`Rc` in RFC 5389).
+const STUN_MAX_REQUESTS: u32 = 7;
+/// After the last request we wait this many initial RTOs for the response (`Rm` in RFC 5389).
+const STUN_LAST_REQUEST_WAIT_FACTOR: u32 = 16;
 
 #[derive(Debug, Clone, Hash)]
 /// A remote `UdpRendezvousServer` that we can query for our external address.
@@ -32,34 +42,24 @@ impl UdpAddrQuerier for RemoteUdpRendezvousServer {
 
         let msg = EchoRequest { client_pk };
         let msg = try_bfut!(
+            version::seal_server_msg(&msg)
+                .map_err(|e| Box::new(QueryPublicAddrError::Serialize(e)) as Box<Error + Send>)
+        );
+        let request = try_bfut!(
             self.pub_key
                 .anonymously_encrypt(&msg)
                 .map_err(|e| Box::new(QueryPublicAddrError::Encrypt(e)) as Box<Error + Send>)
         );
+        let mut msg = request.clone();
 
         let mut timeout = Timeout::new(Duration::new(0, 0), &handle);
         future::poll_fn(move || {
             while let Async::Ready(()) = timeout.poll().void_unwrap() {
-                match socket.send(&msg[..]) {
-                    Ok(n) => {
-                        let len = msg.len();
-                        if len != n {
-                            let e = io::Error::new(
-                                io::ErrorKind::Other,
-                                format!(
-                                    "failed to send complete request. \
-                                     Sent {} bytes of {}",
-                                    len, n
-                                ),
-                            );
-                            return Err(QueryPublicAddrError::SendRequest(e));
-                        }
+                match send_request(&socket, &msg)? {
+                    Async::Ready(()) => {
                         timeout.reset(Instant::now() + Duration::from_millis(500));
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        break;
-                    }
-                    Err(e) => return Err(QueryPublicAddrError::SendRequest(e)),
+                    Async::NotReady => break,
                 }
             }
 
@@ -70,9 +70,15 @@ impl UdpAddrQuerier for RemoteUdpRendezvousServer {
                         if recv_addr != server_addr {
                             continue;
                         }
-                        let external_addr = shared_secret
-                            .decrypt(&buffer[..len])
-                            .map_err(QueryPublicAddrError::Decrypt)?;
+                        // servers requiring cookies answer the first request with a challenge
+                        if let Some(cookie) = cookie::open_challenge(&buffer[..len]) {
+                            trace!("rendezvous server {} sent us a cookie", server_addr);
+                            msg = cookie::with_cookie(cookie, &request);
+                            let _ = send_request(&socket, &msg)?;
+                            continue;
+                        }
+                        let external_addr =
+                            version::open_server_response(&buffer[..len], &shared_secret)?;
                         return Ok(Async::Ready(external_addr));
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -87,3 +93,206 @@ impl UdpAddrQuerier for RemoteUdpRendezvousServer {
         .into_boxed()
     }
 }
+
+/// Sends the whole `msg` unless the socket is not ready for writing.
+fn send_request(socket: &UdpSocket, msg: &[u8]) -> Result<Async<()>, QueryPublicAddrError> {
+    match socket.send(msg) {
+        Ok(n) => {
+            let len = msg.len();
+            if len != n {
+                let e = io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "failed to send complete request. \
+                         Sent {} bytes of {}",
+                        len, n
+                    ),
+                );
+                return Err(QueryPublicAddrError::SendRequest(e));
+            }
+            Ok(Async::Ready(()))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(QueryPublicAddrError::SendRequest(e)),
+    }
+}
+
+/// A standard STUN (RFC 5389) server that we can query for our external address.
+#[derive(Debug, Clone, Hash)]
+pub struct StunUdpAddrQuerier {
+    addr: SocketAddr,
+}
+
+impl StunUdpAddrQuerier {
+    /// Define a new STUN server.
+    pub fn new(addr: SocketAddr) -> StunUdpAddrQuerier {
+        StunUdpAddrQuerier { addr }
+    }
+}
+
+impl UdpAddrQuerier for StunUdpAddrQuerier {
+    #[allow(trivial_casts)] // needed for as Box<Error>
+    fn query(
+        &self,
+        bind_addr: &SocketAddr,
+        handle: &Handle,
+    ) -> BoxFuture<SocketAddr, Box<Error + Send>> {
+        let socket = try_bfut!(
+            UdpSocket::bind_connect_reusable(bind_addr, &self.addr, handle)
+                .map_err(|e| Box::new(QueryPublicAddrError::Bind(e)) as Box<Error + Send>)
+        );
+
+        let server_addr = self.addr;
+        let request = StunMessage::request(stun::BINDING);
+        let transaction_id = request.transaction_id;
+        let msg = request.encode(None);
+
+        let initial_rto = Duration::from_millis(STUN_INITIAL_RTO_MS);
+        let mut rto = initial_rto;
+        let mut requests_sent = 0;
+        let mut timeout = Timeout::new(Duration::new(0, 0), &handle);
+        future::poll_fn(move || {
+            while let Async::Ready(()) = timeout.poll().void_unwrap() {
+                if requests_sent == STUN_MAX_REQUESTS {
+                    return Err(QueryPublicAddrError::ResponseTimeout);
+                }
+                match socket.send(&msg[..]) {
+                    Ok(_) => {
+                        requests_sent += 1;
+                        let wait = if requests_sent == STUN_MAX_REQUESTS {
+                            initial_rto * STUN_LAST_REQUEST_WAIT_FACTOR
+                        } else {
+                            rto
+                        };
+                        timeout.reset(Instant::now() + wait);
+                        rto *= 2;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(e) => return Err(QueryPublicAddrError::SendRequest(e)),
+                }
+            }
+
+            loop {
+                let mut buffer = [0u8; 512];
+                let (len, recv_addr) = match socket.recv_from(&mut buffer) {
+                    Ok(res) => res,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(QueryPublicAddrError::ReadResponse(e)),
+                };
+                if recv_addr != server_addr {
+                    continue;
+                }
+                let response = match StunMessage::decode(&buffer[..len]) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        debug!("ignoring invalid message from STUN server: {}", e);
+                        continue;
+                    }
+                };
+                if response.method != stun::BINDING || response.transaction_id != transaction_id {
+                    debug!("ignoring STUN message of unknown transaction");
+                    continue;
+                }
+                match response.class {
+                    Class::Success => {
+                        let external_addr = response
+                            .mapped_addr()
+                            .ok_or(QueryPublicAddrError::InvalidResponse)?;
+                        return Ok(Async::Ready(external_addr));
+                    }
+                    Class::Error => {
+                        let (code, reason) = response
+                            .error_code()
+                            .ok_or(QueryPublicAddrError::InvalidResponse)?;
+                        return Err(QueryPublicAddrError::ErrorResponse(code, reason));
+                    }
+                    Class::Request | Class::Indication => continue,
+                }
+            }
+        }).map_err(|e| Box::new(e) as Box<Error + Send>)
+        .into_boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    /// Answers Binding requests with the given attribute. Drops the first `drop_requests`
+    /// requests and precedes each response with one of an unknown transaction.
+    fn spawn_stun_server<F>(handle: &Handle, drop_requests: usize, mapped: F) -> SocketAddr
+    where
+        F: Fn(SocketAddr) -> stun::Attribute + 'static,
+    {
+        let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let server_addr = unwrap!(socket.local_addr());
+        let mut dropped = 0;
+        let server = future::poll_fn(move || {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                if dropped < drop_requests {
+                    dropped += 1;
+                    continue;
+                }
+                let request = unwrap!(StunMessage::decode(&buffer[..len]));
+                let bogus = StunMessage::new(stun::BINDING, Class::Success, [0; 12])
+                    .with(stun::Attribute::XorMappedAddress(addr!("1.2.3.4:5")));
+                let _ = unwrap!(socket.send_to(&bogus.encode(None), &addr));
+                let response = request.success().with(mapped(addr));
+                let _ = unwrap!(socket.send_to(&response.encode(None), &addr));
+            }
+            Ok(Async::NotReady)
+        });
+        handle.spawn(server);
+        server_addr
+    }
+
+    mod stun_udp_addr_querier {
+        use super::*;
+
+        #[test]
+        fn it_returns_xor_mapped_address() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_stun_server(&handle, 0, stun::Attribute::XorMappedAddress);
+            let querier = StunUdpAddrQuerier::new(server_addr);
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let our_addr = unwrap!(core.run(querier.query(&bind_addr, &handle)));
+
+            assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
+            assert_ne!(our_addr.port(), 0);
+        }
+
+        #[test]
+        fn it_falls_back_to_mapped_address() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_stun_server(&handle, 0, stun::Attribute::MappedAddress);
+            let querier = StunUdpAddrQuerier::new(server_addr);
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let our_addr = unwrap!(core.run(querier.query(&bind_addr, &handle)));
+
+            assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
+        }
+
+        #[test]
+        fn it_retransmits_lost_requests() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = spawn_stun_server(&handle, 2, stun::Attribute::XorMappedAddress);
+            let querier = StunUdpAddrQuerier::new(server_addr);
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let our_addr = unwrap!(core.run(querier.query(&bind_addr, &handle)));
+
+            assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Birthday paradox hole punching for NATs that allocate external ports randomly.
+//!
+//! The side behind such a NAT opens a lot of sockets and sends probes to the peer from all of
+//! them, which creates a lot of NAT mappings. The other side sprays probes over random ports of
+//! the peer's public IP hoping to hit one of those mappings. With `n` mappings and `k` probes the
+//! chance of a hit is roughly `1 - e^(-n * k / 64512)`, so a few hundred sockets and a couple
+//! thousand probes give very good odds.
+
+use futures::stream::{FuturesUnordered, StreamFuture};
+use priv_prelude::*;
+use rand;
+use tokio_shared_udp_socket::{SharedUdpSocket, WithAddress};
+use udp::socket::{
+    HolePunchError, HolePunchMsg, HolePunching, UdpRendezvousConnectError,
+    HOLE_PUNCH_DELAY_TOLERANCE_SEC, HOLE_PUNCH_INITIAL_TTL, SANE_DEFAULT_TTL,
+};
+use version::{Capabilities, Envelope};
+
+/// Ports below this one are usually not handed out by NATs.
+const MIN_NAT_PORT: u16 = 1024;
+/// How many probes are sent at once.
+const PROBES_PER_BURST: usize = 32;
+/// Delay between probe bursts. Spraying too fast might trigger flood protection in routers.
+const PROBE_BURST_PERIOD_MS: u64 = 20;
+/// Max number of hole punchers running at the same time for a single spraying socket.
+const MAX_CONCURRENT_PUNCHERS: usize = 32;
+
+type Punchers = BoxStream<(WithAddress, bool), HolePunchError>;
+
+/// We are behind a NAT that allocates ports randomly, while peer's NAT is predictable.
+/// Opens up to `p2p.birthday_socket_budget()` sockets and punches from all of them to the same
+/// peer address. The peer is expected to spray probes over our ports.
+pub fn punch_from_many_sockets<Ei, Eo>(
+    handle: &Handle,
+    p2p: &P2p,
+    their_addr: SocketAddr,
+    shared_secret: &SharedSecretKey,
+) -> Result<Punchers, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let sockets = bind_sockets(handle, p2p.birthday_socket_budget())
+        .map_err(UdpRendezvousConnectError::Rebind)?;
+    trace!(
+        "punching to {} from {} birthday sockets",
+        their_addr,
+        sockets.len()
+    );
+
+    let mut punchers = FuturesUnordered::new();
+    for socket in sockets {
+        socket
+            .set_ttl(HOLE_PUNCH_INITIAL_TTL)
+            .map_err(UdpRendezvousConnectError::SetTtl)?;
+        let with_addr = SharedUdpSocket::share(socket).with_address(their_addr);
+        punchers.push(HolePunching::new_ttl_incrementer(
+            handle,
+            p2p,
+            with_addr,
+            shared_secret.clone(),
+            Duration::from_secs(HOLE_PUNCH_DELAY_TOLERANCE_SEC),
+        ));
+    }
+    Ok(punchers.into_boxed())
+}
+
+/// Peer is behind a NAT that allocates ports randomly. Sprays `p2p.birthday_packet_budget()`
+/// probes over random ports of `their_ip` from the given socket.
+pub fn spray_from_socket<Ei, Eo>(
+    handle: &Handle,
+    p2p: &P2p,
+    socket: SharedUdpSocket,
+    their_ip: IpAddr,
+    shared_secret: &SharedSecretKey,
+) -> Result<Punchers, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let spray = BirthdaySpray::new(
+        handle,
+        p2p,
+        socket,
+        their_ip,
+        shared_secret,
+        random_ports(p2p.birthday_packet_budget()),
+    )?;
+    Ok(spray.buffer_unordered(MAX_CONCURRENT_PUNCHERS).into_boxed())
+}
+
+/// Both we and the peer are behind NATs that allocate ports randomly. Opens up to
+/// `p2p.birthday_socket_budget()` sockets and each of them sprays its share of
+/// `p2p.birthday_packet_budget()` probes over random ports of `their_ip`.
+///
+/// This is the worst case scenario and the chances of success are quite low.
+pub fn spray_from_many_sockets<Ei, Eo>(
+    handle: &Handle,
+    p2p: &P2p,
+    their_ip: IpAddr,
+    shared_secret: &SharedSecretKey,
+) -> Result<Punchers, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let sockets = bind_sockets(handle, p2p.birthday_socket_budget())
+        .map_err(UdpRendezvousConnectError::Rebind)?;
+    let probes_per_socket = p2p.birthday_packet_budget() / sockets.len().max(1);
+    trace!(
+        "spraying {} probes from each of {} birthday sockets",
+        probes_per_socket,
+        sockets.len()
+    );
+
+    let mut sprays = SelectAll::new();
+    for socket in sockets {
+        let spray = BirthdaySpray::new(
+            handle,
+            p2p,
+            SharedUdpSocket::share(socket),
+            their_ip,
+            shared_secret,
+            random_ports(probes_per_socket),
+        )?;
+        sprays.push(spray.buffer_unordered(MAX_CONCURRENT_PUNCHERS).into_boxed());
+    }
+    Ok(sprays.into_boxed())
+}
+
+/// Binds up to `count` sockets to random ports. Fails only if not a single socket could be bound,
+/// since we might hit the open file descriptor limit long before `count`.
+fn bind_sockets(handle: &Handle, count: usize) -> io::Result<Vec<UdpSocket>> {
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        match UdpSocket::bind_reusable(&addr!("0.0.0.0:0"), handle) {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => {
+                if sockets.is_empty() {
+                    return Err(e);
+                }
+                debug!(
+                    "managed to bind only {} of {} birthday sockets: {}",
+                    sockets.len(),
+                    count,
+                    e
+                );
+                break;
+            }
+        }
+    }
+    Ok(sockets)
+}
+
+/// Returns `count` unique random ports that NATs might allocate.
+fn random_ports(count: usize) -> Vec<u16> {
+    let mut ports: Vec<u16> = (MIN_NAT_PORT..u16::max_value()).collect();
+    ports.push(u16::max_value());
+    rand::thread_rng().shuffle(&mut ports);
+    ports.truncate(count);
+    ports
+}
+
+/// Sprays hole punching probes over given ports of the remote IP address and yields a hole puncher
+/// for each remote endpoint that responds.
+struct BirthdaySpray {
+    handle: Handle,
+    p2p: P2p,
+    socket: SharedUdpSocket,
+    their_ip: IpAddr,
+    shared_secret: SharedSecretKey,
+    syn: Bytes,
+    ports: Vec<u16>,
+    responded: HashSet<SocketAddr>,
+    ttl_set: bool,
+    next_burst: Timeout,
+    deadline: Timeout,
+}
+
+impl BirthdaySpray {
+    fn new<Ei, Eo>(
+        handle: &Handle,
+        p2p: &P2p,
+        socket: SharedUdpSocket,
+        their_ip: IpAddr,
+        shared_secret: &SharedSecretKey,
+        ports: Vec<u16>,
+    ) -> Result<BirthdaySpray, UdpRendezvousConnectError<Ei, Eo>> {
+        let envelope = Envelope::seal(&HolePunchMsg::Syn, Capabilities::empty(), None)
+            .map_err(UdpRendezvousConnectError::SerializeMsg)?;
+        let syn = shared_secret
+            .encrypt(&envelope)
+            .map_err(UdpRendezvousConnectError::Encrypt)?;
+        let deadline = Duration::from_secs(HOLE_PUNCH_DELAY_TOLERANCE_SEC);
+        Ok(BirthdaySpray {
+            handle: handle.clone(),
+            p2p: p2p.clone(),
+            socket,
+            their_ip,
+            shared_secret: shared_secret.clone(),
+            syn: Bytes::from(syn),
+            ports,
+            responded: HashSet::new(),
+            ttl_set: false,
+            next_burst: Timeout::new(Duration::new(0, 0), handle),
+            deadline: Timeout::new(deadline, handle),
+        })
+    }
+
+    /// Sends the next bursts of probes, if it's time to do so.
+    fn spray(&mut self) -> Result<(), HolePunchError> {
+        while let Async::Ready(()) = self.next_burst.poll().void_unwrap() {
+            for _ in 0..PROBES_PER_BURST {
+                let port = match self.ports.pop() {
+                    Some(port) => port,
+                    None => return Ok(()),
+                };
+                let addr = SocketAddr::new(self.their_ip, port);
+                if self.responded.contains(&addr) {
+                    continue;
+                }
+
+                let mut with_addr = self.socket.with_address(addr);
+                if !self.ttl_set {
+                    // probes must reach the peer's NAT, so no low TTL tricks here
+                    with_addr
+                        .set_ttl(SANE_DEFAULT_TTL)
+                        .map_err(HolePunchError::SetTtl)?;
+                    self.ttl_set = true;
+                }
+                match with_addr.start_send(self.syn.clone()) {
+                    Err(e) => return Err(HolePunchError::SendMessage(e)),
+                    Ok(AsyncSink::NotReady(_)) => {
+                        self.ports.push(port);
+                        break;
+                    }
+                    Ok(AsyncSink::Ready) => (),
+                }
+                let _ = with_addr
+                    .poll_complete()
+                    .map_err(HolePunchError::SendMessage)?;
+            }
+            let burst_period = Duration::from_millis(PROBE_BURST_PERIOD_MS);
+            self.next_burst.reset(Instant::now() + burst_period);
+        }
+        Ok(())
+    }
+}
+
+impl Stream for BirthdaySpray {
+    type Item = HolePunching;
+    type Error = HolePunchError;
+
+    fn poll(&mut self) -> Result<Async<Option<HolePunching>>, HolePunchError> {
+        if let Async::Ready(()) = self.deadline.poll().void_unwrap() {
+            trace!("birthday spray to {} timed out", self.their_ip);
+            return Ok(Async::Ready(None));
+        }
+
+        loop {
+            match self.socket.poll() {
+                Err(e) => return Err(HolePunchError::ReadMessage(e)),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::Ready(Some(with_addr))) => {
+                    let addr = with_addr.remote_addr();
+                    if addr.ip() != self.their_ip {
+                        trace!("ignoring unexpected packet from {}", addr);
+                        continue;
+                    }
+                    trace!("birthday probe got a response from {}", addr);
+                    let _ = self.responded.insert(addr);
+                    // all responders share the socket, TTL of which must stay high for the probes
+                    return Ok(Async::Ready(Some(HolePunching::new_fixed_ttl(
+                        &self.handle,
+                        &self.p2p,
+                        with_addr,
+                        self.shared_secret.clone(),
+                    ))));
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        self.spray()?;
+        Ok(Async::NotReady)
+    }
+}
+
+/// Yields items of all the given streams as they come. Unlike a chain of `select()`s, polling
+/// it doesn't walk every stream, only the ones that were woken up.
+struct SelectAll<S> {
+    streams: FuturesUnordered<StreamFuture<S>>,
+}
+
+impl<S: Stream> SelectAll<S> {
+    fn new() -> SelectAll<S> {
+        SelectAll {
+            streams: FuturesUnordered::new(),
+        }
+    }
+
+    fn push(&mut self, stream: S) {
+        self.streams.push(stream.into_future());
+    }
+}
+
+impl<S: Stream> Stream for SelectAll<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        loop {
+            match self.streams.poll() {
+                Ok(Async::Ready(Some((Some(item), stream)))) => {
+                    self.push(stream);
+                    return Ok(Async::Ready(Some(item)));
+                }
+                Ok(Async::Ready(Some((None, _stream)))) => continue,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err((e, stream)) => {
+                    self.push(stream);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod random_ports {
+        use super::*;
+
+        #[test]
+        fn it_returns_requested_number_of_unique_ports() {
+            let ports = random_ports(2048);
+
+            assert_eq!(ports.len(), 2048);
+            let unique_ports: HashSet<u16> = ports.iter().cloned().collect();
+            assert_eq!(unique_ports.len(), 2048);
+        }
+
+        #[test]
+        fn it_never_returns_well_known_ports() {
+            let ports = random_ports(10_000);
+
+            assert!(ports.iter().all(|port| *port >= MIN_NAT_PORT));
+        }
+
+        #[test]
+        fn when_more_ports_requested_than_exist_it_returns_all_nat_ports() {
+            let ports = random_ports(100_000);
+
+            assert_eq!(ports.len(), (u16::max_value() - MIN_NAT_PORT) as usize + 1);
+        }
+    }
+
+    mod select_all {
+        use super::*;
+
+        #[test]
+        fn it_yields_items_of_all_streams() {
+            let mut streams = SelectAll::new();
+            streams.push(stream::iter_ok::<_, ()>(vec![1, 2]));
+            streams.push(stream::iter_ok::<_, ()>(vec![3]));
+
+            let mut items = unwrap!(streams.collect().wait());
+            items.sort();
+
+            assert_eq!(items, vec![1, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Return-routability cookies of the UDP rendezvous server.
+//!
+//! UDP source addresses are trivial to spoof, so a server answering every echo request can be used
+//! to reflect traffic to a victim. Servers that require cookies answer unknown clients with a
+//! challenge carrying a cookie instead. Only clients that actually receive packets sent to their
+//! address can echo the cookie back in their next request. Cookies are an HMAC of the client
+//! address and issue time, so the server keeps no state per client.
+
+use priv_prelude::*;
+use rand;
+use stun;
+use util::{read_u32, u32_bytes};
+
+/// Prefix of challenges and of requests carrying cookies. The first byte has the top bits set, so
+/// these are never mistaken for STUN messages.
+const MAGIC: [u8; 4] = [0xfe, b'C', b'K', b'Y'];
+/// Issue time in seconds followed by HMAC-SHA1.
+const COOKIE_LEN: usize = 4 + 20;
+/// Length of the challenge sent to clients.
+const CHALLENGE_LEN: usize = 4 + COOKIE_LEN;
+/// How long clients can use a cookie.
+const COOKIE_LIFETIME_SEC: u32 = 60;
+
+/// Issues and verifies cookies of a single server.
+pub struct CookieJar {
+    key: [u8; 32],
+    started: Instant,
+    required: bool,
+}
+
+impl CookieJar {
+    /// Creates a jar with a random key. Cookies are not required until `set_required()` is called.
+    pub fn new() -> CookieJar {
+        CookieJar {
+            key: rand::random(),
+            started: Instant::now(),
+            required: false,
+        }
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    pub fn set_required(&mut self, required: bool) {
+        self.required = required;
+    }
+
+    /// Returns a challenge carrying a fresh cookie for `addr`, unless the challenge would be
+    /// longer than `request_len`. Larger responses would make the server an amplifier.
+    pub fn challenge(&self, addr: SocketAddr, request_len: usize, now: Instant) -> Option<Vec<u8>> {
+        if request_len < CHALLENGE_LEN {
+            return None;
+        }
+        let issued = u32_bytes(self.secs_since_start(now));
+        let mut challenge = Vec::with_capacity(CHALLENGE_LEN);
+        challenge.extend_from_slice(&MAGIC);
+        challenge.extend_from_slice(&issued);
+        challenge.extend_from_slice(&self.mac(&issued, addr));
+        Some(challenge)
+    }
+
+    /// Checks that `cookie` was issued to `addr` by this jar and hasn't expired yet.
+    pub fn verify(&self, cookie: &[u8], addr: SocketAddr, now: Instant) -> bool {
+        if cookie.len() != COOKIE_LEN {
+            return false;
+        }
+        let issued = read_u32(cookie);
+        let age = match self.secs_since_start(now).checked_sub(issued) {
+            Some(age) => age,
+            None => return false,
+        };
+        let mac = self.mac(&cookie[..4], addr);
+        age <= COOKIE_LIFETIME_SEC && stun::constant_time_eq(&mac, &cookie[4..])
+    }
+
+    fn mac(&self, issued: &[u8], addr: SocketAddr) -> [u8; 20] {
+        let mut data = issued.to_vec();
+        data.extend_from_slice(&stun::encode_addr(&addr));
+        stun::hmac_sha1(&self.key, &data)
+    }
+
+    fn secs_since_start(&self, now: Instant) -> u32 {
+        if now <= self.started {
+            return 0;
+        }
+        (now - self.started).as_secs() as u32
+    }
+}
+
+/// Splits a request into its cookie and the request itself. Returns `None`, if the request
+/// carries no cookie.
+pub fn split_cookie(msg: &[u8]) -> Option<(&[u8], &[u8])> {
+    if msg.len() < CHALLENGE_LEN || msg[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    Some((&msg[MAGIC.len()..CHALLENGE_LEN], &msg[CHALLENGE_LEN..]))
+}
+
+/// Returns the cookie, if `msg` is a challenge.
+pub fn open_challenge(msg: &[u8]) -> Option<&[u8]> {
+    if msg.len() != CHALLENGE_LEN || msg[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    Some(&msg[MAGIC.len()..])
+}
+
+/// Prepends `cookie` to `request`.
+pub fn with_cookie(cookie: &[u8], request: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(MAGIC.len() + cookie.len() + request.len());
+    msg.extend_from_slice(&MAGIC);
+    msg.extend_from_slice(cookie);
+    msg.extend_from_slice(request);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod cookie_jar {
+        use super::*;
+
+        fn issue(jar: &CookieJar, addr: SocketAddr, now: Instant) -> Vec<u8> {
+            let challenge = unwrap!(jar.challenge(addr, 100, now));
+            unwrap!(open_challenge(&challenge)).to_vec()
+        }
+
+        #[test]
+        fn it_accepts_cookies_it_issued() {
+            let jar = CookieJar::new();
+            let now = Instant::now();
+            let cookie = issue(&jar, addr!("1.2.3.4:5000"), now);
+
+            let request = with_cookie(&cookie, b"request");
+            let (received_cookie, request) = unwrap!(split_cookie(&request));
+
+            assert!(jar.verify(received_cookie, addr!("1.2.3.4:5000"), now));
+            assert_eq!(request, b"request");
+        }
+
+        #[test]
+        fn it_rejects_cookies_issued_to_other_addresses() {
+            let jar = CookieJar::new();
+            let now = Instant::now();
+            let cookie = issue(&jar, addr!("1.2.3.4:5000"), now);
+
+            assert!(!jar.verify(&cookie, addr!("1.2.3.4:5001"), now));
+            assert!(!CookieJar::new().verify(&cookie, addr!("1.2.3.4:5000"), now));
+        }
+
+        #[test]
+        fn it_rejects_expired_cookies() {
+            let jar = CookieJar::new();
+            let now = Instant::now();
+            let cookie = issue(&jar, addr!("1.2.3.4:5000"), now);
+            let later = now + Duration::from_secs(u64::from(COOKIE_LIFETIME_SEC) + 1);
+
+            assert!(!jar.verify(&cookie, addr!("1.2.3.4:5000"), later));
+        }
+
+        #[test]
+        fn it_does_not_challenge_requests_shorter_than_challenge() {
+            let jar = CookieJar::new();
+
+            let challenge = jar.challenge(addr!("1.2.3.4:5000"), CHALLENGE_LEN - 1, Instant::now());
+
+            assert!(challenge.is_none());
+        }
+    }
+}
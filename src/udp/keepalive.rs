@@ -0,0 +1,427 @@
+//! Keeps NAT mappings of hole punched UDP sockets alive.
+
+use futures::StartSend;
+use priv_prelude::*;
+use rand;
+use std::collections::VecDeque;
+use udp::session::ReplayWindow;
+use udp::socket::{decrypt_hole_punch_msg, encrypt_hole_punch_msg, HolePunchError, HolePunchMsg};
+use udp::turn::TurnProxyHandle;
+
+/// Default interval between keepalive messages. Most NATs drop idle UDP mappings after 30
+/// seconds or more.
+pub const DEFAULT_KEEPALIVE_INTERVAL_SEC: u64 = 15;
+/// Default number of keepalive messages peer may leave unanswered before it's considered dead.
+pub const DEFAULT_MAX_MISSED_KEEPALIVES: u32 = 3;
+
+quick_error! {
+    /// Errors returned by `UdpKeepalive`.
+    #[derive(Debug)]
+    pub enum UdpKeepaliveError {
+        /// Failure to read from the socket.
+        Read(e: io::Error) {
+            description("error reading from socket")
+            display("error reading from socket: {}", e)
+            cause(e)
+        }
+        /// Failure to write to the socket.
+        Write(e: io::Error) {
+            description("error writing to socket")
+            display("error writing to socket: {}", e)
+            cause(e)
+        }
+        /// Failure to encrypt keepalive message or datagram.
+        Keepalive(e: HolePunchError) {
+            description("error encrypting keepalive message")
+            display("error encrypting keepalive message: {}", e)
+            cause(e)
+        }
+        /// Remote peer didn't answer the configured number of keepalive messages in a row.
+        PeerDead(missed: u32) {
+            description("remote peer stopped responding")
+            display("remote peer didn't answer {} keepalive messages", missed)
+        }
+    }
+}
+
+/// Wraps a socket returned by `UdpSocketExt::rendezvous_connect` and periodically sends encrypted
+/// keepalive messages to the remote peer, so that NAT mappings on both ends don't expire. Both
+/// peers are expected to use `UdpKeepalive`.
+///
+/// Keepalives are sent and answered while the stream is polled. Datagrams sent through the sink
+/// are encrypted as well, so that they can't be confused with keepalives. Keepalive messages are
+/// filtered out of the stream, as are datagrams that fail to decrypt, were already received or
+/// come from addresses other than the peer's. A keepalive is answered only by a reply echoing its
+/// random nonce. Our own keepalives and datagrams reflected back to us are dropped, see
+/// `UdpSession`. Replies to any of the keepalives that may still count as missed are accepted, so
+/// a round trip longer than the interval doesn't make peer look dead. Authenticated datagrams from
+/// the peer prove it's alive too. Once the peer leaves too many keepalives unanswered, the stream
+/// yields `UdpKeepaliveError::PeerDead`.
+pub struct UdpKeepalive {
+    socket: UdpSocket,
+    their_addr: SocketAddr,
+    shared_secret: SharedSecretKey,
+    initiator: bool,
+    interval: Duration,
+    max_missed: u32,
+    missed: u32,
+    /// Nonces of the last `max_missed` keepalives sent since the peer was last heard of.
+    awaiting_reply: VecDeque<u64>,
+    timeout: Timeout,
+    next_seq: u64,
+    replay_window: ReplayWindow,
+    turn_proxy: Option<TurnProxyHandle>,
+}
+
+impl UdpKeepalive {
+    /// Starts sending keepalives to `their_addr` encrypted with the secret negotiated during
+    /// rendezvous connect. `initiator` must be `true` for exactly one of the peers, see
+    /// `UdpRendezvousConnection::initiator`.
+    pub fn new(
+        handle: &Handle,
+        socket: UdpSocket,
+        their_addr: SocketAddr,
+        shared_secret: SharedSecretKey,
+        initiator: bool,
+    ) -> UdpKeepalive {
+        let interval = Duration::from_secs(DEFAULT_KEEPALIVE_INTERVAL_SEC);
+        UdpKeepalive {
+            socket,
+            their_addr,
+            shared_secret,
+            initiator,
+            interval,
+            max_missed: DEFAULT_MAX_MISSED_KEEPALIVES,
+            missed: 0,
+            awaiting_reply: VecDeque::new(),
+            timeout: Timeout::new(interval, handle),
+            next_seq: 1,
+            replay_window: ReplayWindow::new(),
+            turn_proxy: None,
+        }
+    }
+
+    /// Sets the interval between keepalive messages.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        self.timeout.reset(Instant::now() + interval);
+    }
+
+    /// Sets the number of keepalive messages peer may leave unanswered before it's considered
+    /// dead.
+    pub fn set_max_missed(&mut self, max_missed: u32) {
+        self.max_missed = max_missed;
+    }
+
+    /// Returns the remote peer address.
+    pub fn their_addr(&self) -> SocketAddr {
+        self.their_addr
+    }
+
+    /// Keeps the TURN proxy the connection goes via running for as long as this value lives.
+    pub fn set_turn_proxy(&mut self, turn_proxy: TurnProxyHandle) {
+        self.turn_proxy = Some(turn_proxy);
+    }
+
+    /// Stops sending keepalives and returns the underlying socket. The TURN proxy set via
+    /// `set_turn_proxy()`, if any, stops too.
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+
+    fn poll_timer(&mut self) -> Result<(), UdpKeepaliveError> {
+        while let Async::Ready(()) = self.timeout.poll().void_unwrap() {
+            if !self.awaiting_reply.is_empty() {
+                self.missed += 1;
+                trace!("peer {} missed {} keepalives", self.their_addr, self.missed);
+                if self.missed >= self.max_missed {
+                    return Err(UdpKeepaliveError::PeerDead(self.missed));
+                }
+            }
+            let nonce = rand::random();
+            let initiator = self.initiator;
+            self.send_msg(&HolePunchMsg::Keepalive { initiator, nonce })?;
+            self.awaiting_reply.push_back(nonce);
+            while self.awaiting_reply.len() > self.max_missed as usize {
+                let _ = self.awaiting_reply.pop_front();
+            }
+            self.timeout.reset(Instant::now() + self.interval);
+        }
+        Ok(())
+    }
+
+    fn on_keepalive_ack(&mut self, nonce: u64) {
+        if !self.awaiting_reply.contains(&nonce) {
+            trace!("ignoring keepalive reply with unexpected nonce");
+            return;
+        }
+        self.on_peer_alive();
+    }
+
+    fn on_peer_alive(&mut self) {
+        self.missed = 0;
+        self.awaiting_reply.clear();
+    }
+
+    /// Keepalives are best effort, hence they are dropped if the socket is not writable.
+    fn send_msg(&mut self, msg: &HolePunchMsg) -> Result<(), UdpKeepaliveError> {
+        let msg =
+            encrypt_hole_punch_msg(msg, &self.shared_secret).map_err(UdpKeepaliveError::Keepalive)?;
+        match self.socket.send_to(&msg, &self.their_addr) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!("socket not writable, dropping keepalive message");
+                Ok(())
+            }
+            Err(e) => Err(UdpKeepaliveError::Write(e)),
+        }
+    }
+}
+
+impl Stream for UdpKeepalive {
+    type Item = Bytes;
+    type Error = UdpKeepaliveError;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, UdpKeepaliveError> {
+        self.poll_timer()?;
+
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(UdpKeepaliveError::Read(e)),
+            };
+            if addr != self.their_addr {
+                trace!("ignoring datagram from unknown address {}", addr);
+                continue;
+            }
+            match decrypt_hole_punch_msg(&buffer[..len], &self.shared_secret) {
+                Ok(HolePunchMsg::Keepalive { initiator, .. })
+                | Ok(HolePunchMsg::Data { initiator, .. })
+                    if initiator == self.initiator =>
+                {
+                    debug!("dropping our own message reflected back to us");
+                }
+                Ok(HolePunchMsg::Keepalive { nonce, .. }) => {
+                    self.send_msg(&HolePunchMsg::KeepaliveAck { nonce })?;
+                }
+                Ok(HolePunchMsg::KeepaliveAck { nonce }) => self.on_keepalive_ack(nonce),
+                Ok(HolePunchMsg::Data { seq, payload, .. }) => {
+                    if self.replay_window.accept(seq) {
+                        self.on_peer_alive();
+                        return Ok(Async::Ready(Some(Bytes::from(payload))));
+                    }
+                    debug!("dropping replayed datagram #{}", seq);
+                }
+                // leftovers from hole punching
+                Ok(msg) => trace!("ignoring hole punching message: {:?}", msg),
+                Err(e) => debug!("dropping invalid datagram: {}", e),
+            }
+        }
+    }
+}
+
+impl Sink for UdpKeepalive {
+    type SinkItem = Bytes;
+    type SinkError = UdpKeepaliveError;
+
+    fn start_send(&mut self, item: Bytes) -> StartSend<Bytes, UdpKeepaliveError> {
+        let msg = HolePunchMsg::Data {
+            initiator: self.initiator,
+            seq: self.next_seq,
+            payload: item.to_vec(),
+        };
+        let msg = encrypt_hole_punch_msg(&msg, &self.shared_secret)
+            .map_err(UdpKeepaliveError::Keepalive)?;
+        match self.socket.send_to(&msg, &self.their_addr) {
+            Ok(_) => {
+                self.next_seq += 1;
+                Ok(AsyncSink::Ready)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(AsyncSink::NotReady(item)),
+            Err(e) => Err(UdpKeepaliveError::Write(e)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, UdpKeepaliveError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    fn keepalive_pair(handle: &Handle) -> (UdpKeepalive, UdpKeepalive) {
+        let sock0 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let sock1 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+        let addr0 = unwrap!(sock0.local_addr());
+        let addr1 = unwrap!(sock1.local_addr());
+        let (pk0, sk0) = gen_encrypt_keypair();
+        let (pk1, sk1) = gen_encrypt_keypair();
+        let keepalive0 = UdpKeepalive::new(handle, sock0, addr1, sk0.shared_secret(&pk1), true);
+        let keepalive1 = UdpKeepalive::new(handle, sock1, addr0, sk1.shared_secret(&pk0), false);
+        (keepalive0, keepalive1)
+    }
+
+    mod udp_keepalive {
+        use super::*;
+
+        #[test]
+        fn it_filters_out_keepalives() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (mut keepalive0, mut keepalive1) = keepalive_pair(&handle);
+            keepalive0.set_interval(Duration::from_millis(10));
+            keepalive1.set_interval(Duration::from_millis(10));
+
+            let (sink0, stream0) = keepalive0.split();
+            handle.spawn(
+                stream0
+                    .for_each(|msg| -> Result<(), UdpKeepaliveError> {
+                        panic!("unexpected message: {:?}", msg)
+                    }).map_err(|e| panic!("keepalive error: {}", e)),
+            );
+            // let a few keepalives flow in both directions before sending data
+            let send = Timeout::new(Duration::from_millis(100), &handle)
+                .infallible()
+                .and_then(move |()| {
+                    sink0
+                        .send(Bytes::from(&b"hello"[..]))
+                        .map_err(|e| panic!("send error: {}", e))
+                });
+            let recv = keepalive1
+                .into_future()
+                .map(|(msg_opt, _keepalive1)| unwrap!(msg_opt))
+                .map_err(|(e, _keepalive1)| panic!("keepalive error: {}", e));
+
+            let (_sink0, msg) = unwrap!(core.run(send.join(recv)));
+
+            assert_eq!(msg, Bytes::from(&b"hello"[..]));
+        }
+
+        #[test]
+        fn it_drops_datagrams_that_fail_to_decrypt() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (keepalive0, keepalive1) = keepalive_pair(&handle);
+            let addr1 = unwrap!(keepalive1.socket.local_addr());
+
+            let _ = unwrap!(keepalive0.socket.send_to(b"garbage", &addr1));
+            let send = keepalive0
+                .send(Bytes::from(&b"hello"[..]))
+                .map_err(|e| panic!("send error: {}", e));
+            let recv = keepalive1
+                .into_future()
+                .map(|(msg_opt, _keepalive1)| unwrap!(msg_opt))
+                .map_err(|(e, _keepalive1)| panic!("keepalive error: {}", e));
+
+            let (_keepalive0, msg) = unwrap!(core.run(send.join(recv)));
+
+            assert_eq!(msg, Bytes::from(&b"hello"[..]));
+        }
+
+        #[test]
+        fn it_ignores_replies_that_dont_echo_the_nonce() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (mut keepalive0, keepalive1) = keepalive_pair(&handle);
+            keepalive0.set_interval(Duration::from_millis(10));
+            keepalive0.set_max_missed(2);
+            let shared_secret = keepalive1.shared_secret.clone();
+            let addr0 = unwrap!(keepalive0.socket.local_addr());
+            let socket1 = keepalive1.into_inner();
+
+            // peer answers every keepalive, but not with the nonce it got
+            let forge_replies = future::poll_fn(move || -> Result<Async<()>, Void> {
+                let mut buffer = [0u8; 64 * 1024];
+                while let Ok((len, _addr)) = socket1.recv_from(&mut buffer) {
+                    if let Ok(HolePunchMsg::Keepalive { nonce, .. }) =
+                        decrypt_hole_punch_msg(&buffer[..len], &shared_secret)
+                    {
+                        let reply = HolePunchMsg::KeepaliveAck {
+                            nonce: nonce.wrapping_add(1),
+                        };
+                        let reply = unwrap!(encrypt_hole_punch_msg(&reply, &shared_secret));
+                        let _ = unwrap!(socket1.send_to(&reply, &addr0));
+                    }
+                }
+                Ok(Async::NotReady)
+            });
+            handle.spawn(forge_replies.infallible());
+
+            let res = core.run(keepalive0.into_future().map_err(|(e, _)| e));
+
+            match res {
+                Err(UdpKeepaliveError::PeerDead(2)) => (),
+                res => panic!("unexpected result: {:?}", res.map(|(msg, _)| msg)),
+            }
+        }
+
+        #[test]
+        fn it_accepts_replies_arriving_after_the_next_keepalive() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (mut keepalive0, keepalive1) = keepalive_pair(&handle);
+            keepalive0.set_interval(Duration::from_millis(10));
+            keepalive0.set_max_missed(3);
+            let shared_secret = keepalive1.shared_secret.clone();
+            let addr0 = unwrap!(keepalive0.socket.local_addr());
+            let socket1 = keepalive1.into_inner();
+
+            // peer answers every keepalive only after it got the next one
+            let mut delayed_nonce = None;
+            let slow_replies = future::poll_fn(move || -> Result<Async<()>, Void> {
+                let mut buffer = [0u8; 64 * 1024];
+                while let Ok((len, _addr)) = socket1.recv_from(&mut buffer) {
+                    if let Ok(HolePunchMsg::Keepalive { nonce, .. }) =
+                        decrypt_hole_punch_msg(&buffer[..len], &shared_secret)
+                    {
+                        if let Some(nonce) = delayed_nonce.take() {
+                            let reply = HolePunchMsg::KeepaliveAck { nonce };
+                            let reply = unwrap!(encrypt_hole_punch_msg(&reply, &shared_secret));
+                            let _ = unwrap!(socket1.send_to(&reply, &addr0));
+                        }
+                        delayed_nonce = Some(nonce);
+                    }
+                }
+                Ok(Async::NotReady)
+            });
+            handle.spawn(slow_replies.infallible());
+
+            let res = core.run(
+                keepalive0
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .with_timeout(Duration::from_millis(200), &handle),
+            );
+
+            match res {
+                Ok(None) => (),
+                res => panic!("unexpected result: {:?}", res.map(|opt| opt.map(|(msg, _)| msg))),
+            }
+        }
+
+        #[test]
+        fn when_peer_doesnt_answer_it_reports_dead_peer() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let (mut keepalive0, keepalive1) = keepalive_pair(&handle);
+            keepalive0.set_interval(Duration::from_millis(10));
+            keepalive0.set_max_missed(2);
+            // peer never polls its end, so keepalives go unanswered
+            let _socket1 = keepalive1.into_inner();
+
+            let res = core.run(keepalive0.into_future().map_err(|(e, _)| e));
+
+            match res {
+                Err(UdpKeepaliveError::PeerDead(2)) => (),
+                res => panic!("unexpected result: {:?}", res.map(|(msg, _)| msg)),
+            }
+        }
+    }
+}
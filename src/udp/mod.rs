@@ -1,3 +1,11 @@
 pub mod addr_querier;
+mod birthday;
+mod cookie;
+pub mod keepalive;
+pub mod nat_behaviour;
+pub mod relay;
 pub mod rendezvous_server;
+pub mod session;
 pub mod socket;
+mod trickle;
+pub mod turn;
@@ -0,0 +1,435 @@
+//! NAT behaviour discovery (RFC 5780).
+//!
+//! `NatType` tells how our NAT maps ports, but not how it filters incoming traffic: a full cone
+//! NAT and a port restricted one look the same to it. Here we probe a STUN server that has an
+//! alternate IP and port, such as `UdpRendezvousServer::bind_with_alternate()`, and classify both
+//! mapping and filtering behaviour.
+
+use priv_prelude::*;
+use std::cmp;
+use stun::{self, Attribute, Class, StunMessage};
+
+/// How often probes are retransmitted.
+const PROBE_INTERVAL_MS: u64 = 500;
+/// How long we wait for a response. Filtering tests expect some responses to never arrive, so
+/// this is much shorter than the RFC 5389 retransmission schedule.
+const PROBE_TIMEOUT_SEC: u64 = 3;
+
+/// How the NAT maps our local endpoint to public ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingBehaviour {
+    /// Same public endpoint is used for every remote endpoint.
+    EndpointIndependent,
+    /// Public endpoint depends on the remote IP.
+    AddressDependent,
+    /// Public endpoint depends on both remote IP and port.
+    AddressAndPortDependent,
+}
+
+/// Which incoming packets the NAT lets through to a mapped endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilteringBehaviour {
+    /// Packets from any remote endpoint pass, aka full cone.
+    EndpointIndependent,
+    /// Only packets from IPs we've sent to pass, aka restricted cone.
+    AddressDependent,
+    /// Only packets from endpoints we've sent to pass, aka port restricted cone.
+    AddressAndPortDependent,
+}
+
+/// NAT behaviour discovered by `udp_discover_nat_behaviour()`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NatBehaviour {
+    /// Mapping behaviour.
+    pub mapping: MappingBehaviour,
+    /// Filtering behaviour.
+    pub filtering: FilteringBehaviour,
+    /// Our public address, as seen by the primary address of the server.
+    pub public_addr: SocketAddr,
+}
+
+quick_error! {
+    /// Errors returned by `udp_discover_nat_behaviour()`.
+    #[derive(Debug)]
+    pub enum NatBehaviourError {
+        /// Failure to bind socket.
+        Bind(e: io::Error) {
+            description("error binding to socket address")
+            display("error binding to socket address: {}", e)
+            cause(e)
+        }
+        /// Failure to send probe.
+        SendRequest(e: io::Error) {
+            description("error sending request to STUN server")
+            display("error sending request to STUN server: {}", e)
+            cause(e)
+        }
+        /// Failure to receive response.
+        ReadResponse(e: io::Error) {
+            description("error reading response from STUN server")
+            display("error reading response from STUN server: {}", e)
+            cause(e)
+        }
+        /// Server doesn't respond at all.
+        NoResponse {
+            description("STUN server didn't respond")
+        }
+        /// Server doesn't have an alternate address, hence can't be used for discovery.
+        NoOtherAddress {
+            description("STUN server doesn't advertise an alternate address")
+        }
+        /// Server response doesn't carry our address.
+        InvalidResponse {
+            description("invalid response from STUN server")
+        }
+    }
+}
+
+/// Discovers mapping and filtering behaviour of our NAT by probing `server_addr` from a socket
+/// bound to `bind_addr`. The server must advertise an alternate address via `OTHER-ADDRESS`.
+///
+/// Filtering is probed from another socket bound to the IP of `bind_addr`, since mapping probes
+/// open our NAT to the alternate IP of the server.
+pub fn discover_nat_behaviour(
+    bind_addr: &SocketAddr,
+    server_addr: &SocketAddr,
+    handle: &Handle,
+) -> BoxFuture<NatBehaviour, NatBehaviourError> {
+    let socket = try_bfut!(UdpSocket::bind(bind_addr, handle).map_err(NatBehaviourError::Bind));
+    let handle = handle.clone();
+    let server_addr = *server_addr;
+    let filtering_bind_addr = SocketAddr::new(bind_addr.ip(), 0);
+
+    trace!("discovering NAT behaviour via {}", server_addr);
+    probe(&handle, socket, server_addr, None)
+        .and_then(move |(socket, response)| {
+            let response = response.ok_or(NatBehaviourError::NoResponse)?;
+            let public_addr = response
+                .mapped_addr()
+                .ok_or(NatBehaviourError::InvalidResponse)?;
+            let other_addr = response
+                .other_addr()
+                .ok_or(NatBehaviourError::NoOtherAddress)?;
+            Ok((socket, public_addr, other_addr))
+        }).and_then(move |(socket, public_addr, other_addr)| {
+            let handle0 = handle.clone();
+            let handle1 = handle.clone();
+            discover_mapping(&handle, socket, server_addr, other_addr, public_addr)
+                .and_then(move |(_socket, mapping)| {
+                    let socket = UdpSocket::bind(&filtering_bind_addr, &handle0)
+                        .map_err(NatBehaviourError::Bind)?;
+                    Ok((socket, mapping))
+                }).and_then(move |(socket, mapping)| {
+                    discover_filtering(&handle1, socket, server_addr)
+                        .map(move |filtering| NatBehaviour {
+                            mapping,
+                            filtering,
+                            public_addr,
+                        })
+                })
+        }).into_boxed()
+}
+
+/// Compares our public addresses as seen by the alternate IP and then the alternate IP and port
+/// of the server (RFC 5780, section 4.3).
+fn discover_mapping(
+    handle: &Handle,
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    other_addr: SocketAddr,
+    public_addr: SocketAddr,
+) -> BoxFuture<(UdpSocket, MappingBehaviour), NatBehaviourError> {
+    let handle = handle.clone();
+    let alternate_ip_addr = SocketAddr::new(other_addr.ip(), server_addr.port());
+    probe(&handle, socket, alternate_ip_addr, None)
+        .and_then(move |(socket, response)| {
+            let response = response.ok_or(NatBehaviourError::NoResponse)?;
+            let alternate_ip_public_addr = response
+                .mapped_addr()
+                .ok_or(NatBehaviourError::InvalidResponse)?;
+            Ok((socket, alternate_ip_public_addr))
+        }).and_then(move |(socket, alternate_ip_public_addr)| {
+            if alternate_ip_public_addr == public_addr {
+                let mapping = MappingBehaviour::EndpointIndependent;
+                return future::ok((socket, mapping)).into_boxed();
+            }
+            probe(&handle, socket, other_addr, None)
+                .and_then(move |(socket, response)| {
+                    let response = response.ok_or(NatBehaviourError::NoResponse)?;
+                    let other_public_addr = response
+                        .mapped_addr()
+                        .ok_or(NatBehaviourError::InvalidResponse)?;
+                    let mapping = if other_public_addr == alternate_ip_public_addr {
+                        MappingBehaviour::AddressDependent
+                    } else {
+                        MappingBehaviour::AddressAndPortDependent
+                    };
+                    Ok((socket, mapping))
+                }).into_boxed()
+        }).into_boxed()
+}
+
+/// Asks the server to respond from its alternate IP and port, then from its alternate port only
+/// and checks which responses get through our NAT (RFC 5780, section 4.4). `socket` must not have
+/// sent anything to the alternate IP of the server.
+fn discover_filtering(
+    handle: &Handle,
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+) -> BoxFuture<FilteringBehaviour, NatBehaviourError> {
+    let handle = handle.clone();
+    let change_both = Attribute::ChangeRequest {
+        change_ip: true,
+        change_port: true,
+    };
+    probe(&handle, socket, server_addr, Some(change_both))
+        .and_then(move |(socket, response)| {
+            if response.is_some() {
+                return future::ok(FilteringBehaviour::EndpointIndependent).into_boxed();
+            }
+            let change_port = Attribute::ChangeRequest {
+                change_ip: false,
+                change_port: true,
+            };
+            probe(&handle, socket, server_addr, Some(change_port))
+                .map(|(_socket, response)| match response {
+                    Some(_) => FilteringBehaviour::AddressDependent,
+                    None => FilteringBehaviour::AddressAndPortDependent,
+                }).into_boxed()
+        }).into_boxed()
+}
+
+/// Sends Binding request to `dest` until the response arrives or we time out, in which case
+/// `None` is yielded. Responses are accepted from any address, since the server may be asked to
+/// respond from its alternate one.
+fn probe(
+    handle: &Handle,
+    socket: UdpSocket,
+    dest: SocketAddr,
+    change_request: Option<Attribute>,
+) -> BoxFuture<(UdpSocket, Option<StunMessage>), NatBehaviourError> {
+    let mut request = StunMessage::request(stun::BINDING);
+    if let Some(change_request) = change_request {
+        request = request.with(change_request);
+    }
+    let transaction_id = request.transaction_id;
+    let msg = request.encode(None);
+
+    let deadline = Instant::now() + Duration::from_secs(PROBE_TIMEOUT_SEC);
+    let mut socket_opt = Some(socket);
+    let mut timeout = Timeout::new(Duration::new(0, 0), handle);
+    future::poll_fn(move || {
+        while let Async::Ready(()) = timeout.poll().void_unwrap() {
+            if Instant::now() >= deadline {
+                return Ok(Async::Ready((unwrap!(socket_opt.take()), None)));
+            }
+            match unwrap!(socket_opt.as_ref()).send_to(&msg, &dest) {
+                Ok(_) => {
+                    let next = Instant::now() + Duration::from_millis(PROBE_INTERVAL_MS);
+                    timeout.reset(cmp::min(next, deadline));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(NatBehaviourError::SendRequest(e)),
+            }
+        }
+
+        let mut buffer = [0u8; 512];
+        loop {
+            let (len, addr) = match unwrap!(socket_opt.as_ref()).recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(NatBehaviourError::ReadResponse(e)),
+            };
+            let response = match StunMessage::decode(&buffer[..len]) {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("ignoring invalid message from {}: {}", addr, e);
+                    continue;
+                }
+            };
+            // responses of earlier probes might arrive late
+            if response.transaction_id != transaction_id || response.class != Class::Success {
+                continue;
+            }
+            trace!("STUN server responded from {}", addr);
+            return Ok(Async::Ready((unwrap!(socket_opt.take()), Some(response))));
+        }
+    }).into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod discover_nat_behaviour {
+        use super::*;
+
+        #[test]
+        fn without_nat_mapping_and_filtering_are_endpoint_independent() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server = unwrap!(UdpRendezvousServer::bind_with_alternate(
+                &addr!("127.0.0.1:0"),
+                &addr!("127.0.0.2:0"),
+                &handle,
+            ));
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let behaviour = unwrap!(core.run(discover_nat_behaviour(
+                &bind_addr,
+                &server.local_addr(),
+                &handle,
+            )));
+
+            assert_eq!(behaviour.mapping, MappingBehaviour::EndpointIndependent);
+            assert_eq!(behaviour.filtering, FilteringBehaviour::EndpointIndependent);
+            assert_eq!(behaviour.public_addr.ip(), ipv4!("127.0.0.1"));
+        }
+
+        #[test]
+        fn when_server_has_no_alternate_address_it_returns_error() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("127.0.0.1:0"), &handle));
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let res = core.run(discover_nat_behaviour(
+                &bind_addr,
+                &server.local_addr(),
+                &handle,
+            ));
+
+            match res {
+                Err(NatBehaviourError::NoOtherAddress) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+#[cfg(feature = "netsim")]
+mod netsim_test {
+    use super::*;
+    use env_logger;
+    use netsim::device::ipv4::Ipv4NatBuilder;
+    use netsim::node;
+    use netsim::{self, Ipv4Range, Network};
+    use std::net;
+    use std::sync::mpsc;
+    use tokio_core::reactor::Core;
+
+    /// Discovers behaviour of the given NAT. Netsim machines have a single IP, so the server's
+    /// sockets bound to the alternate IP are created on another machine.
+    fn discover_nat_behaviour_behind(nat: Ipv4NatBuilder) -> NatBehaviour {
+        let _ = env_logger::init();
+
+        let mut core = unwrap!(Core::new());
+        let handle = core.handle();
+        let network = Network::new(&handle);
+        let network_handle = network.handle();
+
+        let res = core.run(future::lazy(|| {
+            let (primary_port_tx, primary_port_rx) = mpsc::channel();
+            let (alternate_sockets_tx, alternate_sockets_rx) = mpsc::channel();
+            let (server_addr_tx, server_addr_rx) = mpsc::channel();
+            let (primary_drop_tx, primary_drop_rx) = drop_notify();
+            let (alternate_drop_tx, alternate_drop_rx) = drop_notify();
+
+            let primary = node::ipv4::machine(move |ip| {
+                let mut core = unwrap!(Core::new());
+                let handle = core.handle();
+
+                let bind_addr = SocketAddr::new(IpAddr::V4(ip), 0);
+                let socket = unwrap!(UdpSocket::bind(&bind_addr, &handle));
+                let primary_addr = unwrap!(socket.local_addr());
+                unwrap!(primary_port_tx.send(primary_addr.port()));
+                let (change_ip, change_both): (net::UdpSocket, net::UdpSocket) =
+                    unwrap!(alternate_sockets_rx.recv());
+                let other_port = unwrap!(change_both.local_addr()).port();
+                let change_port = unwrap!(UdpSocket::bind(
+                    &SocketAddr::new(IpAddr::V4(ip), other_port),
+                    &handle
+                ));
+                let server = unwrap!(UdpRendezvousServer::from_sockets_with_alternate(
+                    socket,
+                    unwrap!(UdpSocket::from_socket(change_ip, &handle)),
+                    change_port,
+                    unwrap!(UdpSocket::from_socket(change_both, &handle)),
+                    &handle,
+                ));
+                unwrap!(server_addr_tx.send(primary_addr));
+
+                unwrap!(core.run(primary_drop_rx.map(|()| drop(server))))
+            });
+            // sockets created here keep sending and receiving via this machine, even though they
+            // are polled by the primary one
+            let alternate = node::ipv4::machine(move |ip| {
+                let mut core = unwrap!(Core::new());
+
+                let primary_port = unwrap!(primary_port_rx.recv());
+                let change_ip = unwrap!(net::UdpSocket::bind(SocketAddr::new(
+                    IpAddr::V4(ip),
+                    primary_port
+                )));
+                let change_both = loop {
+                    let socket = unwrap!(net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(ip), 0)));
+                    if unwrap!(socket.local_addr()).port() != primary_port {
+                        break socket;
+                    }
+                };
+                unwrap!(alternate_sockets_tx.send((change_ip, change_both)));
+
+                unwrap!(core.run(alternate_drop_rx))
+            });
+            let client = node::ipv4::nat(
+                nat,
+                node::ipv4::machine(move |_ip| {
+                    let mut core = unwrap!(Core::new());
+                    let handle = core.handle();
+
+                    let server_addr = unwrap!(server_addr_rx.recv());
+                    let res = core.run(discover_nat_behaviour(
+                        &addr!("0.0.0.0:0"),
+                        &server_addr,
+                        &handle,
+                    ));
+                    drop(primary_drop_tx);
+                    drop(alternate_drop_tx);
+                    unwrap!(res)
+                }),
+            );
+
+            let network = node::ipv4::router((primary, alternate, client));
+            let (spawn_complete, _plug) =
+                netsim::spawn::ipv4_tree(&network_handle, Ipv4Range::global(), network);
+
+            spawn_complete
+                .resume_unwind()
+                .map(|((), (), behaviour)| behaviour)
+                .with_timeout(Duration::from_secs(30), &handle)
+                .map(|opt| unwrap!(opt, "test timed out!"))
+        }));
+        res.void_unwrap()
+    }
+
+    #[test]
+    fn it_detects_endpoint_dependent_mapping() {
+        let behaviour = discover_nat_behaviour_behind(Ipv4NatBuilder::default().symmetric());
+
+        assert_ne!(behaviour.mapping, MappingBehaviour::EndpointIndependent);
+    }
+
+    #[test]
+    fn it_detects_address_dependent_filtering() {
+        let behaviour =
+            discover_nat_behaviour_behind(Ipv4NatBuilder::default().blacklist_unrecognized_addrs());
+
+        assert_eq!(behaviour.mapping, MappingBehaviour::EndpointIndependent);
+        assert_eq!(behaviour.filtering, FilteringBehaviour::AddressDependent);
+    }
+}
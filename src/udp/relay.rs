@@ -0,0 +1,344 @@
+//! UDP relay server and client.
+
+use priv_prelude::*;
+use relay::{
+    seal_relay_response, RelayError, RelayRequest, RelaySessions, RELAY_PAIRING_TIMEOUT_SEC,
+};
+use version;
+
+/// How long paired peers may stay silent before the relay server forgets them.
+const RELAY_IDLE_TIMEOUT_SEC: u64 = 300;
+/// How often the relay server forgets expired clients.
+const RELAY_CLEANUP_INTERVAL_SEC: u64 = 10;
+
+/// A remote `UdpRelayServer` that peers can fall back to when UDP hole punching fails.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteUdpRelayServer {
+    addr: SocketAddr,
+    pub_key: PublicEncryptKey,
+}
+
+impl RemoteUdpRelayServer {
+    /// Define a new remote relay server.
+    pub fn new(addr: SocketAddr, pub_key: PublicEncryptKey) -> RemoteUdpRelayServer {
+        RemoteUdpRelayServer { addr, pub_key }
+    }
+
+    /// Returns relay server address.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns relay server public key.
+    pub fn public_key(&self) -> &PublicEncryptKey {
+        &self.pub_key
+    }
+}
+
+/// Relays datagrams between peers that failed to punch holes to each other. Peers are paired by
+/// the ephemeral keys they exchanged during rendezvous connect. Once paired, every datagram a peer
+/// sends to the server, except relay requests, is forwarded to the other peer.
+pub struct UdpRelayServer {
+    local_addr: SocketAddr,
+    our_pk: PublicEncryptKey,
+    _drop_tx: DropNotify,
+}
+
+impl UdpRelayServer {
+    /// Takes ownership of already set up UDP socket and starts relay server.
+    pub fn from_socket(socket: UdpSocket, handle: &Handle) -> io::Result<UdpRelayServer> {
+        let local_addr = socket.local_addr()?;
+        let (drop_tx, drop_rx) = drop_notify();
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let relay = UdpRelay {
+            socket,
+            our_sk,
+            our_pk,
+            sessions: RelaySessions::new(),
+            peers: HashMap::new(),
+            cleanup: Timeout::new(Duration::from_secs(RELAY_CLEANUP_INTERVAL_SEC), handle),
+        };
+        handle.spawn(relay.until(drop_rx).map(|_| ()).infallible());
+        Ok(UdpRelayServer {
+            local_addr,
+            our_pk,
+            _drop_tx: drop_tx,
+        })
+    }
+
+    /// Start listening for relay requests.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<UdpRelayServer> {
+        let socket = UdpSocket::bind(addr, handle)?;
+        UdpRelayServer::from_socket(socket, handle)
+    }
+
+    /// Start listening for relay requests and allow other sockets to bind to the same port.
+    pub fn bind_reusable(addr: &SocketAddr, handle: &Handle) -> io::Result<UdpRelayServer> {
+        let socket = UdpSocket::bind_reusable(addr, handle)?;
+        UdpRelayServer::from_socket(socket, handle)
+    }
+
+    /// Returns the local address that this relay server is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns server public key.
+    /// Server expects relay requests to be encrypted with this public key.
+    pub fn public_key(&self) -> &PublicEncryptKey {
+        &self.our_pk
+    }
+}
+
+/// Peer that was paired with another peer.
+struct RelayedPeer {
+    their_addr: SocketAddr,
+    client_pk: PublicEncryptKey,
+    /// Sealed request that got the peer paired. Our response might have been lost, so the peer's
+    /// retransmissions of it are answered instead of forwarded.
+    request: Vec<u8>,
+    last_active: Instant,
+}
+
+/// Relay server task.
+struct UdpRelay {
+    socket: UdpSocket,
+    our_sk: SecretEncryptKey,
+    our_pk: PublicEncryptKey,
+    sessions: RelaySessions<(SocketAddr, Vec<u8>)>,
+    peers: HashMap<SocketAddr, RelayedPeer>,
+    cleanup: Timeout,
+}
+
+impl UdpRelay {
+    /// Only datagrams of unknown clients are decrypted, paired peers' traffic is forwarded as is.
+    fn handle_datagram(&mut self, msg: &[u8], addr: SocketAddr) {
+        let paired = self.peers.get_mut(&addr).map(|peer| {
+            peer.last_active = Instant::now();
+            (peer.their_addr, peer.client_pk, peer.request[..] == *msg)
+        });
+        let their_addr = match paired {
+            Some((_their_addr, client_pk, true)) => return self.respond(addr, &client_pk),
+            Some((their_addr, _client_pk, false)) => their_addr,
+            None => {
+                match RelayRequest::open(msg, &self.our_sk, &self.our_pk) {
+                    Ok(request) => self.handle_request(&request, msg, addr),
+                    Err(e) => debug!("relay server ignoring datagram from {}: {}", addr, e),
+                }
+                return;
+            }
+        };
+        match self.socket.send_to(msg, &their_addr) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!("socket not writable, dropping relayed datagram");
+            }
+            Err(e) => info!("failed to relay datagram to {}: {}", their_addr, e),
+        }
+    }
+
+    fn handle_request(&mut self, request: &RelayRequest, msg: &[u8], addr: SocketAddr) {
+        let pair = self.sessions.pair(request, (addr, msg.to_vec()));
+        let ((addr, client_request), (peer_addr, peer_request)) = match pair {
+            Some(pair) => pair,
+            None => {
+                trace!("relay client {} waits for its peer", addr);
+                return;
+            }
+        };
+        if addr == peer_addr {
+            debug!("relay client {} asked to be paired with itself", addr);
+            return;
+        }
+
+        trace!("relaying between {} and {}", addr, peer_addr);
+        let now = Instant::now();
+        let _ = self.peers.insert(
+            addr,
+            RelayedPeer {
+                their_addr: peer_addr,
+                client_pk: request.client_pk,
+                request: client_request,
+                last_active: now,
+            },
+        );
+        let _ = self.peers.insert(
+            peer_addr,
+            RelayedPeer {
+                their_addr: addr,
+                client_pk: request.peer_pk,
+                request: peer_request,
+                last_active: now,
+            },
+        );
+        self.respond(addr, &request.client_pk);
+        self.respond(peer_addr, &request.peer_pk);
+    }
+
+    /// Responses are retransmitted on client's request, hence they are dropped if the socket is
+    /// not writable.
+    fn respond(&mut self, addr: SocketAddr, client_pk: &PublicEncryptKey) {
+        let shared_secret = self.our_sk.shared_secret(client_pk);
+        let msg = match seal_relay_response(&addr, &shared_secret) {
+            Ok(msg) => msg,
+            Err(e) => {
+                info!("failed to prepare relay response: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.socket.send_to(&msg, &addr) {
+            debug!("failed to send relay response to {}: {}", addr, e);
+        }
+    }
+
+    fn expire(&mut self) {
+        self.sessions
+            .expire(Duration::from_secs(RELAY_PAIRING_TIMEOUT_SEC));
+        let now = Instant::now();
+        let idle_timeout = Duration::from_secs(RELAY_IDLE_TIMEOUT_SEC);
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_active) < idle_timeout);
+    }
+}
+
+impl Future for UdpRelay {
+    type Item = ();
+    type Error = Void;
+
+    fn poll(&mut self) -> Result<Async<()>, Void> {
+        while let Async::Ready(()) = self.cleanup.poll().void_unwrap() {
+            self.expire();
+            self.cleanup
+                .reset(Instant::now() + Duration::from_secs(RELAY_CLEANUP_INTERVAL_SEC));
+        }
+
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                // errors of a single datagram, e.g. ICMP port unreachable on Windows
+                Err(e) => {
+                    debug!("relay server failed to read from socket: {}", e);
+                    continue;
+                }
+            };
+            self.handle_datagram(&buffer[..len], addr);
+        }
+    }
+}
+
+/// Asks the relay server to pair us with the peer holding `their_pk`. Yields a socket whose
+/// datagrams sent to the relay server are forwarded to the peer and vice versa, along with our
+/// address as seen by the relay server.
+pub fn relay_connect(
+    handle: &Handle,
+    relay: &RemoteUdpRelayServer,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    their_pk: &PublicEncryptKey,
+) -> BoxFuture<(UdpSocket, SocketAddr), RelayError> {
+    let bind_addr = if relay.addr.is_ipv4() {
+        addr!("0.0.0.0:0")
+    } else {
+        addr!("[::]:0")
+    };
+    let socket = try_bfut!(UdpSocket::bind(&bind_addr, handle).map_err(RelayError::Bind));
+    let msg = try_bfut!(RelayRequest::seal(
+        &relay.pub_key,
+        our_pk,
+        our_sk,
+        their_pk
+    ));
+    let shared_secret = our_sk.shared_secret(&relay.pub_key);
+    let relay_addr = relay.addr;
+
+    let mut socket_opt = Some(socket);
+    let mut timeout = Timeout::new(Duration::new(0, 0), handle);
+    future::poll_fn(move || {
+        while let Async::Ready(()) = timeout.poll().void_unwrap() {
+            match unwrap!(socket_opt.as_ref()).send_to(&msg, &relay_addr) {
+                Ok(_) => timeout.reset(Instant::now() + Duration::from_millis(500)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(RelayError::SendRequest(e)),
+            }
+        }
+
+        let mut buffer = [0u8; 256];
+        loop {
+            let res = unwrap!(socket_opt.as_ref()).recv_from(&mut buffer);
+            match res {
+                Ok((len, recv_addr)) => {
+                    if recv_addr != relay_addr {
+                        continue;
+                    }
+                    match version::open_server_response::<SocketAddr>(
+                        &buffer[..len],
+                        &shared_secret,
+                    ) {
+                        Ok(our_addr) => {
+                            trace!("relay server {} paired us, we are {}", relay_addr, our_addr);
+                            let socket = unwrap!(socket_opt.take());
+                            return Ok(Async::Ready((socket, our_addr)));
+                        }
+                        Err(e @ QueryPublicAddrError::VersionMismatch(..)) => {
+                            return Err(RelayError::Response(e))
+                        }
+                        // peer might already be sending data, while our response got lost
+                        Err(e) => debug!("ignoring datagram from relay server: {}", e),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(RelayError::ReadResponse(e)),
+            }
+        }
+    }).with_timeout(Duration::from_secs(RELAY_PAIRING_TIMEOUT_SEC), handle)
+    .and_then(|opt| opt.ok_or(RelayError::Timeout))
+    .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod udp_relay_server {
+        use super::*;
+
+        #[test]
+        fn it_relays_datagrams_between_paired_peers() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server = unwrap!(UdpRelayServer::bind(&addr!("127.0.0.1:0"), &handle));
+            let relay = RemoteUdpRelayServer::new(server.local_addr(), *server.public_key());
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let (pk2, sk2) = gen_encrypt_keypair();
+
+            let relayed1 = relay_connect(&handle, &relay, &pk1, &sk1, &pk2);
+            let relayed2 = relay_connect(&handle, &relay, &pk2, &sk2, &pk1);
+            let relay_addr = relay.addr();
+            let task = relayed1
+                .join(relayed2)
+                .map_err(|e| panic!("relay connect failed: {}", e))
+                .and_then(move |((socket1, _addr1), (socket2, _addr2))| {
+                    socket1
+                        .send_dgram(b"hello".to_vec(), relay_addr)
+                        .map_err(|e| panic!("error sending: {}", e))
+                        .and_then(move |(_socket1, _msg)| {
+                            socket2
+                                .recv_dgram(vec![0u8; 256])
+                                .map_err(|e| panic!("error receiving: {}", e))
+                        })
+                });
+
+            let (_socket2, msg, len, addr) = unwrap!(core.run(task));
+
+            assert_eq!(addr, relay_addr);
+            assert_eq!(&msg[..len], b"hello");
+        }
+    }
+}
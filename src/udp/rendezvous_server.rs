@@ -1,8 +1,13 @@
 use bytes::Bytes;
 use open_addr::BindPublicError;
+use port_mapping::PortMapping;
 use priv_prelude::*;
-use tokio_shared_udp_socket::{SharedUdpSocket, WithAddress};
+use rate_limit::RateLimiter;
+use std::rc::Rc;
+use stun::{self, Attribute};
+use udp::cookie::{self, CookieJar};
 use udp::socket;
+use version::{self, Capabilities, Envelope};
 
 /// Sends response to echo address request (`ECHO_REQ`).
 /// NOTE: this function is almost identical to `tcp::rendezous_server::respond_with_addr()`,
@@ -16,30 +21,43 @@ pub fn respond_with_addr<S>(
 where
     S: Sink<SinkItem = Bytes, SinkError = io::Error> + 'static,
 {
-    let encrypted = try_bfut!(
-        shared_secret
-            .encrypt(&addr)
-            .map_err(RendezvousServerError::Encrypt)
-            .map(Bytes::from)
-    );
+    let encrypted = try_bfut!(addr_response(addr, shared_secret));
     sink.send(encrypted)
         .map_err(RendezvousServerError::SendError)
         .into_boxed()
 }
 
+/// Encrypts response to echo address request.
+fn addr_response(
+    addr: SocketAddr,
+    shared_secret: &SharedSecretKey,
+) -> Result<Bytes, RendezvousServerError> {
+    let envelope = version::seal_server_msg(&addr).map_err(RendezvousServerError::Serialize)?;
+    shared_secret
+        .encrypt(&envelope)
+        .map_err(RendezvousServerError::Encrypt)
+        .map(Bytes::from)
+}
+
 /// Traversal server implementation for UDP.
-/// Acts much like STUN server except doesn't implement the standard protocol - RFC 5389.
+/// Acts much like STUN server: answers our encrypted echo requests and, on the same socket,
+/// standard STUN (RFC 5389) Binding requests, unless cookies are required.
+/// Requests exceeding `ServerLimits` are dropped without being decrypted.
 pub struct UdpRendezvousServer {
     local_addr: SocketAddr,
+    alternate_addr: Option<SocketAddr>,
     our_pk: PublicEncryptKey,
+    limiter: Arc<Mutex<RateLimiter>>,
+    cookies: Arc<Mutex<CookieJar>>,
     _drop_tx: DropNotify,
+    _alternate_drop_tx: Option<DropNotify>,
 }
 
 impl UdpRendezvousServer {
     /// Takes ownership of already set up UDP socket and starts rendezvous server.
     pub fn from_socket(socket: UdpSocket, handle: &Handle) -> io::Result<UdpRendezvousServer> {
         let local_addr = socket.local_addr()?;
-        Ok(from_socket_inner(socket, &local_addr, handle))
+        Ok(from_socket_inner(socket, &local_addr, None, handle))
     }
 
     /// Start listening for incoming connections.
@@ -56,16 +74,74 @@ impl UdpRendezvousServer {
         Ok(server)
     }
 
-    /// Try to get an external address and start listening for incoming connections.
+    /// Start listening on `addr` and on the alternate IP and port given by `alternate_addr`. This
+    /// allows clients to discover the mapping and filtering behaviour of their NAT (RFC 5780),
+    /// see `udp_discover_nat_behaviour()`. Both addresses must have specific IPs of the same
+    /// family: the server binds to all four combinations of their IPs and ports.
+    pub fn bind_with_alternate(
+        addr: &SocketAddr,
+        alternate_addr: &SocketAddr,
+        handle: &Handle,
+    ) -> io::Result<UdpRendezvousServer> {
+        let socket = UdpSocket::bind(addr, handle)?;
+        let primary_addr = socket.local_addr()?;
+        let change_both = UdpSocket::bind(alternate_addr, handle)?;
+        let other_addr = change_both.local_addr()?;
+        let change_ip = UdpSocket::bind(
+            &SocketAddr::new(other_addr.ip(), primary_addr.port()),
+            handle,
+        )?;
+        let change_port = UdpSocket::bind(
+            &SocketAddr::new(primary_addr.ip(), other_addr.port()),
+            handle,
+        )?;
+        UdpRendezvousServer::from_sockets_with_alternate(
+            socket,
+            change_ip,
+            change_port,
+            change_both,
+            handle,
+        )
+    }
+
+    /// Like `bind_with_alternate()`, but takes ownership of already set up sockets. `change_ip`
+    /// must be bound to the alternate IP and the primary port, `change_port` to the primary IP
+    /// and the alternate port and `change_both` to the alternate IP and port.
+    pub fn from_sockets_with_alternate(
+        socket: UdpSocket,
+        change_ip: UdpSocket,
+        change_port: UdpSocket,
+        change_both: UdpSocket,
+        handle: &Handle,
+    ) -> io::Result<UdpRendezvousServer> {
+        let primary_addr = socket.local_addr()?;
+        let other_addr = change_both.local_addr()?;
+        let alternate = AlternateSockets {
+            primary_addr,
+            other_addr,
+            change_ip,
+            change_port,
+            change_both,
+        };
+        Ok(from_socket_inner(
+            socket,
+            &primary_addr,
+            Some(Rc::new(alternate)),
+            handle,
+        ))
+    }
+
+    /// Try to get an external address and start listening for incoming connections. The external
+    /// port stays open for as long as the returned `PortMapping` lives.
     pub fn bind_public(
         addr: &SocketAddr,
         handle: &Handle,
         mc: &P2p,
-    ) -> BoxFuture<(UdpRendezvousServer, SocketAddr), BindPublicError> {
+    ) -> BoxFuture<(UdpRendezvousServer, PortMapping), BindPublicError> {
         let handle = handle.clone();
         socket::bind_public_with_addr(addr, &handle, mc)
-            .map(move |(socket, bind_addr, public_addr)| {
-                (from_socket_inner(socket, &bind_addr, &handle), public_addr)
+            .map(move |(socket, bind_addr, mapping)| {
+                (from_socket_inner(socket, &bind_addr, None, &handle), mapping)
             }).into_boxed()
     }
 
@@ -74,6 +150,11 @@ impl UdpRendezvousServer {
         self.local_addr
     }
 
+    /// Returns the alternate address of this rendezvous server, if it was bound with one.
+    pub fn alternate_addr(&self) -> Option<SocketAddr> {
+        self.alternate_addr
+    }
+
     /// Returns all local addresses of this rendezvous server, expanding the unspecified address
     /// into a vector of all local interface addresses.
     pub fn expanded_local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
@@ -85,6 +166,34 @@ impl UdpRendezvousServer {
     pub fn public_key(&self) -> &PublicEncryptKey {
         &self.our_pk
     }
+
+    /// Returns the limits of requests this server processes.
+    pub fn limits(&self) -> ServerLimits {
+        unwrap!(self.limiter.lock()).limits()
+    }
+
+    /// Changes the limits of requests this server processes. `max_tcp_connections` is ignored.
+    pub fn set_limits(&self, limits: ServerLimits) {
+        unwrap!(self.limiter.lock()).set_limits(limits)
+    }
+
+    /// Returns how many requests this server has dropped so far.
+    pub fn dropped_requests(&self) -> DroppedRequests {
+        unwrap!(self.limiter.lock()).dropped()
+    }
+
+    /// Makes the server answer echo requests only from clients that prove they receive packets
+    /// sent to their address. Unknown clients get a cookie they have to send back with the
+    /// request, which costs them an extra round trip, but keeps spoofed requests from turning the
+    /// server into a reflector. `RemoteUdpRendezvousServer` handles cookies transparently.
+    /// STUN clients can't prove their address this way, so STUN requests, including the ones
+    /// sent to the alternate addresses, are ignored while cookies are required. Requests shorter
+    /// than the challenge, 28 bytes, get no answer, since the challenge would make the server an
+    /// amplifier. Requests of `RemoteUdpRendezvousServer` are always longer, other clients must
+    /// pad theirs.
+    pub fn set_require_cookies(&self, required: bool) {
+        unwrap!(self.cookies.lock()).set_required(required)
+    }
 }
 
 /// Main UDP rendezvous server logic.
@@ -93,70 +202,278 @@ impl UdpRendezvousServer {
 fn from_socket_inner(
     socket: UdpSocket,
     bind_addr: &SocketAddr,
+    alternate: Option<Rc<AlternateSockets>>,
     handle: &Handle,
 ) -> UdpRendezvousServer {
     let (drop_tx, drop_rx) = drop_notify();
     let (our_pk, our_sk) = gen_encrypt_keypair();
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(ServerLimits::default())));
+    let cookies = Arc::new(Mutex::new(CookieJar::new()));
+    let alternate_addr = alternate.as_ref().map(|alternate| alternate.other_addr);
+    let alternate_drop_tx = alternate.as_ref().map(|alternate| {
+        let (alternate_drop_tx, alternate_drop_rx) = drop_notify();
+        let responder = AlternateResponder {
+            sockets: alternate.clone(),
+            limiter: limiter.clone(),
+            cookies: cookies.clone(),
+        };
+        handle.spawn(responder.until(alternate_drop_rx).map(|_| ()).infallible());
+        alternate_drop_tx
+    });
 
-    let f = {
-        let socket = SharedUdpSocket::share(socket);
-        trace!("rendezvous server starting");
-
-        socket
-            .map_err(RendezvousServerError::AcceptError)
-            .map(move |with_addr| {
-                trace!(
-                    "rendezvous server started conversation with {}",
-                    with_addr.remote_addr()
-                );
-
-                let our_sk = our_sk.clone();
-                with_addr
-                    .into_future()
-                    .map_err(|(e, _with_addr)| RendezvousServerError::ReadError(e))
-                    .and_then(move |(msg_opt, with_addr)| match msg_opt {
-                        Some(msg) => on_addr_echo_request(&msg, with_addr, &our_sk, &our_pk),
-                        None => future::ok(()).into_boxed(),
-                    })
-            }).buffer_unordered(1024)
-            .log_errors(LogLevel::Info, "processing echo request")
-            .until(drop_rx)
-            .for_each(|()| Ok(()))
-            .map(|x| {
-                trace!("rendezvous server exiting");
-                x
-            }).infallible()
+    trace!("rendezvous server starting");
+    let responder = Responder {
+        socket,
+        our_sk,
+        our_pk,
+        limiter: limiter.clone(),
+        cookies: cookies.clone(),
+        alternate,
     };
-    handle.spawn(f);
+    handle.spawn(
+        responder
+            .until(drop_rx)
+            .map(|_| trace!("rendezvous server exiting"))
+            .infallible(),
+    );
     UdpRendezvousServer {
         _drop_tx: drop_tx,
+        _alternate_drop_tx: alternate_drop_tx,
         our_pk,
         local_addr: *bind_addr,
+        alternate_addr,
+        limiter,
+        cookies,
     }
 }
 
-/// Handles randezvous server request.
-///
-/// Reponds with client address.
-fn on_addr_echo_request(
+/// Answers requests sent to the primary address of the server. Requests are read straight from
+/// the socket and checked for cookies, if those are required, before any state is kept for the
+/// client address. Hence spoofed requests only ever cost the server a challenge.
+struct Responder {
+    socket: UdpSocket,
+    our_sk: SecretEncryptKey,
+    our_pk: PublicEncryptKey,
+    limiter: Arc<Mutex<RateLimiter>>,
+    cookies: Arc<Mutex<CookieJar>>,
+    alternate: Option<Rc<AlternateSockets>>,
+}
+
+impl Responder {
+    /// Handles randezvous server request.
+    ///
+    /// Reponds with client address. If cookies are required, clients without a valid one get a
+    /// challenge instead and STUN requests, which can't carry our cookies, are ignored. Requests
+    /// are only counted towards `ServerLimits` once the client address is verified.
+    fn on_request(&self, msg: &[u8], addr: SocketAddr) -> Result<(), RendezvousServerError> {
+        trace!("udp rendezvous server received message from {}", addr);
+        let (cookie, sealed_request) = match cookie::split_cookie(msg) {
+            Some((cookie, sealed_request)) => (Some(cookie), sealed_request),
+            None => (None, msg),
+        };
+        {
+            let cookies = unwrap!(self.cookies.lock());
+            if cookies.required() {
+                if stun::is_stun(msg) {
+                    trace!("udp rendezvous server ignoring STUN request from unverified {}", addr);
+                    return Ok(());
+                }
+                let now = Instant::now();
+                let valid = cookie.map_or(false, |cookie| cookies.verify(cookie, addr, now));
+                if !valid {
+                    trace!("udp rendezvous server challenging {} with a cookie", addr);
+                    return match cookies.challenge(addr, msg.len(), now) {
+                        Some(challenge) => send_response(&self.socket, addr, &challenge),
+                        None => Ok(()),
+                    };
+                }
+            }
+        }
+
+        if !unwrap!(self.limiter.lock()).allow(addr.ip(), Instant::now()) {
+            trace!("rendezvous server dropped rate limited request from {}", addr);
+            return Ok(());
+        }
+        if stun::is_stun(msg) {
+            return on_stun_request(msg, &self.socket, addr, self.alternate.as_ref().map(|a| &**a));
+        }
+        let envelope: Envelope = self
+            .our_sk
+            .anonymously_decrypt(sealed_request, &self.our_pk)
+            .map_err(RendezvousServerError::Decrypt)?;
+        if let Err(e) = envelope.negotiate_version(Capabilities::empty()) {
+            debug!("client {} speaks incompatible protocol: {}", addr, e);
+            let resp =
+                version::unsupported_version_response().map_err(RendezvousServerError::Serialize)?;
+            return send_response(&self.socket, addr, &resp);
+        }
+        let request: EchoRequest = envelope.open().map_err(RendezvousServerError::Deserialize)?;
+
+        trace!("udp rendezvous server received echo request from {}", addr);
+        let shared_secret = self.our_sk.shared_secret(&request.client_pk);
+        let response = addr_response(addr, &shared_secret)?;
+        send_response(&self.socket, addr, &response)
+    }
+}
+
+impl Future for Responder {
+    type Item = ();
+    type Error = Void;
+
+    fn poll(&mut self) -> Result<Async<()>, Void> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => {
+                    debug!("rendezvous server failed to read from socket: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.on_request(&buffer[..len], addr) {
+                info!("error processing echo request: {}", e);
+            }
+        }
+    }
+}
+
+/// Sends response to `addr`. Clients retransmit requests, so a response dropped due to a full
+/// buffer is fine.
+fn send_response(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    response: &[u8],
+) -> Result<(), RendezvousServerError> {
+    match socket.send_to(response, &addr) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(RendezvousServerError::SendError(e)),
+    }
+}
+
+/// Answers STUN Binding request with client address. If the server has an alternate address,
+/// the response advertises it and honours `CHANGE-REQUEST`.
+fn on_stun_request(
     msg: &[u8],
-    with_addr: WithAddress,
-    our_sk: &SecretEncryptKey,
-    our_pk: &PublicEncryptKey,
-) -> BoxFuture<(), RendezvousServerError> {
-    let addr = with_addr.remote_addr();
-    trace!("udp rendezvous server received message from {}", addr);
-    let request: EchoRequest = try_bfut!(
-        our_sk
-            .anonymously_decrypt(msg, our_pk)
-            .map_err(RendezvousServerError::Decrypt)
-    );
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    alternate: Option<&AlternateSockets>,
+) -> Result<(), RendezvousServerError> {
+    trace!("udp rendezvous server received STUN message from {}", addr);
+    let request = match stun::decode_binding_request(msg, addr) {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let response = stun::binding_response(&request, addr);
+    let alternate = match alternate {
+        Some(alternate) => alternate,
+        None => return send_response(socket, addr, &response.encode(None)),
+    };
 
-    trace!("udp rendezvous server received echo request from {}", addr);
-    let shared_secret = our_sk.shared_secret(&request.client_pk);
-    respond_with_addr(with_addr, addr, &shared_secret)
-        .map(|_with_addr| ())
-        .into_boxed()
+    let (change_ip, change_port) = request.change_request();
+    let socket = match alternate.changed(change_ip, change_port) {
+        Some(socket) => socket,
+        None => {
+            let response = response
+                .with(Attribute::ResponseOrigin(alternate.primary_addr))
+                .with(Attribute::OtherAddress(alternate.other_addr));
+            return send_response(socket, addr, &response.encode(None));
+        }
+    };
+    let origin = socket.local_addr().map_err(RendezvousServerError::SendError)?;
+    let response = response
+        .with(Attribute::ResponseOrigin(origin))
+        .with(Attribute::OtherAddress(alternate.other_addr));
+    send_response(socket, addr, &response.encode(None))
+}
+
+/// Sockets bound to the alternate IP and/or port of the server, which let clients discover the
+/// behaviour of their NAT.
+struct AlternateSockets {
+    primary_addr: SocketAddr,
+    other_addr: SocketAddr,
+    change_ip: UdpSocket,
+    change_port: UdpSocket,
+    change_both: UdpSocket,
+}
+
+impl AlternateSockets {
+    /// Returns the socket to respond from, if the client asked for a change.
+    fn changed(&self, change_ip: bool, change_port: bool) -> Option<&UdpSocket> {
+        match (change_ip, change_port) {
+            (false, false) => None,
+            (true, false) => Some(&self.change_ip),
+            (false, true) => Some(&self.change_port),
+            (true, true) => Some(&self.change_both),
+        }
+    }
+}
+
+/// Answers STUN Binding requests sent to the alternate addresses. `CHANGE-REQUEST` is only
+/// honoured for requests sent to the primary address, which is all RFC 5780 discovery needs.
+/// Requests count towards the same limits as the requests sent to the primary address and are
+/// ignored while cookies are required, before any state is kept for the client address.
+struct AlternateResponder {
+    sockets: Rc<AlternateSockets>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    cookies: Arc<Mutex<CookieJar>>,
+}
+
+impl AlternateResponder {
+    /// Answers requests until the socket has nothing more to read. Read errors, such as ICMP
+    /// errors reported for earlier responses, only affect a single datagram.
+    fn poll_socket(&self, socket: &UdpSocket) {
+        let mut buffer = [0u8; 512];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("rendezvous server failed to read from alternate socket: {}", e);
+                    continue;
+                }
+            };
+            if unwrap!(self.cookies.lock()).required() {
+                trace!("rendezvous server ignoring STUN request from unverified {}", addr);
+                continue;
+            }
+            if !unwrap!(self.limiter.lock()).allow(addr.ip(), Instant::now()) {
+                trace!("rendezvous server dropped rate limited request from {}", addr);
+                continue;
+            }
+            let request = match stun::decode_binding_request(&buffer[..len], addr) {
+                Some(request) => request,
+                None => continue,
+            };
+            let origin = match socket.local_addr() {
+                Ok(origin) => origin,
+                Err(e) => {
+                    debug!("failed to get alternate socket address: {}", e);
+                    continue;
+                }
+            };
+            let response = stun::binding_response(&request, addr)
+                .with(Attribute::ResponseOrigin(origin))
+                .with(Attribute::OtherAddress(self.sockets.other_addr));
+            if let Err(e) = socket.send_to(&response.encode(None), &addr) {
+                debug!("failed to respond to STUN request from {}: {}", addr, e);
+            }
+        }
+    }
+}
+
+impl Future for AlternateResponder {
+    type Item = ();
+    type Error = Void;
+
+    fn poll(&mut self) -> Result<Async<()>, Void> {
+        let sockets = &*self.sockets;
+        for socket in &[&sockets.change_ip, &sockets.change_port, &sockets.change_both] {
+            self.poll_socket(socket);
+        }
+        Ok(Async::NotReady)
+    }
 }
 
 #[cfg(test)]
@@ -207,7 +524,7 @@ mod test {
             let server_addr = server.local_addr().unspecified_to_localhost();
             let server_pk = server.public_key();
             let (client_pk, _) = gen_encrypt_keypair();
-            let request = EchoRequest { client_pk };
+            let request = unwrap!(version::seal_server_msg(&EchoRequest { client_pk }));
             let encrypted_request =
                 BytesMut::from(unwrap!(server_pk.anonymously_encrypt(&request)));
             let (_, sk) = gen_encrypt_keypair();
@@ -227,7 +544,7 @@ mod test {
                             .map(|msg_opt| {
                                 let (_socket, msg, n, addr) = unwrap!(msg_opt);
                                 assert_eq!(addr, server_addr);
-                                match invalid_shared_secret.decrypt::<EchoRequest>(&msg[..n]) {
+                                match invalid_shared_secret.decrypt::<Envelope>(&msg[..n]) {
                                     Err(_) => (),
                                     Ok(x) => panic!("unexpected success: {:?}", x),
                                 }
@@ -237,5 +554,172 @@ mod test {
 
             evloop.run(f).void_unwrap()
         }
+
+        #[test]
+        fn it_answers_stun_binding_requests() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            let server_addr = server.local_addr().unspecified_to_localhost();
+            let querier = StunUdpAddrQuerier::new(server_addr);
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let our_addr = unwrap!(evloop.run(querier.query(&bind_addr, &handle)));
+
+            assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
+            assert_ne!(our_addr.port(), 0);
+        }
+
+        #[test]
+        fn it_drops_requests_exceeding_per_ip_limit() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            server.set_limits(ServerLimits {
+                per_ip_rate: 0,
+                per_ip_burst: 1,
+                ..ServerLimits::default()
+            });
+            let server_addr = server.local_addr().unspecified_to_localhost();
+
+            let sockets: Vec<_> = (0..3)
+                .map(|_| unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle)))
+                .collect();
+            for socket in &sockets {
+                unwrap!(socket.send_to(b"not a request", &server_addr));
+            }
+            evloop
+                .run(Timeout::new(Duration::from_millis(200), &handle))
+                .void_unwrap();
+
+            assert_eq!(server.dropped_requests().per_ip_limit, 2);
+        }
+
+        #[test]
+        fn it_drops_requests_to_alternate_address_exceeding_per_ip_limit() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind_with_alternate(
+                &addr!("127.0.0.1:0"),
+                &addr!("127.0.0.2:0"),
+                &handle,
+            ));
+            server.set_limits(ServerLimits {
+                per_ip_rate: 0,
+                per_ip_burst: 1,
+                ..ServerLimits::default()
+            });
+            let alternate_addr = unwrap!(server.alternate_addr());
+
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            for _ in 0..3 {
+                unwrap!(socket.send_to(b"not a request", &alternate_addr));
+            }
+            evloop
+                .run(Timeout::new(Duration::from_millis(200), &handle))
+                .void_unwrap();
+
+            assert_eq!(server.dropped_requests().per_ip_limit, 2);
+        }
+
+        #[test]
+        fn when_cookies_are_required_it_challenges_requests_without_cookie() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            server.set_require_cookies(true);
+            let server_addr = server.local_addr().unspecified_to_localhost();
+            let (client_pk, _) = gen_encrypt_keypair();
+            let request = unwrap!(version::seal_server_msg(&EchoRequest { client_pk }));
+            let encrypted_request =
+                BytesMut::from(unwrap!(server.public_key().anonymously_encrypt(&request)));
+
+            let socket = unwrap!(UdpSocket::bind(&addr!("0.0.0.0:0"), &handle));
+
+            let f = {
+                socket
+                    .send_dgram(encrypted_request, server_addr)
+                    .map_err(|e| panic!("error sending: {}", e))
+                    .and_then(|(socket, _msg)| {
+                        socket
+                            .recv_dgram(util::zeroed_vec(256))
+                            .map_err(|e| panic!("error receiving: {}", e))
+                            .with_timeout(Duration::from_secs(1), &handle)
+                            .map(|msg_opt| {
+                                let (_socket, msg, n, _addr) = unwrap!(msg_opt);
+                                assert!(cookie::open_challenge(&msg[..n]).is_some());
+                            })
+                    })
+            };
+
+            evloop.run(f).void_unwrap()
+        }
+
+        #[test]
+        fn when_cookies_are_required_unverified_requests_dont_count_towards_limits() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            server.set_require_cookies(true);
+            server.set_limits(ServerLimits {
+                per_ip_rate: 0,
+                per_ip_burst: 1,
+                ..ServerLimits::default()
+            });
+            let server_addr = server.local_addr().unspecified_to_localhost();
+
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            for _ in 0..3 {
+                unwrap!(socket.send_to(&[0u8; 64], &server_addr));
+            }
+            evloop
+                .run(Timeout::new(Duration::from_millis(200), &handle))
+                .void_unwrap();
+
+            assert_eq!(server.dropped_requests(), DroppedRequests::default());
+        }
+
+        #[test]
+        fn when_cookies_are_required_it_ignores_stun_requests() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind_with_alternate(
+                &addr!("127.0.0.1:0"),
+                &addr!("127.0.0.2:0"),
+                &handle,
+            ));
+            server.set_require_cookies(true);
+            let request = stun::StunMessage::request(stun::BINDING)
+                .with(Attribute::ChangeRequest {
+                    change_ip: true,
+                    change_port: true,
+                }).encode(None);
+
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            unwrap!(socket.send_to(&request, &server.local_addr()));
+            unwrap!(socket.send_to(&request, &unwrap!(server.alternate_addr())));
+
+            let f = socket
+                .recv_dgram(util::zeroed_vec(256))
+                .map_err(|e| panic!("error receiving: {}", e))
+                .with_timeout(Duration::from_millis(500), &handle)
+                .map(|msg_opt| assert!(msg_opt.is_none()));
+            evloop.run(f).void_unwrap()
+        }
+
+        #[test]
+        fn when_cookies_are_required_remote_server_query_still_succeeds() {
+            let mut evloop = unwrap!(Core::new());
+            let handle = evloop.handle();
+            let server = unwrap!(UdpRendezvousServer::bind(&addr!("0.0.0.0:0"), &handle));
+            server.set_require_cookies(true);
+            let server_addr = server.local_addr().unspecified_to_localhost();
+            let querier = RemoteUdpRendezvousServer::new(server_addr, *server.public_key());
+
+            let bind_addr = addr!("127.0.0.1:0");
+            let our_addr = unwrap!(evloop.run(querier.query(&bind_addr, &handle)));
+
+            assert_eq!(our_addr.ip(), ipv4!("127.0.0.1"));
+        }
     }
 }
@@ -0,0 +1,341 @@
+//! Encrypted datagram session over a hole punched UDP socket.
+
+use futures::StartSend;
+use priv_prelude::*;
+use udp::socket::{decrypt_hole_punch_msg, encrypt_hole_punch_msg, HolePunchError, HolePunchMsg};
+use udp::turn::TurnProxyHandle;
+
+/// How many of the most recent sequence numbers are remembered. Datagrams older than that are
+/// dropped.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+quick_error! {
+    /// Errors returned by `UdpSession`.
+    #[derive(Debug)]
+    pub enum UdpSessionError {
+        /// Failure to read from the socket.
+        Read(e: io::Error) {
+            description("error reading from socket")
+            display("error reading from socket: {}", e)
+            cause(e)
+        }
+        /// Failure to write to the socket.
+        Write(e: io::Error) {
+            description("error writing to socket")
+            display("error writing to socket: {}", e)
+            cause(e)
+        }
+        /// Failure to encrypt datagram.
+        Encrypt(e: HolePunchError) {
+            description("error encrypting datagram")
+            display("error encrypting datagram: {}", e)
+            cause(e)
+        }
+    }
+}
+
+/// Stream and sink of datagrams exchanged with the peer over a socket returned by
+/// `UdpSocketExt::rendezvous_connect`. Every datagram is encrypted and authenticated with the
+/// secret negotiated during rendezvous connect. Datagrams that fail authentication, come from
+/// addresses other than the peer's or were already received are silently dropped. Both peers use
+/// the same secret, so every datagram also carries the role of its sender and our own datagrams
+/// reflected back to us are dropped too.
+pub struct UdpSession {
+    socket: UdpSocket,
+    their_addr: SocketAddr,
+    shared_secret: SharedSecretKey,
+    initiator: bool,
+    next_seq: u64,
+    replay_window: ReplayWindow,
+    turn_proxy: Option<TurnProxyHandle>,
+}
+
+impl UdpSession {
+    /// Starts a session with the peer at `their_addr`. `initiator` must be `true` for exactly one
+    /// of the peers, see `UdpRendezvousConnection::initiator`.
+    pub fn new(
+        socket: UdpSocket,
+        their_addr: SocketAddr,
+        shared_secret: SharedSecretKey,
+        initiator: bool,
+    ) -> UdpSession {
+        UdpSession {
+            socket,
+            their_addr,
+            shared_secret,
+            initiator,
+            next_seq: 1,
+            replay_window: ReplayWindow::new(),
+            turn_proxy: None,
+        }
+    }
+
+    /// Returns the remote peer address.
+    pub fn their_addr(&self) -> SocketAddr {
+        self.their_addr
+    }
+
+    /// Returns the secret shared with the remote peer.
+    pub fn shared_secret(&self) -> &SharedSecretKey {
+        &self.shared_secret
+    }
+
+    /// Keeps the TURN proxy the connection goes via running for as long as this value lives.
+    pub fn set_turn_proxy(&mut self, turn_proxy: TurnProxyHandle) {
+        self.turn_proxy = Some(turn_proxy);
+    }
+
+    /// Returns the underlying socket. The TURN proxy set via `set_turn_proxy()`, if any, stops.
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+}
+
+impl Stream for UdpSession {
+    type Item = Bytes;
+    type Error = UdpSessionError;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, UdpSessionError> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(UdpSessionError::Read(e)),
+            };
+            if addr != self.their_addr {
+                trace!("ignoring datagram from unknown address {}", addr);
+                continue;
+            }
+            match decrypt_hole_punch_msg(&buffer[..len], &self.shared_secret) {
+                Ok(HolePunchMsg::Data { initiator, .. }) if initiator == self.initiator => {
+                    debug!("dropping our own datagram reflected back to us");
+                }
+                Ok(HolePunchMsg::Data { seq, payload, .. }) => {
+                    if self.replay_window.accept(seq) {
+                        return Ok(Async::Ready(Some(Bytes::from(payload))));
+                    }
+                    debug!("dropping replayed datagram #{}", seq);
+                }
+                Ok(msg) => trace!("ignoring hole punching message: {:?}", msg),
+                Err(e) => debug!("dropping invalid datagram: {}", e),
+            }
+        }
+    }
+}
+
+impl Sink for UdpSession {
+    type SinkItem = Bytes;
+    type SinkError = UdpSessionError;
+
+    fn start_send(&mut self, item: Bytes) -> StartSend<Bytes, UdpSessionError> {
+        let msg = HolePunchMsg::Data {
+            initiator: self.initiator,
+            seq: self.next_seq,
+            payload: item.to_vec(),
+        };
+        let msg =
+            encrypt_hole_punch_msg(&msg, &self.shared_secret).map_err(UdpSessionError::Encrypt)?;
+        match self.socket.send_to(&msg, &self.their_addr) {
+            Ok(_) => {
+                self.next_seq += 1;
+                Ok(AsyncSink::Ready)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(AsyncSink::NotReady(item)),
+            Err(e) => Err(UdpSessionError::Write(e)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, UdpSessionError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Sliding window of received sequence numbers. Datagrams may arrive out of order, so we remember
+/// which of the last `REPLAY_WINDOW_SIZE` sequence numbers were seen.
+pub struct ReplayWindow {
+    /// Highest sequence number received so far, 0 if none.
+    max_seq: u64,
+    /// `i`-th bit is set if `max_seq - i` was received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> ReplayWindow {
+        ReplayWindow { max_seq: 0, seen: 0 }
+    }
+
+    /// Returns `true` and remembers the sequence number, if it wasn't seen before.
+    pub fn accept(&mut self, seq: u64) -> bool {
+        if seq == 0 {
+            return false;
+        }
+        if seq > self.max_seq {
+            let shift = seq - self.max_seq;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.max_seq = seq;
+            return true;
+        }
+
+        let offset = self.max_seq - seq;
+        if offset >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+        let mask = 1 << offset;
+        if self.seen & mask != 0 {
+            return false;
+        }
+        self.seen |= mask;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    mod replay_window {
+        use super::*;
+
+        #[test]
+        fn it_accepts_increasing_sequence_numbers() {
+            let mut window = ReplayWindow::new();
+
+            assert!(window.accept(1));
+            assert!(window.accept(2));
+            assert!(window.accept(5));
+        }
+
+        #[test]
+        fn it_rejects_repeated_sequence_numbers() {
+            let mut window = ReplayWindow::new();
+
+            assert!(window.accept(1));
+            assert!(window.accept(3));
+
+            assert!(!window.accept(1));
+            assert!(!window.accept(3));
+        }
+
+        #[test]
+        fn it_accepts_reordered_sequence_numbers_within_window() {
+            let mut window = ReplayWindow::new();
+
+            assert!(window.accept(10));
+            assert!(window.accept(8));
+            assert!(window.accept(9));
+
+            assert!(!window.accept(8));
+        }
+
+        #[test]
+        fn it_rejects_sequence_numbers_older_than_window() {
+            let mut window = ReplayWindow::new();
+
+            assert!(window.accept(100));
+
+            assert!(!window.accept(100 - REPLAY_WINDOW_SIZE));
+            assert!(window.accept(100 - REPLAY_WINDOW_SIZE + 1));
+        }
+
+        #[test]
+        fn it_rejects_zero() {
+            let mut window = ReplayWindow::new();
+
+            assert!(!window.accept(0));
+        }
+    }
+
+    mod udp_session {
+        use super::*;
+
+        #[test]
+        fn it_exchanges_encrypted_datagrams_and_drops_replays() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let sock0 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let sock1 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let addr0 = unwrap!(sock0.local_addr());
+            let addr1 = unwrap!(sock1.local_addr());
+            let (pk0, sk0) = gen_encrypt_keypair();
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let shared_secret = sk0.shared_secret(&pk1);
+            let session0 = UdpSession::new(sock0, addr1, sk0.shared_secret(&pk1), true);
+            let session1 = UdpSession::new(sock1, addr0, sk1.shared_secret(&pk0), false);
+
+            // an attacker replays the first datagram and sends unauthenticated garbage
+            let replayed = unwrap!(encrypt_hole_punch_msg(
+                &HolePunchMsg::Data {
+                    initiator: true,
+                    seq: 1,
+                    payload: b"hello".to_vec(),
+                },
+                &shared_secret,
+            ));
+            let send = session0
+                .send(Bytes::from(&b"hello"[..]))
+                .and_then(|session0| {
+                    let _ = unwrap!(session0.socket.send_to(&replayed, &addr1));
+                    let _ = unwrap!(session0.socket.send_to(b"garbage", &addr1));
+                    session0.send(Bytes::from(&b"world"[..]))
+                }).map_err(|e| panic!("send error: {}", e));
+            let recv = session1
+                .take(2)
+                .collect()
+                .map_err(|e| panic!("recv error: {}", e));
+
+            let (_session0, msgs) = unwrap!(core.run(send.join(recv)));
+
+            assert_eq!(
+                msgs,
+                vec![Bytes::from(&b"hello"[..]), Bytes::from(&b"world"[..])]
+            );
+        }
+
+        #[test]
+        fn it_drops_its_own_datagrams_reflected_back() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+
+            let sock0 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let sock1 = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let addr0 = unwrap!(sock0.local_addr());
+            let addr1 = unwrap!(sock1.local_addr());
+            let (pk0, sk0) = gen_encrypt_keypair();
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let session0 = UdpSession::new(sock0, addr1, sk0.shared_secret(&pk1), true);
+            let session1 = UdpSession::new(sock1, addr0, sk1.shared_secret(&pk0), false);
+
+            // an attacker captures a datagram of session1 and sends it back from the peer address
+            let reflected = unwrap!(encrypt_hole_punch_msg(
+                &HolePunchMsg::Data {
+                    initiator: false,
+                    seq: 1,
+                    payload: b"reflected".to_vec(),
+                },
+                session1.shared_secret(),
+            ));
+            let _ = unwrap!(session0.socket.send_to(&reflected, &addr1));
+            let send = session0
+                .send(Bytes::from(&b"hello"[..]))
+                .map_err(|e| panic!("send error: {}", e));
+            let recv = session1
+                .into_future()
+                .map(|(msg_opt, _session1)| unwrap!(msg_opt))
+                .map_err(|(e, _session1)| panic!("recv error: {}", e));
+
+            let (_session0, msg) = unwrap!(core.run(send.join(recv)));
+
+            assert_eq!(msg, Bytes::from(&b"hello"[..]));
+        }
+    }
+}
@@ -1,24 +1,94 @@
 use futures::future::Loop;
-use futures::stream::FuturesUnordered;
-use maidsafe_utilities::serialisation;
+use futures::stream::{FuturesUnordered, SplitSink, SplitStream};
+use hairpin::detect_hairpin;
+use identity::{prove_identity, verify_identity, IdentityError, RendezvousAuth};
 use open_addr::{open_addr, BindPublicError};
+use port_mapping::PortMapping;
 use priv_prelude::*;
-use rendezvous_addr::{rendezvous_addr, RendezvousAddrError};
+use relay::{choose_relay, RelayError};
+use rendezvous_addr::{rendezvous_candidates, RendezvousAddrError};
+use socket_addr::ipv6_addrs;
 use std::error::Error;
 use tokio_shared_udp_socket::{SharedUdpSocket, WithAddress};
-
-const RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC: u64 = 120;
-const HOLE_PUNCH_DELAY_TOLERANCE_SEC: u64 = 120;
-const HOLE_PUNCH_INITIAL_TTL: u32 = 2;
+use udp::keepalive::UdpKeepalive;
+use udp::relay::{relay_connect, RemoteUdpRelayServer};
+use udp::session::UdpSession;
+use udp::turn::{
+    choose_owner, exchange_candidate, turn_connect, TurnCandidate, TurnError, TurnProxyHandle,
+};
+use udp::{birthday, trickle};
+use version::{self, Capabilities, Envelope, OpenEnvelopeError, VersionMismatch};
+
+pub const RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC: u64 = 120;
+pub const HOLE_PUNCH_DELAY_TOLERANCE_SEC: u64 = 120;
+pub const HOLE_PUNCH_INITIAL_TTL: u32 = 2;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum UdpRendezvousMsg {
     Init {
         enc_pk: PublicEncryptKey,
-        rendezvous_addrs: Vec<SocketAddr>,
+        /// Proof that `enc_pk` belongs to our long-term identity, if peers authenticate each
+        /// other.
+        identity_proof: Option<Vec<u8>>,
+        /// Addresses of each of our hole punching sockets.
+        sockets: Vec<PunchingSocketAddrs>,
+        /// Our public IP, if our NAT allocates ports randomly. In such case rendezvous addresses
+        /// of our sockets are empty and birthday paradox hole punching is used.
+        random_ports_ip: Option<IpAddr>,
+        /// Addresses of our IPv6 hole punching socket, if IPv6 is available. Global IPv6
+        /// addresses are used as rendezvous addresses.
+        ipv6_socket: Option<PunchingSocketAddrs>,
+        /// Relay server we offer to use, if hole punching fails.
+        relay: Option<RemoteUdpRelayServer>,
+        /// Whether we are able to allocate a relayed address on our TURN server, once hole
+        /// punching fails.
+        turn: bool,
+    },
+    /// Trickle mode: the first message, sent before any of our addresses are known.
+    Hello {
+        enc_pk: PublicEncryptKey,
+        identity_proof: Option<Vec<u8>>,
+        relay: Option<RemoteUdpRelayServer>,
+        turn: bool,
+    },
+    /// Trickle mode: addresses of our `index`-th hole punching socket.
+    Candidate {
+        index: usize,
+        addrs: PunchingSocketAddrs,
+    },
+    /// Trickle mode: addresses of our IPv6 hole punching socket.
+    Ipv6Candidate(PunchingSocketAddrs),
+    /// Relayed address we allocated on our TURN server once hole punching failed, `None` if the
+    /// allocation failed. Sent after all other messages by the peer that owns the TURN candidate.
+    TurnCandidate(Option<SocketAddr>),
+    /// Trickle mode: all our candidates were sent.
+    End {
+        /// Our public IP, if our NAT allocates ports randomly.
+        random_ports_ip: Option<IpAddr>,
     },
 }
 
+/// Addresses a single hole punching socket can be reached on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PunchingSocketAddrs {
+    /// Candidate public addresses, most likely first.
+    pub rendezvous_addrs: Vec<SocketAddr>,
+    /// Addresses on our network interfaces. These are the only ones that work when both peers
+    /// are on the same LAN behind a NAT that doesn't support hairpinning.
+    pub local_addrs: Vec<SocketAddr>,
+}
+
+impl PunchingSocketAddrs {
+    /// Returns both rendezvous and local addresses.
+    pub fn all(&self) -> Vec<SocketAddr> {
+        self.rendezvous_addrs
+            .iter()
+            .chain(&self.local_addrs)
+            .cloned()
+            .collect()
+    }
+}
+
 /// Errors returned by `UdpSocketExt::rendezvous_connect`.
 #[derive(Debug)]
 pub enum UdpRendezvousConnectError<Ei, Eo> {
@@ -52,6 +122,17 @@ pub enum UdpRendezvousConnectError<Ei, Eo> {
     AllAttemptsFailed(Vec<HolePunchError>, Vec<RendezvousAddrError>),
     /// Failure to get rendezvous address.
     RendezvousAddrErrors(Vec<RendezvousAddrError>),
+    /// Peer sent a rendezvous message we didn't expect. This happens when only one of the peers
+    /// has candidate trickling enabled.
+    UnexpectedMessage,
+    /// Peer speaks a rendezvous protocol we don't understand.
+    VersionMismatch(VersionMismatch),
+    /// Peer failed to prove it's the peer we expect to connect to.
+    Identity(IdentityError),
+    /// Hole punching failed and so did the fallback to relay server.
+    Relay(RelayError),
+    /// Hole punching failed and so did the fallback to TURN server.
+    Turn(TurnError),
 }
 
 impl<Ei, Eo> fmt::Display for UdpRendezvousConnectError<Ei, Eo>
@@ -100,9 +181,15 @@ where
             DeserializeMsg(ref e) => Some(e),
             Encrypt(ref e) => Some(e),
             Decrypt(ref e) => Some(e),
-            ChannelClosed | ChannelTimedOut | AllAttemptsFailed(..) | RendezvousAddrErrors(..) => {
-                None
-            }
+            VersionMismatch(ref e) => Some(e),
+            Identity(ref e) => Some(e),
+            Relay(ref e) => Some(e),
+            Turn(ref e) => Some(e),
+            ChannelClosed
+            | ChannelTimedOut
+            | AllAttemptsFailed(..)
+            | RendezvousAddrErrors(..)
+            | UnexpectedMessage => None,
         }
     }
 
@@ -125,11 +212,86 @@ where
             Decrypt(..) => "error decrypting message received from remote peer",
             AllAttemptsFailed(..) => "all attempts to contact the remote peer failed",
             RendezvousAddrErrors(..) => "failed to find rendezvous address",
+            UnexpectedMessage => "unexpected message received via rendezvous channel",
+            VersionMismatch(..) => "peer speaks incompatible rendezvous protocol",
+            Identity(..) => "failed to authenticate remote peer",
+            Relay(..) => "failed to relay connection via relay server",
+            Turn(..) => "failed to relay connection via TURN server",
         }
     }
 }
 
-type RendezvousConnectResult = (UdpSocket, SocketAddr, SocketAddr);
+impl<Ei, Eo> From<OpenEnvelopeError> for UdpRendezvousConnectError<Ei, Eo> {
+    fn from(e: OpenEnvelopeError) -> UdpRendezvousConnectError<Ei, Eo> {
+        match e {
+            OpenEnvelopeError::Deserialize(e) => UdpRendezvousConnectError::DeserializeMsg(e),
+            OpenEnvelopeError::VersionMismatch(e) => UdpRendezvousConnectError::VersionMismatch(e),
+        }
+    }
+}
+
+type RendezvousConnectResult = UdpRendezvousConnection;
+
+/// Socket hole punched by `UdpSocketExt::rendezvous_connect`.
+pub struct UdpRendezvousConnection {
+    /// Hole punched socket.
+    pub socket: UdpSocket,
+    /// Remote peer address. Its family tells whether IPv4 or IPv6 hole punching succeeded.
+    pub their_addr: SocketAddr,
+    /// Address datagrams for the peer should be sent to. Same as `their_addr`, unless the
+    /// connection goes via our TURN allocation, see `relayed`.
+    pub send_addr: SocketAddr,
+    /// Our public address used to punch a hole, if one was detected. If our NAT allocates ports
+    /// randomly, the port of this address is 0. If IPv4 hole punching was not possible, this is
+    /// our global IPv6 address.
+    pub our_public_addr: SocketAddr,
+    /// Secret negotiated with the remote peer during rendezvous connect.
+    pub shared_secret: SharedSecretKey,
+    /// Whether hole punching failed and the connection goes via relay server. In such case
+    /// `their_addr` is the address of the relay server. If the connection goes via TURN server,
+    /// `their_addr` is either the relayed address allocated by the peer or the peer's address as
+    /// seen by our TURN server. In the latter case `send_addr` is the address of a local proxy
+    /// forwarding datagrams via our allocation.
+    pub relayed: bool,
+    /// Whether we initiated the connection, which is true for exactly one of the peers. Peers tell
+    /// the directions of the connection apart by it, see `UdpSession`.
+    pub initiator: bool,
+    /// Set if the connection goes via our TURN allocation. The proxy forwards datagrams only for
+    /// as long as this handle is kept, so don't drop it before `socket`. `keepalive()` and
+    /// `session()` take care of that.
+    pub turn_proxy: Option<TurnProxyHandle>,
+}
+
+impl UdpRendezvousConnection {
+    /// Wraps the hole punched socket into `UdpKeepalive` which keeps NAT mappings alive.
+    pub fn keepalive(self, handle: &Handle) -> UdpKeepalive {
+        let mut keepalive = UdpKeepalive::new(
+            handle,
+            self.socket,
+            self.send_addr,
+            self.shared_secret,
+            self.initiator,
+        );
+        if let Some(turn_proxy) = self.turn_proxy {
+            keepalive.set_turn_proxy(turn_proxy);
+        }
+        keepalive
+    }
+
+    /// Wraps the hole punched socket into `UdpSession` which encrypts all datagrams.
+    pub fn session(self) -> UdpSession {
+        let mut session = UdpSession::new(
+            self.socket,
+            self.send_addr,
+            self.shared_secret,
+            self.initiator,
+        );
+        if let Some(turn_proxy) = self.turn_proxy {
+            session.set_turn_proxy(turn_proxy);
+        }
+        session
+    }
+}
 
 /// Extension methods for `UdpSocket`.
 pub trait UdpSocketExt {
@@ -149,14 +311,13 @@ pub trait UdpSocketExt {
 
     /// Perform a UDP rendezvous connection to another peer. Both peers must call this
     /// simultaneously and `channel` must provide a channel through which the peers can communicate
-    /// out-of-band.
+    /// out-of-band. If IPv6 is available, it is attempted alongside IPv4. If candidate trickling
+    /// is enabled in `mc`, `channel` must be able to carry multiple messages.
     ///
     /// # Returns
     ///
-    /// A future that yields a tuple of
-    /// 1. hole punched socket
-    /// 2. remote peer address
-    /// 3. our public address used to punch a hole, if one was detected
+    /// A future that yields the hole punched socket along with the remote peer address and the
+    /// secret negotiated with the peer. See `UdpRendezvousConnection`.
     fn rendezvous_connect<C>(
         channel: C,
         handle: &Handle,
@@ -169,6 +330,22 @@ pub trait UdpSocketExt {
         <C as Sink>::SinkError: fmt::Debug,
         C: 'static;
 
+    /// Like `rendezvous_connect()`, but also makes sure the remote peer holds the long-term
+    /// identity given in `auth`. Both peers must use this method. Fails with
+    /// `UdpRendezvousConnectError::Identity` if the peer can't prove its identity.
+    fn rendezvous_connect_authenticated<C>(
+        channel: C,
+        handle: &Handle,
+        mc: &P2p,
+        auth: &RendezvousAuth,
+    ) -> BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<C::Error, C::SinkError>>
+    where
+        C: Stream<Item = Bytes>,
+        C: Sink<SinkItem = Bytes>,
+        <C as Stream>::Error: fmt::Debug,
+        <C as Sink>::SinkError: fmt::Debug,
+        C: 'static;
+
     /// Send a datagram to the address previously bound via connect().
     fn send_dgram_connected<T>(self, buf: T) -> BoxFuture<(UdpSocket, T), io::Error>
     where
@@ -228,58 +405,23 @@ impl UdpSocketExt for UdpSocket {
         <C as Sink>::SinkError: fmt::Debug,
         C: 'static,
     {
-        let handle0 = handle.clone();
-        let handle1 = handle.clone();
-        let mc0 = mc.clone();
-        let (our_pk, our_sk) = gen_encrypt_keypair();
-        let our_sk0 = our_sk.clone();
-
-        trace!("starting rendezvous connect");
-        try_hole_punching(&handle0, &mc0, &our_sk0, &our_pk, channel)
-            .and_then(
-                move |(their_pk, incoming, our_public_addr, rendezvous_errors)| {
-                    let shared_secret = our_sk.shared_secret(&their_pk);
-                    if our_pk > their_pk {
-                        trace!("we are choosing the connection");
-                        incoming
-                            .and_then(|(socket, chosen)| {
-                                if chosen {
-                                    return Err(HolePunchError::UnexpectedMessage);
-                                }
-                                trace!("successful connection found!");
-                                Ok(socket)
-                            }).first_ok()
-                            .map_err(|v| {
-                                trace!("all attempts failed (us)");
-                                UdpRendezvousConnectError::AllAttemptsFailed(v, rendezvous_errors)
-                            }).and_then(move |socket| {
-                                choose(&handle1, shared_secret, socket, 0).map(
-                                    move |(socket, their_addr)| {
-                                        (socket, their_addr, our_public_addr)
-                                    },
-                                )
-                            }).into_boxed()
-                    } else {
-                        trace!("they are choosing the connection");
-                        incoming
-                            .map(move |(socket, chosen)| {
-                                if chosen {
-                                    return future::ok(got_chosen(socket)).into_boxed();
-                                }
-                                take_chosen(&handle1, shared_secret.clone(), socket)
-                            }).buffer_unordered(256)
-                            .filter_map(move |opt| {
-                                opt.map(move |(socket, their_addr)| {
-                                    (socket, their_addr, our_public_addr)
-                                })
-                            }).first_ok()
-                            .map_err(|v| {
-                                trace!("all attempts failed (them)");
-                                UdpRendezvousConnectError::AllAttemptsFailed(v, rendezvous_errors)
-                            }).into_boxed()
-                    }
-                },
-            ).into_boxed()
+        rendezvous_connect(channel, handle, mc, None)
+    }
+
+    fn rendezvous_connect_authenticated<C>(
+        channel: C,
+        handle: &Handle,
+        mc: &P2p,
+        auth: &RendezvousAuth,
+    ) -> BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<C::Error, C::SinkError>>
+    where
+        C: Stream<Item = Bytes>,
+        C: Sink<SinkItem = Bytes>,
+        <C as Stream>::Error: fmt::Debug,
+        <C as Sink>::SinkError: fmt::Debug,
+        C: 'static,
+    {
+        rendezvous_connect(channel, handle, mc, Some(auth))
     }
 
     /// Send a datagram to the address previously bound via connect().
@@ -311,13 +453,262 @@ impl UdpSocketExt for UdpSocket {
     }
 }
 
-type HolePunchingResult = (
+fn rendezvous_connect<C>(
+    channel: C,
+    handle: &Handle,
+    mc: &P2p,
+    auth: Option<&RendezvousAuth>,
+) -> BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<C::Error, C::SinkError>>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    let mc = &start_attempt(mc);
+    let handle0 = handle.clone();
+    let handle1 = handle.clone();
+    let handle2 = handle.clone();
+    let mc0 = mc.clone();
+    let mc1 = mc.clone();
+    let (our_pk, our_sk) = gen_encrypt_keypair();
+    let our_sk0 = our_sk.clone();
+
+    trace!("starting rendezvous connect");
+    let hole_punching = if mc0.is_candidate_trickling_enabled() {
+        trickle::try_hole_punching(&handle0, &mc0, &our_sk0, &our_pk, auth, channel)
+    } else {
+        try_hole_punching(&handle0, &mc0, &our_sk0, &our_pk, auth, channel)
+    };
+    hole_punching
+        .and_then(
+            move |(their_pk, incoming, our_public_addr, rendezvous_errors, relay, turn)| {
+                let shared_secret = our_sk.shared_secret(&their_pk);
+                let shared_secret1 = shared_secret.clone();
+                let direct = if our_pk > their_pk {
+                    trace!("we are choosing the connection");
+                    incoming
+                        .and_then(|(socket, chosen)| {
+                            if chosen {
+                                return Err(HolePunchError::UnexpectedMessage);
+                            }
+                            trace!("successful connection found!");
+                            Ok(socket)
+                        }).first_ok()
+                        .map_err(|v| {
+                            trace!("all attempts failed (us)");
+                            UdpRendezvousConnectError::AllAttemptsFailed(v, rendezvous_errors)
+                        }).and_then(move |socket| {
+                            choose(&handle1, shared_secret.clone(), socket, 0).map(
+                                move |(socket, their_addr)| UdpRendezvousConnection {
+                                    socket,
+                                    their_addr,
+                                    send_addr: their_addr,
+                                    our_public_addr,
+                                    shared_secret,
+                                    relayed: false,
+                                    initiator: true,
+                                    turn_proxy: None,
+                                },
+                            )
+                        }).into_boxed()
+                } else {
+                    trace!("they are choosing the connection");
+                    let shared_secret0 = shared_secret.clone();
+                    incoming
+                        .map(move |(socket, chosen)| {
+                            if chosen {
+                                return future::ok(got_chosen(socket)).into_boxed();
+                            }
+                            take_chosen(&handle1, shared_secret0.clone(), socket)
+                        }).buffer_unordered(256)
+                        .filter_map(move |opt| {
+                            opt.map(|(socket, their_addr)| UdpRendezvousConnection {
+                                socket,
+                                their_addr,
+                                send_addr: their_addr,
+                                our_public_addr,
+                                shared_secret: shared_secret.clone(),
+                                relayed: false,
+                                initiator: false,
+                                turn_proxy: None,
+                            })
+                        }).first_ok()
+                        .map_err(|v| {
+                            trace!("all attempts failed (them)");
+                            UdpRendezvousConnectError::AllAttemptsFailed(v, rendezvous_errors)
+                        }).into_boxed()
+                };
+                match relay {
+                    Some(relay) => {
+                        relay_if_failed(&handle2, direct, relay, &our_pk, &our_sk, &their_pk)
+                    }
+                    None => turn_if_failed(
+                        &handle2,
+                        direct,
+                        turn,
+                        our_public_addr,
+                        &shared_secret1,
+                        our_pk > their_pk,
+                    ),
+                }
+            },
+        ).map(move |conn| {
+            emit_event(
+                &mc1,
+                RendezvousEventKind::CandidateChosen {
+                    protocol: Protocol::Udp,
+                    their_addr: conn.their_addr,
+                },
+            );
+            conn
+        }).into_boxed()
+}
+
+/// Falls back to the relay server, if all attempts to punch a hole to the peer fail.
+fn relay_if_failed<Ei, Eo>(
+    handle: &Handle,
+    direct: BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<Ei, Eo>>,
+    relay: RemoteUdpRelayServer,
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    their_pk: &PublicEncryptKey,
+) -> BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let handle = handle.clone();
+    let our_pk = *our_pk;
+    let our_sk = our_sk.clone();
+    let their_pk = *their_pk;
+    direct
+        .or_else(move |e| match e {
+            UdpRendezvousConnectError::AllAttemptsFailed(errors, _rendezvous_errors) => {
+                debug!(
+                    "hole punching failed, falling back to relay server {}: {:?}",
+                    relay.addr(),
+                    errors
+                );
+                let relay_addr = relay.addr();
+                let shared_secret = our_sk.shared_secret(&their_pk);
+                let initiator = our_pk > their_pk;
+                relay_connect(&handle, &relay, &our_pk, &our_sk, &their_pk)
+                    .map(move |(socket, our_relayed_addr)| UdpRendezvousConnection {
+                        socket,
+                        their_addr: relay_addr,
+                        send_addr: relay_addr,
+                        our_public_addr: our_relayed_addr,
+                        shared_secret,
+                        relayed: true,
+                        initiator,
+                        turn_proxy: None,
+                    }).map_err(UdpRendezvousConnectError::Relay)
+                    .into_boxed()
+            }
+            e => future::err(e).into_boxed(),
+        }).into_boxed()
+}
+
+/// Falls back to the TURN candidate, if all attempts to punch a hole to the peer fail and either
+/// of the peers has a TURN server. `turn` allocates the relayed address only once polled.
+fn turn_if_failed<Ei, Eo>(
+    handle: &Handle,
+    direct: BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<Ei, Eo>>,
+    turn: BoxFuture<Option<TurnCandidate>, Void>,
+    our_public_addr: SocketAddr,
+    shared_secret: &SharedSecretKey,
+    initiator: bool,
+) -> BoxFuture<RendezvousConnectResult, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let handle = handle.clone();
+    let shared_secret = shared_secret.clone();
+    direct
+        .or_else(move |e| match e {
+            UdpRendezvousConnectError::AllAttemptsFailed(errors, rendezvous_errors) => turn
+                .infallible()
+                .and_then(move |candidate_opt| {
+                    let candidate = match candidate_opt {
+                        Some(candidate) => candidate,
+                        None => {
+                            return future::err(UdpRendezvousConnectError::AllAttemptsFailed(
+                                errors,
+                                rendezvous_errors,
+                            )).into_boxed()
+                        }
+                    };
+                    debug!("hole punching failed, falling back to TURN: {:?}", errors);
+                    turn_connect(&handle, candidate, &shared_secret)
+                        .map(move |conn| UdpRendezvousConnection {
+                            socket: conn.socket,
+                            their_addr: conn.their_addr,
+                            send_addr: conn.send_addr,
+                            our_public_addr: conn.our_relayed_addr.unwrap_or(our_public_addr),
+                            shared_secret,
+                            relayed: true,
+                            initiator,
+                            turn_proxy: conn.proxy,
+                        }).map_err(UdpRendezvousConnectError::Turn)
+                        .into_boxed()
+                }).into_boxed(),
+            e => future::err(e).into_boxed(),
+        }).into_boxed()
+}
+
+pub type HolePunchingResult = (
     PublicEncryptKey,
     BoxStream<(WithAddress, bool), HolePunchError>,
     SocketAddr, // our public address
     Vec<RendezvousAddrError>,
+    Option<RemoteUdpRelayServer>, // relay server both peers fall back to
+    BoxFuture<Option<TurnCandidate>, Void>, // TURN candidate both peers fall back to
 );
 
+/// Public IPs of the peer, which we permit to send data to our TURN allocation.
+pub fn public_ips(
+    sockets: &[PunchingSocketAddrs],
+    ipv6_socket: Option<&PunchingSocketAddrs>,
+    random_ports_ip: Option<IpAddr>,
+) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = sockets
+        .iter()
+        .chain(ipv6_socket)
+        .flat_map(|addrs| addrs.rendezvous_addrs.iter().map(|addr| addr.ip()))
+        .chain(random_ports_ip)
+        .collect();
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+/// Returns public IP we share with the peer, if the NAT we are both behind is known to not support
+/// hairpinning. Peer's public addresses are unreachable then, so only local ones are worth trying.
+/// Subscribers are notified whenever the peer shares our public IP.
+pub fn unreachable_shared_ip(
+    p2p: &P2p,
+    our_ips: &[IpAddr],
+    their_ips: &[IpAddr],
+) -> Option<IpAddr> {
+    let ip = *our_ips.iter().find(|ip| their_ips.contains(ip))?;
+    let hairpin = p2p.hairpin_support(ip);
+    emit_event(
+        p2p,
+        RendezvousEventKind::SharedPublicIp {
+            protocol: Protocol::Udp,
+            ip,
+            hairpin,
+        },
+    );
+    match hairpin {
+        Some(false) => Some(ip),
+        _ => None,
+    }
+}
+
 /// Hole punching is attempted when we fail to receive a public address: either via IGD
 /// or if we are behind a full cone NAT.
 fn try_hole_punching<C>(
@@ -325,6 +716,7 @@ fn try_hole_punching<C>(
     p2p: &P2p,
     our_sk: &SecretEncryptKey,
     our_pk: &PublicEncryptKey,
+    auth: Option<&RendezvousAuth>,
     conn_info_channel: C,
 ) -> BoxFuture<HolePunchingResult, UdpRendezvousConnectError<C::Error, C::SinkError>>
 where
@@ -338,74 +730,465 @@ where
     let p2p = p2p.clone();
     let our_sk = our_sk.clone();
     let our_pk = *our_pk;
+    let identity_proof = try_bfut!(
+        prove_identity(auth, &our_pk).map_err(UdpRendezvousConnectError::Encrypt)
+    );
+    let auth = auth.cloned();
 
+    let handle0 = handle.clone();
     hole_punching_sockets(&handle, &p2p)
         .and_then(move |(sockets, rendezvous_errors)| {
-            // All hole punching sockets should have the same rendezvous IP address, so we'll just
-            // take the first one.
-            let pub_addr_opt = sockets.iter().map(|(_socket, addr)| addr).cloned().nth(0);
-            if let Some(pub_addr) = pub_addr_opt {
-                Ok((sockets, pub_addr, rendezvous_errors))
-            } else {
-                Err(UdpRendezvousConnectError::RendezvousAddrErrors(
+            let ipv6_socket = ipv6_hole_punching_socket(&handle0);
+            let pub_addr_opt = our_public_addr(
+                sockets.iter().map(|(_socket, addrs)| addrs),
+                ipv6_socket.as_ref().map(|(_socket, addrs)| addrs),
+                &rendezvous_errors,
+            );
+            match pub_addr_opt {
+                Some((pub_addr, random_ports_ip)) => Ok((
+                    sockets,
+                    ipv6_socket,
+                    pub_addr,
+                    random_ports_ip,
                     rendezvous_errors,
-                ))
+                )),
+                None => Err(UdpRendezvousConnectError::RendezvousAddrErrors(
+                    rendezvous_errors,
+                )),
             }
-        }).and_then(move |(sockets, our_pub_addr, rendezvous_errors)| {
-            let (sockets, rendezvous_addrs): (_, Vec<SocketAddr>) =
-                sockets.into_iter().unzip::<_, _, Vec<_>, _>();
-            trace!("our rendezvous addresses are: {:#?}", rendezvous_addrs);
-            let msg = UdpRendezvousMsg::Init {
-                enc_pk: our_pk,
-                rendezvous_addrs,
-            };
+        }).and_then(
+            move |(
+                sockets,
+                ipv6_socket,
+                our_pub_addr,
+                our_random_ports_ip,
+                rendezvous_errors,
+            )| {
+                let (sockets, our_sockets_addrs): (_, Vec<PunchingSocketAddrs>) =
+                    sockets.into_iter().unzip::<_, _, Vec<_>, _>();
+                trace!("our hole punching socket addresses are: {:#?}", our_sockets_addrs);
+                let (ipv6_socket, our_ipv6_addrs) = match ipv6_socket {
+                    Some((socket, addrs)) => (Some(socket), Some(addrs)),
+                    None => (None, None),
+                };
+                trace!("our IPv6 hole punching socket addresses are: {:?}", our_ipv6_addrs);
+                let sent_addrs = our_sockets_addrs
+                    .iter()
+                    .chain(&our_ipv6_addrs)
+                    .flat_map(|addrs| addrs.all())
+                    .collect();
+                let our_ips = public_ips(&our_sockets_addrs, None, our_random_ports_ip);
+                let our_relay = p2p.udp_relay_server();
+                let our_turn = p2p.turn_server().is_some();
+                let msg = UdpRendezvousMsg::Init {
+                    enc_pk: our_pk,
+                    identity_proof,
+                    sockets: our_sockets_addrs,
+                    random_ports_ip: our_random_ports_ip,
+                    ipv6_socket: our_ipv6_addrs,
+                    relay: our_relay.clone(),
+                    turn: our_turn,
+                };
+
+                trace!("exchanging rendezvous info with peer");
+                emit_event(
+                    &p2p,
+                    RendezvousEventKind::CandidatesSent {
+                        protocol: Protocol::Udp,
+                        addrs: sent_addrs,
+                    },
+                );
+                exchange_msgs(&handle, &p2p, conn_info_channel, &msg)
+                    .and_then(move |(their_msg, channel_tx, channel_rx)| {
+                        let (
+                            their_pk,
+                            their_sockets,
+                            their_random_ports_ip,
+                            their_ipv6_addrs,
+                            their_relay,
+                            their_turn,
+                        ) = match their_msg {
+                            UdpRendezvousMsg::Init {
+                                enc_pk,
+                                identity_proof,
+                                sockets,
+                                random_ports_ip,
+                                ipv6_socket,
+                                relay,
+                                turn,
+                            } => {
+                                verify_identity(
+                                    auth.as_ref(),
+                                    &enc_pk,
+                                    identity_proof.as_ref().map(|proof| &proof[..]),
+                                ).map_err(UdpRendezvousConnectError::Identity)?;
+                                (enc_pk, sockets, random_ports_ip, ipv6_socket, relay, turn)
+                            }
+                            _ => return Err(UdpRendezvousConnectError::UnexpectedMessage),
+                        };
+                        trace!(
+                            "their hole punching socket addresses are: {:#?}",
+                            their_sockets
+                        );
+                        let their_ips = public_ips(
+                            &their_sockets,
+                            their_ipv6_addrs.as_ref(),
+                            their_random_ports_ip,
+                        );
+
+                        // Probes sprayed over peer's ports must reach their NAT, hence no low TTL
+                        // tricks in such case.
+                        let initial_ttl = match their_random_ports_ip {
+                            Some(_) => SANE_DEFAULT_TTL,
+                            None => HOLE_PUNCH_INITIAL_TTL,
+                        };
+                        let mut shared_sockets = Vec::with_capacity(sockets.len());
+                        for socket in sockets {
+                            socket
+                                .set_ttl(initial_ttl)
+                                .map_err(UdpRendezvousConnectError::SetTtl)?;
+                            shared_sockets.push(SharedUdpSocket::share(socket));
+                        }
 
-            trace!("exchanging rendezvous info with peer");
-            exchange_msgs(&handle, conn_info_channel, &msg)
-                .and_then(move |their_msg| {
-                    let UdpRendezvousMsg::Init {
-                        enc_pk: their_pk,
-                        rendezvous_addrs: their_rendezvous_addrs,
-                    } = their_msg;
-                    trace!(
-                        "their rendezvous addresses are: {:#?}",
-                        their_rendezvous_addrs
-                    );
-
-                    let shared_secret = our_sk.shared_secret(&their_pk);
-                    let mut punchers = FuturesUnordered::new();
-                    let iter = { sockets.into_iter().zip(their_rendezvous_addrs).enumerate() };
-                    for (i, (socket, their_addr)) in iter {
-                        socket
-                            .set_ttl(HOLE_PUNCH_INITIAL_TTL)
-                            .map_err(UdpRendezvousConnectError::SetTtl)?;
-                        let shared = SharedUdpSocket::share(socket);
-                        let with_addr = shared.with_address(their_addr);
-                        let delay_tolerance = Duration::from_secs(HOLE_PUNCH_DELAY_TOLERANCE_SEC);
-                        let duration = delay_tolerance / (1 << i);
-                        punchers.push(HolePunching::new_ttl_incrementer(
+                        let shared_secret = our_sk.shared_secret(&their_pk);
+                        // Peers on the same LAN might not be able to reach each other via public
+                        // addresses, if their NAT doesn't support hairpinning. So we try local
+                        // addresses in parallel with the public ones.
+                        let local = punch_local(
                             &handle,
-                            with_addr,
-                            shared_secret.clone(),
-                            duration,
-                        ));
-                    }
+                            &p2p,
+                            &shared_sockets,
+                            &their_sockets,
+                            &shared_secret,
+                        );
+                        let remote = match unreachable_shared_ip(&p2p, &our_ips, &their_ips) {
+                            Some(ip) => {
+                                debug!("peer shares our public IP {} and our NAT doesn't support \
+                                        hairpinning, punching to local addresses only", ip);
+                                stream::once(Err(HolePunchError::NoHairpin(ip))).into_boxed()
+                            }
+                            None => match (our_random_ports_ip, their_random_ports_ip) {
+                                (None, None) => punch_predictable(
+                                    &handle,
+                                    &p2p,
+                                    shared_sockets,
+                                    &their_sockets,
+                                    &shared_secret,
+                                ),
+                                (None, Some(their_ip)) => {
+                                    trace!("their NAT allocates ports randomly: {}", their_ip);
+                                    // Peer punches to our first rendezvous address, so we spray
+                                    // from the corresponding socket.
+                                    match shared_sockets.into_iter().next() {
+                                        Some(socket) => birthday::spray_from_socket(
+                                            &handle,
+                                            &p2p,
+                                            socket,
+                                            their_ip,
+                                            &shared_secret,
+                                        )?,
+                                        None => stream::empty().into_boxed(),
+                                    }
+                                }
+                                (Some(_), None) => {
+                                    trace!("our NAT allocates ports randomly");
+                                    let their_addr_opt = their_sockets
+                                        .iter()
+                                        .filter_map(|addrs| addrs.rendezvous_addrs.first())
+                                        .next();
+                                    match their_addr_opt {
+                                        Some(their_addr) => birthday::punch_from_many_sockets(
+                                            &handle,
+                                            &p2p,
+                                            *their_addr,
+                                            &shared_secret,
+                                        )?,
+                                        None => stream::empty().into_boxed(),
+                                    }
+                                }
+                                (Some(_), Some(their_ip)) => {
+                                    trace!("both NATs allocate ports randomly");
+                                    birthday::spray_from_many_sockets(
+                                        &handle,
+                                        &p2p,
+                                        their_ip,
+                                        &shared_secret,
+                                    )?
+                                }
+                            },
+                        };
 
-                    let incoming = punchers.into_boxed();
-                    Ok((their_pk, incoming, our_pub_addr, rendezvous_errors))
-                }).into_boxed()
-        }).into_boxed()
+                        let ipv6 = match (ipv6_socket, their_ipv6_addrs) {
+                            (Some(socket), Some(their_addrs)) => {
+                                punch_ipv6(&handle, &p2p, socket, &their_addrs, &shared_secret)?
+                            }
+                            _ => stream::empty().into_boxed(),
+                        };
+
+                        let incoming = local.select(remote).select(ipv6).into_boxed();
+                        let relay = choose_relay(&our_pk, &their_pk, our_relay, their_relay);
+                        // the rendezvous channel is kept for the TURN candidate exchange
+                        let turn = match choose_owner(&our_pk, &their_pk, our_turn, their_turn) {
+                            Some(owner) => exchange_candidate(
+                                &handle, &p2p, owner, their_ips, channel_tx, channel_rx,
+                            ),
+                            None => future::ok(None).into_boxed(),
+                        };
+                        Ok((their_pk, incoming, our_pub_addr, rendezvous_errors, relay, turn))
+                    }).into_boxed()
+            },
+        ).into_boxed()
+}
+
+/// Punches holes when both NATs map ports predictably: `i`-th socket of ours punches to all
+/// candidate addresses of the `i`-th socket of the peer.
+fn punch_predictable(
+    handle: &Handle,
+    p2p: &P2p,
+    sockets: Vec<SharedUdpSocket>,
+    their_sockets: &[PunchingSocketAddrs],
+    shared_secret: &SharedSecretKey,
+) -> BoxStream<(WithAddress, bool), HolePunchError> {
+    let mut punchers = FuturesUnordered::new();
+    for (i, (socket, their_addrs)) in sockets.iter().zip(their_sockets).enumerate() {
+        for puncher in public_punchers(handle, p2p, i, socket, their_addrs, shared_secret) {
+            punchers.push(puncher);
+        }
+    }
+    punchers.into_boxed()
+}
+
+/// Punches holes to peer's addresses on their local network interfaces: `i`-th socket of ours
+/// punches to all local addresses of the `i`-th socket of the peer. The rest of our sockets are
+/// not touched.
+fn punch_local(
+    handle: &Handle,
+    p2p: &P2p,
+    sockets: &[SharedUdpSocket],
+    their_sockets: &[PunchingSocketAddrs],
+    shared_secret: &SharedSecretKey,
+) -> BoxStream<(WithAddress, bool), HolePunchError> {
+    let mut punchers = FuturesUnordered::new();
+    for (socket, their_addrs) in sockets.iter().zip(their_sockets) {
+        for puncher in local_punchers(handle, p2p, socket, their_addrs, shared_secret) {
+            punchers.push(puncher);
+        }
+    }
+    punchers.into_boxed()
+}
+
+/// Punches holes from our IPv6 socket to all IPv6 addresses of the peer. There's usually no NAT
+/// involved, but a stateful firewall, which we punch through the same way, except for the low TTL
+/// trick: `set_ttl()` sets `IP_TTL`, which doesn't affect IPv6 packets.
+fn punch_ipv6<Ei, Eo>(
+    handle: &Handle,
+    p2p: &P2p,
+    socket: UdpSocket,
+    their_addrs: &PunchingSocketAddrs,
+    shared_secret: &SharedSecretKey,
+) -> Result<BoxStream<(WithAddress, bool), HolePunchError>, UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    let socket = SharedUdpSocket::share(socket);
+    let punchers: FuturesUnordered<_> =
+        ipv6_punchers(handle, p2p, &socket, their_addrs, shared_secret)
+            .into_iter()
+            .collect();
+    Ok(punchers.into_boxed())
+}
+
+/// Creates hole punchers from our `index`-th socket to public addresses of the `index`-th socket
+/// of the peer. The later the socket, the faster its TTL grows.
+pub fn public_punchers(
+    handle: &Handle,
+    p2p: &P2p,
+    index: usize,
+    socket: &SharedUdpSocket,
+    their_addrs: &PunchingSocketAddrs,
+    shared_secret: &SharedSecretKey,
+) -> Vec<HolePunching> {
+    let delay_tolerance = Duration::from_secs(HOLE_PUNCH_DELAY_TOLERANCE_SEC);
+    let duration = delay_tolerance / (1 << index);
+    // Note that TTL is a property of the socket, so punchers of the same socket share it. Hence
+    // the puncher of the most likely candidate increments it for all of them.
+    their_addrs
+        .rendezvous_addrs
+        .iter()
+        .enumerate()
+        .map(|(i, their_addr)| {
+            let socket = socket.with_address(*their_addr);
+            if i == 0 {
+                HolePunching::new_ttl_incrementer(
+                    handle,
+                    p2p,
+                    socket,
+                    shared_secret.clone(),
+                    duration,
+                )
+            } else {
+                HolePunching::new_ttl_follower(handle, p2p, socket, shared_secret.clone())
+            }
+        }).collect()
+}
+
+/// Creates hole punchers from our socket to local addresses of the peer's socket. They leave TTL
+/// to the public puncher of the same socket: peers on the same LAN are a hop or two away, so even
+/// the initial TTL reaches them.
+pub fn local_punchers(
+    handle: &Handle,
+    p2p: &P2p,
+    socket: &SharedUdpSocket,
+    their_addrs: &PunchingSocketAddrs,
+    shared_secret: &SharedSecretKey,
+) -> Vec<HolePunching> {
+    their_addrs
+        .local_addrs
+        .iter()
+        // skip addresses that are both local and public - those are punched to anyway
+        .filter(|addr| !their_addrs.rendezvous_addrs.contains(*addr))
+        .map(|their_addr| {
+            HolePunching::new_ttl_follower(
+                handle,
+                p2p,
+                socket.with_address(*their_addr),
+                shared_secret.clone(),
+            )
+        }).collect()
+}
+
+/// Creates hole punchers from our IPv6 socket to all addresses of the peer's IPv6 socket. The
+/// punchers keep the default hop limit: `set_ttl()` sets `IP_TTL`, which doesn't affect IPv6
+/// packets, and the hop limit (`IPV6_UNICAST_HOPS`) is not exposed by the shared socket.
+pub fn ipv6_punchers(
+    handle: &Handle,
+    p2p: &P2p,
+    socket: &SharedUdpSocket,
+    their_addrs: &PunchingSocketAddrs,
+    shared_secret: &SharedSecretKey,
+) -> Vec<HolePunching> {
+    let local_addrs = their_addrs
+        .local_addrs
+        .iter()
+        .filter(|addr| !their_addrs.rendezvous_addrs.contains(*addr));
+    their_addrs
+        .rendezvous_addrs
+        .iter()
+        .chain(local_addrs)
+        .map(|their_addr| {
+            HolePunching::new_fixed_ttl(
+                handle,
+                p2p,
+                socket.with_address(*their_addr),
+                shared_secret.clone(),
+            )
+        }).collect()
+}
+
+/// Picks our public address out of the addresses of our hole punching sockets. If our NAT
+/// allocates ports randomly, our public IP is returned as well. Returns `None`, if we are not
+/// reachable from the internet.
+pub fn our_public_addr<'a, I>(
+    sockets: I,
+    ipv6_socket: Option<&PunchingSocketAddrs>,
+    rendezvous_errors: &[RendezvousAddrError],
+) -> Option<(SocketAddr, Option<IpAddr>)>
+where
+    I: IntoIterator<Item = &'a PunchingSocketAddrs>,
+{
+    // All hole punching sockets should have the same rendezvous IP address, so we'll just take
+    // the first one.
+    let pub_addr_opt = sockets
+        .into_iter()
+        .filter_map(|addrs| addrs.rendezvous_addrs.first())
+        .next();
+    if let Some(pub_addr) = pub_addr_opt {
+        return Some((*pub_addr, None));
+    }
+    // Our NAT allocates ports randomly, but we can still try birthday paradox hole punching.
+    if let Some(ip) = random_ports_ip(rendezvous_errors) {
+        return Some((SocketAddr::new(ip, 0), Some(ip)));
+    }
+    // IPv4 hole punching is not possible, but we might still be reachable via IPv6.
+    ipv6_socket
+        .and_then(|addrs| addrs.rendezvous_addrs.first())
+        .map(|pub_addr| (*pub_addr, None))
+}
+
+/// Binds an IPv6 socket for hole punching. Returns `None`, if IPv6 is not available on this host.
+pub fn ipv6_hole_punching_socket(handle: &Handle) -> Option<(UdpSocket, PunchingSocketAddrs)> {
+    let try = || -> io::Result<_> {
+        let socket = UdpSocket::bind_reusable(&addr!("[::]:0"), handle)?;
+        let bind_addr = socket.local_addr()?;
+        let (rendezvous_addrs, local_addrs) = ipv6_addrs(&bind_addr)?;
+        let addrs = PunchingSocketAddrs {
+            rendezvous_addrs,
+            local_addrs,
+        };
+        Ok((socket, addrs))
+    };
+    match try() {
+        Ok((_, ref addrs)) if addrs.local_addrs.is_empty() => {
+            debug!("no usable IPv6 addresses found");
+            None
+        }
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            debug!("failed to bind IPv6 hole punching socket: {}", e);
+            None
+        }
+    }
+}
+
+/// If our NAT allocates ports randomly, returns our public IP address.
+fn random_ports_ip(rendezvous_errors: &[RendezvousAddrError]) -> Option<IpAddr> {
+    rendezvous_errors
+        .iter()
+        .filter_map(|e| e.unpredictable_ports_ip())
+        .next()
 }
 
 // Note that ths type is here just to make clippy and rust fmt happy. Although, it also might
 // indicate too complex types.
-type SocketsWithAddr = Vec<(UdpSocket, SocketAddr)>;
+type SocketsWithAddr = Vec<(UdpSocket, PunchingSocketAddrs)>;
 
 /// Tries to create N sockets for hole punching. If couldn't create at least 1 socket, fails.
 fn hole_punching_sockets<Ei, Eo>(
     handle: &Handle,
     p2p: &P2p,
 ) -> BoxFuture<(SocketsWithAddr, Vec<RendezvousAddrError>), UdpRendezvousConnectError<Ei, Eo>>
+where
+    Ei: 'static,
+    Eo: 'static,
+{
+    gather_hole_punching_sockets(handle, p2p)
+        .fold(
+            (Vec::new(), Vec::new()),
+            |(mut sockets, mut rendezvous_addr_errors), gathered| {
+                match gathered {
+                    GatheredSocket::Socket(socket, addrs) => sockets.push((socket, addrs)),
+                    GatheredSocket::Error(e) => rendezvous_addr_errors.push(e),
+                }
+                Ok((sockets, rendezvous_addr_errors))
+            },
+        ).into_boxed()
+}
+
+/// Result of an attempt to create a hole punching socket.
+pub enum GatheredSocket {
+    /// Socket along with the addresses it can be reached on.
+    Socket(UdpSocket, PunchingSocketAddrs),
+    /// Failure to get rendezvous addresses for a socket.
+    Error(RendezvousAddrError),
+}
+
+/// Tries to create N sockets for hole punching, yielding each of them as soon as its rendezvous
+/// addresses are known.
+pub fn gather_hole_punching_sockets<Ei, Eo>(
+    handle: &Handle,
+    p2p: &P2p,
+) -> BoxStream<GatheredSocket, UdpRendezvousConnectError<Ei, Eo>>
 where
     Ei: 'static,
     Eo: 'static,
@@ -413,60 +1196,105 @@ where
     let p2p = p2p.clone();
     let handle = handle.clone();
 
-    future::loop_fn(
-        (Vec::new(), Vec::new()),
-        move |(mut sockets, mut rendezvous_addr_errors)| {
-            if sockets.len() + rendezvous_addr_errors.len() == 6 {
-                return future::ok(Loop::Break((sockets, rendezvous_addr_errors))).into_boxed();
+    stream::unfold(
+        (0, 0, false),
+        move |(sockets_count, errors_count, finished)| {
+            if finished || sockets_count + errors_count == 6 {
+                return None;
             }
 
-            let socket = try_bfut!(
-                UdpSocket::bind_reusable(&addr!("0.0.0.0:0"), &handle)
-                    .map_err(UdpRendezvousConnectError::Rebind)
-            );
-            let bind_addr = try_bfut!(
-                socket
+            let try = || -> Result<_, UdpRendezvousConnectError<Ei, Eo>> {
+                let socket = UdpSocket::bind_reusable(&addr!("0.0.0.0:0"), &handle)
+                    .map_err(UdpRendezvousConnectError::Rebind)?;
+                let bind_addr = socket
                     .local_addr()
-                    .map_err(UdpRendezvousConnectError::Rebind)
-            );
-            try_bfut!(
-                socket
-                    .set_ttl(HOLE_PUNCH_INITIAL_TTL)
-                    .map_err(UdpRendezvousConnectError::SetTtl)
-            );
+                    .map_err(UdpRendezvousConnectError::Rebind)?;
+                let local_addrs = socket
+                    .expanded_local_addrs()
+                    .map_err(UdpRendezvousConnectError::IfAddrs)?;
+                Ok((socket, bind_addr, local_addrs))
+            };
+            let (socket, bind_addr, local_addrs) = match try() {
+                Ok(res) => res,
+                Err(e) => return Some(future::err(e).into_boxed()),
+            };
 
-            rendezvous_addr(Protocol::Udp, &bind_addr, &handle, &p2p)
-                .then(move |res| {
+            let handle0 = handle.clone();
+            let p2p0 = p2p.clone();
+            let gathered = rendezvous_candidates(Protocol::Udp, &bind_addr, &handle, &p2p)
+                .then(move |res| -> BoxFuture<_, UdpRendezvousConnectError<Ei, Eo>> {
                     match res {
-                        Ok((addr, _nat_type)) => {
-                            sockets.push((socket, addr));
-                            trace!("generated {} rendezvous sockets", sockets.len());
+                        Ok(candidates) => {
+                            let mut rendezvous_addrs = candidates.addrs;
+                            rendezvous_addrs.truncate(candidates.confidence.max_candidates());
+                            let addrs = PunchingSocketAddrs {
+                                rendezvous_addrs,
+                                local_addrs,
+                            };
+                            trace!("generated {} rendezvous sockets", sockets_count + 1);
+                            let state = (sockets_count + 1, errors_count, false);
+                            // NAT is the same for all sockets, so it's enough to check
+                            // hairpinning with the first one. The check consumes whatever arrives
+                            // on the socket, hence it's done before its addresses are handed out.
+                            let public_addr = addrs.rendezvous_addrs.first().cloned();
+                            let socket = match public_addr {
+                                Some(public_addr) if sockets_count == 0 => {
+                                    detect_hairpin(socket, public_addr, &handle0, &p2p0)
+                                }
+                                _ => future::ok(socket).into_boxed(),
+                            };
+                            socket
+                                .infallible()
+                                .map(move |socket| {
+                                    (vec![GatheredSocket::Socket(socket, addrs)], state)
+                                }).into_boxed()
                         }
                         Err(err) => {
-                            rendezvous_addr_errors.push(err);
+                            // if ports are unpredictable for one socket, they will be for others
+                            // too, so no point trying any more
+                            let unpredictable_ports = err.unpredictable_ports_ip().is_some();
+                            let gathered = if unpredictable_ports && sockets_count == 0 {
+                                // the socket is still useful to reach peers on the same LAN
+                                let addrs = PunchingSocketAddrs {
+                                    rendezvous_addrs: Vec::new(),
+                                    local_addrs,
+                                };
+                                let gathered = vec![
+                                    GatheredSocket::Error(err),
+                                    GatheredSocket::Socket(socket, addrs),
+                                ];
+                                (gathered, (1, errors_count + 1, true))
+                            } else {
+                                let state = (sockets_count, errors_count + 1, false);
+                                (vec![GatheredSocket::Error(err)], state)
+                            };
+                            future::ok(gathered).into_boxed()
                         }
                     }
-                    Ok(Loop::Continue((sockets, rendezvous_addr_errors)))
-                }).into_boxed()
+                });
+            Some(gathered)
         },
-    ).into_boxed()
+    ).map(stream::iter_ok::<_, UdpRendezvousConnectError<Ei, Eo>>)
+    .flatten()
+    .into_boxed()
 }
 
-/// Returns a `UdpSocket` bound to the given address along with a public `SocketAddr`
-/// that can be used to send datagrams to the socket from across the internet.
+/// Returns a `UdpSocket` bound to the given address along with a public address that can be used
+/// to send datagrams to the socket from across the internet.
 ///
 /// This method will try to open a port on the local router (if there is one) and return the
-/// external address of the port if successful.
+/// external address of the port if successful. The port is closed once the returned `PortMapping`
+/// is dropped, so keep it for as long as the socket is used.
 pub fn bind_public_with_addr(
     addr: &SocketAddr,
     handle: &Handle,
     p2p: &P2p,
-) -> BoxFuture<(UdpSocket, SocketAddr, SocketAddr), BindPublicError> {
+) -> BoxFuture<(UdpSocket, SocketAddr, PortMapping), BindPublicError> {
     let socket = try_bfut!(UdpSocket::bind_reusable(addr, &handle).map_err(BindPublicError::Bind));
     let bind_addr = try_bfut!(socket.local_addr().map_err(BindPublicError::Bind));
     open_addr(Protocol::Udp, &bind_addr, &handle, p2p)
         .map_err(BindPublicError::OpenAddr)
-        .map(move |public_addr| (socket, bind_addr, public_addr))
+        .map(move |mapping| (socket, bind_addr, mapping))
         .into_boxed()
 }
 
@@ -508,11 +1336,31 @@ quick_error! {
         TimedOut {
             description("hole punching timed out without making a connection")
         }
+        NoHairpin(ip: IpAddr) {
+            description("peer is behind the same NAT, which doesn't support hairpinning")
+            display("peer is behind the same NAT with public IP {}, which doesn't support \
+                     hairpinning. Only local addresses could be tried", ip)
+        }
         Encrypt(e: EncryptionError) {
             description("error encrypting message to be sent to peer")
             display("error encrypting message to be sent to peer: {}", e)
             cause(e)
         }
+        SerializeMsg(e: SerialisationError) {
+            description("error serializing message to be sent to peer")
+            display("error serializing message to be sent to peer: {}", e)
+            cause(e)
+        }
+        DeserializeMsg(e: SerialisationError) {
+            description("error deserializing message from peer")
+            display("error deserializing message from peer: {}", e)
+            cause(e)
+        }
+        VersionMismatch(e: VersionMismatch) {
+            description("peer speaks incompatible hole punching protocol")
+            display("peer speaks incompatible hole punching protocol: {}", e)
+            cause(e)
+        }
     }
 }
 
@@ -523,14 +1371,15 @@ const MAX_TTL: u32 = 128;
 
 /// This is the default TTL used on Linux. Other OSes use anything from 30 to 128, but 64 is the
 /// most common and the median value.
-const SANE_DEFAULT_TTL: u32 = 64;
+pub const SANE_DEFAULT_TTL: u32 = 64;
 
 /// How many hops we expect it to take, at most, to reach the peer. The slowest TTL runner will
 /// reach this value over the course of HOLE_PUNCH_DELAY_TOLERANCE.
 const REALISTIC_MAX_TTL: u32 = 16;
 const HOLE_PUNCH_MSG_PERIOD_MS: u64 = 200;
 
-struct HolePunching {
+pub struct HolePunching {
+    p2p: P2p,
     socket: Option<WithAddress>,
     sending_msg: Option<Bytes>,
     timeout: Timeout,
@@ -541,7 +1390,7 @@ struct HolePunching {
 enum HolePunchingPhase {
     Syn {
         time_of_last_ttl_increment: Instant,
-        ttl_increment_duration: Duration,
+        ttl_control: TtlControl,
     },
     Ack,
     AckAck {
@@ -550,23 +1399,83 @@ enum HolePunchingPhase {
     },
 }
 
+/// How a hole puncher treats TTL of its socket. TTL is a property of the socket, so only one of
+/// the punchers sharing a socket may increment it.
+#[derive(Debug, Clone, Copy)]
+enum TtlControl {
+    /// TTL is left as it is.
+    Fixed,
+    /// TTL is incremented by another puncher of the same socket. Once the peer is reached, TTL is
+    /// reset to the default one.
+    Follower,
+    /// TTL is incremented every given duration. Once the peer is reached, TTL is reset to the
+    /// default one.
+    Incrementer(Duration),
+}
+
 impl HolePunching {
+    /// Creates a hole puncher that increments socket TTL, so that it reaches `REALISTIC_MAX_TTL`
+    /// within `duration_to_reach_max_ttl`. There must be only one such puncher per socket, the
+    /// rest should be created with `new_ttl_follower()`.
     pub fn new_ttl_incrementer(
         handle: &Handle,
+        p2p: &P2p,
         socket: WithAddress,
         shared_secret: SharedSecretKey,
         duration_to_reach_max_ttl: Duration,
     ) -> HolePunching {
         HolePunching {
+            p2p: p2p.clone(),
             socket: Some(socket),
             sending_msg: None,
             timeout: Timeout::new(Duration::new(0, 0), handle),
             shared_secret,
             phase: HolePunchingPhase::Syn {
                 time_of_last_ttl_increment: Instant::now(),
-                ttl_increment_duration: {
-                    duration_to_reach_max_ttl / (REALISTIC_MAX_TTL - HOLE_PUNCH_INITIAL_TTL)
-                },
+                ttl_control: TtlControl::Incrementer(
+                    duration_to_reach_max_ttl / (REALISTIC_MAX_TTL - HOLE_PUNCH_INITIAL_TTL),
+                ),
+            },
+        }
+    }
+
+    /// Creates a hole puncher for a socket, TTL of which is incremented by a puncher created with
+    /// `new_ttl_incrementer()`.
+    pub fn new_ttl_follower(
+        handle: &Handle,
+        p2p: &P2p,
+        socket: WithAddress,
+        shared_secret: SharedSecretKey,
+    ) -> HolePunching {
+        HolePunching {
+            p2p: p2p.clone(),
+            socket: Some(socket),
+            sending_msg: None,
+            timeout: Timeout::new(Duration::new(0, 0), handle),
+            shared_secret,
+            phase: HolePunchingPhase::Syn {
+                time_of_last_ttl_increment: Instant::now(),
+                ttl_control: TtlControl::Follower,
+            },
+        }
+    }
+
+    /// Creates a hole puncher that leaves socket TTL as it is.
+    pub fn new_fixed_ttl(
+        handle: &Handle,
+        p2p: &P2p,
+        socket: WithAddress,
+        shared_secret: SharedSecretKey,
+    ) -> HolePunching {
+        HolePunching {
+            p2p: p2p.clone(),
+            socket: Some(socket),
+            sending_msg: None,
+            timeout: Timeout::new(Duration::new(0, 0), handle),
+            shared_secret,
+            phase: HolePunchingPhase::Syn {
+                time_of_last_ttl_increment: Instant::now(),
+                ttl_control: TtlControl::Fixed,
             },
         }
     }
@@ -595,11 +1504,7 @@ impl HolePunching {
     }
 
     fn send_msg(&mut self, msg: &HolePunchMsg) -> Result<(), HolePunchError> {
-        let encrypted = self
-            .shared_secret
-            .encrypt(msg)
-            .map_err(HolePunchError::Encrypt)?;
-        let bytes = Bytes::from(encrypted);
+        let bytes = encrypt_hole_punch_msg(msg, &self.shared_secret)?;
         debug_assert!(self.sending_msg.is_none());
         self.sending_msg = Some(bytes);
         Ok(())
@@ -612,10 +1517,8 @@ impl HolePunching {
             Ok(Async::Ready(None)) => return Err(HolePunchError::SocketStolen),
             Ok(Async::Ready(Some(bytes))) => bytes,
         };
-        match self.shared_secret.decrypt(&bytes) {
-            Ok(msg) => Ok(Async::Ready(msg)),
-            Err(e) => Err(HolePunchError::Decrypt(e)),
-        }
+        let msg = decrypt_hole_punch_msg(&bytes, &self.shared_secret)?;
+        Ok(Async::Ready(msg))
     }
 
     fn send_next_message(&mut self) -> Result<Async<WithAddress>, HolePunchError> {
@@ -624,21 +1527,29 @@ impl HolePunching {
         let msg = match self.phase {
             HolePunchingPhase::Syn {
                 ref mut time_of_last_ttl_increment,
-                ttl_increment_duration,
+                ttl_control,
             } => {
                 let now = Instant::now();
-                while now - *time_of_last_ttl_increment > ttl_increment_duration {
-                    let ttl = {
-                        unwrap!(self.socket.as_mut())
-                            .ttl()
-                            .map_err(HolePunchError::GetTtl)
-                    }?;
-                    if ttl < MAX_TTL {
-                        unwrap!(self.socket.as_mut())
-                            .set_ttl(ttl + 1)
-                            .map_err(HolePunchError::SetTtl)?;
+                if let TtlControl::Incrementer(ttl_increment_duration) = ttl_control {
+                    while now - *time_of_last_ttl_increment > ttl_increment_duration {
+                        let ttl = {
+                            unwrap!(self.socket.as_mut())
+                                .ttl()
+                                .map_err(HolePunchError::GetTtl)
+                        }?;
+                        if ttl < MAX_TTL {
+                            let socket = unwrap!(self.socket.as_mut());
+                            socket.set_ttl(ttl + 1).map_err(HolePunchError::SetTtl)?;
+                            emit_event(
+                                &self.p2p,
+                                RendezvousEventKind::TtlIncremented {
+                                    their_addr: socket.remote_addr(),
+                                    ttl: ttl + 1,
+                                },
+                            );
+                        }
+                        *time_of_last_ttl_increment += ttl_increment_duration;
                     }
-                    *time_of_last_ttl_increment += ttl_increment_duration;
                 }
                 HolePunchMsg::Syn
             }
@@ -659,13 +1570,30 @@ impl HolePunching {
     }
 
     fn process_msg(&mut self, msg: &HolePunchMsg) -> Result<Async<WithAddress>, HolePunchError> {
+        let their_addr = unwrap!(self.socket.as_ref()).remote_addr();
+        let event = match *msg {
+            HolePunchMsg::Syn => Some(RendezvousEventKind::SynReceived { their_addr }),
+            HolePunchMsg::Ack => Some(RendezvousEventKind::AckReceived { their_addr }),
+            HolePunchMsg::AckAck => Some(RendezvousEventKind::AckAckReceived { their_addr }),
+            _ => None,
+        };
+        if let Some(event) = event {
+            emit_event(&self.p2p, event);
+        }
+
         match *msg {
             HolePunchMsg::Syn => match self.phase {
-                HolePunchingPhase::Syn { .. } => {
+                HolePunchingPhase::Syn { ttl_control, .. } => {
                     self.phase = HolePunchingPhase::Ack;
-                    unwrap!(self.socket.as_mut())
-                        .set_ttl(SANE_DEFAULT_TTL)
-                        .map_err(HolePunchError::SetTtl)?;
+                    let ttl_changed = match ttl_control {
+                        TtlControl::Fixed => false,
+                        TtlControl::Follower | TtlControl::Incrementer(..) => true,
+                    };
+                    if ttl_changed {
+                        unwrap!(self.socket.as_mut())
+                            .set_ttl(SANE_DEFAULT_TTL)
+                            .map_err(HolePunchError::SetTtl)?;
+                    }
                     self.timeout.reset(Instant::now());
                 }
                 HolePunchingPhase::Ack => {
@@ -711,16 +1639,15 @@ impl HolePunching {
                     return Ok(Async::Ready(unwrap!(self.socket.take())))
                 }
             },
+            // peer is already connected via some other socket
+            HolePunchMsg::Keepalive { .. }
+            | HolePunchMsg::KeepaliveAck { .. }
+            | HolePunchMsg::Data { .. } => (),
         }
         Ok(Async::NotReady)
     }
-}
-
-impl Future for HolePunching {
-    type Item = (WithAddress, bool);
-    type Error = HolePunchError;
 
-    fn poll(&mut self) -> Result<Async<(WithAddress, bool)>, HolePunchError> {
+    fn poll_punching(&mut self) -> Result<Async<(WithAddress, bool)>, HolePunchError> {
         loop {
             match self.flush()? {
                 Async::NotReady => return Ok(Async::NotReady),
@@ -746,6 +1673,29 @@ impl Future for HolePunching {
     }
 }
 
+impl Future for HolePunching {
+    type Item = (WithAddress, bool);
+    type Error = HolePunchError;
+
+    fn poll(&mut self) -> Result<Async<(WithAddress, bool)>, HolePunchError> {
+        let their_addr = self.socket.as_ref().map(|socket| socket.remote_addr());
+        match self.poll_punching() {
+            Err(e) => {
+                emit_event(
+                    &self.p2p,
+                    RendezvousEventKind::AttemptFailed {
+                        protocol: Protocol::Udp,
+                        their_addr,
+                        error: e.to_string(),
+                    },
+                );
+                Err(e)
+            }
+            res => res,
+        }
+    }
+}
+
 // choose the given socket+address to be the socket+address we return successfully with.
 fn choose<Ei, Eo>(
     handle: &Handle,
@@ -770,9 +1720,13 @@ where
     );
 
     let handle = handle.clone();
+    let envelope = try_bfut!(
+        Envelope::seal(&HolePunchMsg::Choose, Capabilities::empty(), None)
+            .map_err(UdpRendezvousConnectError::SerializeMsg)
+    );
     let encrypted = try_bfut!(
         shared_secret
-            .encrypt(&HolePunchMsg::Choose)
+            .encrypt(&envelope)
             .map_err(UdpRendezvousConnectError::Encrypt)
     );
 
@@ -800,7 +1754,7 @@ fn take_chosen(
         .map_err(|(e, _)| HolePunchError::ReadMessage(e))
         .and_then(move |(msg_opt, socket)| match msg_opt {
             None => future::ok(None).into_boxed(),
-            Some(msg) => match shared_secret.decrypt(&msg) {
+            Some(msg) => match decrypt_hole_punch_msg(&msg, &shared_secret) {
                 Err(e) => {
                     warn!("error deserializing packet from peer: {:?}", e);
                     take_chosen(&handle, shared_secret, socket)
@@ -821,44 +1775,113 @@ fn got_chosen(socket: WithAddress) -> Option<(UdpSocket, SocketAddr)> {
 }
 
 // exchange rendezvous messages along the channel
+/// Sends our `Init` and waits for the peer's one. Yields the rendezvous channel along with it.
 fn exchange_msgs<C>(
     handle: &Handle,
+    p2p: &P2p,
     channel: C,
     msg: &UdpRendezvousMsg,
-) -> BoxFuture<UdpRendezvousMsg, UdpRendezvousConnectError<C::Error, C::SinkError>>
+) -> BoxFuture<
+    (UdpRendezvousMsg, SplitSink<C>, SplitStream<C>),
+    UdpRendezvousConnectError<C::Error, C::SinkError>,
+>
 where
     C: Stream<Item = Bytes>,
     C: Sink<SinkItem = Bytes>,
     C: 'static,
 {
     let handle = handle.clone();
-    let msg =
-        try_bfut!(serialisation::serialise(&msg).map_err(UdpRendezvousConnectError::SerializeMsg));
+    let p2p = p2p.clone();
+    let msg = try_bfut!(version::seal(&msg, &p2p).map_err(UdpRendezvousConnectError::SerializeMsg));
+    let (channel_tx, channel_rx) = channel.split();
 
-    channel
+    channel_tx
         .send(Bytes::from(msg))
         .map_err(UdpRendezvousConnectError::ChannelWrite)
-        .and_then(move |channel| {
-            channel
-                .map_err(UdpRendezvousConnectError::ChannelRead)
-                .next_or_else(|| UdpRendezvousConnectError::ChannelClosed)
-                .with_timeout(
-                    Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC),
-                    &handle,
-                ).and_then(|opt| opt.ok_or(UdpRendezvousConnectError::ChannelTimedOut))
-                .and_then(|(msg, _channel)| {
-                    serialisation::deserialise(&msg)
-                        .map_err(UdpRendezvousConnectError::DeserializeMsg)
-                })
+        .and_then(move |channel_tx| {
+            future::loop_fn(channel_rx, move |channel_rx| {
+                let p2p = p2p.clone();
+                channel_rx
+                    .into_future()
+                    .map_err(|(e, _channel_rx)| UdpRendezvousConnectError::ChannelRead(e))
+                    .and_then(move |(msg_opt, channel_rx)| {
+                        let msg = match msg_opt {
+                            Some(msg) => msg,
+                            None => return Err(UdpRendezvousConnectError::ChannelClosed),
+                        };
+                        match version::open(&msg, &p2p) {
+                            Ok((msg @ UdpRendezvousMsg::Init { .. }, negotiated)) => {
+                                trace!("negotiated rendezvous protocol: {:?}", negotiated);
+                                Ok(Loop::Break((msg, channel_rx)))
+                            }
+                            // peer trickles its candidates before it knows we don't, but then
+                            // sends them all in `Init` too
+                            Ok(_) => Ok(Loop::Continue(channel_rx)),
+                            Err(e) => Err(UdpRendezvousConnectError::from(e)),
+                        }
+                    })
+            }).with_timeout(
+                Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC),
+                &handle,
+            ).and_then(|opt| opt.ok_or(UdpRendezvousConnectError::ChannelTimedOut))
+            .map(move |(msg, channel_rx)| (msg, channel_tx, channel_rx))
         }).into_boxed()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-enum HolePunchMsg {
+pub enum HolePunchMsg {
     Syn,
     Ack,
     AckAck,
     Choose,
+    /// Sent by `UdpKeepalive` once the connection is established.
+    Keepalive {
+        /// Whether sent by the initiator of the connection. Keepalives reflected back to their
+        /// sender are not answered.
+        initiator: bool,
+        /// Random number the reply must echo.
+        nonce: u64,
+    },
+    /// Reply to `Keepalive`.
+    KeepaliveAck {
+        /// Nonce of the `Keepalive` we reply to.
+        nonce: u64,
+    },
+    /// Application data sent by `UdpSession` or `UdpKeepalive`.
+    Data {
+        /// Whether sent by the initiator of the connection. Both peers encrypt with the same
+        /// secret, so this tells datagrams reflected back to their sender apart.
+        initiator: bool,
+        /// Sequence number used to detect replayed datagrams.
+        seq: u64,
+        payload: Vec<u8>,
+    },
+}
+
+/// Encrypts hole punching message. The protocol version was already negotiated via the
+/// rendezvous channel, so the envelope carries neither capabilities nor app tag.
+pub fn encrypt_hole_punch_msg(
+    msg: &HolePunchMsg,
+    shared_secret: &SharedSecretKey,
+) -> Result<Bytes, HolePunchError> {
+    let envelope =
+        Envelope::seal(msg, Capabilities::empty(), None).map_err(HolePunchError::SerializeMsg)?;
+    let encrypted = shared_secret
+        .encrypt(&envelope)
+        .map_err(HolePunchError::Encrypt)?;
+    Ok(Bytes::from(encrypted))
+}
+
+/// Decrypts hole punching message encrypted with `encrypt_hole_punch_msg()`.
+pub fn decrypt_hole_punch_msg(
+    msg: &[u8],
+    shared_secret: &SharedSecretKey,
+) -> Result<HolePunchMsg, HolePunchError> {
+    let envelope: Envelope = shared_secret.decrypt(msg).map_err(HolePunchError::Decrypt)?;
+    let _ = envelope
+        .negotiate_version(Capabilities::empty())
+        .map_err(HolePunchError::VersionMismatch)?;
+    envelope.open().map_err(HolePunchError::DeserializeMsg)
 }
 
 #[cfg(test)]
@@ -932,8 +1955,13 @@ mod test {
         let sock_shared_secret = sock_sk.shared_secret(&recv_sock_pk);
         let recv_shared_secret = recv_sock_sk.shared_secret(&sock_pk);
         let delay_tolerance = Duration::from_secs(5);
-        let hole_punching =
-            HolePunching::new_ttl_incrementer(&handle, sock, sock_shared_secret, delay_tolerance);
+        let hole_punching = HolePunching::new_ttl_incrementer(
+            &handle,
+            &P2p::default(),
+            sock,
+            sock_shared_secret,
+            delay_tolerance,
+        );
 
         let recv_side = {
             recv_sock
@@ -943,14 +1971,16 @@ mod test {
                     let recv_shared_secret = recv_shared_secret.clone();
                     move |(msg_opt, recv_sock)| {
                         let msg = unwrap!(msg_opt);
-                        let msg = unwrap!(recv_shared_secret.decrypt(&msg));
+                        let msg = unwrap!(decrypt_hole_punch_msg(&msg, &recv_shared_secret));
                         match msg {
                             HolePunchMsg::Syn => (),
                             _ => panic!("unexpected msg {:?}", msg),
                         };
 
-                        let msg = unwrap!(recv_shared_secret.encrypt(&HolePunchMsg::Ack));
-                        let msg = Bytes::from(msg);
+                        let msg = unwrap!(encrypt_hole_punch_msg(
+                            &HolePunchMsg::Ack,
+                            &recv_shared_secret
+                        ));
                         recv_sock.send(msg).map_err(|e| panic!("send error: {}", e))
                     }
                 }).and_then(|recv_sock| {
@@ -962,14 +1992,16 @@ mod test {
                     let recv_shared_secret = recv_shared_secret.clone();
                     move |(msg_opt, recv_sock)| {
                         let msg = unwrap!(msg_opt);
-                        let msg = unwrap!(recv_shared_secret.decrypt(&msg));
+                        let msg = unwrap!(decrypt_hole_punch_msg(&msg, &recv_shared_secret));
                         match msg {
                             HolePunchMsg::AckAck => (),
                             _ => panic!("unexpected msg {:?}", msg),
                         };
 
-                        let msg = unwrap!(recv_shared_secret.encrypt(&HolePunchMsg::AckAck));
-                        let msg = Bytes::from(msg);
+                        let msg = unwrap!(encrypt_hole_punch_msg(
+                            &HolePunchMsg::AckAck,
+                            &recv_shared_secret
+                        ));
                         recv_sock.send(msg).map_err(|e| panic!("send error: {}", e))
                     }
                 }).and_then(|recv_sock| {
@@ -984,7 +2016,7 @@ mod test {
                         trace!("read until end of stream: {:#?}", collected);
                         assert_eq!(collected.len(), 5);
                         for msg in &collected[..4] {
-                            let msg = unwrap!(recv_shared_secret.decrypt(msg));
+                            let msg = unwrap!(decrypt_hole_punch_msg(msg, &recv_shared_secret));
                             match msg {
                                 HolePunchMsg::AckAck => (),
                                 _ => panic!("unexpected msg {:?}", msg),
@@ -1036,6 +2068,23 @@ mod netsim_test {
         nat_0: Ipv4NatBuilder,
         nat_1: Ipv4NatBuilder,
         start_delay: Duration,
+    ) {
+        udp_rendezvous_connect_between_natted_hosts_trickling(
+            num_servers,
+            nat_0,
+            nat_1,
+            start_delay,
+            false,
+        )
+    }
+
+    /// Only peer 0 trickles its candidates, if `trickle_0` is `true`.
+    fn udp_rendezvous_connect_between_natted_hosts_trickling(
+        num_servers: usize,
+        nat_0: Ipv4NatBuilder,
+        nat_1: Ipv4NatBuilder,
+        start_delay: Duration,
+        trickle_0: bool,
     ) {
         let _ = env_logger::init();
 
@@ -1103,6 +2152,9 @@ mod netsim_test {
                                 for server_querier in server_queriers {
                                     p2p.add_udp_addr_querier(server_querier);
                                 }
+                                if trickle_0 {
+                                    p2p.enable_candidate_trickling();
+                                }
                                 p2p
                             }).and_then(|p2p| {
                                 UdpSocket::rendezvous_connect(ch0, &handle, &p2p)
@@ -1223,6 +2275,21 @@ mod netsim_test {
         );
     }
 
+    #[test]
+    fn udp_rendezvous_connect_between_natted_hosts_when_only_one_peer_trickles() {
+        udp_rendezvous_connect_between_natted_hosts_trickling(
+            1,
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .restrict_endpoints(),
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .restrict_endpoints(),
+            Duration::from_secs(0),
+            true,
+        );
+    }
+
     #[test]
     fn udp_rendezvous_connect_between_natted_hosts_both_symmetric_with_no_delay() {
         udp_rendezvous_connect_between_natted_hosts(
@@ -1237,6 +2304,41 @@ mod netsim_test {
         );
     }
 
+    /// Neither peer can predict its ports and they don't accept packets from endpoints they
+    /// haven't sent anything to, so only birthday paradox spraying from many sockets connects.
+    #[test]
+    fn udp_rendezvous_connect_between_natted_hosts_both_with_random_ports() {
+        udp_rendezvous_connect_between_natted_hosts(
+            3,
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .randomize_port_allocation()
+                .symmetric(),
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .randomize_port_allocation()
+                .symmetric(),
+            Duration::from_secs(0),
+        );
+    }
+
+    /// Peer 1 can't predict its ports and peer 0 only accepts packets from endpoints it has sent
+    /// something to, so only birthday paradox punching from many sockets of peer 1 connects.
+    #[test]
+    fn udp_rendezvous_connect_between_natted_hosts_one_with_random_ports() {
+        udp_rendezvous_connect_between_natted_hosts(
+            3,
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .restrict_endpoints(),
+            Ipv4NatBuilder::default()
+                .blacklist_unrecognized_addrs()
+                .randomize_port_allocation()
+                .symmetric(),
+            Duration::from_secs(0),
+        );
+    }
+
     #[test]
     fn udp_rendezvous_connect_between_natted_hosts_with_short_delay_one_server() {
         udp_rendezvous_connect_between_natted_hosts(
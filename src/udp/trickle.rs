@@ -0,0 +1,761 @@
+//! Candidate trickling for UDP rendezvous connections.
+//!
+//! Instead of waiting until all our hole punching sockets are mapped and sending their addresses
+//! in a single message, we send a `Hello` with our public key right away, then a `Candidate` for
+//! each socket as soon as it is mapped, and finally an `End`. Punching from our `i`-th socket
+//! starts as soon as both it and the peer's `i`-th candidate are known.
+//!
+//! Peers that don't trickle send a single `Init` and ignore our trickled messages, so once we
+//! learn that from their `Init` we also send all our candidates in an `Init` of our own.
+//!
+//! The rendezvous channel is kept after the candidates are exchanged, since the TURN candidate is
+//! only sent over it once hole punching fails.
+
+use futures::stream::{FuturesUnordered, SplitSink, SplitStream};
+use futures::unsync::oneshot;
+use identity::{prove_identity, verify_identity, RendezvousAuth};
+use priv_prelude::*;
+use relay::choose_relay;
+use rendezvous_addr::RendezvousAddrError;
+use std::collections::VecDeque;
+use tokio_shared_udp_socket::{SharedUdpSocket, WithAddress};
+use udp::birthday;
+use udp::relay::RemoteUdpRelayServer;
+use udp::socket::{
+    gather_hole_punching_sockets, ipv6_hole_punching_socket, ipv6_punchers, local_punchers,
+    our_public_addr, public_punchers, GatheredSocket, HolePunchError, HolePunching,
+    HolePunchingResult, PunchingSocketAddrs, UdpRendezvousConnectError, UdpRendezvousMsg,
+    public_ips, unreachable_shared_ip, HOLE_PUNCH_INITIAL_TTL,
+    RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC,
+};
+use udp::turn::{choose_owner, exchange_candidate, TurnCandidate};
+use version::{self, Capabilities};
+
+type ConnectError<C> = UdpRendezvousConnectError<<C as Stream>::Error, <C as Sink>::SinkError>;
+
+/// Like `try_hole_punching()`, but exchanges candidates with the peer one by one. Resolves once
+/// all our candidates are gathered, by which time punching to the peer's candidates that have
+/// arrived so far is already under way.
+pub fn try_hole_punching<C>(
+    handle: &Handle,
+    p2p: &P2p,
+    our_sk: &SecretEncryptKey,
+    our_pk: &PublicEncryptKey,
+    auth: Option<&RendezvousAuth>,
+    conn_info_channel: C,
+) -> BoxFuture<HolePunchingResult, ConnectError<C>>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    let (turn_tx, turn_rx) = oneshot::channel();
+    let trickle = try_bfut!(TrickleHolePunching::new(
+        handle,
+        p2p,
+        our_sk,
+        our_pk,
+        auth,
+        conn_info_channel,
+        turn_tx,
+    ));
+    // TURN candidate exchange is set up once both peers sent all their candidates
+    let mut turn_rx_opt = Some(turn_rx);
+    let mut trickle_opt = Some(trickle);
+    future::poll_fn(move || -> Poll<HolePunchingResult, ConnectError<C>> {
+        let (their_pk, our_pub_addr, rendezvous_errors, relay) = {
+            let trickle = unwrap!(trickle_opt.as_mut());
+            trickle.poll_progress()?;
+            trickle.buffer_finished_punchers();
+            if trickle.gathering.is_some() {
+                return Ok(Async::NotReady);
+            }
+            let their_pk = match trickle.their_pk {
+                Some(their_pk) => their_pk,
+                None => return Ok(Async::NotReady),
+            };
+
+            let rendezvous_errors = mem::replace(&mut trickle.rendezvous_errors, Vec::new());
+            let pub_addr_opt = our_public_addr(
+                &trickle.our_sockets_addrs,
+                trickle.our_ipv6_addrs.as_ref(),
+                &rendezvous_errors,
+            );
+            match pub_addr_opt {
+                Some((our_pub_addr, _random_ports_ip)) => (
+                    their_pk,
+                    our_pub_addr,
+                    rendezvous_errors,
+                    trickle.relay.clone(),
+                ),
+                None => {
+                    return Err(UdpRendezvousConnectError::RendezvousAddrErrors(
+                        rendezvous_errors,
+                    ))
+                }
+            }
+        };
+        let incoming = unwrap!(trickle_opt.take()).into_boxed();
+        let turn = unwrap!(turn_rx_opt.take())
+            .then(|res| match res {
+                Ok(turn) => turn,
+                Err(_cancelled) => future::ok(None).into_boxed(),
+            }).into_boxed();
+        Ok(Async::Ready((
+            their_pk,
+            incoming,
+            our_pub_addr,
+            rendezvous_errors,
+            relay,
+            turn,
+        )))
+    }).into_boxed()
+}
+
+struct TrickleHolePunching<C>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    C: 'static,
+{
+    handle: Handle,
+    p2p: P2p,
+    our_sk: SecretEncryptKey,
+    our_pk: PublicEncryptKey,
+    auth: Option<RendezvousAuth>,
+    identity_proof: Option<Vec<u8>>,
+    their_pk: Option<PublicEncryptKey>,
+    /// `Some` once we know whether peer trickles.
+    their_trickle: Option<bool>,
+    init_sent: bool,
+    /// Relay server we offered to the peer.
+    our_relay: Option<RemoteUdpRelayServer>,
+    /// Relay server both peers fall back to, known once we receive peer's `Hello`.
+    relay: Option<RemoteUdpRelayServer>,
+    /// Whether we offered the peer to fall back to our TURN server.
+    our_turn: bool,
+    /// Whether the peer offered to fall back to its TURN server, known once we receive `Hello`.
+    their_turn: bool,
+    /// Taken once the TURN candidate exchange is set up.
+    turn_tx: Option<oneshot::Sender<BoxFuture<Option<TurnCandidate>, Void>>>,
+    shared_secret: Option<SharedSecretKey>,
+    /// Taken along with `idle_channel_rx` for the TURN candidate exchange.
+    channel_tx: Option<SplitSink<C>>,
+    outgoing: VecDeque<Bytes>,
+    channel_rx: Option<SplitStream<C>>,
+    /// Rendezvous channel we no longer read candidates from, kept for the TURN candidate exchange.
+    idle_channel_rx: Option<SplitStream<C>>,
+    channel_deadline: Timeout,
+    gathering: Option<BoxStream<GatheredSocket, ConnectError<C>>>,
+    rendezvous_errors: Vec<RendezvousAddrError>,
+    our_sockets: Vec<Option<SharedUdpSocket>>,
+    our_sockets_addrs: Vec<PunchingSocketAddrs>,
+    our_ipv6_socket: Option<SharedUdpSocket>,
+    our_ipv6_addrs: Option<PunchingSocketAddrs>,
+    their_sockets_addrs: HashMap<usize, PunchingSocketAddrs>,
+    their_ipv6_addrs: Option<PunchingSocketAddrs>,
+    paired: HashSet<usize>,
+    ipv6_paired: bool,
+    /// `Some` once we sent `End`, holds our public IP if our NAT allocates ports randomly.
+    our_end: Option<Option<IpAddr>>,
+    /// `Some` once we received `End`, holds their public IP if their NAT allocates ports randomly.
+    their_end: Option<Option<IpAddr>>,
+    birthday_started: bool,
+    punchers: FuturesUnordered<HolePunching>,
+    birthday_punchers: Option<BoxStream<(WithAddress, bool), HolePunchError>>,
+    finished_punchers: VecDeque<Result<(WithAddress, bool), HolePunchError>>,
+}
+
+impl<C> TrickleHolePunching<C>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    fn new(
+        handle: &Handle,
+        p2p: &P2p,
+        our_sk: &SecretEncryptKey,
+        our_pk: &PublicEncryptKey,
+        auth: Option<&RendezvousAuth>,
+        channel: C,
+        turn_tx: oneshot::Sender<BoxFuture<Option<TurnCandidate>, Void>>,
+    ) -> Result<TrickleHolePunching<C>, ConnectError<C>> {
+        let identity_proof =
+            prove_identity(auth, our_pk).map_err(UdpRendezvousConnectError::Encrypt)?;
+        let (channel_tx, channel_rx) = channel.split();
+        let deadline = Duration::from_secs(RENDEZVOUS_INFO_EXCHANGE_TIMEOUT_SEC);
+        let mut trickle = TrickleHolePunching {
+            handle: handle.clone(),
+            p2p: p2p.clone(),
+            our_sk: our_sk.clone(),
+            our_pk: *our_pk,
+            auth: auth.cloned(),
+            identity_proof: identity_proof.clone(),
+            their_pk: None,
+            their_trickle: None,
+            init_sent: false,
+            our_relay: p2p.udp_relay_server(),
+            relay: None,
+            our_turn: p2p.turn_server().is_some(),
+            their_turn: false,
+            turn_tx: Some(turn_tx),
+            shared_secret: None,
+            channel_tx: Some(channel_tx),
+            outgoing: VecDeque::new(),
+            channel_rx: Some(channel_rx),
+            idle_channel_rx: None,
+            channel_deadline: Timeout::new(deadline, handle),
+            gathering: Some(gather_hole_punching_sockets(handle, p2p)),
+            rendezvous_errors: Vec::new(),
+            our_sockets: Vec::new(),
+            our_sockets_addrs: Vec::new(),
+            our_ipv6_socket: None,
+            our_ipv6_addrs: None,
+            their_sockets_addrs: HashMap::new(),
+            their_ipv6_addrs: None,
+            paired: HashSet::new(),
+            ipv6_paired: false,
+            our_end: None,
+            their_end: None,
+            birthday_started: false,
+            punchers: FuturesUnordered::new(),
+            birthday_punchers: None,
+            finished_punchers: VecDeque::new(),
+        };
+
+        let hello = UdpRendezvousMsg::Hello {
+            enc_pk: *our_pk,
+            identity_proof,
+            relay: trickle.our_relay.clone(),
+            turn: trickle.our_turn,
+        };
+        trickle.send_msg(&hello)?;
+        if let Some((socket, addrs)) = ipv6_hole_punching_socket(handle) {
+            trickle.send_msg(&UdpRendezvousMsg::Ipv6Candidate(addrs.clone()))?;
+            trickle.candidates_sent(&addrs);
+            trickle.our_ipv6_socket = Some(SharedUdpSocket::share(socket));
+            trickle.our_ipv6_addrs = Some(addrs);
+        }
+        Ok(trickle)
+    }
+
+    /// Gathers our candidates, exchanges them with the peer and starts punching holes as soon as
+    /// possible.
+    fn poll_progress(&mut self) -> Result<(), ConnectError<C>> {
+        self.poll_gathering()?;
+        self.poll_channel_rx()?;
+        self.flush_outgoing()?;
+        self.try_choose_turn();
+        Ok(())
+    }
+
+    fn poll_gathering(&mut self) -> Result<(), ConnectError<C>> {
+        loop {
+            let gathered = match self.gathering.as_mut().map(|gathering| gathering.poll()) {
+                None => return Ok(()),
+                Some(Ok(Async::NotReady)) => return Ok(()),
+                Some(Ok(Async::Ready(Some(gathered)))) => gathered,
+                Some(Ok(Async::Ready(None))) => {
+                    self.gathering = None;
+                    return self.try_end_gathering();
+                }
+                Some(Err(e)) => {
+                    self.gathering = None;
+                    return Err(e);
+                }
+            };
+            match gathered {
+                GatheredSocket::Socket(socket, addrs) => {
+                    socket
+                        .set_ttl(HOLE_PUNCH_INITIAL_TTL)
+                        .map_err(UdpRendezvousConnectError::SetTtl)?;
+                    let index = self.our_sockets.len();
+                    trace!("trickling our candidate {}: {:?}", index, addrs);
+                    self.send_msg(&UdpRendezvousMsg::Candidate {
+                        index,
+                        addrs: addrs.clone(),
+                    })?;
+                    self.candidates_sent(&addrs);
+                    self.our_sockets.push(Some(SharedUdpSocket::share(socket)));
+                    self.our_sockets_addrs.push(addrs);
+                    self.try_pair(index);
+                }
+                GatheredSocket::Error(e) => self.rendezvous_errors.push(e),
+            }
+        }
+    }
+
+    fn candidates_sent(&self, addrs: &PunchingSocketAddrs) {
+        emit_event(
+            &self.p2p,
+            RendezvousEventKind::CandidatesSent {
+                protocol: Protocol::Udp,
+                addrs: addrs.all(),
+            },
+        );
+    }
+
+    /// Peer stops reading candidates once it gets our `End`, so it's sent only after all our hole
+    /// punching sockets are gathered.
+    fn try_end_gathering(&mut self) -> Result<(), ConnectError<C>> {
+        if self.gathering.is_some() || self.our_end.is_some() {
+            return Ok(());
+        }
+        self.end_gathering()
+    }
+
+    fn end_gathering(&mut self) -> Result<(), ConnectError<C>> {
+        let random_ports_ip = match our_public_addr(
+            &self.our_sockets_addrs,
+            None,
+            &self.rendezvous_errors,
+        ) {
+            Some((_pub_addr, random_ports_ip)) => random_ports_ip,
+            None => None,
+        };
+        trace!("all our candidates were trickled");
+        self.send_msg(&UdpRendezvousMsg::End { random_ports_ip })?;
+        self.our_end = Some(random_ports_ip);
+        self.try_send_init()?;
+        self.try_birthday()
+    }
+
+    /// Sends all our candidates at once to peers that don't trickle, once they're all gathered.
+    fn try_send_init(&mut self) -> Result<(), ConnectError<C>> {
+        if self.their_trickle != Some(false) || self.init_sent {
+            return Ok(());
+        }
+        let random_ports_ip = match self.our_end {
+            Some(random_ports_ip) => random_ports_ip,
+            None => return Ok(()),
+        };
+        trace!("peer doesn't trickle, sending all our candidates at once");
+        let msg = UdpRendezvousMsg::Init {
+            enc_pk: self.our_pk,
+            identity_proof: self.identity_proof.clone(),
+            sockets: self.our_sockets_addrs.clone(),
+            random_ports_ip,
+            ipv6_socket: self.our_ipv6_addrs.clone(),
+            relay: self.our_relay.clone(),
+            turn: self.our_turn,
+        };
+        self.send_msg(&msg)?;
+        self.init_sent = true;
+        Ok(())
+    }
+
+    fn poll_channel_rx(&mut self) -> Result<(), ConnectError<C>> {
+        if self.channel_rx.is_none() {
+            return Ok(());
+        }
+        if let Async::Ready(()) = self.channel_deadline.poll().void_unwrap() {
+            if self.their_pk.is_none() {
+                return Err(UdpRendezvousConnectError::ChannelTimedOut);
+            }
+            debug!("timed out waiting for more candidates from peer");
+            return self.end_channel_rx();
+        }
+
+        loop {
+            let msg = match unwrap!(self.channel_rx.as_mut()).poll() {
+                Ok(Async::NotReady) => return Ok(()),
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::Ready(None)) => {
+                    if self.their_pk.is_none() {
+                        return Err(UdpRendezvousConnectError::ChannelClosed);
+                    }
+                    return self.end_channel_rx();
+                }
+                Err(e) => return Err(UdpRendezvousConnectError::ChannelRead(e)),
+            };
+            let (msg, negotiated) = version::open(&msg, &self.p2p)?;
+            if self.their_trickle.is_none() {
+                self.their_trickle = Some(negotiated.capabilities.contains(Capabilities::TRICKLE));
+            }
+            match msg {
+                UdpRendezvousMsg::Init { .. } => (),
+                _ if self.their_trickle == Some(false) => {
+                    return Err(UdpRendezvousConnectError::UnexpectedMessage)
+                }
+                _ => (),
+            }
+            self.handle_msg(msg)?;
+            self.try_send_init()?;
+            if self.channel_rx.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Stops reading candidates. If peer didn't send `End` yet, we act as if they did.
+    fn end_channel_rx(&mut self) -> Result<(), ConnectError<C>> {
+        self.idle_channel_rx = self.channel_rx.take();
+        if self.their_end.is_none() {
+            self.their_end = Some(None);
+            return self.try_birthday();
+        }
+        Ok(())
+    }
+
+    fn handle_msg(&mut self, msg: UdpRendezvousMsg) -> Result<(), ConnectError<C>> {
+        match msg {
+            UdpRendezvousMsg::Hello {
+                enc_pk,
+                identity_proof,
+                relay,
+                turn,
+            } => {
+                if self.their_pk.is_some() {
+                    return Err(UdpRendezvousConnectError::UnexpectedMessage);
+                }
+                verify_identity(
+                    self.auth.as_ref(),
+                    &enc_pk,
+                    identity_proof.as_ref().map(|proof| &proof[..]),
+                ).map_err(UdpRendezvousConnectError::Identity)?;
+                self.shared_secret = Some(self.our_sk.shared_secret(&enc_pk));
+                self.relay = choose_relay(&self.our_pk, &enc_pk, self.our_relay.clone(), relay);
+                self.their_turn = turn;
+                self.their_pk = Some(enc_pk);
+                let indices: Vec<usize> = self.their_sockets_addrs.keys().cloned().collect();
+                for index in indices {
+                    self.try_pair(index);
+                }
+                self.try_pair_ipv6();
+                self.try_birthday()
+            }
+            UdpRendezvousMsg::Candidate { index, addrs } => {
+                trace!("got their candidate {}: {:?}", index, addrs);
+                let _ = self.their_sockets_addrs.insert(index, addrs);
+                self.try_pair(index);
+                Ok(())
+            }
+            UdpRendezvousMsg::Ipv6Candidate(addrs) => {
+                trace!("got their IPv6 candidate: {:?}", addrs);
+                self.their_ipv6_addrs = Some(addrs);
+                self.try_pair_ipv6();
+                Ok(())
+            }
+            // only sent once hole punching fails
+            UdpRendezvousMsg::TurnCandidate(..) => {
+                Err(UdpRendezvousConnectError::UnexpectedMessage)
+            }
+            UdpRendezvousMsg::End { random_ports_ip } => {
+                trace!("all their candidates were trickled");
+                self.their_end = Some(random_ports_ip);
+                // there are no more candidates to read
+                self.idle_channel_rx = self.channel_rx.take();
+                self.try_birthday()
+            }
+            // peer sent everything at once, which is just as good
+            UdpRendezvousMsg::Init {
+                enc_pk,
+                identity_proof,
+                sockets,
+                random_ports_ip,
+                ipv6_socket,
+                relay,
+                turn,
+            } => {
+                self.handle_msg(UdpRendezvousMsg::Hello {
+                    enc_pk,
+                    identity_proof,
+                    relay,
+                    turn,
+                })?;
+                for (index, addrs) in sockets.into_iter().enumerate() {
+                    self.handle_msg(UdpRendezvousMsg::Candidate { index, addrs })?;
+                }
+                if let Some(addrs) = ipv6_socket {
+                    self.handle_msg(UdpRendezvousMsg::Ipv6Candidate(addrs))?;
+                }
+                self.handle_msg(UdpRendezvousMsg::End { random_ports_ip })
+            }
+        }
+    }
+
+    fn send_msg(&mut self, msg: &UdpRendezvousMsg) -> Result<(), ConnectError<C>> {
+        let msg = version::seal(msg, &self.p2p).map_err(UdpRendezvousConnectError::SerializeMsg)?;
+        self.outgoing.push_back(Bytes::from(msg));
+        Ok(())
+    }
+
+    fn flush_outgoing(&mut self) -> Result<(), ConnectError<C>> {
+        let channel_tx = match self.channel_tx {
+            Some(ref mut channel_tx) => channel_tx,
+            None => return Ok(()),
+        };
+        while let Some(msg) = self.outgoing.pop_front() {
+            match channel_tx.start_send(msg) {
+                Ok(AsyncSink::Ready) => (),
+                Ok(AsyncSink::NotReady(msg)) => {
+                    self.outgoing.push_front(msg);
+                    break;
+                }
+                Err(e) => return Err(UdpRendezvousConnectError::ChannelWrite(e)),
+            }
+        }
+        let _ = channel_tx
+            .poll_complete()
+            .map_err(UdpRendezvousConnectError::ChannelWrite)?;
+        Ok(())
+    }
+
+    /// Starts punching from our `index`-th socket, if both it and the peer's `index`-th candidate
+    /// are known.
+    fn try_pair(&mut self, index: usize) {
+        if self.paired.contains(&index) {
+            return;
+        }
+        let shared_secret = match self.shared_secret {
+            Some(ref shared_secret) => shared_secret,
+            None => return,
+        };
+        let socket = match self.our_sockets.get(index) {
+            Some(&Some(ref socket)) => socket,
+            _ => return,
+        };
+        let their_addrs = match self.their_sockets_addrs.get(&index) {
+            Some(their_addrs) => their_addrs,
+            None => return,
+        };
+
+        trace!("punching from our socket {} to {:?}", index, their_addrs);
+        let our_ips: Vec<IpAddr> = self.our_sockets_addrs[index]
+            .rendezvous_addrs
+            .iter()
+            .map(|addr| addr.ip())
+            .collect();
+        let their_ips: Vec<IpAddr> = their_addrs
+            .rendezvous_addrs
+            .iter()
+            .map(|addr| addr.ip())
+            .collect();
+        let no_hairpin_ip = unreachable_shared_ip(&self.p2p, &our_ips, &their_ips);
+        let public = match no_hairpin_ip {
+            Some(_) => Vec::new(),
+            None => public_punchers(
+                &self.handle,
+                &self.p2p,
+                index,
+                socket,
+                their_addrs,
+                shared_secret,
+            ),
+        };
+        let local = local_punchers(&self.handle, &self.p2p, socket, their_addrs, shared_secret);
+        for puncher in public.into_iter().chain(local) {
+            self.punchers.push(puncher);
+        }
+        if let Some(ip) = no_hairpin_ip {
+            self.finished_punchers.push_back(Err(HolePunchError::NoHairpin(ip)));
+        }
+        let _ = self.paired.insert(index);
+    }
+
+    fn try_pair_ipv6(&mut self) {
+        if self.ipv6_paired {
+            return;
+        }
+        let punchers = match (
+            self.shared_secret.as_ref(),
+            self.our_ipv6_socket.as_ref(),
+            self.their_ipv6_addrs.as_ref(),
+        ) {
+            (Some(shared_secret), Some(socket), Some(their_addrs)) => {
+                ipv6_punchers(&self.handle, &self.p2p, socket, their_addrs, shared_secret)
+            }
+            _ => return,
+        };
+        for puncher in punchers {
+            self.punchers.push(puncher);
+        }
+        self.ipv6_paired = true;
+    }
+
+    /// Once both sides sent all their candidates, starts birthday paradox hole punching, if any
+    /// of the NATs allocates ports randomly.
+    fn try_birthday(&mut self) -> Result<(), ConnectError<C>> {
+        if self.birthday_started {
+            return Ok(());
+        }
+        let (our_end, their_end, shared_secret) =
+            match (self.our_end, self.their_end, self.shared_secret.clone()) {
+                (Some(our_end), Some(their_end), Some(shared_secret)) => {
+                    (our_end, their_end, shared_secret)
+                }
+                _ => return Ok(()),
+            };
+        self.birthday_started = true;
+
+        if our_end.is_some() || their_end.is_some() {
+            let their_sockets: Vec<PunchingSocketAddrs> =
+                self.their_sockets_addrs.values().cloned().collect();
+            let our_ips = public_ips(&self.our_sockets_addrs, None, our_end);
+            let their_ips = public_ips(&their_sockets, None, their_end);
+            if let Some(ip) = unreachable_shared_ip(&self.p2p, &our_ips, &their_ips) {
+                trace!("peer shares our public IP {}, skipping birthday hole punching", ip);
+                self.finished_punchers.push_back(Err(HolePunchError::NoHairpin(ip)));
+                return Ok(());
+            }
+        }
+
+        let punchers = match (our_end, their_end) {
+            (None, None) => return Ok(()),
+            (None, Some(their_ip)) => {
+                trace!("their NAT allocates ports randomly: {}", their_ip);
+                // Peer punches to our first rendezvous address, so we spray from the
+                // corresponding socket.
+                match self.our_sockets.get_mut(0).and_then(|socket| socket.take()) {
+                    Some(socket) => birthday::spray_from_socket(
+                        &self.handle,
+                        &self.p2p,
+                        socket,
+                        their_ip,
+                        &shared_secret,
+                    )?,
+                    None => return Ok(()),
+                }
+            }
+            (Some(_), None) => {
+                trace!("our NAT allocates ports randomly");
+                let mut indices: Vec<&usize> = self.their_sockets_addrs.keys().collect();
+                indices.sort();
+                let their_addr_opt = indices
+                    .into_iter()
+                    .filter_map(|index| self.their_sockets_addrs[index].rendezvous_addrs.first())
+                    .next()
+                    .cloned();
+                match their_addr_opt {
+                    Some(their_addr) => birthday::punch_from_many_sockets(
+                        &self.handle,
+                        &self.p2p,
+                        their_addr,
+                        &shared_secret,
+                    )?,
+                    None => return Ok(()),
+                }
+            }
+            (Some(_), Some(their_ip)) => {
+                trace!("both NATs allocate ports randomly");
+                birthday::spray_from_many_sockets(
+                    &self.handle,
+                    &self.p2p,
+                    their_ip,
+                    &shared_secret,
+                )?
+            }
+        };
+        self.birthday_punchers = Some(punchers);
+        Ok(())
+    }
+
+    /// Once both sides sent all their candidates, sets up the exchange of the TURN candidate both
+    /// peers fall back to, if hole punching fails. The exchange takes over the rendezvous channel,
+    /// so it waits until all our messages are sent.
+    fn try_choose_turn(&mut self) {
+        if self.turn_tx.is_none() || !self.outgoing.is_empty() {
+            return;
+        }
+        let (their_pk, their_random_ports_ip) = match (self.their_pk, self.our_end, self.their_end)
+        {
+            (Some(their_pk), Some(_), Some(their_end)) => (their_pk, their_end),
+            _ => return,
+        };
+        let owner = choose_owner(&self.our_pk, &their_pk, self.our_turn, self.their_turn);
+        let channel = (self.channel_tx.take(), self.idle_channel_rx.take());
+        let turn = match (owner, channel) {
+            (Some(owner), (Some(channel_tx), Some(channel_rx))) => {
+                let their_sockets_addrs: Vec<PunchingSocketAddrs> =
+                    self.their_sockets_addrs.values().cloned().collect();
+                let their_ips = public_ips(
+                    &their_sockets_addrs,
+                    self.their_ipv6_addrs.as_ref(),
+                    their_random_ports_ip,
+                );
+                exchange_candidate(
+                    &self.handle,
+                    &self.p2p,
+                    owner,
+                    their_ips,
+                    channel_tx,
+                    channel_rx,
+                )
+            }
+            _ => future::ok(None).into_boxed(),
+        };
+        let _ = unwrap!(self.turn_tx.take()).send(turn);
+    }
+
+    /// Hole punching must go on while we're still gathering our candidates, so we keep the
+    /// results until someone takes them.
+    fn buffer_finished_punchers(&mut self) {
+        loop {
+            match self.poll_punchers() {
+                Ok(Async::Ready(Some(res))) => self.finished_punchers.push_back(Ok(res)),
+                Err(e) => self.finished_punchers.push_back(Err(e)),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return,
+            }
+        }
+    }
+
+    fn poll_punchers(&mut self) -> Poll<Option<(WithAddress, bool)>, HolePunchError> {
+        if let Async::Ready(Some(res)) = self.punchers.poll()? {
+            return Ok(Async::Ready(Some(res)));
+        }
+        let birthday_finished = match self.birthday_punchers {
+            Some(ref mut birthday_punchers) => match birthday_punchers.poll()? {
+                Async::Ready(Some(res)) => return Ok(Async::Ready(Some(res))),
+                Async::Ready(None) => true,
+                Async::NotReady => false,
+            },
+            None => false,
+        };
+        if birthday_finished {
+            self.birthday_punchers = None;
+        }
+
+        let finished = self.gathering.is_none()
+            && self.their_end.is_some()
+            && self.punchers.is_empty()
+            && self.birthday_punchers.is_none();
+        if finished {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<C> Stream for TrickleHolePunching<C>
+where
+    C: Stream<Item = Bytes>,
+    C: Sink<SinkItem = Bytes>,
+    <C as Stream>::Error: fmt::Debug,
+    <C as Sink>::SinkError: fmt::Debug,
+    C: 'static,
+{
+    type Item = (WithAddress, bool);
+    type Error = HolePunchError;
+
+    fn poll(&mut self) -> Poll<Option<(WithAddress, bool)>, HolePunchError> {
+        if let Err(e) = self.poll_progress() {
+            // we can still succeed with the candidates we've got so far
+            debug!("candidate trickling failed: {:?}", e);
+            self.gathering = None;
+            self.channel_rx = None;
+            if self.their_end.is_none() {
+                self.their_end = Some(None);
+            }
+        }
+        if let Some(res) = self.finished_punchers.pop_front() {
+            return res.map(|res| Async::Ready(Some(res)));
+        }
+        self.poll_punchers()
+    }
+}
@@ -0,0 +1,1304 @@
+//! TURN (RFC 5766) client for standard relay servers, such as coturn.
+//!
+//! UDP rendezvous connect uses TURN as the last resort candidate: peers tell each other whether
+//! they have a TURN server set via `P2p::set_turn_server()`. Only once hole punching fails, the
+//! peer with the greater public key, or the only one with a TURN server, allocates a relayed
+//! transport address and sends it over the rendezvous channel. The peer that doesn't own the
+//! allocation then sends to the relayed address from a plain socket, while the owner exchanges
+//! data through the TURN server.
+
+use priv_prelude::*;
+use relay::choose_relay;
+use std::cmp;
+use stun::{self, Attribute, Class, StunMessage};
+use udp::socket::{
+    decrypt_hole_punch_msg, encrypt_hole_punch_msg, HolePunchError, HolePunchMsg, UdpRendezvousMsg,
+};
+use version;
+
+/// Lifetime we ask the TURN server to keep our allocation for.
+const TURN_LIFETIME_SEC: u32 = 600;
+/// How often the allocation, its permissions and channel bindings are refreshed. Permissions
+/// expire after 5 minutes.
+const TURN_REFRESH_INTERVAL_SEC: u64 = 120;
+/// Initial retransmission timeout of TURN requests, doubled after each retransmission.
+const TURN_INITIAL_RTO_MS: u64 = 500;
+/// How many times a request is sent before giving up.
+const TURN_MAX_REQUEST_ATTEMPTS: u32 = 7;
+/// How long rendezvous connect waits for the TURN candidate to be allocated.
+const TURN_CANDIDATE_TIMEOUT_SEC: u64 = 10;
+/// How long the peer that doesn't own the TURN candidate waits for it to arrive via the rendezvous
+/// channel. The owner might still be punching holes when we give up on them.
+const TURN_EXCHANGE_TIMEOUT_SEC: u64 = 60;
+/// How long peers try to reach each other via the TURN server.
+const TURN_HANDSHAKE_TIMEOUT_SEC: u64 = 30;
+/// How long a relayed connection may stay idle before the allocation is released.
+const TURN_IDLE_TIMEOUT_SEC: u64 = 300;
+/// UDP protocol number, the only transport we ask TURN server to relay.
+const TRANSPORT_UDP: u8 = 17;
+/// Channel numbers available to TURN clients.
+const MIN_CHANNEL: u16 = 0x4000;
+const MAX_CHANNEL: u16 = 0x7fff;
+
+/// A TURN server along with our long-term credentials for it.
+#[derive(Debug, Clone)]
+pub struct RemoteTurnServer {
+    addr: SocketAddr,
+    username: String,
+    password: String,
+}
+
+impl RemoteTurnServer {
+    /// Define a new TURN server.
+    pub fn new(addr: SocketAddr, username: &str, password: &str) -> RemoteTurnServer {
+        RemoteTurnServer {
+            addr,
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    /// Returns TURN server address.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the username we authenticate with.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+quick_error! {
+    /// Errors returned by the TURN client.
+    #[derive(Debug)]
+    pub enum TurnError {
+        /// Failure to bind socket.
+        Bind(e: io::Error) {
+            description("error binding to port")
+            display("error binding to port: {}", e)
+            cause(e)
+        }
+        /// Failure to send to TURN server or peer.
+        Send(e: io::Error) {
+            description("error sending datagram")
+            display("error sending datagram: {}", e)
+            cause(e)
+        }
+        /// Failure to read from socket.
+        Read(e: io::Error) {
+            description("error reading from socket")
+            display("error reading from socket: {}", e)
+            cause(e)
+        }
+        /// TURN server didn't answer our request.
+        Timeout {
+            description("TURN server didn't respond")
+        }
+        /// TURN server answered our request with an error.
+        Rejected(code: u16, reason: String) {
+            description("TURN server rejected request")
+            display("TURN server rejected request: {} {}", code, reason)
+        }
+        /// TURN server response lacks required attributes.
+        InvalidResponse {
+            description("invalid response from TURN server")
+        }
+        /// All channel numbers are bound already.
+        NoChannels {
+            description("no more TURN channels available")
+        }
+        /// Peer has no addresses of the relayed address family, so it can't be permitted.
+        NoPeerAddrs {
+            description("peer has no addresses we could permit")
+        }
+        /// Failure to prepare handshake message.
+        Handshake(e: HolePunchError) {
+            description("error preparing handshake message")
+            display("error preparing handshake message: {}", e)
+            cause(e)
+        }
+        /// Peer didn't reach us via TURN server in time.
+        HandshakeTimeout {
+            description("timed out waiting for peer via TURN server")
+        }
+    }
+}
+
+/// Transactions with TURN server: retransmissions and long-term credentials.
+struct TurnSession {
+    socket: UdpSocket,
+    handle: Handle,
+    server: RemoteTurnServer,
+    realm: Option<String>,
+    nonce: Option<String>,
+    key: Option<Vec<u8>>,
+    /// Whether the server allocated a relayed address for us, which we release on drop.
+    allocated: bool,
+}
+
+impl TurnSession {
+    fn new(socket: UdpSocket, handle: &Handle, server: &RemoteTurnServer) -> TurnSession {
+        TurnSession {
+            socket,
+            handle: handle.clone(),
+            server: server.clone(),
+            realm: None,
+            nonce: None,
+            key: None,
+            allocated: false,
+        }
+    }
+
+    /// Performs a transaction. If the server asks us to authenticate or our nonce is stale, the
+    /// request is retried once with fresh credentials. Error responses are turned into errors.
+    fn request(self, request: StunMessage) -> BoxFuture<(TurnSession, StunMessage), TurnError> {
+        let method = request.method;
+        let attributes = request.attributes.clone();
+        self.transaction(request)
+            .and_then(move |(mut session, response)| {
+                if response.class == Class::Error && session.update_credentials(&response) {
+                    let mut retry = StunMessage::request(method);
+                    retry.attributes = attributes;
+                    return session.transaction(retry);
+                }
+                future::ok((session, response)).into_boxed()
+            }).and_then(|(session, response)| match response.class {
+                Class::Success => Ok((session, response)),
+                _ => {
+                    let (code, reason) = response.error_code().ok_or(TurnError::InvalidResponse)?;
+                    Err(TurnError::Rejected(code, reason))
+                }
+            }).into_boxed()
+    }
+
+    /// Sends the request until the response arrives.
+    fn transaction(self, request: StunMessage) -> BoxFuture<(TurnSession, StunMessage), TurnError> {
+        let transaction_id = request.transaction_id;
+        let msg = self.sign(request);
+        let mut timeout = Timeout::new(Duration::new(0, 0), &self.handle);
+        let mut rto = Duration::from_millis(TURN_INITIAL_RTO_MS);
+        let mut attempts = 0;
+        let mut session_opt = Some(self);
+        future::poll_fn(move || {
+            while let Async::Ready(()) = timeout.poll().void_unwrap() {
+                if attempts == TURN_MAX_REQUEST_ATTEMPTS {
+                    return Err(TurnError::Timeout);
+                }
+                let session = unwrap!(session_opt.as_ref());
+                match session.socket.send_to(&msg, &session.server.addr) {
+                    Ok(_) => {
+                        attempts += 1;
+                        timeout.reset(Instant::now() + rto);
+                        rto *= 2;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(TurnError::Send(e)),
+                }
+            }
+
+            let response = match unwrap!(session_opt.as_mut()).poll_response(&transaction_id)? {
+                Async::Ready(response) => response,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            Ok(Async::Ready((unwrap!(session_opt.take()), response)))
+        }).into_boxed()
+    }
+
+    /// Waits for the response to the given transaction. Anything else is dropped.
+    fn poll_response(&mut self, transaction_id: &[u8; 12]) -> Poll<StunMessage, TurnError> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(TurnError::Read(e)),
+            };
+            if addr != self.server.addr {
+                continue;
+            }
+            let response = match StunMessage::decode(&buffer[..len]) {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("ignoring invalid message from TURN server: {}", e);
+                    continue;
+                }
+            };
+            if response.transaction_id != *transaction_id
+                || response.class == Class::Request
+                || response.class == Class::Indication
+            {
+                trace!("ignoring unexpected message from TURN server: {:?}", response);
+                continue;
+            }
+            if !self.is_authentic(&response, &buffer[..len]) {
+                debug!("ignoring TURN response with invalid message integrity");
+                continue;
+            }
+            return Ok(Async::Ready(response));
+        }
+    }
+
+    /// Encodes the request along with our credentials, once we know server's realm.
+    fn sign(&self, request: StunMessage) -> Vec<u8> {
+        match (&self.realm, &self.nonce, &self.key) {
+            (&Some(ref realm), &Some(ref nonce), &Some(ref key)) => request
+                .with(Attribute::Username(self.server.username.clone()))
+                .with(Attribute::Realm(realm.clone()))
+                .with(Attribute::Nonce(nonce.clone()))
+                .encode(Some(key)),
+            _ => request.encode(None),
+        }
+    }
+
+    /// Success responses must be signed with our key, once we authenticate.
+    fn is_authentic(&self, response: &StunMessage, encoded: &[u8]) -> bool {
+        match (response.class, &self.key) {
+            (Class::Success, &Some(ref key)) => stun::check_integrity(encoded, key),
+            _ => true,
+        }
+    }
+
+    /// Picks up realm and nonce from `401 Unauthorized` and `438 Stale Nonce` responses. Returns
+    /// `true`, if the request is worth retrying with the new credentials.
+    fn update_credentials(&mut self, response: &StunMessage) -> bool {
+        let realm = response.find(|attr| match *attr {
+            Attribute::Realm(ref realm) => Some(realm.clone()),
+            _ => None,
+        });
+        let nonce = response.find(|attr| match *attr {
+            Attribute::Nonce(ref nonce) => Some(nonce.clone()),
+            _ => None,
+        });
+        let retry = match (response.error_code(), &nonce) {
+            (Some((401, _)), &Some(_)) => self.key.is_none(),
+            (Some((438, _)), &Some(_)) => nonce != self.nonce,
+            _ => false,
+        };
+        if !retry {
+            return false;
+        }
+        if let Some(realm) = realm {
+            self.key = Some(stun::long_term_key(
+                &self.server.username,
+                &realm,
+                &self.server.password,
+            ));
+            self.realm = Some(realm);
+        }
+        self.nonce = nonce;
+        self.key.is_some()
+    }
+
+    /// Sends a request we don't wait the response for. Error responses are handled by
+    /// `TurnAllocation::poll_recv_from()`.
+    fn send_request(&self, request: StunMessage) {
+        let msg = self.sign(request);
+        if let Err(e) = self.socket.send_to(&msg, &self.server.addr) {
+            debug!("failed to send request to TURN server: {}", e);
+        }
+    }
+}
+
+impl Drop for TurnSession {
+    fn drop(&mut self) {
+        if self.allocated {
+            trace!("releasing TURN allocation on {}", self.server.addr);
+            self.send_request(StunMessage::request(stun::REFRESH).with(Attribute::Lifetime(0)));
+        }
+    }
+}
+
+/// Relayed transport address allocated on a TURN server. The allocation is released when this
+/// value is dropped.
+///
+/// Only peers that were given a permission may send data to the relayed address. The allocation,
+/// permissions and channel bindings expire, unless `refresh()` is called every couple of minutes.
+pub struct TurnAllocation {
+    session: TurnSession,
+    relayed_addr: SocketAddr,
+    mapped_addr: Option<SocketAddr>,
+    permissions: HashSet<IpAddr>,
+    channels: HashMap<SocketAddr, u16>,
+}
+
+impl TurnAllocation {
+    /// Allocates a relayed transport address on the given TURN server.
+    pub fn allocate(
+        handle: &Handle,
+        server: &RemoteTurnServer,
+    ) -> BoxFuture<TurnAllocation, TurnError> {
+        let bind_addr = if server.addr.is_ipv4() {
+            addr!("0.0.0.0:0")
+        } else {
+            addr!("[::]:0")
+        };
+        let socket = try_bfut!(UdpSocket::bind(&bind_addr, handle).map_err(TurnError::Bind));
+        let request = StunMessage::request(stun::ALLOCATE)
+            .with(Attribute::RequestedTransport(TRANSPORT_UDP))
+            .with(Attribute::Lifetime(TURN_LIFETIME_SEC));
+        TurnSession::new(socket, handle, server)
+            .request(request)
+            .and_then(|(mut session, response)| {
+                session.allocated = true;
+                let relayed_addr = response
+                    .find(|attr| match *attr {
+                        Attribute::XorRelayedAddress(addr) => Some(addr),
+                        _ => None,
+                    }).ok_or(TurnError::InvalidResponse)?;
+                trace!(
+                    "TURN server {} allocated {}",
+                    session.server.addr,
+                    relayed_addr
+                );
+                Ok(TurnAllocation {
+                    session,
+                    relayed_addr,
+                    mapped_addr: response.mapped_addr(),
+                    permissions: HashSet::new(),
+                    channels: HashMap::new(),
+                })
+            }).into_boxed()
+    }
+
+    /// Returns the relayed transport address peers can send data to.
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    /// Returns our address as seen by the TURN server, if the server told it.
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.mapped_addr
+    }
+
+    /// Allows peers with the given IPs to send data to our relayed address.
+    pub fn create_permission(self, peer_ips: &[IpAddr]) -> BoxFuture<TurnAllocation, TurnError> {
+        let mut this = self;
+        let mut request = StunMessage::request(stun::CREATE_PERMISSION);
+        for ip in peer_ips {
+            request = request.with(Attribute::XorPeerAddress(SocketAddr::new(*ip, 0)));
+            let _ = this.permissions.insert(*ip);
+        }
+        this.request(request).map(|(this, _response)| this).into_boxed()
+    }
+
+    /// Binds a channel to the peer, which makes relayed datagrams smaller. Also permits the peer
+    /// to send data to our relayed address. Yields the channel number.
+    pub fn channel_bind(
+        self,
+        peer_addr: SocketAddr,
+    ) -> BoxFuture<(TurnAllocation, u16), TurnError> {
+        let channel = match self.channels.get(&peer_addr) {
+            Some(channel) => *channel,
+            None => MIN_CHANNEL + self.channels.len() as u16,
+        };
+        if channel > MAX_CHANNEL {
+            return future::err(TurnError::NoChannels).into_boxed();
+        }
+        let request = StunMessage::request(stun::CHANNEL_BIND)
+            .with(Attribute::ChannelNumber(channel))
+            .with(Attribute::XorPeerAddress(peer_addr));
+        self.request(request)
+            .map(move |(mut this, _response)| {
+                let _ = this.channels.insert(peer_addr, channel);
+                let _ = this.permissions.insert(peer_addr.ip());
+                (this, channel)
+            }).into_boxed()
+    }
+
+    /// Refreshes the allocation, its permissions and channel bindings. Doesn't wait for
+    /// responses, which are handled while `poll_recv_from()` is polled.
+    pub fn refresh(&mut self) {
+        self.session.send_request(
+            StunMessage::request(stun::REFRESH).with(Attribute::Lifetime(TURN_LIFETIME_SEC)),
+        );
+        if !self.permissions.is_empty() {
+            let mut request = StunMessage::request(stun::CREATE_PERMISSION);
+            for ip in &self.permissions {
+                request = request.with(Attribute::XorPeerAddress(SocketAddr::new(*ip, 0)));
+            }
+            self.session.send_request(request);
+        }
+        for (peer_addr, channel) in &self.channels {
+            self.session.send_request(
+                StunMessage::request(stun::CHANNEL_BIND)
+                    .with(Attribute::ChannelNumber(*channel))
+                    .with(Attribute::XorPeerAddress(*peer_addr)),
+            );
+        }
+    }
+
+    /// Sends data to the peer via TURN server. Uses the channel bound to the peer, if any.
+    pub fn send_to(&self, data: &[u8], peer_addr: &SocketAddr) -> io::Result<usize> {
+        let msg = match self.channels.get(peer_addr) {
+            Some(channel) => encode_channel_data(*channel, data),
+            None => StunMessage::indication(stun::SEND)
+                .with(Attribute::XorPeerAddress(*peer_addr))
+                .with(Attribute::Data(data.to_vec()))
+                .encode(None),
+        };
+        let _ = self.session.socket.send_to(&msg, &self.session.server.addr)?;
+        Ok(data.len())
+    }
+
+    /// Receives data relayed from peers. Yields the number of bytes read and the peer's address.
+    pub fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, SocketAddr), TurnError> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let (len, addr) = match self.session.socket.recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(TurnError::Read(e)),
+            };
+            if addr != self.session.server.addr {
+                continue;
+            }
+
+            if let Some((channel, data)) = decode_channel_data(&buffer[..len]) {
+                let peer_addr = self
+                    .channels
+                    .iter()
+                    .find(|&(_, bound)| *bound == channel)
+                    .map(|(peer_addr, _)| *peer_addr);
+                match peer_addr {
+                    Some(peer_addr) => return Ok(Async::Ready((copy_data(data, buf), peer_addr))),
+                    None => continue,
+                }
+            }
+
+            let msg = match StunMessage::decode(&buffer[..len]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("ignoring invalid message from TURN server: {}", e);
+                    continue;
+                }
+            };
+            match (msg.method, msg.class) {
+                (stun::DATA, Class::Indication) => {
+                    let peer_addr = msg.find(|attr| match *attr {
+                        Attribute::XorPeerAddress(addr) => Some(addr),
+                        _ => None,
+                    });
+                    let data = msg.find(|attr| match *attr {
+                        Attribute::Data(ref data) => Some(data.clone()),
+                        _ => None,
+                    });
+                    if let (Some(peer_addr), Some(data)) = (peer_addr, data) {
+                        return Ok(Async::Ready((copy_data(&data, buf), peer_addr)));
+                    }
+                }
+                (_, Class::Error) => {
+                    if self.session.update_credentials(&msg) {
+                        self.refresh();
+                    } else {
+                        info!("TURN request failed: {:?}", msg.error_code());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn request(self, request: StunMessage) -> BoxFuture<(TurnAllocation, StunMessage), TurnError> {
+        let TurnAllocation {
+            session,
+            relayed_addr,
+            mapped_addr,
+            permissions,
+            channels,
+        } = self;
+        session
+            .request(request)
+            .map(move |(session, response)| {
+                let this = TurnAllocation {
+                    session,
+                    relayed_addr,
+                    mapped_addr,
+                    permissions,
+                    channels,
+                };
+                (this, response)
+            }).into_boxed()
+    }
+}
+
+fn encode_channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(4 + data.len());
+    msg.extend_from_slice(&[(channel >> 8) as u8, channel as u8]);
+    msg.extend_from_slice(&[(data.len() >> 8) as u8, data.len() as u8]);
+    msg.extend_from_slice(data);
+    msg
+}
+
+/// Returns channel number and data, if the datagram is a ChannelData message.
+fn decode_channel_data(buf: &[u8]) -> Option<(u16, &[u8])> {
+    if buf.len() < 4 || buf[0] & 0xc0 != 0x40 {
+        return None;
+    }
+    let channel = (u16::from(buf[0]) << 8) | u16::from(buf[1]);
+    let len = (usize::from(buf[2]) << 8) | usize::from(buf[3]);
+    if buf.len() < 4 + len {
+        return None;
+    }
+    Some((channel, &buf[4..4 + len]))
+}
+
+fn copy_data(data: &[u8], buf: &mut [u8]) -> usize {
+    let len = cmp::min(data.len(), buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    len
+}
+
+/// TURN candidate both peers fall back to.
+pub enum TurnCandidate {
+    /// We allocated the relayed address. Peer sends to it from one of the given IPs.
+    Ours(TurnAllocation, Vec<IpAddr>),
+    /// Relayed address allocated by the peer.
+    Theirs(SocketAddr),
+}
+
+/// Peer that allocates the TURN candidate both peers fall back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnOwner {
+    Us,
+    Them,
+}
+
+/// Allocates a relayed address on the TURN server set in `p2p`, if any. Failures are only
+/// logged, since rendezvous connect doesn't depend on TURN.
+fn allocate_candidate(handle: &Handle, p2p: &P2p) -> BoxFuture<Option<TurnAllocation>, Void> {
+    let server = match p2p.turn_server() {
+        Some(server) => server,
+        None => return future::ok(None).into_boxed(),
+    };
+    TurnAllocation::allocate(handle, &server)
+        .with_timeout(Duration::from_secs(TURN_CANDIDATE_TIMEOUT_SEC), handle)
+        .and_then(|opt| opt.ok_or(TurnError::Timeout))
+        .then(move |res| match res {
+            Ok(allocation) => Ok(Some(allocation)),
+            Err(e) => {
+                info!("failed to allocate TURN candidate on {}: {}", server.addr, e);
+                Ok(None)
+            }
+        }).into_boxed()
+}
+
+/// Picks the peer that allocates the TURN candidate: the one with the greater key, or the other
+/// one, if that peer has no TURN server.
+pub fn choose_owner(
+    our_pk: &PublicEncryptKey,
+    their_pk: &PublicEncryptKey,
+    our_turn: bool,
+    their_turn: bool,
+) -> Option<TurnOwner> {
+    let ours = if our_turn { Some(TurnOwner::Us) } else { None };
+    let theirs = if their_turn {
+        Some(TurnOwner::Them)
+    } else {
+        None
+    };
+    choose_relay(our_pk, their_pk, ours, theirs)
+}
+
+/// Exchanges the TURN candidate over the rendezvous channel. Nothing happens until the future is
+/// polled, so the relayed address is only allocated once hole punching fails. If we are the
+/// owner, the allocation is sent to the peer, otherwise we wait for the peer's one. Failures are
+/// only logged and yield `None`.
+pub fn exchange_candidate<Tx, Rx>(
+    handle: &Handle,
+    p2p: &P2p,
+    owner: TurnOwner,
+    their_ips: Vec<IpAddr>,
+    channel_tx: Tx,
+    channel_rx: Rx,
+) -> BoxFuture<Option<TurnCandidate>, Void>
+where
+    Tx: Sink<SinkItem = Bytes> + 'static,
+    Rx: Stream<Item = Bytes> + 'static,
+    Tx::SinkError: fmt::Debug,
+    Rx::Error: fmt::Debug,
+{
+    let handle = handle.clone();
+    let p2p = p2p.clone();
+    future::lazy(move || match owner {
+        TurnOwner::Us => allocate_candidate(&handle, &p2p)
+            .and_then(move |allocation_opt| {
+                // peer waits for our candidate, even if we failed to allocate one
+                let relayed_addr = allocation_opt.as_ref().map(|turn| turn.relayed_addr());
+                let msg = UdpRendezvousMsg::TurnCandidate(relayed_addr);
+                let msg = match version::seal(&msg, &p2p) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        debug!("failed to serialize our TURN candidate: {}", e);
+                        return future::ok(None).into_boxed();
+                    }
+                };
+                trace!("sending our TURN candidate: {:?}", relayed_addr);
+                channel_tx
+                    .send(Bytes::from(msg))
+                    .then(move |res| {
+                        if let Err(e) = res {
+                            debug!("failed to send our TURN candidate: {:?}", e);
+                            return Ok(None);
+                        }
+                        let allocation = match allocation_opt {
+                            Some(allocation) => allocation,
+                            None => return Ok(None),
+                        };
+                        emit_event(
+                            &p2p,
+                            RendezvousEventKind::CandidatesSent {
+                                protocol: Protocol::Udp,
+                                addrs: vec![allocation.relayed_addr()],
+                            },
+                        );
+                        Ok(Some(TurnCandidate::Ours(allocation, their_ips)))
+                    }).into_boxed()
+            }).into_boxed(),
+        TurnOwner::Them => channel_rx
+            .filter_map(move |msg| match version::open(&msg, &p2p) {
+                Ok((UdpRendezvousMsg::TurnCandidate(relayed_addr), _negotiated)) => {
+                    Some(relayed_addr)
+                }
+                _ => None,
+            }).into_future()
+            .map(|(relayed_addr_opt, _channel_rx)| relayed_addr_opt.and_then(|addr| addr))
+            .map_err(|(e, _channel_rx)| debug!("failed to read TURN candidate: {:?}", e))
+            .with_timeout(Duration::from_secs(TURN_EXCHANGE_TIMEOUT_SEC), &handle)
+            .then(|res| match res {
+                Ok(Some(Some(relayed_addr))) => {
+                    trace!("got their TURN candidate: {}", relayed_addr);
+                    Ok(Some(TurnCandidate::Theirs(relayed_addr)))
+                }
+                Ok(Some(None)) => {
+                    debug!("peer has no TURN candidate");
+                    Ok(None)
+                }
+                Ok(None) => {
+                    debug!("timed out waiting for TURN candidate from peer");
+                    Ok(None)
+                }
+                Err(()) => Ok(None),
+            }).into_boxed(),
+    }).into_boxed()
+}
+
+/// Keeps the local proxy forwarding datagrams via our TURN allocation running. Once dropped, the
+/// proxy stops and the allocation is released.
+pub struct TurnProxyHandle {
+    _drop_tx: DropNotify,
+}
+
+/// Connection to the peer via TURN candidate.
+pub struct TurnConnection {
+    /// Socket to exchange datagrams with the peer from.
+    pub socket: UdpSocket,
+    /// Address datagrams for the peer should be sent to.
+    pub send_addr: SocketAddr,
+    /// Address of the peer: as seen by the TURN server, if the allocation is ours, or its relayed
+    /// address otherwise.
+    pub their_addr: SocketAddr,
+    /// Our relayed address, if the allocation is ours.
+    pub our_relayed_addr: Option<SocketAddr>,
+    /// Set if the allocation is ours, forwarding stops once it's dropped.
+    pub proxy: Option<TurnProxyHandle>,
+}
+
+/// Connects to the peer via the TURN candidate.
+///
+/// The owner of the allocation gets a socket connected to a local proxy, which forwards datagrams
+/// to the peer via TURN server for as long as the returned proxy handle is kept.
+pub fn turn_connect(
+    handle: &Handle,
+    candidate: TurnCandidate,
+    shared_secret: &SharedSecretKey,
+) -> BoxFuture<TurnConnection, TurnError> {
+    match candidate {
+        TurnCandidate::Ours(allocation, their_ips) => {
+            serve_peer(handle, allocation, &their_ips, shared_secret)
+        }
+        TurnCandidate::Theirs(relayed_addr) => reach_peer(handle, relayed_addr, shared_secret)
+            .map(move |socket| TurnConnection {
+                socket,
+                send_addr: relayed_addr,
+                their_addr: relayed_addr,
+                our_relayed_addr: None,
+                proxy: None,
+            }).into_boxed(),
+    }
+}
+
+/// Permits the peer, waits until it reaches our relayed address and starts forwarding datagrams
+/// between the peer and a local socket.
+fn serve_peer(
+    handle: &Handle,
+    allocation: TurnAllocation,
+    their_ips: &[IpAddr],
+    shared_secret: &SharedSecretKey,
+) -> BoxFuture<TurnConnection, TurnError> {
+    let relayed_addr = allocation.relayed_addr();
+    let their_ips: Vec<IpAddr> = their_ips
+        .iter()
+        .filter(|ip| ip.is_ipv4() == relayed_addr.is_ipv4())
+        .cloned()
+        .collect();
+    if their_ips.is_empty() {
+        return future::err(TurnError::NoPeerAddrs).into_boxed();
+    }
+    let ack = try_bfut!(
+        encrypt_hole_punch_msg(&HolePunchMsg::Ack, shared_secret).map_err(TurnError::Handshake)
+    );
+    let handle = handle.clone();
+    let shared_secret = shared_secret.clone();
+
+    trace!("waiting for peer at our relayed address {}", relayed_addr);
+    allocation
+        .create_permission(&their_ips)
+        .and_then(move |allocation| {
+            let mut allocation_opt = Some(allocation);
+            let mut buffer = [0u8; 64 * 1024];
+            future::poll_fn(move || loop {
+                let (len, peer_addr) =
+                    match unwrap!(allocation_opt.as_mut()).poll_recv_from(&mut buffer)? {
+                        Async::Ready(res) => res,
+                        Async::NotReady => return Ok(Async::NotReady),
+                    };
+                let msg = decrypt_hole_punch_msg(&buffer[..len], &shared_secret);
+                if let Ok(HolePunchMsg::Syn) = msg {
+                    let allocation = unwrap!(allocation_opt.take());
+                    let _ = allocation.send_to(&ack, &peer_addr).map_err(TurnError::Send)?;
+                    return Ok(Async::Ready((allocation, peer_addr, ack.clone())));
+                }
+            }).with_timeout(Duration::from_secs(TURN_HANDSHAKE_TIMEOUT_SEC), &handle)
+            .and_then(|opt| opt.ok_or(TurnError::HandshakeTimeout))
+            .and_then(|(allocation, peer_addr, ack)| {
+                trace!("peer {} reached our relayed address", peer_addr);
+                allocation
+                    .channel_bind(peer_addr)
+                    .map(move |(allocation, _channel)| (allocation, peer_addr, ack))
+            }).and_then(move |(allocation, peer_addr, ack)| {
+                let loopback = addr!("127.0.0.1:0");
+                let proxy_socket = UdpSocket::bind(&loopback, &handle).map_err(TurnError::Bind)?;
+                let socket = UdpSocket::bind(&loopback, &handle).map_err(TurnError::Bind)?;
+                let proxy_addr = proxy_socket.local_addr().map_err(TurnError::Bind)?;
+                let user_addr = socket.local_addr().map_err(TurnError::Bind)?;
+                let proxy = TurnProxy {
+                    allocation,
+                    socket: proxy_socket,
+                    user_addr,
+                    peer_addr,
+                    ack,
+                    shared_secret,
+                    refresh: Timeout::new(Duration::from_secs(TURN_REFRESH_INTERVAL_SEC), &handle),
+                    idle: Timeout::new(Duration::from_secs(TURN_IDLE_TIMEOUT_SEC), &handle),
+                };
+                let (drop_tx, drop_rx) = drop_notify();
+                handle.spawn(proxy.until(drop_rx).map(|_| ()).infallible());
+                Ok(TurnConnection {
+                    socket,
+                    send_addr: proxy_addr,
+                    their_addr: peer_addr,
+                    our_relayed_addr: Some(relayed_addr),
+                    proxy: Some(TurnProxyHandle { _drop_tx: drop_tx }),
+                })
+            })
+        }).into_boxed()
+}
+
+/// Sends handshakes to peer's relayed address from a fresh socket, until the peer answers.
+fn reach_peer(
+    handle: &Handle,
+    relayed_addr: SocketAddr,
+    shared_secret: &SharedSecretKey,
+) -> BoxFuture<UdpSocket, TurnError> {
+    let bind_addr = if relayed_addr.is_ipv4() {
+        addr!("0.0.0.0:0")
+    } else {
+        addr!("[::]:0")
+    };
+    let socket = try_bfut!(UdpSocket::bind(&bind_addr, handle).map_err(TurnError::Bind));
+    let syn = try_bfut!(
+        encrypt_hole_punch_msg(&HolePunchMsg::Syn, shared_secret).map_err(TurnError::Handshake)
+    );
+    let shared_secret = shared_secret.clone();
+
+    trace!("reaching peer at its relayed address {}", relayed_addr);
+    let mut socket_opt = Some(socket);
+    let mut timeout = Timeout::new(Duration::new(0, 0), handle);
+    future::poll_fn(move || {
+        while let Async::Ready(()) = timeout.poll().void_unwrap() {
+            match unwrap!(socket_opt.as_ref()).send_to(&syn, &relayed_addr) {
+                Ok(_) => timeout.reset(Instant::now() + Duration::from_millis(TURN_INITIAL_RTO_MS)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(TurnError::Send(e)),
+            }
+        }
+
+        let mut buffer = [0u8; 256];
+        loop {
+            let (len, addr) = match unwrap!(socket_opt.as_ref()).recv_from(&mut buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(TurnError::Read(e)),
+            };
+            if addr != relayed_addr {
+                continue;
+            }
+            if let Ok(HolePunchMsg::Ack) = decrypt_hole_punch_msg(&buffer[..len], &shared_secret) {
+                trace!("peer answered via its relayed address {}", relayed_addr);
+                return Ok(Async::Ready(unwrap!(socket_opt.take())));
+            }
+        }
+    }).with_timeout(Duration::from_secs(TURN_HANDSHAKE_TIMEOUT_SEC), handle)
+    .and_then(|opt| opt.ok_or(TurnError::HandshakeTimeout))
+    .into_boxed()
+}
+
+/// Forwards datagrams between a local socket and the peer via TURN server. Handshakes the peer
+/// retransmits are answered rather than forwarded. Stops once the connection is idle for too
+/// long or `TurnProxyHandle` is dropped, which releases the allocation.
+struct TurnProxy {
+    allocation: TurnAllocation,
+    socket: UdpSocket,
+    user_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    ack: Bytes,
+    shared_secret: SharedSecretKey,
+    refresh: Timeout,
+    idle: Timeout,
+}
+
+impl TurnProxy {
+    fn forward_from_peer(&mut self, buffer: &mut [u8]) -> Result<(), TurnError> {
+        while let Async::Ready((len, addr)) = self.allocation.poll_recv_from(buffer)? {
+            if addr != self.peer_addr {
+                continue;
+            }
+            self.touch();
+            let msg = decrypt_hole_punch_msg(&buffer[..len], &self.shared_secret);
+            if let Ok(HolePunchMsg::Syn) = msg {
+                let _ = self.allocation.send_to(&self.ack, &self.peer_addr);
+                continue;
+            }
+            if let Err(e) = self.socket.send_to(&buffer[..len], &self.user_addr) {
+                debug!("failed to forward datagram from TURN server: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn forward_to_peer(&mut self, buffer: &mut [u8]) -> Result<(), TurnError> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(buffer) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(TurnError::Read(e)),
+            };
+            if addr != self.user_addr {
+                continue;
+            }
+            self.touch();
+            if let Err(e) = self.allocation.send_to(&buffer[..len], &self.peer_addr) {
+                debug!("failed to forward datagram to TURN server: {}", e);
+            }
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle
+            .reset(Instant::now() + Duration::from_secs(TURN_IDLE_TIMEOUT_SEC));
+    }
+}
+
+impl Future for TurnProxy {
+    type Item = ();
+    type Error = Void;
+
+    fn poll(&mut self) -> Result<Async<()>, Void> {
+        while let Async::Ready(()) = self.refresh.poll().void_unwrap() {
+            self.allocation.refresh();
+            self.refresh
+                .reset(Instant::now() + Duration::from_secs(TURN_REFRESH_INTERVAL_SEC));
+        }
+
+        let mut buffer = [0u8; 64 * 1024];
+        let res = self
+            .forward_from_peer(&mut buffer)
+            .and_then(|()| self.forward_to_peer(&mut buffer));
+        if let Err(e) = res {
+            info!("TURN proxy failed: {}", e);
+            return Ok(Async::Ready(()));
+        }
+
+        if let Async::Ready(()) = self.idle.poll().void_unwrap() {
+            trace!("relayed connection to {} is idle, stopping", self.peer_addr);
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    const REALM: &str = "p2p";
+    const NONCE: &str = "abcdef";
+
+    /// Minimal TURN server: serves a single allocation to clients authenticated with the given
+    /// credentials.
+    struct TurnResponder {
+        socket: UdpSocket,
+        relay_socket: UdpSocket,
+        key: Vec<u8>,
+        client_addr: Option<SocketAddr>,
+        permissions: HashSet<IpAddr>,
+        channels: HashMap<u16, SocketAddr>,
+    }
+
+    impl TurnResponder {
+        fn spawn(handle: &Handle, username: &str, password: &str) -> SocketAddr {
+            let socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+            let relay_socket = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), handle));
+            let addr = unwrap!(socket.local_addr());
+            let responder = TurnResponder {
+                socket,
+                relay_socket,
+                key: stun::long_term_key(username, REALM, password),
+                client_addr: None,
+                permissions: HashSet::new(),
+                channels: HashMap::new(),
+            };
+            handle.spawn(responder.infallible());
+            addr
+        }
+
+        fn handle_msg(&mut self, buf: &[u8], addr: SocketAddr) {
+            if let Some((channel, data)) = decode_channel_data(buf) {
+                if let Some(peer_addr) = self.channels.get(&channel) {
+                    let _ = unwrap!(self.relay_socket.send_to(data, peer_addr));
+                }
+                return;
+            }
+            let msg = unwrap!(StunMessage::decode(buf));
+            if msg.class == Class::Indication {
+                let peer_addr = msg.find(|attr| match *attr {
+                    Attribute::XorPeerAddress(addr) => Some(addr),
+                    _ => None,
+                });
+                let data = msg.find(|attr| match *attr {
+                    Attribute::Data(ref data) => Some(data.clone()),
+                    _ => None,
+                });
+                if let (Some(peer_addr), Some(data)) = (peer_addr, data) {
+                    if self.permissions.contains(&peer_addr.ip()) {
+                        let _ = unwrap!(self.relay_socket.send_to(&data, &peer_addr));
+                    }
+                }
+                return;
+            }
+            if !stun::check_integrity(buf, &self.key) {
+                let response = msg
+                    .error(401, "Unauthorized")
+                    .with(Attribute::Realm(String::from(REALM)))
+                    .with(Attribute::Nonce(String::from(NONCE)));
+                let _ = unwrap!(self.socket.send_to(&response.encode(None), &addr));
+                return;
+            }
+
+            let mut response = msg.success();
+            for attr in &msg.attributes {
+                match *attr {
+                    Attribute::XorPeerAddress(peer_addr) => {
+                        let _ = self.permissions.insert(peer_addr.ip());
+                    }
+                    Attribute::ChannelNumber(channel) => {
+                        let peer_addr = unwrap!(msg.find(|attr| match *attr {
+                            Attribute::XorPeerAddress(addr) => Some(addr),
+                            _ => None,
+                        }));
+                        let _ = self.channels.insert(channel, peer_addr);
+                    }
+                    _ => (),
+                }
+            }
+            if msg.method == stun::ALLOCATE {
+                self.client_addr = Some(addr);
+                let relayed_addr = unwrap!(self.relay_socket.local_addr());
+                response = response
+                    .with(Attribute::XorRelayedAddress(relayed_addr))
+                    .with(Attribute::XorMappedAddress(addr))
+                    .with(Attribute::Lifetime(TURN_LIFETIME_SEC));
+            }
+            let _ = unwrap!(self.socket.send_to(&response.encode(Some(&self.key)), &addr));
+        }
+
+        fn relay(&mut self, data: &[u8], peer_addr: SocketAddr) {
+            let client_addr = match self.client_addr {
+                Some(client_addr) => client_addr,
+                None => return,
+            };
+            if !self.permissions.contains(&peer_addr.ip()) {
+                return;
+            }
+            let channel = self
+                .channels
+                .iter()
+                .find(|&(_, addr)| *addr == peer_addr)
+                .map(|(channel, _)| *channel);
+            let msg = match channel {
+                Some(channel) => encode_channel_data(channel, data),
+                None => StunMessage::indication(stun::DATA)
+                    .with(Attribute::XorPeerAddress(peer_addr))
+                    .with(Attribute::Data(data.to_vec()))
+                    .encode(None),
+            };
+            let _ = unwrap!(self.socket.send_to(&msg, &client_addr));
+        }
+    }
+
+    impl Future for TurnResponder {
+        type Item = ();
+        type Error = Void;
+
+        fn poll(&mut self) -> Result<Async<()>, Void> {
+            let mut buffer = [0u8; 64 * 1024];
+            loop {
+                let mut progress = false;
+                if let Ok((len, addr)) = self.socket.recv_from(&mut buffer) {
+                    self.handle_msg(&buffer[..len], addr);
+                    progress = true;
+                }
+                if let Ok((len, addr)) = self.relay_socket.recv_from(&mut buffer) {
+                    self.relay(&buffer[..len], addr);
+                    progress = true;
+                }
+                if !progress {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+
+    fn recv_from(
+        allocation: TurnAllocation,
+    ) -> BoxFuture<(TurnAllocation, Vec<u8>, SocketAddr), TurnError> {
+        let mut allocation_opt = Some(allocation);
+        future::poll_fn(move || {
+            let mut buffer = [0u8; 256];
+            let (len, addr) = match unwrap!(allocation_opt.as_mut()).poll_recv_from(&mut buffer)? {
+                Async::Ready(res) => res,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            let allocation = unwrap!(allocation_opt.take());
+            Ok(Async::Ready((allocation, buffer[..len].to_vec(), addr)))
+        }).into_boxed()
+    }
+
+    mod turn_allocation {
+        use super::*;
+
+        #[test]
+        fn it_allocates_relayed_address_with_long_term_credentials() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = TurnResponder::spawn(&handle, "user", "pass");
+            let server = RemoteTurnServer::new(server_addr, "user", "pass");
+
+            let allocation = unwrap!(core.run(TurnAllocation::allocate(&handle, &server)));
+
+            assert_eq!(allocation.relayed_addr().ip(), ipv4!("127.0.0.1"));
+            assert_ne!(allocation.relayed_addr(), server_addr);
+            assert!(allocation.mapped_addr().is_some());
+        }
+
+        #[test]
+        fn when_password_is_wrong_it_returns_error() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = TurnResponder::spawn(&handle, "user", "pass");
+            let server = RemoteTurnServer::new(server_addr, "user", "wrong");
+
+            let res = core.run(TurnAllocation::allocate(&handle, &server));
+
+            match res {
+                Err(TurnError::Rejected(401, _)) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("allocation succeeded with wrong password"),
+            }
+        }
+
+        #[test]
+        fn it_exchanges_data_with_permitted_peer() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = TurnResponder::spawn(&handle, "user", "pass");
+            let server = RemoteTurnServer::new(server_addr, "user", "pass");
+            let peer = unwrap!(UdpSocket::bind(&addr!("127.0.0.1:0"), &handle));
+            let peer_addr = unwrap!(peer.local_addr());
+
+            let allocation = unwrap!(core.run(
+                TurnAllocation::allocate(&handle, &server)
+                    .and_then(move |allocation| allocation.create_permission(&[peer_addr.ip()]))
+            ));
+            let relayed_addr = allocation.relayed_addr();
+
+            // peer -> us via Data indication
+            let task = peer
+                .send_dgram(b"hello".to_vec(), relayed_addr)
+                .map_err(|e| panic!("error sending: {}", e))
+                .and_then(move |(peer, _msg)| {
+                    recv_from(allocation)
+                        .map(move |(allocation, data, addr)| (peer, allocation, data, addr))
+                });
+            let (peer, allocation, data, addr) = unwrap!(core.run(task));
+            assert_eq!(&data[..], b"hello");
+            assert_eq!(addr, peer_addr);
+
+            // us -> peer via Send indication
+            let _ = unwrap!(allocation.send_to(b"hi", &peer_addr));
+            let (peer, data, len, addr) = unwrap!(core.run(peer.recv_dgram(vec![0u8; 256])));
+            assert_eq!(&data[..len], b"hi");
+            assert_eq!(addr, relayed_addr);
+
+            // both ways via channel
+            let (allocation, _channel) = unwrap!(core.run(allocation.channel_bind(peer_addr)));
+            let _ = unwrap!(allocation.send_to(b"via channel", &peer_addr));
+            let (peer, data, len, _addr) = unwrap!(core.run(peer.recv_dgram(vec![0u8; 256])));
+            assert_eq!(&data[..len], b"via channel");
+            let task = peer
+                .send_dgram(b"back via channel".to_vec(), relayed_addr)
+                .map_err(|e| panic!("error sending: {}", e))
+                .and_then(move |(_peer, _msg)| recv_from(allocation));
+            let (_allocation, data, addr) = unwrap!(core.run(task));
+            assert_eq!(&data[..], b"back via channel");
+            assert_eq!(addr, peer_addr);
+        }
+    }
+
+    mod turn_connect {
+        use super::*;
+
+        #[test]
+        fn peers_exchange_datagrams_via_relayed_address() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = TurnResponder::spawn(&handle, "user", "pass");
+            let server = RemoteTurnServer::new(server_addr, "user", "pass");
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let (pk2, sk2) = gen_encrypt_keypair();
+            let shared_secret1 = sk1.shared_secret(&pk2);
+            let shared_secret2 = sk2.shared_secret(&pk1);
+
+            let allocation = unwrap!(core.run(TurnAllocation::allocate(&handle, &server)));
+            let relayed_addr = allocation.relayed_addr();
+            let ours = TurnCandidate::Ours(allocation, vec![ipv4!("127.0.0.1")]);
+            let theirs = TurnCandidate::Theirs(relayed_addr);
+            let task = turn_connect(&handle, ours, &shared_secret1)
+                .join(turn_connect(&handle, theirs, &shared_secret2))
+                .map_err(|e| panic!("TURN connect failed: {}", e))
+                .and_then(|(conn1, conn2)| {
+                    assert_eq!(conn1.our_relayed_addr, Some(relayed_addr));
+                    assert_eq!(conn1.their_addr, unwrap!(conn2.socket.local_addr()));
+                    assert_eq!(conn2.send_addr, relayed_addr);
+                    assert_eq!(conn2.their_addr, relayed_addr);
+                    let TurnConnection {
+                        socket: socket1,
+                        send_addr: send_addr1,
+                        proxy,
+                        ..
+                    } = conn1;
+                    conn2
+                        .socket
+                        .send_dgram(b"hello".to_vec(), conn2.send_addr)
+                        .map_err(|e| panic!("error sending: {}", e))
+                        .and_then(move |(_socket2, _msg)| {
+                            socket1
+                                .recv_dgram(vec![0u8; 256])
+                                .map_err(|e| panic!("error receiving: {}", e))
+                        }).map(move |(_socket1, data, len, addr)| {
+                            drop(proxy);
+                            (data[..len].to_vec(), addr, send_addr1)
+                        })
+                });
+
+            let (data, addr, send_addr1) = unwrap!(core.run(task));
+
+            assert_eq!(&data[..], b"hello");
+            assert_eq!(addr, send_addr1);
+        }
+
+        #[test]
+        fn when_proxy_handle_is_dropped_it_stops_forwarding() {
+            let mut core = unwrap!(Core::new());
+            let handle = core.handle();
+            let server_addr = TurnResponder::spawn(&handle, "user", "pass");
+            let server = RemoteTurnServer::new(server_addr, "user", "pass");
+            let (pk1, sk1) = gen_encrypt_keypair();
+            let (pk2, sk2) = gen_encrypt_keypair();
+            let shared_secret1 = sk1.shared_secret(&pk2);
+            let shared_secret2 = sk2.shared_secret(&pk1);
+
+            let allocation = unwrap!(core.run(TurnAllocation::allocate(&handle, &server)));
+            let relayed_addr = allocation.relayed_addr();
+            let ours = TurnCandidate::Ours(allocation, vec![ipv4!("127.0.0.1")]);
+            let theirs = TurnCandidate::Theirs(relayed_addr);
+            let (conn1, conn2) = unwrap!(core.run(
+                turn_connect(&handle, ours, &shared_secret1)
+                    .join(turn_connect(&handle, theirs, &shared_secret2))
+            ));
+            drop(conn1.proxy);
+
+            let socket1 = conn1.socket;
+            let handle2 = handle.clone();
+            let task = conn2
+                .socket
+                .send_dgram(b"hello".to_vec(), conn2.send_addr)
+                .map_err(|e| panic!("error sending: {}", e))
+                .and_then(move |(_socket2, _msg)| {
+                    socket1
+                        .recv_dgram(vec![0u8; 256])
+                        .map_err(|e| panic!("error receiving: {}", e))
+                        .with_timeout(Duration::from_millis(500), &handle2)
+                });
+            let res = unwrap!(core.run(task));
+
+            assert!(res.is_none());
+        }
+    }
+
+    mod choose_owner {
+        use super::*;
+
+        #[test]
+        fn when_neither_peer_has_turn_server_it_returns_none() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+
+            assert!(choose_owner(&pk1, &pk2, false, false).is_none());
+        }
+
+        #[test]
+        fn peers_agree_on_the_owner() {
+            let (pk1, _) = gen_encrypt_keypair();
+            let (pk2, _) = gen_encrypt_keypair();
+
+            let owner1 = choose_owner(&pk1, &pk2, true, true);
+            let owner2 = choose_owner(&pk2, &pk1, true, true);
+
+            match (owner1, owner2) {
+                (Some(TurnOwner::Us), Some(TurnOwner::Them))
+                | (Some(TurnOwner::Them), Some(TurnOwner::Us)) => (),
+                owners => panic!("peers disagree on the owner: {:?}", owners),
+            }
+            assert_eq!(choose_owner(&pk1, &pk2, false, true), Some(TurnOwner::Them));
+        }
+    }
+}
@@ -1,6 +1,8 @@
 mod hash_ext;
+mod net_order;
 
 pub use self::hash_ext::*;
+pub use self::net_order::*;
 
 #[cfg(test)]
 #[macro_use]
@@ -0,0 +1,21 @@
+//! Reading and writing integers in network byte order.
+
+/// Reads big endian `u16` from the start of `buf`.
+pub fn read_u16(buf: &[u8]) -> u16 {
+    (u16::from(buf[0]) << 8) | u16::from(buf[1])
+}
+
+/// Reads big endian `u32` from the start of `buf`.
+pub fn read_u32(buf: &[u8]) -> u32 {
+    (u32::from(read_u16(buf)) << 16) | u32::from(read_u16(&buf[2..]))
+}
+
+/// Returns big endian bytes of `n`.
+pub fn u16_bytes(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, n as u8]
+}
+
+/// Returns big endian bytes of `n`.
+pub fn u32_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
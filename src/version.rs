@@ -0,0 +1,359 @@
+//! Versioning of the wire protocol spoken between peers and traversal servers.
+//!
+//! Every message is wrapped into an `Envelope`, which describes the protocol versions and
+//! capabilities of the sender. The envelope format itself never changes, so the receiver can
+//! always tell whether it's able to understand the message before trying to deserialize it.
+
+use maidsafe_utilities::serialisation;
+use priv_prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp;
+
+/// Newest wire protocol version implemented by this crate.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest wire protocol version this crate is still able to speak.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Set of optional protocol features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Candidate trickling over the rendezvous channel.
+    pub const TRICKLE: Capabilities = Capabilities(1);
+    /// Hole punching over IPv6.
+    pub const IPV6: Capabilities = Capabilities(1 << 1);
+    /// Birthday paradox hole punching for NATs that allocate ports randomly.
+    pub const BIRTHDAY: Capabilities = Capabilities(1 << 2);
+
+    /// Set with no capabilities.
+    pub fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    /// Tests if all capabilities in `other` are in this set too.
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns capabilities present in either of the sets.
+    pub fn union(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// Returns capabilities present in both sets.
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Protocol parameters both sides agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Highest protocol version spoken by both sides.
+    pub version: u16,
+    /// Capabilities supported by both sides.
+    pub capabilities: Capabilities,
+}
+
+quick_error! {
+    /// Failure to agree on the wire protocol with the remote side.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum VersionMismatch {
+        /// There's no protocol version both sides are able to speak.
+        Version(ours: (u16, u16), theirs: (u16, u16)) {
+            description("no common protocol version")
+            display("no common protocol version: we speak versions {}-{}, they speak {}-{}",
+                    ours.0, ours.1, theirs.0, theirs.1)
+        }
+        /// Remote peer belongs to some other application.
+        AppTag(ours: Option<String>, theirs: Option<String>) {
+            description("application tag mismatch")
+            display("application tag mismatch: ours is {:?}, theirs is {:?}", ours, theirs)
+        }
+    }
+}
+
+quick_error! {
+    /// Failure to unwrap a versioned message.
+    #[derive(Debug)]
+    pub enum OpenEnvelopeError {
+        /// Message is malformed.
+        Deserialize(e: SerialisationError) {
+            description("error deserializing message")
+            display("error deserializing message: {}", e)
+            cause(e)
+        }
+        /// Sender speaks a protocol we don't understand.
+        VersionMismatch(e: VersionMismatch) {
+            description("protocol version mismatch")
+            display("protocol version mismatch: {}", e)
+            cause(e)
+        }
+    }
+}
+
+/// Versioned wrapper of every message we send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Oldest protocol version the sender speaks.
+    pub min_version: u16,
+    /// Newest protocol version the sender speaks.
+    pub max_version: u16,
+    /// Optional features the sender supports.
+    pub capabilities: Capabilities,
+    /// Tag of the application the sender belongs to. Peers only talk to peers of the same
+    /// application, traversal servers serve everyone.
+    pub app_tag: Option<String>,
+    /// Serialized message.
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wraps given message into an envelope describing our protocol.
+    pub fn seal<T: Serialize>(
+        msg: &T,
+        capabilities: Capabilities,
+        app_tag: Option<String>,
+    ) -> Result<Envelope, SerialisationError> {
+        Ok(Envelope {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities,
+            app_tag,
+            payload: serialisation::serialise(msg)?,
+        })
+    }
+
+    /// Picks the highest protocol version both us and the sender speak.
+    pub fn negotiate_version(
+        &self,
+        our_capabilities: Capabilities,
+    ) -> Result<Negotiated, VersionMismatch> {
+        let version = negotiate_version(
+            (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            (self.min_version, self.max_version),
+        )?;
+        Ok(Negotiated {
+            version,
+            capabilities: our_capabilities.intersection(self.capabilities),
+        })
+    }
+
+    /// Like `negotiate_version()`, but also makes sure the sender belongs to the same
+    /// application as we do.
+    pub fn negotiate(
+        &self,
+        our_capabilities: Capabilities,
+        our_app_tag: Option<&str>,
+    ) -> Result<Negotiated, VersionMismatch> {
+        if our_app_tag != self.app_tag.as_ref().map(|tag| &tag[..]) {
+            return Err(VersionMismatch::AppTag(
+                our_app_tag.map(|tag| tag.to_owned()),
+                self.app_tag.clone(),
+            ));
+        }
+        self.negotiate_version(our_capabilities)
+    }
+
+    /// Deserializes the wrapped message. Must only be called after successful negotiation.
+    pub fn open<T: DeserializeOwned>(&self) -> Result<T, SerialisationError> {
+        serialisation::deserialise(&self.payload)
+    }
+}
+
+/// Returns the highest version within both given version ranges.
+pub fn negotiate_version(ours: (u16, u16), theirs: (u16, u16)) -> Result<u16, VersionMismatch> {
+    let highest = cmp::min(ours.1, theirs.1);
+    let lowest = cmp::max(ours.0, theirs.0);
+    if highest < lowest {
+        return Err(VersionMismatch::Version(ours, theirs));
+    }
+    Ok(highest)
+}
+
+/// Serializes a message to be sent to the remote peer.
+pub fn seal<T: Serialize>(msg: &T, p2p: &P2p) -> Result<Vec<u8>, SerialisationError> {
+    let envelope = Envelope::seal(msg, p2p.capabilities(), p2p.app_tag())?;
+    serialisation::serialise(&envelope)
+}
+
+/// Deserializes a message received from the remote peer, making sure we speak the same protocol.
+pub fn open<T: DeserializeOwned>(
+    bytes: &[u8],
+    p2p: &P2p,
+) -> Result<(T, Negotiated), OpenEnvelopeError> {
+    let envelope: Envelope =
+        serialisation::deserialise(bytes).map_err(OpenEnvelopeError::Deserialize)?;
+    let app_tag = p2p.app_tag();
+    let negotiated = envelope
+        .negotiate(p2p.capabilities(), app_tag.as_ref().map(|tag| &tag[..]))
+        .map_err(OpenEnvelopeError::VersionMismatch)?;
+    let msg = envelope.open().map_err(OpenEnvelopeError::Deserialize)?;
+    Ok((msg, negotiated))
+}
+
+/// Serializes a message to be sent to or by a traversal server. Servers serve every application
+/// and don't care about capabilities, so the envelope carries neither.
+pub fn seal_server_msg<T: Serialize>(msg: &T) -> Result<Envelope, SerialisationError> {
+    Envelope::seal(msg, Capabilities::empty(), None)
+}
+
+/// Traversal server's response to a request of a protocol version it doesn't speak. Such
+/// response is not encrypted, since the server can't understand the request to learn the client's
+/// key.
+pub fn unsupported_version_response() -> Result<Vec<u8>, SerialisationError> {
+    let envelope = seal_server_msg(&())?;
+    serialisation::serialise(&envelope)
+}
+
+/// Decrypts traversal server's response to our request.
+pub fn open_server_response<T: DeserializeOwned>(
+    msg: &[u8],
+    shared_secret: &SharedSecretKey,
+) -> Result<T, QueryPublicAddrError> {
+    let envelope: Envelope = match shared_secret.decrypt(msg) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            if let Ok(envelope) = serialisation::deserialise::<Envelope>(msg) {
+                if let Err(mismatch) = envelope.negotiate_version(Capabilities::empty()) {
+                    return Err(QueryPublicAddrError::VersionMismatch(mismatch));
+                }
+            }
+            return Err(QueryPublicAddrError::Decrypt(e));
+        }
+    };
+    let _ = envelope
+        .negotiate_version(Capabilities::empty())
+        .map_err(QueryPublicAddrError::VersionMismatch)?;
+    envelope.open().map_err(QueryPublicAddrError::Deserialize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod negotiate_version {
+        use super::*;
+
+        #[test]
+        fn it_picks_the_highest_common_version() {
+            assert_eq!(unwrap!(negotiate_version((1, 3), (2, 5))), 3);
+            assert_eq!(unwrap!(negotiate_version((2, 5), (1, 3))), 3);
+            assert_eq!(unwrap!(negotiate_version((1, 1), (1, 1))), 1);
+        }
+
+        #[test]
+        fn when_version_ranges_dont_overlap_it_returns_error() {
+            let res = negotiate_version((1, 2), (3, 4));
+
+            assert_eq!(
+                res,
+                Err(VersionMismatch::Version((1, 2), (3, 4)))
+            );
+        }
+    }
+
+    mod envelope {
+        use super::*;
+
+        #[test]
+        fn it_negotiates_common_capabilities() {
+            let ours = Capabilities::TRICKLE.union(Capabilities::IPV6);
+            let theirs = Capabilities::IPV6.union(Capabilities::BIRTHDAY);
+            let envelope = unwrap!(Envelope::seal(&123u32, theirs, None));
+
+            let negotiated = unwrap!(envelope.negotiate(ours, None));
+
+            assert_eq!(negotiated.version, PROTOCOL_VERSION);
+            assert_eq!(negotiated.capabilities, Capabilities::IPV6);
+        }
+
+        #[test]
+        fn when_app_tags_differ_it_returns_error() {
+            let envelope = unwrap!(Envelope::seal(
+                &123u32,
+                Capabilities::empty(),
+                Some("app1".to_owned())
+            ));
+
+            let res = envelope.negotiate(Capabilities::empty(), Some("app2"));
+
+            match res {
+                Err(VersionMismatch::AppTag(..)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn when_app_tags_differ_version_negotiation_still_succeeds() {
+            let envelope = unwrap!(Envelope::seal(
+                &123u32,
+                Capabilities::empty(),
+                Some("app1".to_owned())
+            ));
+
+            let res = envelope.negotiate_version(Capabilities::empty());
+
+            assert!(res.is_ok());
+        }
+
+        #[test]
+        fn when_sender_speaks_newer_protocol_only_it_returns_error() {
+            let mut envelope = unwrap!(Envelope::seal(&123u32, Capabilities::empty(), None));
+            envelope.min_version = PROTOCOL_VERSION + 1;
+            envelope.max_version = PROTOCOL_VERSION + 1;
+
+            let res = envelope.negotiate(Capabilities::empty(), None);
+
+            match res {
+                Err(VersionMismatch::Version(..)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+
+        #[test]
+        fn it_opens_sealed_message() {
+            let envelope = unwrap!(Envelope::seal(&123u32, Capabilities::empty(), None));
+
+            let msg: u32 = unwrap!(envelope.open());
+
+            assert_eq!(msg, 123);
+        }
+    }
+
+    mod open_server_response {
+        use super::*;
+
+        #[test]
+        fn it_decrypts_server_response() {
+            let (pk, sk) = gen_encrypt_keypair();
+            let shared_secret = sk.shared_secret(&pk);
+            let addr = addr!("1.2.3.4:5000");
+            let envelope = unwrap!(seal_server_msg(&addr));
+            let msg = unwrap!(shared_secret.encrypt(&envelope));
+
+            let received_addr: SocketAddr = unwrap!(open_server_response(&msg, &shared_secret));
+
+            assert_eq!(received_addr, addr);
+        }
+
+        #[test]
+        fn when_server_speaks_other_versions_it_returns_version_mismatch() {
+            let (pk, sk) = gen_encrypt_keypair();
+            let shared_secret = sk.shared_secret(&pk);
+            let mut envelope = unwrap!(seal_server_msg(&()));
+            envelope.min_version = PROTOCOL_VERSION + 1;
+            envelope.max_version = PROTOCOL_VERSION + 1;
+            let msg = unwrap!(serialisation::serialise(&envelope));
+
+            let res = open_server_response::<SocketAddr>(&msg, &shared_secret);
+
+            match res {
+                Err(QueryPublicAddrError::VersionMismatch(..)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}